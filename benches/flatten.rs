@@ -0,0 +1,70 @@
+//! Times `mover::execute` on a synthetic `project/project/...` tree.
+//!
+//! Compare serial and parallel execution with:
+//!
+//! ```text
+//! cargo bench --bench flatten
+//! cargo bench --bench flatten --features parallel
+//! ```
+//!
+//! `FLATTEN_BENCH_FILES` sets the number of files (default 20000). The gain
+//! is largest when the nested directory is on another filesystem, where
+//! every move is a copy.
+
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use fs_cleaner::analyzer;
+use fs_cleaner::mover::{self, FlattenOptions};
+use tempfile::TempDir;
+
+const RUNS: usize = 5;
+
+fn build_tree(root: &Path, files: usize) {
+    let nested = root.join("project").join("project");
+    for dir in 0..files.div_ceil(1000) {
+        let dir = nested.join(format!("dir{dir:03}"));
+        fs::create_dir_all(&dir).unwrap();
+    }
+    for i in 0..files {
+        let path = nested.join(format!("dir{:03}", i / 1000));
+        fs::write(path.join(format!("file{i:06}.txt")), i.to_string()).unwrap();
+    }
+    for i in 0..files.min(1000) {
+        fs::write(nested.join(format!("top{i:04}.txt")), i.to_string()).unwrap();
+    }
+}
+
+fn run_once(files: usize) -> Duration {
+    let tmp = TempDir::new().unwrap();
+    build_tree(tmp.path(), files);
+
+    let candidates = analyzer::detect_nesting(&tmp.path().join("project")).unwrap();
+    let plan = mover::plan(&candidates[0], &FlattenOptions::default()).unwrap();
+
+    let start = Instant::now();
+    mover::execute(&plan, false, None).unwrap();
+    start.elapsed()
+}
+
+fn main() {
+    let files = std::env::var("FLATTEN_BENCH_FILES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(20_000);
+    let mode = if cfg!(feature = "parallel") {
+        "parallel"
+    } else {
+        "serial"
+    };
+
+    let mut times: Vec<Duration> = (0..RUNS).map(|_| run_once(files)).collect();
+    times.sort();
+    println!(
+        "execute ({mode}, {files} files): median {:?}, min {:?}, max {:?}",
+        times[RUNS / 2],
+        times[0],
+        times[RUNS - 1]
+    );
+}
@@ -1,67 +1,749 @@
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsStr;
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
+
+use log::warn;
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use unicode_normalization::UnicodeNormalization;
 
 use crate::{Error, Result};
 
+/// Default traversal depth for [`detect_nesting_recursive`].
+pub const DEFAULT_MAX_DEPTH: usize = 8;
+
+/// File names [`DetectOptions::noise`] ignores unless overridden: metadata
+/// that archivers and file browsers leave next to real content.
+pub const DEFAULT_NOISE: &[&str] = &[".DS_Store", "__MACOSX", "Thumbs.db"];
+
 /// A detected case of redundant directory nesting.
 #[derive(Debug, Clone)]
 pub struct NestingCandidate {
     /// The parent directory (e.g. `/home/app`).
     pub parent: PathBuf,
-    /// The redundant child directory (e.g. `/home/app/app`).
+    /// The innermost redundant directory (e.g. `/home/app/app`, or
+    /// `/home/app/app/app` when several levels collapse at once).
     pub nested: PathBuf,
-    /// Items inside the nested directory that would be moved up.
+    /// Items inside the nested directory that would be moved up, in
+    /// [`DetectOptions::sort`] order. The planned moves (and so the journal
+    /// and rollback) follow this order, unless
+    /// [`FlattenOptions::sort_moves`](crate::mover::FlattenOptions::sort_moves)
+    /// regroups it.
     pub children: Vec<PathBuf>,
+    /// Number of redundant levels between `parent` and `nested` (1 for
+    /// `app/app`, 2 for `app/app/app`, ...).
+    pub collapse_depth: usize,
+    /// The path as given, when it was a symlink resolving to `parent`.
+    /// Every other path in the candidate is canonical.
+    pub via_symlink: Option<PathBuf>,
+    /// The same-named entry in `parent` when it was a symlink, followed to
+    /// `nested` because of [`DetectOptions::follow_links`]. It is removed
+    /// along with the redundant directories.
+    pub nested_link: Option<PathBuf>,
+    /// Noise files (see [`DetectOptions::noise`]) that were ignored beside
+    /// a redundant directory, at the parent or an intermediate level.
+    pub noise: Vec<PathBuf>,
+}
+
+impl NestingCandidate {
+    /// How many redundant levels the chain collapses, as a measure of its
+    /// severity: 1 for `app/app`, 3 for `app/app/app/app`. The same as
+    /// [`collapse_depth`](Self::collapse_depth).
+    pub fn depth(&self) -> usize {
+        self.collapse_depth
+    }
+
+    /// The redundant directories that become empty once the children are
+    /// moved, ordered innermost first (i.e. the order they must be removed).
+    pub fn redundant_dirs(&self) -> Vec<PathBuf> {
+        self.nested
+            .ancestors()
+            .take(self.collapse_depth)
+            .map(Path::to_path_buf)
+            .collect()
+    }
+
+    /// Where `child` lands when flattened: the parent joined with its name.
+    /// `None` for a child named like one of the redundant directories
+    /// (those are removed, not moved) or without a name.
+    pub fn destination(&self, child: &Path) -> Option<PathBuf> {
+        let dest = self.parent.join(child.file_name()?);
+        (!self.redundant_dirs().contains(&dest)).then_some(dest)
+    }
+
+    /// Whether there is nothing to move: flattening only deletes the
+    /// redundant directories.
+    pub fn is_empty_nesting(&self) -> bool {
+        self.children.is_empty()
+    }
+
+    /// A short hex id that stays the same across runs for as long as the
+    /// nested directory's canonical path does, so tools can remember
+    /// decisions about a candidate. It hashes the path's components joined
+    /// with `/`, making it independent of the platform's separator.
+    pub fn id(&self) -> String {
+        let components: Vec<_> = self
+            .nested
+            .components()
+            .filter(|c| !matches!(c, Component::RootDir))
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect();
+        let digest = Sha256::digest(components.join("/").as_bytes());
+        digest[..6].iter().map(|b| format!("{b:02x}")).collect()
+    }
+}
+
+/// The order of [`NestingCandidate::children`]. Both orders compare file
+/// names alone, as Unicode text, so the result is the same on every
+/// platform and filesystem whatever order the directory is read in. Names
+/// that are not valid UTF-8 compare by their lossy conversion, then by
+/// their raw bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SortOrder {
+    /// By Unicode code point, so `B` sorts before `a` (the default).
+    #[default]
+    CodePoint,
+    /// Ignoring case (`a`, `B`, `c`), ties broken by code point so the
+    /// order is still total.
+    CaseInsensitive,
+}
+
+impl SortOrder {
+    /// Sort `paths` by file name in this order.
+    pub fn sort(self, paths: &mut [PathBuf]) {
+        paths.sort_by(|a, b| {
+            let (a_name, b_name) = (a.file_name(), b.file_name());
+            let (a_text, b_text) = (
+                a_name.map(OsStr::to_string_lossy),
+                b_name.map(OsStr::to_string_lossy),
+            );
+            let folded = match self {
+                SortOrder::CodePoint => std::cmp::Ordering::Equal,
+                SortOrder::CaseInsensitive => a_text
+                    .as_deref()
+                    .map(str::to_lowercase)
+                    .cmp(&b_text.as_deref().map(str::to_lowercase)),
+            };
+            folded
+                .then_with(|| a_text.cmp(&b_text))
+                .then_with(|| a_name.cmp(&b_name))
+                .then_with(|| a.cmp(b))
+        });
+    }
+}
+
+/// How a child directory's name is compared against its parent's name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum NameMatch {
+    /// Byte-for-byte equality (the default).
+    #[default]
+    Exact,
+    /// Unicode-aware case folding, for case-insensitive filesystems where
+    /// `Photos/photos` is effectively the same name.
+    CaseInsensitive,
+    /// Equality after stripping the longest matching prefix in
+    /// `strip_prefixes` and the longest matching suffix in `strip_suffixes`
+    /// from each name, so decorated copies such as `My Project/My Project
+    /// (copy)` or `data/data_backup` count as redundant. With `ignore_case`
+    /// names and affixes are compared as [`CaseInsensitive`](Self::CaseInsensitive)
+    /// does. A name that would be stripped to nothing is kept whole.
+    ///
+    /// This is riskier than the other modes: a sibling that merely looks
+    /// like a copy (`data/data 2`, a second dataset) gets flattened too.
+    /// Review such flattens with `apply --interactive`.
+    Fuzzy {
+        strip_suffixes: Vec<String>,
+        strip_prefixes: Vec<String>,
+        ignore_case: bool,
+    },
+}
+
+impl NameMatch {
+    /// Returns `true` if `child` counts as the same name as `parent`.
+    pub fn matches(&self, parent: &OsStr, child: &OsStr) -> bool {
+        match self {
+            NameMatch::Exact => parent == child,
+            // Names that are not valid UTF-8 fall back to exact comparison.
+            NameMatch::CaseInsensitive => match (parent.to_str(), child.to_str()) {
+                (Some(p), Some(c)) => p.to_lowercase() == c.to_lowercase(),
+                _ => parent == child,
+            },
+            NameMatch::Fuzzy {
+                strip_suffixes,
+                strip_prefixes,
+                ignore_case,
+            } => match (parent.to_str(), child.to_str()) {
+                (Some(p), Some(c)) => {
+                    let fold = |s: &str| {
+                        if *ignore_case {
+                            s.to_lowercase()
+                        } else {
+                            s.to_string()
+                        }
+                    };
+                    let prefixes: Vec<_> = strip_prefixes.iter().map(|a| fold(a)).collect();
+                    let suffixes: Vec<_> = strip_suffixes.iter().map(|a| fold(a)).collect();
+                    strip_affixes(&fold(p), &prefixes, &suffixes)
+                        == strip_affixes(&fold(c), &prefixes, &suffixes)
+                }
+                _ => parent == child,
+            },
+        }
+    }
+}
+
+/// `name` without its longest matching prefix and then its longest
+/// matching suffix, or whole if that would leave nothing.
+fn strip_affixes<'a>(name: &'a str, prefixes: &[String], suffixes: &[String]) -> &'a str {
+    let longest = |affixes: &[String], strip: &dyn Fn(&str) -> Option<&'a str>| {
+        affixes
+            .iter()
+            .filter(|a| !a.is_empty())
+            .filter_map(|a| strip(a))
+            .min_by_key(|rest| rest.len())
+    };
+    let rest = longest(prefixes, &|a| name.strip_prefix(a)).unwrap_or(name);
+    let rest = longest(suffixes, &|a| rest.strip_suffix(a)).unwrap_or(rest);
+    if rest.is_empty() { name } else { rest }
+}
+
+/// Knobs for [`detect_nesting_with_options`].
+#[derive(Debug, Clone)]
+pub struct DetectOptions {
+    /// How nested directory names are compared against the parent's name.
+    pub name_match: NameMatch,
+    /// Skip candidates whose nested directory has fewer distinct immediate
+    /// children than this (default 1). Empty nestings are always reported,
+    /// since removing them loses nothing.
+    pub min_children: usize,
+    /// Don't descend into directories excluded by `.gitignore`, `.ignore`
+    /// and similar files. Only [`detect_nesting_recursive`] walks a tree, so
+    /// this has no effect on single-directory detection.
+    pub respect_ignore: bool,
+    /// File names that don't count when deciding whether a directory holds
+    /// nothing but the next redundant level (or, for
+    /// [`detect_single_wrapper_with_options`], the wrapped directory).
+    /// Defaults to [`DEFAULT_NOISE`].
+    pub noise: Vec<String>,
+    /// Compare names after trimming leading and trailing whitespace and
+    /// control characters and NFC-normalizing them, so `project ` (as some
+    /// archivers write it) matches `project`. Off by default.
+    pub normalize_names: bool,
+    /// Flatten through a nested entry that is a symlink to a directory:
+    /// the target's contents move into the parent, then the target and the
+    /// link are removed. Off by default, when such a nesting is refused
+    /// with [`Error::NestedIsSymlink`]: treating the link as an ordinary
+    /// directory would move the link itself, leaving the real contents
+    /// where they were. Mind that the target may live anywhere, and is
+    /// emptied and deleted wherever that is.
+    pub follow_links: bool,
+    /// The order of each candidate's children, and so of the moves unless
+    /// [`FlattenOptions::sort_moves`](crate::mover::FlattenOptions::sort_moves)
+    /// regroups them. The contents of merged directories are always listed
+    /// in code-point order.
+    pub sort: SortOrder,
+    /// Only report nesting when the same-named directory is the parent's
+    /// sole entry (besides [noise](Self::noise)). Off by default, when the
+    /// parent may hold other entries too.
+    pub only_child: bool,
+}
+
+impl DetectOptions {
+    /// Whether `child` counts as the same name as `parent` under
+    /// [`name_match`](Self::name_match), after
+    /// [normalization](Self::normalize_names) if enabled. Names that are not
+    /// valid UTF-8 are never normalized.
+    pub fn names_match(&self, parent: &OsStr, child: &OsStr) -> bool {
+        if !self.normalize_names {
+            return self.name_match.matches(parent, child);
+        }
+        match (parent.to_str(), child.to_str()) {
+            (Some(p), Some(c)) => self.name_match.matches(
+                OsStr::new(&normalize_name(p)),
+                OsStr::new(&normalize_name(c)),
+            ),
+            _ => self.name_match.matches(parent, child),
+        }
+    }
+}
+
+/// Trim whitespace and control characters from both ends and convert to
+/// Unicode normalization form C.
+fn normalize_name(name: &str) -> String {
+    name.trim_matches(|c: char| c.is_whitespace() || c.is_control())
+        .nfc()
+        .collect()
+}
+
+impl Default for DetectOptions {
+    fn default() -> Self {
+        Self {
+            name_match: NameMatch::default(),
+            min_children: 1,
+            respect_ignore: false,
+            noise: DEFAULT_NOISE.iter().map(|n| n.to_string()).collect(),
+            normalize_names: false,
+            follow_links: false,
+            sort: SortOrder::default(),
+            only_child: false,
+        }
+    }
 }
 
 /// Analyze a directory tree for redundant nesting patterns.
 ///
-/// A directory is considered redundantly nested when it contains a single
+/// A directory is considered redundantly nested when it contains a
 /// subdirectory whose name matches its own name (e.g. `project/project/...`).
+/// Detection keeps descending while each level holds nothing but another
+/// same-named directory, so `project/project/project/...` collapses into a
+/// single candidate with `collapse_depth == 2`.
+///
+/// The parent itself may hold other entries beside the same-named
+/// directory (`project/{project,README.md}`): they stay where they are and
+/// the nested children move in next to them, subject to the collision
+/// checks. Set [`DetectOptions::only_child`] to require the same-named
+/// directory to be the parent's only entry, noise aside.
+///
+/// Uses [`DetectOptions::default`], i.e. exact name matching and at least
+/// one child. This is `detect_nesting_with(root, exact_match)`, except that
+/// the name is looked up directly instead of listing `root`.
 pub fn detect_nesting(root: &Path) -> Result<Vec<NestingCandidate>> {
-    let root = root.canonicalize().map_err(|e| Error::Io {
-        path: root.to_path_buf(),
-        source: e,
-    })?;
+    detect_nesting_with_options(root, &DetectOptions::default())
+}
+
+/// Whether `root` holds redundant nesting: a subdirectory with exactly its
+/// own name. Uses the default heuristic of [`detect_nesting`] (exact names,
+/// one level) but stops at that single lookup, without listing either
+/// directory. A same-named symlink does not count, since [`detect_nesting`]
+/// refuses it without [`DetectOptions::follow_links`].
+///
+/// Fails with [`Error::NotADirectory`] if `root` is not a directory.
+pub fn is_redundant_nesting(root: &Path) -> Result<bool> {
+    let (root, _) = resolve_root(root)?;
+    let Some(name) = root.file_name() else {
+        return Ok(false);
+    };
+    let nested = root.join(name);
+    Ok(fs::symlink_metadata(&nested).is_ok_and(|m| m.is_dir()))
+}
+
+/// Like [`detect_nesting`], with explicit detection options.
+pub fn detect_nesting_with_options(
+    root: &Path,
+    options: &DetectOptions,
+) -> Result<Vec<NestingCandidate>> {
+    let lookup = options.name_match == NameMatch::Exact && !options.normalize_names;
+    detect_nesting_by(root, options, &|p, c| options.names_match(p, c), lookup)
+}
+
+/// Like [`detect_nesting`], with `predicate` deciding what counts as
+/// redundant: it is called with the parent's name and a child directory's
+/// name, at every level of a chain. The other options are the defaults.
+///
+/// Where several children qualify, one spelled exactly like the parent
+/// wins, then the first in sorted order.
+pub fn detect_nesting_with(
+    root: &Path,
+    predicate: impl Fn(&OsStr, &OsStr) -> bool,
+) -> Result<Vec<NestingCandidate>> {
+    detect_nesting_by(root, &DetectOptions::default(), &predicate, false)
+}
+
+/// The predicate [`detect_nesting`] uses: the names are identical.
+pub fn exact_match(parent: &OsStr, child: &OsStr) -> bool {
+    parent == child
+}
+
+/// Detection with names compared by `matches`. With `lookup`, `matches` must
+/// be exact equality, and the child is found by name instead of by listing.
+fn detect_nesting_by(
+    root: &Path,
+    options: &DetectOptions,
+    matches: &dyn Fn(&OsStr, &OsStr) -> bool,
+    lookup: bool,
+) -> Result<Vec<NestingCandidate>> {
+    let (root, via_symlink) = resolve_root(root)?;
 
     let dir_name = root
         .file_name()
         .ok_or_else(|| Error::Other(format!("cannot determine name of {}", root.display())))?;
 
-    let candidate = root.join(dir_name);
+    let Some(mut nested) = find_named_child(&root, dir_name, matches, lookup)? else {
+        return Ok(vec![]);
+    };
+    if options.only_child
+        && single_entry(&root, &options.noise)?.is_none_or(|(only, _)| only != nested)
+    {
+        return Ok(vec![]);
+    }
+
+    let mut nested_link = None;
+    if nested.is_symlink() {
+        let target = nested
+            .canonicalize()
+            .map_err(|e| Error::io(nested.clone(), e))?;
+        if !options.follow_links {
+            return Err(Error::NestedIsSymlink {
+                link: nested,
+                target,
+            });
+        }
+        if root.starts_with(&target) {
+            return Err(Error::Other(format!(
+                "{} points at {}, which contains it; there is nothing to flatten",
+                nested.display(),
+                target.display()
+            )));
+        }
+        nested_link = Some(nested);
+        nested = target;
+    }
+
+    let mut collapse_depth = 1;
+    let mut noise = Vec::new();
 
-    if !candidate.is_dir() {
+    // Descend while the current level contains exactly one entry (besides
+    // noise): a real (non-symlink) directory with the same name. Symlinks
+    // stop the chain so every path stays under the canonicalized root.
+    while let Some((only, level_noise)) = single_entry(&nested, &options.noise)? {
+        let is_real_dir = fs::symlink_metadata(&only)
+            .map(|m| m.is_dir())
+            .unwrap_or(false);
+        let same_name = only.file_name().is_some_and(|name| matches(dir_name, name));
+        if !is_real_dir || !same_name {
+            break;
+        }
+        nested = only;
+        noise.extend(level_noise);
+        collapse_depth += 1;
+    }
+    let children = list_dir_sorted(&nested, options.sort)?;
+
+    if !children.is_empty() && distinct_entries(&children) < options.min_children {
         return Ok(vec![]);
     }
 
-    let children = list_dir(&candidate)?;
+    Ok(vec![NestingCandidate {
+        parent: root.clone(),
+        nested,
+        children,
+        collapse_depth,
+        via_symlink,
+        nested_link,
+        noise,
+    }])
+}
+
+/// Walk the whole tree beneath `root` and collect every nesting candidate.
+///
+/// Directories deeper than `max_depth` levels below `root` are not examined.
+/// Once a directory is flagged, its redundant child is not descended into,
+/// so a chain is reported once rather than once per level. Unreadable
+/// subdirectories are logged and skipped. With
+/// [`DetectOptions::respect_ignore`], directories matched by ignore files
+/// (e.g. `node_modules` listed in `.gitignore`) are skipped too.
+pub fn detect_nesting_recursive(
+    root: &Path,
+    max_depth: usize,
+    options: &DetectOptions,
+) -> Result<Vec<NestingCandidate>> {
+    let (root, via_symlink) = resolve_root(root)?;
+
+    let mut candidates: Vec<NestingCandidate> = Vec::new();
+    // Redundant children of flagged directories, pruned as the walk
+    // reaches them.
+    let skip: Arc<Mutex<Vec<PathBuf>>> = Arc::default();
+    let walker = ignore::WalkBuilder::new(&root)
+        .standard_filters(options.respect_ignore)
+        .hidden(false)
+        .require_git(false)
+        .max_depth(Some(max_depth))
+        .follow_links(false)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .filter_entry({
+            let skip = Arc::clone(&skip);
+            move |entry| {
+                let skip = skip.lock().expect("skip list is never poisoned");
+                !skip.iter().any(|s| s == entry.path())
+            }
+        })
+        .build();
+
+    for entry in walker {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("skipping unreadable entry: {e}");
+                continue;
+            }
+        };
+        if !entry.file_type().is_some_and(|t| t.is_dir()) {
+            continue;
+        }
+
+        examine(
+            entry.path(),
+            (&root, via_symlink.as_deref()),
+            options,
+            &mut candidates,
+            &mut skip.lock().expect("skip list is never poisoned"),
+        )?;
+    }
+
+    Ok(candidates)
+}
+
+/// Group the result of [`detect_nesting_recursive`] over `root` by the
+/// top-level project each candidate lies in: the child of `root` holding
+/// it, or `root` itself for a nesting found at the top. Keys are canonical,
+/// so the grouping depends only on the scan root; candidates keep their
+/// walk order within a group.
+pub fn group_by_project(
+    root: &Path,
+    candidates: Vec<NestingCandidate>,
+) -> Result<BTreeMap<PathBuf, Vec<NestingCandidate>>> {
+    let (root, _) = resolve_root(root)?;
+    let mut groups: BTreeMap<PathBuf, Vec<NestingCandidate>> = BTreeMap::new();
+    for candidate in candidates {
+        let project = match candidate.parent.strip_prefix(&root) {
+            Ok(rel) => match rel.components().next() {
+                Some(first) => root.join(first),
+                None => root.clone(),
+            },
+            Err(_) => candidate.parent.clone(),
+        };
+        groups.entry(project).or_default().push(candidate);
+    }
+    Ok(groups)
+}
+
+/// Run single-directory detection on `path` during a recursive walk,
+/// recording any candidate and the redundant child not to descend into.
+/// `root` is the walk's canonical root and the symlink it was reached by.
+fn examine(
+    path: &Path,
+    (root, root_link): (&Path, Option<&Path>),
+    options: &DetectOptions,
+    candidates: &mut Vec<NestingCandidate>,
+    skip: &mut Vec<PathBuf>,
+) -> Result<()> {
+    match detect_nesting_with_options(path, options) {
+        Ok(found) => {
+            for mut candidate in found {
+                if path == root {
+                    candidate.via_symlink = root_link.map(Path::to_path_buf);
+                }
+                skip.extend(candidate.redundant_dirs().pop());
+                candidates.push(candidate);
+            }
+        }
+        Err(e) if path == root => return Err(e),
+        Err(e) => warn!("skipping {}: {e}", path.display()),
+    }
+    Ok(())
+}
+
+/// Analyze a directory for a single-wrapper layout.
+///
+/// A directory is a single wrapper when its only entry is itself a directory,
+/// regardless of name (e.g. `mypkg/mypkg-1.2.3/...` as produced by `unzip`
+/// or `tar`). This is more eager than [`detect_nesting`], so callers must opt
+/// in explicitly.
+pub fn detect_single_wrapper(root: &Path) -> Result<Vec<NestingCandidate>> {
+    detect_single_wrapper_with_options(root, &DetectOptions::default())
+}
+
+/// Like [`detect_single_wrapper`], with noise file names taken from
+/// `options`; the other options don't apply to wrappers.
+pub fn detect_single_wrapper_with_options(
+    root: &Path,
+    options: &DetectOptions,
+) -> Result<Vec<NestingCandidate>> {
+    detect_wrapper(root, options, |_, _| true)
+}
+
+/// Detect a lone subdirectory named after a version of its parent.
+///
+/// The child must be a version (`1.0.0`, `v2`) or the parent's name plus one
+/// (`tool-v2`, `tool_1.4.0-rc.1`), compared under `options` like
+/// same-name nesting. This catches the common release-archive layouts that
+/// [`detect_nesting`] misses without firing on every single-child directory
+/// the way [`detect_single_wrapper`] does. Callers still opt in explicitly.
+pub fn detect_version_wrapper(
+    root: &Path,
+    options: &DetectOptions,
+) -> Result<Vec<NestingCandidate>> {
+    detect_wrapper(root, options, |parent, child| {
+        is_version_of(parent, child, options)
+    })
+}
+
+/// A version string: an optional `v`, dot-separated numbers, and an
+/// optional pre-release or build suffix.
+static VERSION: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[vV]?\d+(\.\d+)*([-+][0-9A-Za-z.+-]+)?$").unwrap());
+
+/// Whether `child` is a version, or `parent`'s name followed by one (with
+/// an optional `-`, `_`, `.` or space between them).
+fn is_version_of(parent: &OsStr, child: &OsStr, options: &DetectOptions) -> bool {
+    let (Some(parent), Some(child)) = (parent.to_str(), child.to_str()) else {
+        return false;
+    };
+    if VERSION.is_match(child) {
+        return true;
+    }
+    let Some((prefix, rest)) = child.split_at_checked(parent.len()) else {
+        return false;
+    };
+    let rest = rest.strip_prefix(['-', '_', '.', ' ']).unwrap_or(rest);
+    options.names_match(OsStr::new(parent), OsStr::new(prefix)) && VERSION.is_match(rest)
+}
+
+/// A candidate for `root`'s only entry (noise aside), if it is a real
+/// directory whose name `accept` takes given the parent's.
+fn detect_wrapper(
+    root: &Path,
+    options: &DetectOptions,
+    accept: impl Fn(&OsStr, &OsStr) -> bool,
+) -> Result<Vec<NestingCandidate>> {
+    let (root, via_symlink) = resolve_root(root)?;
+
+    let Some((only, noise)) = single_entry(&root, &options.noise)? else {
+        return Ok(vec![]);
+    };
+
+    let is_real_dir = fs::symlink_metadata(&only)
+        .map(|m| m.is_dir())
+        .unwrap_or(false);
+    let named = match (root.file_name(), only.file_name()) {
+        (Some(parent), Some(child)) => accept(parent, child),
+        _ => false,
+    };
+    if !is_real_dir || !named {
+        return Ok(vec![]);
+    }
+
+    let children = list_dir_sorted(&only, options.sort)?;
 
     Ok(vec![NestingCandidate {
         parent: root.clone(),
-        nested: candidate,
+        nested: only,
         children,
+        collapse_depth: 1,
+        via_symlink,
+        nested_link: None,
+        noise,
     }])
 }
 
-/// List immediate children of a directory.
-fn list_dir(path: &Path) -> Result<Vec<PathBuf>> {
-    let entries = fs::read_dir(path).map_err(|e| Error::Io {
-        path: path.to_path_buf(),
-        source: e,
-    })?;
+/// Canonicalize a root given by the user, keeping the original path if it
+/// was a symlink so messages can mention the name the user typed. Anything
+/// but a directory is refused with [`Error::NotADirectory`].
+fn resolve_root(root: &Path) -> Result<(PathBuf, Option<PathBuf>)> {
+    let canonical = root
+        .canonicalize()
+        .map_err(|e| Error::io(root.to_path_buf(), e))?;
+    if !canonical.is_dir() {
+        return Err(Error::NotADirectory {
+            path: root.to_path_buf(),
+        });
+    }
+    Ok((canonical, root.is_symlink().then(|| root.to_path_buf())))
+}
+
+/// Find the subdirectory of `root` whose name matches `name`.
+///
+/// Non-exact modes (including normalized names) list `root` so the
+/// returned path carries the on-disk spelling; an exact match is preferred
+/// when several entries qualify.
+fn find_named_child(
+    root: &Path,
+    name: &OsStr,
+    matches: &dyn Fn(&OsStr, &OsStr) -> bool,
+    lookup: bool,
+) -> Result<Option<PathBuf>> {
+    if lookup {
+        let candidate = root.join(name);
+        return Ok(candidate.is_dir().then_some(candidate));
+    }
 
-    let mut result = Vec::new();
-    for entry in entries {
-        let entry = entry.map_err(|e| Error::Io {
-            path: path.to_path_buf(),
-            source: e,
-        })?;
-        result.push(entry.path());
+    // Stop at an exact match; otherwise keep the first match in sorted
+    // order without collecting the whole listing.
+    let mut best: Option<PathBuf> = None;
+    for path in list_dir_iter(root)? {
+        let path = path?;
+        if !path.file_name().is_some_and(|n| matches(name, n)) || !path.is_dir() {
+            continue;
+        }
+        if path.file_name() == Some(name) {
+            return Ok(Some(path));
+        }
+        if best.as_ref().is_none_or(|b| path < *b) {
+            best = Some(path);
+        }
     }
-    result.sort();
+    Ok(best)
+}
+
+/// Count `paths` after resolving symlinks, so a link to a sibling is not
+/// counted twice. Paths that cannot be resolved count as themselves.
+fn distinct_entries(paths: &[PathBuf]) -> usize {
+    paths
+        .iter()
+        .map(|p| p.canonicalize().unwrap_or_else(|_| p.clone()))
+        .collect::<HashSet<_>>()
+        .len()
+}
+
+/// List immediate children of a directory, sorted by
+/// [`SortOrder::CodePoint`].
+pub(crate) fn list_dir(path: &Path) -> Result<Vec<PathBuf>> {
+    list_dir_sorted(path, SortOrder::default())
+}
+
+/// List immediate children of a directory in `order`.
+pub(crate) fn list_dir_sorted(path: &Path, order: SortOrder) -> Result<Vec<PathBuf>> {
+    let mut result = list_dir_iter(path)?.collect::<Result<Vec<_>>>()?;
+    order.sort(&mut result);
     Ok(result)
 }
 
+/// Lazily yield the immediate children of a directory, in the order the
+/// filesystem returns them. Suited to very large directories where only
+/// a few entries (or a name lookup) are needed.
+pub(crate) fn list_dir_iter(path: &Path) -> Result<impl Iterator<Item = Result<PathBuf>>> {
+    let entries = fs::read_dir(path).map_err(|e| Error::io(path.to_path_buf(), e))?;
+    let dir = path.to_path_buf();
+    Ok(entries.map(move |entry| {
+        entry
+            .map(|e| e.path())
+            .map_err(|e| Error::io(dir.clone(), e))
+    }))
+}
+
+/// The only entry of `path` whose name is not in `noise`, along with the
+/// noise entries beside it; `None` if there are zero or several such
+/// entries. Stops reading at the second one.
+fn single_entry(path: &Path, noise: &[String]) -> Result<Option<(PathBuf, Vec<PathBuf>)>> {
+    let mut only = None;
+    let mut skipped = Vec::new();
+    for entry in list_dir_iter(path)? {
+        let entry = entry?;
+        if entry
+            .file_name()
+            .is_some_and(|n| noise.iter().any(|noise| n == noise.as_str()))
+        {
+            skipped.push(entry);
+        } else if only.replace(entry).is_some() {
+            return Ok(None);
+        }
+    }
+    skipped.sort();
+    Ok(only.map(|only| (only, skipped)))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -87,6 +769,604 @@ mod tests {
             root.canonicalize().unwrap().join("project")
         );
         assert_eq!(candidate.children.len(), 2); // README.md and src/
+        assert_eq!(candidate.collapse_depth, 1);
+    }
+
+    #[test]
+    fn detect_multi_level_nesting() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().join("project");
+        let innermost = root.join("project").join("project").join("project");
+
+        fs::create_dir_all(innermost.join("src")).unwrap();
+        fs::write(innermost.join("README.md"), "hello").unwrap();
+
+        let results = detect_nesting(&root).unwrap();
+        assert_eq!(results.len(), 1);
+
+        let candidate = &results[0];
+        let root_canon = root.canonicalize().unwrap();
+        assert_eq!(candidate.parent, root_canon);
+        assert_eq!(candidate.nested, root_canon.join("project/project/project"));
+        assert_eq!(candidate.collapse_depth, 3);
+        assert_eq!(candidate.depth(), 3);
+        assert_eq!(candidate.children.len(), 2);
+        assert_eq!(
+            candidate.redundant_dirs(),
+            vec![
+                root_canon.join("project/project/project"),
+                root_canon.join("project/project"),
+                root_canon.join("project"),
+            ]
+        );
+    }
+
+    #[test]
+    fn multi_level_stops_when_level_has_siblings() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().join("project");
+        let level1 = root.join("project");
+
+        fs::create_dir_all(level1.join("project").join("src")).unwrap();
+        fs::write(level1.join("notes.txt"), "sibling").unwrap();
+
+        let results = detect_nesting(&root).unwrap();
+        let candidate = &results[0];
+        assert_eq!(candidate.collapse_depth, 1);
+        assert_eq!(
+            candidate.nested,
+            root.canonicalize().unwrap().join("project")
+        );
+        assert_eq!(candidate.children.len(), 2); // notes.txt and project/
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_root_is_recorded_alongside_the_target() {
+        let tmp = TempDir::new().unwrap();
+        let real = tmp.path().join("project");
+        fs::create_dir_all(real.join("project/src")).unwrap();
+        let link = tmp.path().join("shortcut");
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+
+        let candidate = detect_nesting(&link).unwrap().remove(0);
+        assert_eq!(candidate.parent, real.canonicalize().unwrap());
+        assert_eq!(candidate.via_symlink, Some(link.clone()));
+
+        let options = DetectOptions::default();
+        let recursive = detect_nesting_recursive(&link, DEFAULT_MAX_DEPTH, &options).unwrap();
+        assert_eq!(recursive[0].via_symlink, Some(link));
+        assert_eq!(detect_nesting(&real).unwrap()[0].via_symlink, None);
+    }
+
+    #[test]
+    fn case_insensitive_nesting_detected() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().join("Photos");
+        fs::create_dir_all(root.join("photos").join("2024")).unwrap();
+
+        // Exact matching (the default) misses differently-cased names.
+        assert!(detect_nesting(&root).unwrap().is_empty());
+
+        let options = DetectOptions {
+            name_match: NameMatch::CaseInsensitive,
+            ..Default::default()
+        };
+        let results = detect_nesting_with_options(&root, &options).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            results[0].nested,
+            root.canonicalize().unwrap().join("photos")
+        );
+    }
+
+    #[test]
+    fn lazy_listing_keeps_lookup_and_chain_results() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().join("Data");
+        for name in ["dATA", "Data", "data"] {
+            fs::create_dir_all(root.join(name)).unwrap();
+        }
+        for i in 0..500 {
+            fs::write(root.join(format!("f{i}")), "").unwrap();
+        }
+
+        let mut listed = list_dir_iter(&root)
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        listed.sort();
+        assert_eq!(listed, list_dir(&root).unwrap());
+
+        // The exact spelling wins; without it, the first in sorted order.
+        let find = |name: &str| {
+            let options = DetectOptions {
+                name_match: NameMatch::CaseInsensitive,
+                ..Default::default()
+            };
+            let matches = |p: &OsStr, c: &OsStr| options.names_match(p, c);
+            find_named_child(&root, OsStr::new(name), &matches, false)
+                .unwrap()
+                .unwrap()
+        };
+        assert_eq!(find("Data"), root.join("Data"));
+        assert_eq!(find("DATA"), root.join("Data"));
+        assert!(single_entry(&root, &[]).unwrap().is_none());
+        fs::write(root.join("data/only"), "").unwrap();
+        assert_eq!(
+            single_entry(&root.join("data"), &[]).unwrap(),
+            Some((root.join("data/only"), vec![]))
+        );
+    }
+
+    #[test]
+    fn noise_files_do_not_stop_wrappers_or_chains() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().join("pkg");
+        fs::create_dir_all(root.join("pkg-1.2/pkg-1.2/src")).unwrap();
+        fs::create_dir(root.join("__MACOSX")).unwrap();
+        let wrapper = detect_single_wrapper(&root).unwrap().remove(0);
+        let canonical = root.canonicalize().unwrap();
+        assert_eq!(wrapper.nested, canonical.join("pkg-1.2"));
+        assert_eq!(wrapper.noise, vec![canonical.join("__MACOSX")]);
+
+        // Overriding the list makes the stray entry count again.
+        let options = DetectOptions {
+            noise: vec!["Thumbs.db".into()],
+            ..Default::default()
+        };
+        assert!(
+            detect_single_wrapper_with_options(&root, &options)
+                .unwrap()
+                .is_empty()
+        );
+
+        let chain = root.join("pkg-1.2");
+        fs::write(chain.join("pkg-1.2/.DS_Store"), "").unwrap();
+        fs::create_dir(chain.join("pkg-1.2/pkg-1.2")).unwrap();
+        fs::rename(chain.join("pkg-1.2/src"), chain.join("pkg-1.2/pkg-1.2/src")).unwrap();
+        let found = detect_nesting(&chain).unwrap().remove(0);
+        assert_eq!(found.collapse_depth, 2);
+        assert_eq!(
+            found.noise,
+            vec![chain.canonicalize().unwrap().join("pkg-1.2/.DS_Store")]
+        );
+    }
+
+    #[test]
+    fn normalized_names_match_despite_trailing_space() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().join("project");
+        let nested = root.join("project ");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(nested.join("line\nbreak.txt"), "data").unwrap();
+
+        assert!(detect_nesting(&root).unwrap().is_empty());
+        let options = DetectOptions {
+            normalize_names: true,
+            ..Default::default()
+        };
+        let candidate = detect_nesting_with_options(&root, &options)
+            .unwrap()
+            .remove(0);
+        assert!(candidate.nested.ends_with("project "));
+
+        // A name with an embedded newline moves like any other.
+        crate::mover::flatten(&candidate, false, &Default::default()).unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("line\nbreak.txt")).unwrap(),
+            "data"
+        );
+        assert!(!nested.exists());
+    }
+
+    #[test]
+    fn normalization_trims_and_composes() {
+        let options = DetectOptions {
+            normalize_names: true,
+            ..Default::default()
+        };
+        // "é" precomposed vs. "e" + combining acute.
+        assert!(options.names_match(OsStr::new("caf\u{e9}"), OsStr::new("cafe\u{301}\t")));
+        assert!(options.names_match(OsStr::new("\u{7}pkg"), OsStr::new(" pkg ")));
+        assert!(!options.names_match(OsStr::new("pkg"), OsStr::new("p kg")));
+        assert!(!DetectOptions::default().names_match(OsStr::new("pkg"), OsStr::new("pkg ")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_nested_dir_is_refused_unless_followed() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().join("app");
+        let store = tmp.path().join("store");
+        fs::create_dir_all(&root).unwrap();
+        fs::create_dir_all(store.join("src")).unwrap();
+        std::os::unix::fs::symlink(&store, root.join("app")).unwrap();
+
+        let err = detect_nesting(&root).unwrap_err();
+        assert!(matches!(err, Error::NestedIsSymlink { .. }));
+
+        let options = DetectOptions {
+            follow_links: true,
+            ..Default::default()
+        };
+        let candidate = detect_nesting_with_options(&root, &options)
+            .unwrap()
+            .remove(0);
+        assert_eq!(candidate.nested, store.canonicalize().unwrap());
+        assert_eq!(
+            candidate.nested_link,
+            Some(root.canonicalize().unwrap().join("app"))
+        );
+
+        // A link back to the parent itself has nothing to offer.
+        fs::remove_file(root.join("app")).unwrap();
+        std::os::unix::fs::symlink(".", root.join("app")).unwrap();
+        assert!(matches!(
+            detect_nesting_with_options(&root, &options),
+            Err(Error::Other(_))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn min_children_counts_symlinked_entries_once() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().join("project");
+        let nested = root.join("project");
+        fs::create_dir_all(nested.join("v2")).unwrap();
+        std::os::unix::fs::symlink("v2", nested.join("latest")).unwrap();
+
+        let options = |min_children| DetectOptions {
+            min_children,
+            ..Default::default()
+        };
+        assert_eq!(
+            detect_nesting_with_options(&root, &options(1))
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(
+            detect_nesting_with_options(&root, &options(2))
+                .unwrap()
+                .is_empty()
+        );
+
+        fs::write(nested.join("README.md"), "hi").unwrap();
+        assert_eq!(
+            detect_nesting_with_options(&root, &options(2))
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn empty_nesting_reported_whatever_the_threshold() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().join("project");
+        fs::create_dir_all(root.join("project")).unwrap();
+
+        let options = DetectOptions {
+            min_children: 5,
+            ..Default::default()
+        };
+        let results = detect_nesting_with_options(&root, &options).unwrap();
+        assert!(results[0].is_empty_nesting());
+    }
+
+    #[test]
+    fn fuzzy_match_strips_configured_affixes() {
+        let fuzzy = |ignore_case| NameMatch::Fuzzy {
+            strip_suffixes: [" (copy)", "_backup", " 2"].map(String::from).to_vec(),
+            strip_prefixes: vec!["Copy of ".into()],
+            ignore_case,
+        };
+        let matches = |mode: &NameMatch, parent: &str, child: &str| {
+            mode.matches(OsStr::new(parent), OsStr::new(child))
+        };
+        let mode = fuzzy(false);
+        assert!(matches(&mode, "My Project", "My Project (copy)"));
+        assert!(matches(&mode, "data", "data_backup"));
+        assert!(matches(&mode, "Photos", "Photos 2"));
+        assert!(matches(&mode, "report", "Copy of report"));
+        assert!(matches(&mode, "data", "data"));
+        assert!(!matches(&mode, "data", "database"));
+        assert!(!matches(&mode, "data", "data 3"));
+        assert!(!matches(&mode, "data", "_backup"));
+        assert!(!matches(&mode, "Photos", "photos 2"));
+        assert!(!NameMatch::Exact.matches(OsStr::new("data"), OsStr::new("data_backup")));
+
+        // Composes with case folding, affixes included.
+        let mode = fuzzy(true);
+        assert!(matches(&mode, "Photos", "photos 2"));
+        assert!(matches(&mode, "DATA", "data_BACKUP"));
+        assert!(matches(&mode, "Report", "copy of report (Copy)"));
+    }
+
+    #[test]
+    fn fuzzy_nesting_detected() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().join("My Project");
+        fs::create_dir_all(root.join("My Project (copy)").join("src")).unwrap();
+
+        assert!(detect_nesting(&root).unwrap().is_empty());
+
+        let options = DetectOptions {
+            name_match: NameMatch::Fuzzy {
+                strip_suffixes: vec![" (copy)".into()],
+                strip_prefixes: Vec::new(),
+                ignore_case: false,
+            },
+            ..Default::default()
+        };
+        let results = detect_nesting_with_options(&root, &options).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            results[0].nested,
+            root.canonicalize().unwrap().join("My Project (copy)")
+        );
+    }
+
+    #[test]
+    fn case_insensitive_match_is_unicode_aware() {
+        let mode = NameMatch::CaseInsensitive;
+        assert!(mode.matches(OsStr::new("Café"), OsStr::new("café")));
+        assert!(mode.matches(OsStr::new("ÉTÉ"), OsStr::new("été")));
+        assert!(!mode.matches(OsStr::new("Café"), OsStr::new("cafe")));
+        assert!(!NameMatch::Exact.matches(OsStr::new("Café"), OsStr::new("café")));
+    }
+
+    #[test]
+    fn recursive_finds_nesting_throughout_tree() {
+        let tmp = TempDir::new().unwrap();
+        let projects = tmp.path().join("projects");
+
+        fs::create_dir_all(projects.join("alpha/alpha/src")).unwrap();
+        fs::create_dir_all(projects.join("beta/beta/beta/lib")).unwrap();
+        fs::create_dir_all(projects.join("gamma/src")).unwrap();
+
+        let results =
+            detect_nesting_recursive(&projects, DEFAULT_MAX_DEPTH, &DetectOptions::default())
+                .unwrap();
+
+        let canon = projects.canonicalize().unwrap();
+        let nested: Vec<_> = results.iter().map(|c| c.nested.clone()).collect();
+        assert_eq!(
+            nested,
+            vec![canon.join("alpha/alpha"), canon.join("beta/beta/beta")]
+        );
+    }
+
+    #[test]
+    fn recursive_results_group_by_top_level_project() {
+        let tmp = TempDir::new().unwrap();
+        let projects = tmp.path().join("projects");
+        fs::create_dir_all(projects.join("projects")).unwrap();
+        fs::write(projects.join("projects/notes.txt"), "").unwrap();
+        fs::create_dir_all(projects.join("alpha/lib/lib/src")).unwrap();
+        fs::create_dir_all(projects.join("alpha/docs/docs/api")).unwrap();
+        fs::create_dir_all(projects.join("beta/beta/src")).unwrap();
+
+        let results =
+            detect_nesting_recursive(&projects, DEFAULT_MAX_DEPTH, &DetectOptions::default())
+                .unwrap();
+        let groups = group_by_project(&projects, results).unwrap();
+
+        let canon = projects.canonicalize().unwrap();
+        let summary: Vec<(PathBuf, usize)> = groups
+            .iter()
+            .map(|(project, candidates)| (project.clone(), candidates.len()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (canon.clone(), 1),
+                (canon.join("alpha"), 2),
+                (canon.join("beta"), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn recursive_does_not_descend_into_flagged_nested_dir() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().join("alpha");
+
+        // `alpha/alpha` is flagged; `alpha/alpha/inner/inner` lives inside it
+        // and must not be reported separately.
+        fs::create_dir_all(root.join("alpha/inner/inner")).unwrap();
+        fs::write(root.join("alpha/README.md"), "hello").unwrap();
+
+        let results =
+            detect_nesting_recursive(&root, DEFAULT_MAX_DEPTH, &DetectOptions::default()).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            results[0].nested,
+            root.canonicalize().unwrap().join("alpha")
+        );
+    }
+
+    #[test]
+    fn recursive_can_skip_ignored_directories() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().join("repo");
+        fs::create_dir_all(root.join("app/app/src")).unwrap();
+        fs::create_dir_all(root.join("node_modules/left-pad/left-pad/lib")).unwrap();
+        fs::write(root.join(".gitignore"), "node_modules/\n").unwrap();
+
+        let found = |respect_ignore| {
+            let options = DetectOptions {
+                respect_ignore,
+                ..Default::default()
+            };
+            detect_nesting_recursive(&root, DEFAULT_MAX_DEPTH, &options)
+                .unwrap()
+                .len()
+        };
+        assert_eq!(found(false), 2);
+        assert_eq!(found(true), 1);
+    }
+
+    #[test]
+    fn recursive_respects_max_depth() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().join("root");
+        fs::create_dir_all(root.join("a/b/deep/deep")).unwrap();
+
+        let options = DetectOptions::default();
+        assert!(
+            detect_nesting_recursive(&root, 2, &options)
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(
+            detect_nesting_recursive(&root, 3, &options).unwrap().len(),
+            1
+        );
+    }
+
+    #[test]
+    fn single_wrapper_detected_regardless_of_name() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().join("mypkg");
+        let wrapper = root.join("mypkg-1.2.3");
+
+        fs::create_dir_all(wrapper.join("bin")).unwrap();
+        fs::write(wrapper.join("README.md"), "hello").unwrap();
+
+        // The default detector ignores differently-named children.
+        assert!(detect_nesting(&root).unwrap().is_empty());
+
+        let results = detect_single_wrapper(&root).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            results[0].nested,
+            root.canonicalize().unwrap().join("mypkg-1.2.3")
+        );
+        assert_eq!(results[0].children.len(), 2);
+    }
+
+    #[test]
+    fn single_wrapper_requires_sole_directory_entry() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().join("mypkg");
+
+        fs::create_dir_all(root.join("mypkg-1.2.3")).unwrap();
+        fs::write(root.join("LICENSE"), "MIT").unwrap();
+        assert!(detect_single_wrapper(&root).unwrap().is_empty());
+
+        let only_file = tmp.path().join("other");
+        fs::create_dir_all(&only_file).unwrap();
+        fs::write(only_file.join("file.txt"), "data").unwrap();
+        assert!(detect_single_wrapper(&only_file).unwrap().is_empty());
+    }
+
+    #[test]
+    fn version_wrappers_need_a_version_name() {
+        let tmp = TempDir::new().unwrap();
+        let options = DetectOptions::default();
+        for name in ["tool-v2", "tool_1.4.0-rc.1", "1.0.0", "v3"] {
+            let root = tmp.path().join(name).join("tool");
+            fs::create_dir_all(root.join(name).join("bin")).unwrap();
+            let results = detect_version_wrapper(&root, &options).unwrap();
+            assert_eq!(results.len(), 1, "{name}");
+            assert_eq!(results[0].children.len(), 1);
+        }
+
+        for name in ["src", "tool-linux", "other-1.0", "1.0.0 final"] {
+            let root = tmp.path().join(name).join("tool");
+            fs::create_dir_all(root.join(name)).unwrap();
+            assert!(
+                detect_version_wrapper(&root, &options).unwrap().is_empty(),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn candidate_id_is_stable_and_path_specific() {
+        let tmp = TempDir::new().unwrap();
+        for name in ["app", "web"] {
+            fs::create_dir_all(tmp.path().join(name).join(name).join("src")).unwrap();
+        }
+        let detect = |name: &str| detect_nesting(&tmp.path().join(name)).unwrap().remove(0);
+
+        let id = detect("app").id();
+        assert_eq!(id.len(), 12);
+        assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+
+        fs::write(tmp.path().join("app/app/new.txt"), "").unwrap();
+        assert_eq!(detect("app").id(), id);
+        assert_ne!(detect("web").id(), id);
+    }
+
+    #[test]
+    fn children_sort_by_code_point_or_ignoring_case() {
+        // In memory: `A` and `a` cannot both exist on a case-insensitive
+        // filesystem.
+        let names = |order: SortOrder| -> Vec<String> {
+            let mut paths: Vec<PathBuf> = ["b", "A", "ä", "a", "B"]
+                .iter()
+                .map(|name| Path::new("/app/app").join(name))
+                .collect();
+            order.sort(&mut paths);
+            paths
+                .iter()
+                .map(|c| c.file_name().unwrap().to_string_lossy().into_owned())
+                .collect()
+        };
+
+        assert_eq!(names(SortOrder::CodePoint), ["A", "B", "a", "b", "ä"]);
+        assert_eq!(names(SortOrder::CaseInsensitive), ["A", "a", "B", "b", "ä"]);
+    }
+
+    #[test]
+    fn custom_predicate_decides_what_is_redundant() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().join("site");
+        fs::create_dir_all(root.join("site-dist/site-dist-old/assets")).unwrap();
+
+        let suffixed = |parent: &OsStr, child: &OsStr| {
+            child
+                .to_str()
+                .zip(parent.to_str())
+                .is_some_and(|(c, p)| c.starts_with(p) && c.contains("-dist"))
+        };
+        assert!(detect_nesting_with(&root, exact_match).unwrap().is_empty());
+        let found = detect_nesting_with(&root, suffixed).unwrap().remove(0);
+        assert_eq!(found.collapse_depth, 2);
+        assert!(found.nested.ends_with("site-dist/site-dist-old"));
+    }
+
+    #[test]
+    fn only_child_requires_a_sole_same_named_entry() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().join("app");
+        fs::create_dir_all(root.join("app/src")).unwrap();
+        fs::write(root.join(".DS_Store"), "").unwrap();
+        let only_child = DetectOptions {
+            only_child: true,
+            ..Default::default()
+        };
+
+        // Noise doesn't count as another entry.
+        assert_eq!(
+            detect_nesting_with_options(&root, &only_child)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        fs::write(root.join("README.md"), "").unwrap();
+        assert!(
+            detect_nesting_with_options(&root, &only_child)
+                .unwrap()
+                .is_empty()
+        );
+        // By default the sibling stays put and the nesting is still found.
+        assert_eq!(detect_nesting(&root).unwrap().len(), 1);
     }
 
     #[test]
@@ -100,4 +1380,32 @@ mod tests {
         let results = detect_nesting(&root).unwrap();
         assert!(results.is_empty());
     }
+
+    #[test]
+    fn is_redundant_nesting_checks_for_a_same_named_child() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().join("project");
+        fs::create_dir_all(root.join("other")).unwrap();
+        assert!(!is_redundant_nesting(&root).unwrap());
+
+        fs::create_dir(root.join("project")).unwrap();
+        assert!(is_redundant_nesting(&root).unwrap());
+        assert_eq!(
+            is_redundant_nesting(&root).unwrap(),
+            !detect_nesting(&root).unwrap().is_empty()
+        );
+    }
+
+    #[test]
+    fn file_input_is_not_a_directory() {
+        let tmp = TempDir::new().unwrap();
+        let file = tmp.path().join("notes.txt");
+        fs::write(&file, "").unwrap();
+
+        let err = detect_nesting(&file).unwrap_err();
+        assert!(matches!(&err, Error::NotADirectory { path } if *path == file));
+        assert!(
+            detect_nesting_recursive(&file, DEFAULT_MAX_DEPTH, &DetectOptions::default()).is_err()
+        );
+    }
 }
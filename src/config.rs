@@ -0,0 +1,176 @@
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Deserializer};
+
+use crate::mover::CollisionPolicy;
+use crate::output::Output;
+use crate::reporter::Format;
+use crate::{Error, Result};
+
+/// File name looked up in each target directory.
+pub const CONFIG_FILE: &str = ".fs-cleaner.toml";
+
+/// Defaults for command-line options, read from a `.fs-cleaner.toml`.
+///
+/// Every field is optional. Values are layered with [`or`](Self::or):
+/// command-line flags first, then the config file, then the built-in
+/// defaults applied by the accessors.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub on_collision: Option<CollisionPolicy>,
+    #[serde(deserialize_with = "patterns")]
+    pub exclude: Option<Vec<glob::Pattern>>,
+    pub min_children: Option<usize>,
+    pub output: Option<Output>,
+    pub format: Option<Format>,
+}
+
+impl Config {
+    /// Parse the config file at `path`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = fs::read_to_string(path).map_err(|e| Error::io(path.to_path_buf(), e))?;
+        toml::from_str(&data).map_err(|e| Error::Config {
+            path: path.to_path_buf(),
+            reason: e.message().to_string(),
+        })
+    }
+
+    /// The config for a run on `target`: the file at `explicit` if given,
+    /// otherwise `.fs-cleaner.toml` in `target` if there is one, otherwise
+    /// an empty config.
+    pub fn resolve(explicit: Option<&Path>, target: &Path) -> Result<Self> {
+        if let Some(path) = explicit {
+            return Self::load(path);
+        }
+        let discovered = target.join(CONFIG_FILE);
+        if discovered.is_file() {
+            Self::load(&discovered)
+        } else {
+            Ok(Self::default())
+        }
+    }
+
+    /// Fill every unset field of `self` from `fallback`.
+    pub fn or(self, fallback: Config) -> Config {
+        Config {
+            on_collision: self.on_collision.or(fallback.on_collision),
+            exclude: self.exclude.or(fallback.exclude),
+            min_children: self.min_children.or(fallback.min_children),
+            output: self.output.or(fallback.output),
+            format: self.format.or(fallback.format),
+        }
+    }
+
+    pub fn on_collision(&self) -> CollisionPolicy {
+        self.on_collision.unwrap_or_default()
+    }
+
+    pub fn exclude(&self) -> Vec<glob::Pattern> {
+        self.exclude.clone().unwrap_or_default()
+    }
+
+    /// Defaults to 1, as in [`DetectOptions`](crate::analyzer::DetectOptions).
+    pub fn min_children(&self) -> usize {
+        self.min_children.unwrap_or(1)
+    }
+
+    pub fn output(&self) -> Output {
+        self.output.unwrap_or_default()
+    }
+
+    pub fn format(&self) -> Format {
+        self.format.unwrap_or_default()
+    }
+}
+
+fn patterns<'de, D: Deserializer<'de>>(
+    de: D,
+) -> std::result::Result<Option<Vec<glob::Pattern>>, D::Error> {
+    let Some(raw) = Option::<Vec<String>>::deserialize(de)? else {
+        return Ok(None);
+    };
+    raw.iter()
+        .map(|p| glob::Pattern::new(p).map_err(serde::de::Error::custom))
+        .collect::<std::result::Result<_, _>>()
+        .map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    fn write_config(dir: &Path, body: &str) -> PathBuf {
+        let path = dir.join(CONFIG_FILE);
+        fs::write(&path, body).unwrap();
+        path
+    }
+
+    #[test]
+    fn parses_every_setting() {
+        let tmp = TempDir::new().unwrap();
+        let path = write_config(
+            tmp.path(),
+            r#"
+            on-collision = "rename"
+            exclude = ["*.log", ".env"]
+            min-children = 3
+            output = "json"
+            format = "yaml"
+            "#,
+        );
+
+        let config = Config::load(&path).unwrap();
+        assert_eq!(config.on_collision(), CollisionPolicy::Rename);
+        assert_eq!(config.exclude().len(), 2);
+        assert_eq!(config.min_children(), 3);
+        assert_eq!(config.output(), Output::Json);
+        assert_eq!(config.format(), Format::Yaml);
+    }
+
+    #[test]
+    fn rejects_unknown_keys_and_bad_patterns() {
+        let tmp = TempDir::new().unwrap();
+        let path = write_config(tmp.path(), "on-colision = \"rename\"\n");
+        assert!(matches!(Config::load(&path), Err(Error::Config { .. })));
+
+        let path = write_config(tmp.path(), "exclude = [\"[\"]\n");
+        assert!(matches!(Config::load(&path), Err(Error::Config { .. })));
+    }
+
+    #[test]
+    fn precedence_is_flags_then_file_then_defaults() {
+        let target = TempDir::new().unwrap();
+        write_config(target.path(), "min-children = 2\noutput = \"json\"\n");
+        let other = TempDir::new().unwrap();
+        let explicit = other.path().join("custom.toml");
+        fs::write(&explicit, "min-children = 4\n").unwrap();
+
+        // Nothing anywhere: built-in defaults.
+        let empty = TempDir::new().unwrap();
+        let config = Config::resolve(None, empty.path()).unwrap();
+        assert_eq!(config.min_children(), 1);
+        assert_eq!(config.output(), Output::Text);
+
+        // The target's file is discovered...
+        let discovered = Config::resolve(None, target.path()).unwrap();
+        assert_eq!(discovered.min_children(), 2);
+
+        // ...unless a file is named explicitly, which replaces it entirely.
+        let named = Config::resolve(Some(&explicit), target.path()).unwrap();
+        assert_eq!(named.min_children(), 4);
+        assert_eq!(named.output(), Output::Text);
+
+        // Flags win over the file; unset flags fall through to it.
+        let flags = Config {
+            min_children: Some(7),
+            ..Default::default()
+        };
+        let merged = flags.or(discovered);
+        assert_eq!(merged.min_children(), 7);
+        assert_eq!(merged.output(), Output::Json);
+    }
+}
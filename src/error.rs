@@ -12,18 +12,169 @@ pub enum Error {
         source: std::io::Error,
     },
 
-    #[error("collision: {existing} already exists at destination")]
-    Collision { existing: PathBuf },
+    #[error("{path} is not a directory")]
+    NotADirectory { path: PathBuf },
+
+    #[error(
+        "collision: {existing} already exists at destination{}",
+        others(.collisions)
+    )]
+    Collision {
+        /// The first conflicting path, in plan order.
+        existing: PathBuf,
+        /// Every collision the plan ran into, `existing`'s included.
+        collisions: Vec<crate::scanner::Collision>,
+    },
+
+    #[error(
+        "refusing to flatten: {path} would land on {existing}, and only one of them is a directory"
+    )]
+    TypeMismatch { path: PathBuf, existing: PathBuf },
+
+    #[error(
+        "refusing to flatten: {existing} is version-control metadata and would be merged or shadowed"
+    )]
+    VcsCollision { existing: PathBuf },
 
     #[error("permission denied: {path}")]
-    Permission { path: PathBuf },
+    Permission {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("could not fully scan {path} ({kind}); symlinks beneath it were not checked")]
+    IncompleteScan {
+        path: PathBuf,
+        kind: std::io::ErrorKind,
+    },
 
     #[error("symlink would break: {link} -> {target}")]
     BrokenSymlink { link: PathBuf, target: PathBuf },
 
-    #[error("cross-device move not yet supported: {path}")]
-    CrossDevice { path: PathBuf },
+    #[error(
+        "{link} is a symlink to {target}: flattening it would move the link, not the directory it points to"
+    )]
+    NestedIsSymlink { link: PathBuf, target: PathBuf },
+
+    #[error(
+        "{nested} is a mount point, a separate filesystem from its parent: flattening it would copy everything out and leave the mount point behind; pass --force --copy-across-mounts to do that"
+    )]
+    MountBoundary { nested: PathBuf },
+
+    #[error("cross-device copy failed at {path}: {source}")]
+    CrossDevice {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("rollback conflict: {path} is occupied and would be overwritten")]
+    RollbackConflict { path: PathBuf },
+
+    #[error(
+        "batch {id} cannot be rolled back on its own; later moves touch the same paths: {}",
+        list_moves(.overlapping)
+    )]
+    BatchDependency {
+        id: u64,
+        /// `(from, to)` of each later move that overlaps the batch.
+        overlapping: Vec<(PathBuf, PathBuf)>,
+    },
+
+    #[error(
+        "refusing to flatten: {planned} moves planned, more than the limit of {limit}; pass --force if that is intended"
+    )]
+    TooManyMoves { planned: usize, limit: usize },
+
+    #[error("saved plan is out of date: {path} changed since it was made")]
+    PlanStale { path: PathBuf },
+
+    #[error("invalid config {path}: {reason}")]
+    Config { path: PathBuf, reason: String },
+
+    #[error(
+        "no journal found in {dir}; run this from the directory that was flattened, or check that an apply (without --dry-run) was made there"
+    )]
+    JournalNotFound { dir: PathBuf },
+
+    #[error("journal {path} is corrupt: {reason}")]
+    JournalCorrupt { path: PathBuf, reason: String },
+
+    #[error(
+        "journal moves paths outside {root}, refusing to roll back: {}",
+        list_moves(.entries)
+    )]
+    JournalOutOfBounds {
+        root: PathBuf,
+        /// `(from, to)` of each offending entry.
+        entries: Vec<(PathBuf, PathBuf)>,
+    },
+
+    #[error(
+        "{path} is held by another fs-cleaner process{}; wait for it to finish, or pass --force if that process is gone",
+        holder(.pid)
+    )]
+    Locked {
+        path: PathBuf,
+        /// The holder's PID, as recorded in the lock file.
+        pid: Option<u32>,
+    },
 
     #[error("{0}")]
     Other(String),
 }
+
+impl Error {
+    /// Wrap an I/O failure at `path`, reporting `PermissionDenied` as
+    /// [`Error::Permission`] so callers can match on it.
+    pub fn io(path: PathBuf, source: std::io::Error) -> Self {
+        if source.kind() == std::io::ErrorKind::PermissionDenied {
+            Error::Permission { path, source }
+        } else {
+            Error::Io { path, source }
+        }
+    }
+}
+
+fn holder(pid: &Option<u32>) -> String {
+    pid.map(|pid| format!(" (pid {pid})")).unwrap_or_default()
+}
+
+fn others(collisions: &[crate::scanner::Collision]) -> String {
+    match collisions.get(1..) {
+        Some(rest) if !rest.is_empty() => format!(
+            " (and {} more: {})",
+            rest.len(),
+            rest.iter()
+                .map(|c| c.existing.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        _ => String::new(),
+    }
+}
+
+fn list_moves(moves: &[(PathBuf, PathBuf)]) -> String {
+    moves
+        .iter()
+        .map(|(from, to)| format!("{} -> {}", from.display(), to.display()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::path::Path;
+
+    #[test]
+    fn permission_denied_maps_to_permission() {
+        let err = Error::io("/x".into(), io::ErrorKind::PermissionDenied.into());
+        assert!(matches!(err, Error::Permission { ref path, .. } if path == Path::new("/x")));
+        assert_eq!(err.to_string(), "permission denied: /x");
+        assert!(std::error::Error::source(&err).is_some());
+
+        let err = Error::io("/x".into(), io::ErrorKind::NotFound.into());
+        assert!(matches!(err, Error::Io { .. }));
+    }
+}
@@ -1,78 +1,1396 @@
+use std::collections::{BTreeSet, HashSet};
 use std::fs;
+use std::io::{self, Read, Write};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
 
-use crate::mover::MoveRecord;
+use crate::mover::{self, MoveOp, MoveRecord};
+use crate::scanner;
 use crate::{Error, Result};
 
 const JOURNAL_FILE: &str = ".fs-cleaner-journal.json";
 
-/// Persistent record of moves performed, enabling rollback.
+/// The first bytes of every gzip stream.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Prefix of the timestamped journals written by [`Journal::timestamped_path`].
+const JOURNAL_PREFIX: &str = "fs-cleaner-journal";
+
+/// Current on-disk format version. Version 0 is the original bare array of
+/// move records, which carries no checksum; version 1 checksums a flat list
+/// of move records; version 2 stores a list of [`Batch`]es.
+const JOURNAL_VERSION: u32 = 2;
+
+/// On-disk envelope. `entries` is kept as raw JSON so the checksum covers
+/// exactly the bytes that were written.
+#[derive(Serialize, Deserialize)]
+struct JournalFile {
+    version: u32,
+    /// Hex SHA-256 of the serialized `entries`.
+    checksum: String,
+    /// Whether relative paths in `entries` are relative to the directory
+    /// holding the journal (see [`Journal::set_relative_paths`]).
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    relative: bool,
+    entries: Box<RawValue>,
+}
+
+/// One move appended after the journal was last saved in full: a line of
+/// its [pending log](Journal::pending_path).
+#[derive(Serialize, Deserialize)]
+struct PendingEntry {
+    batch: u64,
+    started_at: u64,
+    /// Position of `entry` in its batch. Lines whose entry already made it
+    /// into the full journal (a crash between the save and removing the
+    /// log) are recognized by it and not replayed twice.
+    index: usize,
+    /// Whether the paths are relative to the journal's directory.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    relative: bool,
+    entry: MoveRecord,
+}
+
+/// The moves made by one apply, rolled back together.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Batch {
+    /// Sequence number, increasing through the journal's history.
+    pub id: u64,
+    /// When the batch was started, in seconds since the Unix epoch (0 for
+    /// journals written before batches existed).
+    pub started_at: u64,
+    pub entries: Vec<MoveRecord>,
+    /// Directories the apply deleted once its moves emptied them, in
+    /// removal order. Rollback recreates these before moving anything back;
+    /// directories kept with [`FlattenOptions::remove_nested`] off are not
+    /// listed, so rollback leaves them as they are.
+    ///
+    /// [`FlattenOptions::remove_nested`]: crate::mover::FlattenOptions::remove_nested
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed_dirs: Vec<PathBuf>,
+    /// Noise files the apply deleted (with
+    /// [`FlattenOptions::remove_noise`](crate::mover::FlattenOptions::remove_noise)),
+    /// recreated by rollback when their contents were kept.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed_noise: Vec<RemovedNoise>,
+    /// Directories the apply created to move into (a missing
+    /// [`FlattenOptions::into`] and its missing ancestors), outermost first.
+    /// Rollback removes them, innermost first, once their moves are back
+    /// and they are empty.
+    ///
+    /// [`FlattenOptions::into`]: crate::mover::FlattenOptions::into
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub created_dirs: Vec<PathBuf>,
+    /// The directories the apply's moves lie in: the flattened parent, then
+    /// the [`FlattenOptions::into`] destination if it is elsewhere. Rollback
+    /// keeps to these when not given a root, and locks the first. Empty in
+    /// journals from before they were recorded.
+    ///
+    /// [`FlattenOptions::into`]: crate::mover::FlattenOptions::into
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roots: Vec<PathBuf>,
+}
+
+impl Batch {
+    /// This batch with `f` applied to every path it records.
+    fn map_paths(&self, f: impl Fn(&Path) -> PathBuf) -> Self {
+        Self {
+            id: self.id,
+            started_at: self.started_at,
+            entries: self
+                .entries
+                .iter()
+                .map(|e| MoveRecord {
+                    from: f(&e.from),
+                    to: f(&e.to),
+                    ..e.clone()
+                })
+                .collect(),
+            removed_dirs: self.removed_dirs.iter().map(|d| f(d)).collect(),
+            removed_noise: self
+                .removed_noise
+                .iter()
+                .map(|n| RemovedNoise {
+                    path: f(&n.path),
+                    ..n.clone()
+                })
+                .collect(),
+            created_dirs: self.created_dirs.iter().map(|d| f(d)).collect(),
+            roots: self.roots.iter().map(|d| f(d)).collect(),
+        }
+    }
+}
+
+/// Noise files up to this size are kept in the journal when deleted, so
+/// rollback can bring them back.
+pub const NOISE_CONTENT_LIMIT: u64 = 64 * 1024;
+
+/// A noise file deleted by an apply.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemovedNoise {
+    pub path: PathBuf,
+    /// The file's bytes, for regular files up to [`NOISE_CONTENT_LIMIT`].
+    /// `None` for larger files and for directories such as `__MACOSX`,
+    /// which rollback cannot restore. Saved as base64; journals that kept
+    /// them as an array of numbers still load.
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "base64")]
+    pub contents: Option<Vec<u8>>,
+}
+
+impl RemovedNoise {
+    /// Capture `path` before it is deleted.
+    pub fn capture(path: &Path) -> Result<Self> {
+        let meta = fs::symlink_metadata(path).map_err(|e| Error::io(path.to_path_buf(), e))?;
+        let contents = if meta.is_file() && meta.len() <= NOISE_CONTENT_LIMIT {
+            Some(fs::read(path).map_err(|e| Error::io(path.to_path_buf(), e))?)
+        } else {
+            None
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            contents,
+        })
+    }
+
+    /// Write the file back. Fails for noise whose contents were not kept.
+    pub fn restore(&self) -> Result<()> {
+        let Some(contents) = &self.contents else {
+            return Err(Error::Other(format!(
+                "cannot restore {}: its contents were not journaled",
+                self.path.display()
+            )));
+        };
+        fs::write(&self.path, contents).map_err(|e| Error::io(self.path.clone(), e))
+    }
+}
+
+/// (De)serialization of [`RemovedNoise::contents`] as a standard, padded
+/// base64 string.
+mod base64 {
+    use serde::{Deserialize, Deserializer, Serializer, de};
+
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    pub fn serialize<S: Serializer>(bytes: &Option<Vec<u8>>, s: S) -> Result<S::Ok, S::Error> {
+        match bytes {
+            Some(bytes) => s.serialize_str(&encode(bytes)),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<u8>>, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Stored {
+            Base64(String),
+            Bytes(Vec<u8>),
+        }
+        match Option::<Stored>::deserialize(d)? {
+            Some(Stored::Base64(text)) => decode(&text)
+                .map(Some)
+                .ok_or_else(|| de::Error::custom("invalid base64 in noise contents")),
+            Some(Stored::Bytes(bytes)) => Ok(Some(bytes)),
+            None => Ok(None),
+        }
+    }
+
+    pub(super) fn encode(bytes: &[u8]) -> String {
+        let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+        for chunk in bytes.chunks(3) {
+            let n = chunk
+                .iter()
+                .enumerate()
+                .fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+                } else {
+                    out.push('=');
+                }
+            }
+        }
+        out
+    }
+
+    pub(super) fn decode(text: &str) -> Option<Vec<u8>> {
+        let text = text.as_bytes();
+        if !text.len().is_multiple_of(4) {
+            return None;
+        }
+        let mut out = Vec::with_capacity(text.len() / 4 * 3);
+        for (i, chunk) in text.chunks(4).enumerate() {
+            let last = i + 1 == text.len() / 4;
+            let pad = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+            if pad > 2 || (pad > 0 && !last) {
+                return None;
+            }
+            let mut n = 0u32;
+            for (j, &c) in chunk[..4 - pad].iter().enumerate() {
+                let value = ALPHABET.iter().position(|&a| a == c)? as u32;
+                n |= value << (18 - 6 * j);
+            }
+            out.extend_from_slice(&n.to_be_bytes()[1..4 - pad]);
+        }
+        Some(out)
+    }
+}
+
+/// Which batches [`Journal::rollback`] unwinds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RollbackScope {
+    /// Only the most recent batch (the default).
+    #[default]
+    Latest,
+    /// Every batch, newest first.
+    All,
+}
+
+/// What [`Journal::rollback`] does when a move's original location is
+/// occupied again (e.g. the user recreated the file after applying).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RollbackConflict {
+    /// Stop with [`Error::RollbackConflict`] (the default).
+    #[default]
+    Abort,
+    /// Leave the entry in place and carry on with the rest.
+    Skip,
+}
+
+/// Outcome of a rollback (or of a dry run, describing what would happen).
+#[derive(Debug, Default)]
+pub struct RollbackReport {
+    /// Moves that were (or would be) reversed, in the order performed.
+    pub reversed: Vec<MoveRecord>,
+    /// Moves left alone because their original location was occupied.
+    pub skipped: Vec<MoveRecord>,
+    /// Moves whose destination no longer exists (already reverted or
+    /// removed by hand), so there is nothing to move back.
+    pub missing: Vec<MoveRecord>,
+    /// Directories the apply removed that were (or would be) recreated.
+    pub recreated: Vec<PathBuf>,
+    /// Deleted noise files that were (or would be) written back.
+    pub restored_noise: Vec<PathBuf>,
+    /// Directories the apply created that were (or would be) removed.
+    pub removed_dirs: Vec<PathBuf>,
+}
+
+/// A dry-run rollback, entry by entry, with the bytes each would move back:
+/// what `rollback --dry-run` prints. Built from a [`RollbackReport`] made
+/// with [`RollbackConflict::Skip`], so occupied entries are listed rather
+/// than stopping the preview.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RollbackPlan {
+    /// Entries that would be reversed, in rollback order, then the
+    /// occupied ones, then the missing ones.
+    pub entries: Vec<PlannedRevert>,
+    /// Bytes the ready entries would move (or copy) back.
+    pub bytes: u64,
+    /// Directories that would be recreated.
+    pub recreated: Vec<PathBuf>,
+    /// Deleted noise files that would be written back.
+    pub restored_noise: Vec<PathBuf>,
+    /// Directories the apply created that would be removed.
+    pub removed_dirs: Vec<PathBuf>,
+}
+
+/// One entry of a [`RollbackPlan`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PlannedRevert {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    pub op: MoveOp,
+    pub state: RevertState,
+    /// Size of what is at `to` now. Zero for a missing entry, and for a
+    /// removed hard link, which is restored without copying.
+    pub bytes: u64,
+}
+
+/// Whether a [`PlannedRevert`] can go ahead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RevertState {
+    /// Its original location is free.
+    Ready,
+    /// Something now occupies its original location.
+    Occupied,
+    /// Nothing is at its destination: already reverted, or removed.
+    Missing,
+}
+
+impl RollbackPlan {
+    pub fn new(report: &RollbackReport) -> Self {
+        let revert = |record: &MoveRecord, state| PlannedRevert {
+            from: record.from.clone(),
+            to: record.to.clone(),
+            op: record.op,
+            state,
+            bytes: match (state, record.op) {
+                (RevertState::Missing, _) | (_, MoveOp::Unlink) => 0,
+                _ => scanner::bytes_under(&record.to),
+            },
+        };
+        let entries: Vec<PlannedRevert> = report
+            .reversed
+            .iter()
+            .map(|r| revert(r, RevertState::Ready))
+            .chain(
+                report
+                    .skipped
+                    .iter()
+                    .map(|r| revert(r, RevertState::Occupied)),
+            )
+            .chain(
+                report
+                    .missing
+                    .iter()
+                    .map(|r| revert(r, RevertState::Missing)),
+            )
+            .collect();
+        Self {
+            bytes: entries
+                .iter()
+                .filter(|e| e.state == RevertState::Ready)
+                .map(|e| e.bytes)
+                .sum(),
+            entries,
+            recreated: report.recreated.clone(),
+            restored_noise: report.restored_noise.clone(),
+            removed_dirs: report.removed_dirs.clone(),
+        }
+    }
+
+    /// The first entry whose original location is occupied, if any.
+    pub fn first_occupied(&self) -> Option<&PlannedRevert> {
+        self.entries
+            .iter()
+            .find(|e| e.state == RevertState::Occupied)
+    }
+}
+
+/// One journal entry checked against the filesystem by [`Journal::verify`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct EntryStatus {
+    /// The batch the entry belongs to.
+    pub batch: u64,
+    pub from: PathBuf,
+    pub to: PathBuf,
+    /// Whether something is at `to` now.
+    pub to_exists: bool,
+    /// Whether something is at `from` now.
+    pub from_occupied: bool,
+    /// Whether rolling back every batch would move this entry back
+    /// cleanly. This accounts for the entries reversed before it, so a
+    /// `from` that a later move vacates does not count against it.
+    pub rollback_ready: bool,
+}
+
+/// The result of [`Journal::verify`], entries oldest first.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct VerifyReport {
+    pub entries: Vec<EntryStatus>,
+}
+
+impl EntryStatus {
+    /// Whether rollback would leave this entry where it is: it is still
+    /// at `to`, but `from` is occupied.
+    pub fn is_blocked(&self) -> bool {
+        self.to_exists && !self.rollback_ready
+    }
+
+    /// Whether the entry is gone from `to` (already reverted, or removed),
+    /// which rollback reports and passes over.
+    pub fn is_missing(&self) -> bool {
+        !self.to_exists && !self.rollback_ready
+    }
+}
+
+impl VerifyReport {
+    /// Whether a rollback would go through: no entry is blocked. Missing
+    /// entries don't count, since rollback passes over them too.
+    pub fn is_ready(&self) -> bool {
+        !self.entries.iter().any(EntryStatus::is_blocked)
+    }
+}
+
+/// Tracks the paths a dry-run rollback would vacate and fill, so later
+/// entries see the same state a real rollback would.
+#[derive(Default)]
+struct Overlay {
+    vacated: HashSet<PathBuf>,
+    filled: HashSet<PathBuf>,
+}
+
+impl Overlay {
+    fn exists(&self, path: &Path) -> bool {
+        self.filled.contains(path)
+            || (!self.vacated.contains(path) && fs::symlink_metadata(path).is_ok())
+    }
+
+    /// Apply the effect of reverting `record`.
+    fn revert(&mut self, record: &MoveRecord) {
+        if record.op == MoveOp::Rename {
+            self.filled.remove(&record.to);
+            self.vacated.insert(record.to.clone());
+        }
+        self.vacated.remove(&record.from);
+        self.filled.insert(record.from.clone());
+    }
+}
+
+impl RollbackReport {
+    /// Returns `true` if every applicable move was reversed.
+    pub fn is_complete(&self) -> bool {
+        self.skipped.is_empty()
+    }
+}
+
+fn checksum(entries: &RawValue) -> String {
+    format!("{:x}", Sha256::digest(entries.get().as_bytes()))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Persistent record of moves performed, enabling rollback. Moves are
+/// grouped into [`Batch`]es, one per apply, oldest first.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Journal {
-    pub entries: Vec<MoveRecord>,
+    pub batches: Vec<Batch>,
+    /// File the journal flushes itself to on every change, if any.
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    /// Save paths relative to the journal's directory.
+    #[serde(skip)]
+    relative: bool,
+    /// Entries in the file as of its last full save.
+    #[serde(skip)]
+    saved: usize,
+    /// Entries appended to the pending log since then.
+    #[serde(skip)]
+    pending: usize,
 }
 
 impl Journal {
     /// Create a new empty journal.
     pub fn new() -> Self {
         Self {
+            batches: Vec::new(),
+            path: None,
+            relative: false,
+            saved: 0,
+            pending: 0,
+        }
+    }
+
+    /// Open the journal in `dir` (or start one) and begin a new batch. The
+    /// journal is flushed to disk after every [`append`](Self::append) and
+    /// [`retract`](Self::retract), so a crash mid-flatten still leaves a
+    /// usable rollback record, and earlier batches are kept.
+    pub fn persistent(dir: &Path) -> Result<Self> {
+        Self::persistent_at(&Self::default_path(dir))
+    }
+
+    /// Like [`persistent`](Self::persistent), using the file `path`.
+    pub fn persistent_at(path: &Path) -> Result<Self> {
+        let mut journal = if path.exists() {
+            Self::load(path)?
+        } else {
+            Self {
+                path: Some(path.to_path_buf()),
+                ..Self::new()
+            }
+        };
+        journal.begin_batch();
+        Ok(journal)
+    }
+
+    /// Where [`save`](Self::save) writes the journal for `dir`.
+    pub fn default_path(dir: &Path) -> PathBuf {
+        dir.join(JOURNAL_FILE)
+    }
+
+    /// `path` with `.gz` appended. [`save_to`](Self::save_to) gzips any
+    /// journal whose name ends in `.gz`; [`load`](Self::load) recognizes
+    /// compressed journals by their content, whatever their name.
+    pub fn compressed_path(path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(".gz");
+        PathBuf::from(name)
+    }
+
+    /// `path` with `.pending` appended: the log of moves
+    /// [appended](Self::append) since the journal at `path` was last saved
+    /// in full, one JSON object per line. [`load`](Self::load) replays it;
+    /// every full save folds it in and deletes it.
+    pub fn pending_path(path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(".pending");
+        PathBuf::from(name)
+    }
+
+    /// A fresh `fs-cleaner-journal-<name>-<unix secs>.json` path in `dir`,
+    /// so journals for several applies can share a directory without
+    /// overwriting each other. A `-N` suffix is added if the name is taken,
+    /// compressed or not.
+    pub fn timestamped_path(dir: &Path, name: &str) -> PathBuf {
+        let stem = format!("{JOURNAL_PREFIX}-{name}-{}", unix_now());
+        let mut path = dir.join(format!("{stem}.json"));
+        let mut n = 1;
+        while path.exists() || Self::compressed_path(&path).exists() {
+            path = dir.join(format!("{stem}-{n}.json"));
+            n += 1;
+        }
+        path
+    }
+
+    /// Whether `path` is named like a journal this crate writes: the
+    /// default name or a [timestamped](Self::timestamped_path) one, either
+    /// possibly [compressed](Self::compressed_path).
+    pub fn is_journal_path(path: &Path) -> bool {
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            return false;
+        };
+        let name = name.strip_suffix(".gz").unwrap_or(name);
+        name == JOURNAL_FILE || (name.starts_with(JOURNAL_PREFIX) && name.ends_with(".json"))
+    }
+
+    /// Every journal file at or below `dir`, sorted, without following
+    /// symlinks. Unreadable directories are skipped with a warning.
+    pub fn find_all(dir: &Path) -> Result<Vec<PathBuf>> {
+        if !dir.is_dir() {
+            return Err(Error::NotADirectory {
+                path: dir.to_path_buf(),
+            });
+        }
+        let mut journals = Vec::new();
+        for entry in WalkDir::new(dir).follow_links(false) {
+            match entry {
+                Ok(entry) if entry.file_type().is_file() && Self::is_journal_path(entry.path()) => {
+                    journals.push(entry.into_path());
+                }
+                Ok(_) => {}
+                Err(e) => warn!("skipping {e}"),
+            }
+        }
+        journals.sort();
+        Ok(journals)
+    }
+
+    /// Start a new, empty batch; later moves are recorded in it.
+    pub fn begin_batch(&mut self) {
+        let id = self.batches.last().map_or(1, |b| b.id + 1);
+        self.batches.push(Batch {
+            id,
+            started_at: unix_now(),
             entries: Vec::new(),
+            removed_dirs: Vec::new(),
+            removed_noise: Vec::new(),
+            created_dirs: Vec::new(),
+            roots: Vec::new(),
+        });
+    }
+
+    /// The batch moves are currently recorded in, starting one if needed.
+    fn current_batch(&mut self) -> &mut Batch {
+        if self.batches.is_empty() {
+            self.begin_batch();
         }
+        self.batches.last_mut().expect("a batch was just started")
+    }
+
+    /// Every recorded move, oldest first.
+    pub fn entries(&self) -> impl DoubleEndedIterator<Item = &MoveRecord> {
+        self.batches.iter().flat_map(|b| &b.entries)
+    }
+
+    /// The most recently recorded move.
+    pub fn last_entry(&self) -> Option<&MoveRecord> {
+        self.batches.last().and_then(|b| b.entries.last())
     }
 
-    /// Record a batch of moves.
+    /// Record several moves in the current batch.
     pub fn record(&mut self, moves: Vec<MoveRecord>) {
-        self.entries.extend(moves);
+        self.current_batch().entries.extend(moves);
+    }
+
+    /// Record a single completed move, flushing to disk if persistent.
+    ///
+    /// Rewriting the whole journal for every move would make an apply
+    /// quadratic in its size, so the move is appended to the
+    /// [pending log](Self::pending_path) instead, until that log is as long
+    /// as the journal; then the journal is saved in full.
+    pub fn append(&mut self, record: MoveRecord) -> Result<()> {
+        self.current_batch().entries.push(record);
+        let Some(path) = self.path.clone() else {
+            return Ok(());
+        };
+        if self.pending >= self.saved {
+            return self.flush();
+        }
+        self.append_pending(&path)
+            .map_err(|e| Error::io(Self::pending_path(&path), e))?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    /// Write the newest entry as a line of the pending log, synced to disk.
+    fn append_pending(&self, path: &Path) -> io::Result<()> {
+        let batch = self.batches.last().expect("an entry was just recorded");
+        let mut entry = batch
+            .entries
+            .last()
+            .expect("an entry was just recorded")
+            .clone();
+        if self.relative {
+            let base = journal_dir(path);
+            entry.from = relative_to(&base, &entry.from);
+            entry.to = relative_to(&base, &entry.to);
+        }
+        let line = PendingEntry {
+            batch: batch.id,
+            started_at: batch.started_at,
+            index: batch.entries.len() - 1,
+            relative: self.relative,
+            entry,
+        };
+        let mut line = serde_json::to_vec(&line).map_err(io::Error::other)?;
+        line.push(b'\n');
+
+        let log = Self::pending_path(path);
+        let created = !log.exists();
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log)?;
+        file.write_all(&line)?;
+        file.sync_data()?;
+        if created {
+            sync_parent(&log)?;
+        }
+        Ok(())
+    }
+
+    /// Add the entries of the pending log beside `path` that the full
+    /// journal does not hold yet. A last line cut short by a crash is
+    /// dropped with a warning.
+    fn replay_pending(&mut self, path: &Path) -> Result<()> {
+        let log = Self::pending_path(path);
+        let data = match fs::read_to_string(&log) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(Error::io(log, e)),
+        };
+        let corrupt = |reason: String| Error::JournalCorrupt {
+            path: log.clone(),
+            reason,
+        };
+        let lines: Vec<&str> = data.lines().collect();
+        for (i, line) in lines.iter().enumerate() {
+            let pending: PendingEntry = match serde_json::from_str(line) {
+                Ok(pending) => pending,
+                Err(e) if i + 1 == lines.len() => {
+                    warn!("ignoring the torn last line of {}: {e}", log.display());
+                    break;
+                }
+                Err(e) => return Err(corrupt(e.to_string())),
+            };
+            let batch = match self.batches.iter().position(|b| b.id == pending.batch) {
+                Some(i) => &mut self.batches[i],
+                None => {
+                    self.batches.push(Batch {
+                        id: pending.batch,
+                        started_at: pending.started_at,
+                        entries: Vec::new(),
+                        removed_dirs: Vec::new(),
+                        removed_noise: Vec::new(),
+                        created_dirs: Vec::new(),
+                        roots: Vec::new(),
+                    });
+                    self.batches.last_mut().expect("a batch was just added")
+                }
+            };
+            if pending.index < batch.entries.len() {
+                continue;
+            }
+            if pending.index > batch.entries.len() {
+                return Err(corrupt(format!(
+                    "entry {} of batch {} follows a missing one",
+                    pending.index, pending.batch
+                )));
+            }
+            let mut entry = pending.entry;
+            if pending.relative {
+                let base = journal_dir(path);
+                entry.from = scanner::normalize(&base.join(&entry.from));
+                entry.to = scanner::normalize(&base.join(&entry.to));
+            }
+            batch.entries.push(entry);
+            self.pending += 1;
+        }
+        Ok(())
+    }
+
+    /// Drop the most recent entry of the current batch after its move was
+    /// undone, flushing to disk if persistent.
+    pub fn retract(&mut self) -> Result<Option<MoveRecord>> {
+        let record = self.batches.last_mut().and_then(|b| b.entries.pop());
+        self.flush()?;
+        Ok(record)
+    }
+
+    /// Record noise files deleted by the current batch, flushing to disk if
+    /// persistent. Call it before deleting them, so a crash in between
+    /// leaves them restorable.
+    pub fn record_removed_noise(&mut self, noise: Vec<RemovedNoise>) -> Result<()> {
+        if noise.is_empty() {
+            return Ok(());
+        }
+        self.current_batch().removed_noise.extend(noise);
+        self.flush()
+    }
+
+    /// Drop a noise file recorded by the current batch that was not deleted
+    /// after all (or has been restored), flushing to disk if persistent.
+    pub fn retract_removed_noise(&mut self, path: &Path) -> Result<()> {
+        if let Some(batch) = self.batches.last_mut() {
+            batch.removed_noise.retain(|n| n.path != path);
+        }
+        self.flush()
+    }
+
+    /// Record the directories the current batch's moves lie in (see
+    /// [`Batch::roots`]). Written with the batch's first move.
+    pub fn set_roots(&mut self, roots: Vec<PathBuf>) {
+        self.current_batch().roots = roots;
+    }
+
+    /// The first root recorded by each batch, or `None` if any batch
+    /// predates recorded roots.
+    pub fn recorded_roots(&self) -> Option<BTreeSet<&Path>> {
+        self.batches
+            .iter()
+            .filter(|b| !b.entries.is_empty())
+            .map(|b| b.roots.first().map(PathBuf::as_path))
+            .collect()
+    }
+
+    /// Record directories created by the current batch, flushing to disk if
+    /// persistent.
+    pub fn record_created_dirs(&mut self, dirs: Vec<PathBuf>) -> Result<()> {
+        if dirs.is_empty() {
+            return Ok(());
+        }
+        self.current_batch().created_dirs.extend(dirs);
+        self.flush()
+    }
+
+    /// Forget a created directory recorded by the current batch after it
+    /// was removed again, flushing to disk if persistent.
+    pub fn retract_created_dir(&mut self, dir: &Path) -> Result<()> {
+        if let Some(batch) = self.batches.last_mut() {
+            batch.created_dirs.retain(|d| d != dir);
+        }
+        self.flush()
+    }
+
+    /// Record directories removed by the current batch, flushing to disk if
+    /// persistent.
+    pub fn record_removed_dirs(&mut self, dirs: Vec<PathBuf>) -> Result<()> {
+        if dirs.is_empty() {
+            return Ok(());
+        }
+        self.current_batch().removed_dirs.extend(dirs);
+        self.flush()
+    }
+
+    /// Save paths relative to the directory holding the journal file, so
+    /// the journal still applies after the tree (journal included) is moved
+    /// to another mount point or machine. [`load`](Self::load) resolves them
+    /// against wherever the journal then is. A path that cannot be reached
+    /// from there, such as one on another drive, stays absolute.
+    ///
+    /// A loaded journal keeps the form it was saved in.
+    pub fn set_relative_paths(&mut self, relative: bool) {
+        self.relative = relative;
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if let Some(path) = &self.path {
+            self.save_to(path)?;
+            self.saved = self.batches.iter().map(|b| b.entries.len()).sum();
+            self.pending = 0;
+        }
+        Ok(())
     }
 
     /// Write the journal to disk alongside the target directory.
     pub fn save(&self, dir: &Path) -> Result<PathBuf> {
-        let path = dir.join(JOURNAL_FILE);
-        let json =
-            serde_json::to_string_pretty(&self.entries).map_err(|e| Error::Other(e.to_string()))?;
-        fs::write(&path, json).map_err(|e| Error::Io {
-            path: path.clone(),
-            source: e,
-        })?;
+        let path = Self::default_path(dir);
+        self.save_to(&path)?;
         Ok(path)
     }
 
-    /// Load a journal from disk.
-    pub fn load(dir: &Path) -> Result<Self> {
-        let path = dir.join(JOURNAL_FILE);
-        let data = fs::read_to_string(&path).map_err(|e| Error::Io {
-            path: path.clone(),
-            source: e,
+    /// Write the journal to the file `path`. Empty batches are left out.
+    /// A `path` ending in `.gz` gets the same JSON gzip-compressed; the
+    /// checksum still covers the uncompressed entries. The file and its
+    /// directory are synced to disk, then any [pending
+    /// log](Self::pending_path) beside it is deleted.
+    pub fn save_to(&self, path: &Path) -> Result<()> {
+        let batches = self.batches.iter().filter(|b| !b.entries.is_empty());
+        let entries = if self.relative {
+            let base = journal_dir(path);
+            let batches: Vec<Batch> = batches
+                .map(|b| b.map_paths(|p| relative_to(&base, p)))
+                .collect();
+            serde_json::value::to_raw_value(&batches)
+        } else {
+            serde_json::value::to_raw_value(&batches.collect::<Vec<_>>())
+        }
+        .map_err(|e| Error::Other(e.to_string()))?;
+        let file = JournalFile {
+            version: JOURNAL_VERSION,
+            checksum: checksum(&entries),
+            relative: self.relative,
+            entries,
+        };
+        let json = serde_json::to_string_pretty(&file).map_err(|e| Error::Other(e.to_string()))?;
+        let data = if path.extension().is_some_and(|ext| ext == "gz") {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder
+                .write_all(json.as_bytes())
+                .and_then(|()| encoder.finish())
+                .map_err(|e| Error::io(path.to_path_buf(), e))?
+        } else {
+            json.into_bytes()
+        };
+        write_atomic(path, &data).map_err(|e| Error::io(path.to_path_buf(), e))?;
+        let log = Self::pending_path(path);
+        match fs::remove_file(&log) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(Error::io(log, e)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Load a journal from disk. `path` is either a journal file or a
+    /// directory holding one under the default name or its
+    /// [compressed](Self::compressed_path) form (the newer of the two if
+    /// both are there; a tie is refused). The loaded journal stays attached
+    /// to that file: later changes are saved back to it. Moves in its
+    /// [pending log](Self::pending_path) are added to their batches.
+    ///
+    /// Gzip-compressed journals are detected by their magic bytes and
+    /// decompressed before anything else is checked.
+    ///
+    /// The checksum is verified before any entry is trusted; a mismatch or
+    /// unparseable file (e.g. from an interrupted save) yields
+    /// [`Error::JournalCorrupt`]. Unversioned journals from older releases
+    /// are accepted with a warning since they carry no checksum. Journals
+    /// from before batches existed load as a single batch.
+    pub fn load(path: &Path) -> Result<Self> {
+        let path = if path.is_dir() {
+            Self::default_in(path)?
+        } else {
+            path.to_path_buf()
+        };
+        let bytes = fs::read(&path).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => Error::JournalNotFound {
+                dir: path.parent().unwrap_or(&path).to_path_buf(),
+            },
+            _ => Error::io(path.clone(), e),
         })?;
-        let entries: Vec<MoveRecord> =
-            serde_json::from_str(&data).map_err(|e| Error::Other(e.to_string()))?;
-        Ok(Self { entries })
+        let corrupt = |reason: String| Error::JournalCorrupt {
+            path: path.clone(),
+            reason,
+        };
+        let data = if bytes.starts_with(&GZIP_MAGIC) {
+            let mut data = String::new();
+            GzDecoder::new(&bytes[..])
+                .read_to_string(&mut data)
+                .map_err(|e| corrupt(e.to_string()))?;
+            data
+        } else {
+            String::from_utf8(bytes).map_err(|e| corrupt(e.to_string()))?
+        };
+
+        if data.trim_start().starts_with('[') {
+            warn!(
+                "journal {} predates integrity checks; loading without verification",
+                path.display()
+            );
+            let entries = serde_json::from_str(&data).map_err(|e| corrupt(e.to_string()))?;
+            return Ok(Self::from_flat(entries, path));
+        }
+
+        let file: JournalFile = serde_json::from_str(&data).map_err(|e| corrupt(e.to_string()))?;
+        if file.version > JOURNAL_VERSION {
+            return Err(Error::Other(format!(
+                "journal {} has version {}, newer than supported version {JOURNAL_VERSION}",
+                path.display(),
+                file.version
+            )));
+        }
+        if checksum(&file.entries) != file.checksum {
+            return Err(corrupt("checksum mismatch".to_string()));
+        }
+
+        let payload = file.entries.get();
+        if file.version < 2 {
+            let entries = serde_json::from_str(payload).map_err(|e| corrupt(e.to_string()))?;
+            let mut journal = Self::from_flat(entries, path.clone());
+            journal.replay_pending(&path)?;
+            return Ok(journal);
+        }
+        let mut batches: Vec<Batch> =
+            serde_json::from_str(payload).map_err(|e| corrupt(e.to_string()))?;
+        if file.relative {
+            let base = journal_dir(&path);
+            batches = batches
+                .iter()
+                .map(|b| b.map_paths(|p| scanner::normalize(&base.join(p))))
+                .collect();
+        }
+        let mut journal = Self {
+            saved: batches.iter().map(|b| b.entries.len()).sum(),
+            batches,
+            path: Some(path.clone()),
+            relative: file.relative,
+            pending: 0,
+        };
+        journal.replay_pending(&path)?;
+        Ok(journal)
     }
 
-    /// Reverse all recorded moves (last-in, first-out).
-    pub fn rollback(&self) -> Result<usize> {
-        let mut count = 0;
-        for record in self.entries.iter().rev() {
-            if record.to.exists() {
-                fs::rename(&record.to, &record.from).map_err(|e| Error::Io {
-                    path: record.to.clone(),
-                    source: e,
-                })?;
-                count += 1;
+    /// The journal in `dir` under the default name, plain or compressed. If
+    /// both exist (say, one was compressed by hand and kept), the one
+    /// modified last is the one apply has been writing to.
+    fn default_in(dir: &Path) -> Result<PathBuf> {
+        let plain = Self::default_path(dir);
+        let compressed = Self::compressed_path(&plain);
+        let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+        match (modified(&plain), modified(&compressed)) {
+            (Some(p), Some(c)) if p == c => Err(Error::Other(format!(
+                "both {} and {} exist with the same modification time; remove the stale one",
+                plain.display(),
+                compressed.display()
+            ))),
+            (Some(p), Some(c)) => {
+                let (newer, older) = if c > p {
+                    (compressed, plain)
+                } else {
+                    (plain, compressed)
+                };
+                warn!(
+                    "both {} and {} exist; loading the newer {}",
+                    newer.display(),
+                    older.display(),
+                    newer.display()
+                );
+                Ok(newer)
             }
+            (None, Some(_)) => Ok(compressed),
+            _ => Ok(plain),
         }
-        Ok(count)
+    }
+
+    /// Wrap the flat move list of a pre-batch journal in a single batch.
+    fn from_flat(entries: Vec<MoveRecord>, path: PathBuf) -> Self {
+        let batches = if entries.is_empty() {
+            Vec::new()
+        } else {
+            vec![Batch {
+                id: 1,
+                started_at: 0,
+                entries,
+                removed_dirs: Vec::new(),
+                removed_noise: Vec::new(),
+                created_dirs: Vec::new(),
+                roots: Vec::new(),
+            }]
+        };
+        Self {
+            saved: batches.iter().map(|b| b.entries.len()).sum(),
+            batches,
+            path: Some(path),
+            ..Self::new()
+        }
+    }
+
+    /// Reverse the moves of the batches in `scope`, newest batch first and
+    /// each batch last-in, first-out. Reversed (and missing) entries are
+    /// dropped from the journal, along with batches left empty, so the next
+    /// rollback moves on to the batch before.
+    ///
+    /// Directories removed by the flatten (the nested directory, merged
+    /// subdirectories) are recreated as needed, and those it created are
+    /// removed once empty. An original location that is
+    /// occupied again is never overwritten; `on_conflict` decides whether
+    /// that aborts the rollback, before anything is moved, or skips the
+    /// entry.
+    ///
+    /// With a `root`, every entry to reverse must lie inside it (see
+    /// [`Error::JournalOutOfBounds`]); a journal edited or moved to point
+    /// elsewhere is refused before anything is touched. Without one, each
+    /// batch's entries must lie in its recorded [`Batch::roots`], if any.
+    ///
+    /// When `dry_run` is true nothing is touched and the report describes
+    /// what a real rollback would do.
+    pub fn rollback(
+        &mut self,
+        scope: RollbackScope,
+        on_conflict: RollbackConflict,
+        root: Option<&Path>,
+        dry_run: bool,
+    ) -> Result<RollbackReport> {
+        self.batches.retain(|b| !b.entries.is_empty());
+        let first = match scope {
+            RollbackScope::Latest => self.batches.len().saturating_sub(1),
+            RollbackScope::All => 0,
+        };
+        self.rollback_range(first..self.batches.len(), on_conflict, root, dry_run)
+    }
+
+    /// Reverse only the batch with `id`, leaving later batches in place.
+    ///
+    /// Refused with [`Error::BatchDependency`] when a later batch moved a
+    /// path this batch also touched (or one inside or above it), since
+    /// undoing this batch alone would then misplace files. `root` is
+    /// checked as in [`rollback`](Self::rollback).
+    pub fn rollback_batch(
+        &mut self,
+        id: u64,
+        on_conflict: RollbackConflict,
+        root: Option<&Path>,
+        dry_run: bool,
+    ) -> Result<RollbackReport> {
+        self.batches.retain(|b| !b.entries.is_empty());
+        let index = self
+            .batches
+            .iter()
+            .position(|b| b.id == id)
+            .ok_or_else(|| Error::Other(format!("journal has no batch {id}")))?;
+
+        let batch = &self.batches[index];
+        let overlapping: Vec<(PathBuf, PathBuf)> = self.batches[index + 1..]
+            .iter()
+            .flat_map(|b| &b.entries)
+            .filter(|later| batch.entries.iter().any(|e| overlaps(e, later)))
+            .map(|later| (later.from.clone(), later.to.clone()))
+            .collect();
+        if !overlapping.is_empty() {
+            return Err(Error::BatchDependency { id, overlapping });
+        }
+
+        self.rollback_range(index..index + 1, on_conflict, root, dry_run)
+    }
+
+    /// Check every entry against the filesystem without changing anything:
+    /// what is at each end of the move now, and whether a rollback of the
+    /// whole journal would reverse it without a conflict.
+    pub fn verify(&self) -> VerifyReport {
+        let mut overlay = Overlay::default();
+        let mut entries = Vec::new();
+        for batch in self.batches.iter().rev() {
+            for dir in &batch.removed_dirs {
+                overlay.filled.insert(dir.clone());
+            }
+            for record in batch.entries.iter().rev() {
+                let rollback_ready = overlay.exists(&record.to) && !overlay.exists(&record.from);
+                if rollback_ready {
+                    overlay.revert(record);
+                }
+                entries.push(EntryStatus {
+                    batch: batch.id,
+                    from: record.from.clone(),
+                    to: record.to.clone(),
+                    to_exists: fs::symlink_metadata(&record.to).is_ok(),
+                    from_occupied: fs::symlink_metadata(&record.from).is_ok(),
+                    rollback_ready,
+                });
+            }
+        }
+        entries.reverse();
+        VerifyReport { entries }
+    }
+
+    /// Reverse the batches at `range`, newest first.
+    fn rollback_range(
+        &mut self,
+        range: Range<usize>,
+        on_conflict: RollbackConflict,
+        root: Option<&Path>,
+        dry_run: bool,
+    ) -> Result<RollbackReport> {
+        match root {
+            Some(root) => {
+                let root = root
+                    .canonicalize()
+                    .map_err(|e| Error::io(root.to_path_buf(), e))?;
+                check_bounds(
+                    self.batches[range.clone()].iter().flat_map(|b| &b.entries),
+                    &[root],
+                )?;
+            }
+            None => {
+                for batch in &self.batches[range.clone()] {
+                    if !batch.roots.is_empty() {
+                        let roots: Vec<PathBuf> =
+                            batch.roots.iter().map(|r| canonical(r)).collect();
+                        check_bounds(batch.entries.iter(), &roots)?;
+                    }
+                }
+            }
+        }
+        // Aborting must leave the tree as it was, so look for a conflict
+        // in a dry run before moving anything.
+        if !dry_run && on_conflict == RollbackConflict::Abort {
+            self.rollback_range(range.clone(), on_conflict, None, true)?;
+        }
+
+        let mut report = RollbackReport::default();
+        let mut overlay = Overlay::default();
+        for batch in self.batches[range.clone()].iter().rev() {
+            // Outermost first, so each has its parent back.
+            for dir in batch.removed_dirs.iter().rev() {
+                if overlay.exists(dir) {
+                    continue;
+                }
+                if dry_run {
+                    overlay.filled.insert(dir.clone());
+                } else {
+                    fs::create_dir(dir).map_err(|e| Error::io(dir.clone(), e))?;
+                    info!("recreated {}", dir.display());
+                }
+                report.recreated.push(dir.clone());
+            }
+            for noise in &batch.removed_noise {
+                if noise.contents.is_none() {
+                    warn!(
+                        "not restoring {}: its contents were not journaled",
+                        noise.path.display()
+                    );
+                    continue;
+                }
+                if overlay.exists(&noise.path) {
+                    warn!("not restoring {}: it exists", noise.path.display());
+                    continue;
+                }
+                if dry_run {
+                    overlay.filled.insert(noise.path.clone());
+                } else {
+                    noise.restore()?;
+                    info!("restored {}", noise.path.display());
+                }
+                report.restored_noise.push(noise.path.clone());
+            }
+            Self::rollback_entries(batch, on_conflict, dry_run, &mut overlay, &mut report)?;
+            // Innermost first; one still holding something stays.
+            for dir in batch.created_dirs.iter().rev() {
+                if !dry_run {
+                    match fs::remove_dir(dir) {
+                        Ok(()) => info!("removed {}", dir.display()),
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                        Err(e) => {
+                            warn!("not removing {}: {e}", dir.display());
+                            continue;
+                        }
+                    }
+                }
+                report.removed_dirs.push(dir.clone());
+            }
+        }
+
+        if !dry_run {
+            for batch in &mut self.batches[range] {
+                batch.entries.retain(|r| report.skipped.contains(r));
+                batch
+                    .created_dirs
+                    .retain(|d| !report.removed_dirs.contains(d));
+            }
+            self.batches.retain(|b| !b.entries.is_empty());
+            self.flush()?;
+        }
+        Ok(report)
+    }
+
+    /// Reverse one batch's moves, newest first, into `report`.
+    fn rollback_entries(
+        batch: &Batch,
+        on_conflict: RollbackConflict,
+        dry_run: bool,
+        overlay: &mut Overlay,
+        report: &mut RollbackReport,
+    ) -> Result<()> {
+        for record in batch.entries.iter().rev() {
+            if !overlay.exists(&record.to) {
+                report.missing.push(record.clone());
+                continue;
+            }
+            // Checked per entry rather than up front: reversing a later
+            // entry can free the slot an earlier one needs.
+            if overlay.exists(&record.from) {
+                match on_conflict {
+                    RollbackConflict::Abort => {
+                        return Err(Error::RollbackConflict {
+                            path: record.from.clone(),
+                        });
+                    }
+                    RollbackConflict::Skip => {
+                        warn!(
+                            "skipping {}: {} is occupied",
+                            record.to.display(),
+                            record.from.display()
+                        );
+                        report.skipped.push(record.clone());
+                        continue;
+                    }
+                }
+            }
+            if dry_run {
+                overlay.revert(record);
+                report.reversed.push(record.clone());
+                continue;
+            }
+            if let Some(dir) = record.from.parent() {
+                fs::create_dir_all(dir).map_err(|e| Error::io(dir.to_path_buf(), e))?;
+            }
+            mover::revert_record(record)?;
+            report.reversed.push(record.clone());
+        }
+        Ok(())
+    }
+}
+
+/// Refuse the entries with a side outside all of `roots`, which must be
+/// canonical, comparing canonical paths.
+fn check_bounds<'a>(
+    records: impl Iterator<Item = &'a MoveRecord>,
+    roots: &[PathBuf],
+) -> Result<()> {
+    let entries: Vec<(PathBuf, PathBuf)> = records
+        .filter(|r| {
+            ![&r.from, &r.to].iter().all(|p| {
+                let p = canonical(p);
+                roots.iter().any(|root| p.starts_with(root))
+            })
+        })
+        .map(|r| (r.from.clone(), r.to.clone()))
+        .collect();
+    match (entries.is_empty(), roots.first()) {
+        (false, Some(root)) => Err(Error::JournalOutOfBounds {
+            root: root.clone(),
+            entries,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// `path` with its longest existing ancestor canonicalized and the rest
+/// resolved lexically, so a side that doesn't exist yet (or a `..` in it)
+/// can't slip past a prefix check.
+pub(crate) fn canonical(path: &Path) -> PathBuf {
+    let mut rest = Vec::new();
+    let mut existing = path;
+    let base = loop {
+        if let Ok(base) = existing.canonicalize() {
+            break base;
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                rest.push(name);
+                existing = parent;
+            }
+            // `..` at the end, or nothing left that exists: resolve lexically.
+            _ => return crate::scanner::normalize(path),
+        }
+    };
+    crate::scanner::normalize(&rest.iter().rev().fold(base, |p, name| p.join(name)))
+}
+
+/// The canonical directory holding the journal file `path`.
+fn journal_dir(path: &Path) -> PathBuf {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    canonical(dir)
+}
+
+/// `path` relative to the directory `base`, or as it is when it does not
+/// share `base`'s root (another drive, or not absolute at all).
+fn relative_to(base: &Path, path: &Path) -> PathBuf {
+    if path.is_absolute() && path.components().next() == base.components().next() {
+        mover::relative_path(base, &scanner::normalize(path))
+    } else {
+        path.to_path_buf()
     }
 }
 
+/// Whether two moves touch the same path, or one inside the other.
+fn overlaps(a: &MoveRecord, b: &MoveRecord) -> bool {
+    let related = |x: &Path, y: &Path| x.starts_with(y) || y.starts_with(x);
+    [&a.from, &a.to]
+        .iter()
+        .any(|x| [&b.from, &b.to].iter().any(|y| related(x, y)))
+}
+
 impl Default for Journal {
     fn default() -> Self {
         Self::new()
     }
 }
 
+/// Write `data` to a temporary file next to `path`, then rename it into
+/// place, so a crash leaves either the old journal or the new one and never
+/// a truncated file. The temporary file is removed if anything fails.
+pub(crate) fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = path.with_file_name(format!(".{name}.tmp-{}", std::process::id()));
+
+    let result = (|| {
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        sync_parent(path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// Sync the directory holding `path`, so a file just created or renamed
+/// there survives a crash. Directories cannot be opened for syncing on
+/// Windows, where this does nothing.
+fn sync_parent(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        fs::File::open(dir)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -82,17 +1400,481 @@ mod tests {
     fn save_and_load_roundtrip() {
         let tmp = TempDir::new().unwrap();
         let mut journal = Journal::new();
-        journal.record(vec![MoveRecord {
-            from: PathBuf::from("/a/b"),
-            to: PathBuf::from("/a/c"),
-        }]);
+        journal.record(vec![MoveRecord::new(
+            PathBuf::from("/a/b"),
+            PathBuf::from("/a/c"),
+        )]);
 
         let saved = journal.save(tmp.path()).unwrap();
         assert!(saved.exists());
 
         let loaded = Journal::load(tmp.path()).unwrap();
-        assert_eq!(loaded.entries.len(), 1);
-        assert_eq!(loaded.entries[0].from, PathBuf::from("/a/b"));
+        assert_eq!(loaded.entries().count(), 1);
+        assert_eq!(loaded.entries().next().unwrap().from, PathBuf::from("/a/b"));
+    }
+
+    #[test]
+    fn relative_journal_follows_the_tree_it_was_moved_with() {
+        let tmp = TempDir::new().unwrap();
+        let old = tmp.path().canonicalize().unwrap().join("old");
+        fs::create_dir_all(old.join("b")).unwrap();
+        fs::write(old.join("b/file"), "data").unwrap();
+        fs::rename(old.join("b/file"), old.join("file")).unwrap();
+
+        let mut journal = Journal::new();
+        journal.set_relative_paths(true);
+        journal.record(vec![MoveRecord::new(old.join("b/file"), old.join("file"))]);
+        journal.record_removed_dirs(vec![old.join("b")]).unwrap();
+        fs::remove_dir(old.join("b")).unwrap();
+        let saved = journal.save(&old).unwrap();
+        let text = fs::read_to_string(&saved).unwrap();
+        assert!(!text.contains(tmp.path().to_str().unwrap()));
+
+        // Elsewhere, the journal resolves against its new location.
+        let new = old.with_file_name("new");
+        fs::rename(&old, &new).unwrap();
+        let mut loaded = Journal::load(&new).unwrap();
+        assert_eq!(
+            loaded.entries().next().unwrap().from,
+            new.join("b").join("file")
+        );
+        loaded
+            .rollback(
+                RollbackScope::Latest,
+                RollbackConflict::Abort,
+                Some(&new),
+                false,
+            )
+            .unwrap();
+        assert_eq!(fs::read_to_string(new.join("b/file")).unwrap(), "data");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_paths_sharing_the_root_are_made_relative() {
+        let base = Path::new("/journals");
+        assert_eq!(
+            relative_to(base, Path::new("/data/x")),
+            Path::new("../data/x")
+        );
+        assert_eq!(relative_to(base, Path::new("rel/x")), Path::new("rel/x"));
+    }
+
+    fn sample_journal() -> Journal {
+        let mut journal = Journal::new();
+        journal.record(vec![MoveRecord::new(
+            PathBuf::from("/a/b"),
+            PathBuf::from("/a/c"),
+        )]);
+        journal
+    }
+
+    #[test]
+    fn move_times_are_saved_as_rfc3339() {
+        let tmp = TempDir::new().unwrap();
+        let at = UNIX_EPOCH + std::time::Duration::from_millis(1_700_000_000_123);
+        let mut journal = Journal::new();
+        journal.record(vec![MoveRecord {
+            at: Some(at),
+            ..MoveRecord::new("/a/b".into(), "/a/c".into())
+        }]);
+        let saved = journal.save(tmp.path()).unwrap();
+
+        let data = fs::read_to_string(&saved).unwrap();
+        assert!(
+            data.contains(r#""at":"2023-11-14T22:13:20.123Z""#),
+            "{data}"
+        );
+        let loaded = Journal::load(&saved).unwrap();
+        assert_eq!(loaded.last_entry().unwrap().at, Some(at));
+    }
+
+    #[test]
+    fn missing_journal_names_the_directory() {
+        let tmp = TempDir::new().unwrap();
+        let err = Journal::load(tmp.path()).unwrap_err();
+        assert!(matches!(&err, Error::JournalNotFound { dir } if dir == tmp.path()));
+        assert!(err.to_string().contains("directory that was flattened"));
+    }
+
+    #[test]
+    fn tampered_journal_is_rejected() {
+        let tmp = TempDir::new().unwrap();
+        let saved = sample_journal().save(tmp.path()).unwrap();
+
+        let data = fs::read_to_string(&saved).unwrap();
+        fs::write(&saved, data.replace("/a/c", "/etc")).unwrap();
+
+        let err = Journal::load(tmp.path()).unwrap_err();
+        assert!(matches!(err, Error::JournalCorrupt { .. }));
+    }
+
+    #[test]
+    fn compressed_journal_roundtrips_and_is_still_checksummed() {
+        let tmp = TempDir::new().unwrap();
+        let path = Journal::compressed_path(&Journal::default_path(tmp.path()));
+        let journal = sample_journal();
+        journal.save_to(&path).unwrap();
+        assert!(fs::read(&path).unwrap().starts_with(&GZIP_MAGIC));
+
+        // Found from the directory, and recognized by content, not name.
+        let loaded = Journal::load(tmp.path()).unwrap();
+        assert_eq!(loaded.batches, journal.batches);
+        let renamed = tmp.path().join("journal");
+        fs::rename(&path, &renamed).unwrap();
+        assert_eq!(Journal::load(&renamed).unwrap().batches, journal.batches);
+
+        let mut data = String::new();
+        GzDecoder::new(&fs::read(&renamed).unwrap()[..])
+            .read_to_string(&mut data)
+            .unwrap();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(data.replace("/a/c", "/etc").as_bytes())
+            .unwrap();
+        fs::write(&renamed, encoder.finish().unwrap()).unwrap();
+        let err = Journal::load(&renamed).unwrap_err();
+        assert!(matches!(err, Error::JournalCorrupt { .. }));
+    }
+
+    #[test]
+    fn newer_of_plain_and_compressed_journals_is_loaded() {
+        let tmp = TempDir::new().unwrap();
+        let plain = Journal::default_path(tmp.path());
+        let compressed = Journal::compressed_path(&plain);
+        sample_journal().save_to(&plain).unwrap();
+        let mut newer = sample_journal();
+        newer.begin_batch();
+        newer.record(vec![MoveRecord::new(
+            PathBuf::from("/a/d"),
+            PathBuf::from("/a/e"),
+        )]);
+        newer.save_to(&compressed).unwrap();
+        let set_modified = |path: &Path, secs| {
+            fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(UNIX_EPOCH + std::time::Duration::from_secs(secs))
+                .unwrap();
+        };
+
+        set_modified(&plain, 1_000);
+        set_modified(&compressed, 2_000);
+        assert_eq!(Journal::load(tmp.path()).unwrap().batches.len(), 2);
+
+        set_modified(&plain, 3_000);
+        assert_eq!(Journal::load(tmp.path()).unwrap().batches.len(), 1);
+
+        set_modified(&compressed, 3_000);
+        let err = Journal::load(tmp.path()).unwrap_err();
+        assert!(err.to_string().contains("remove the stale one"), "{err}");
+    }
+
+    #[test]
+    fn noise_contents_are_saved_as_base64() {
+        for (bytes, text) in [
+            (&b""[..], ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"foob", "Zm9vYg=="),
+            (b"\xff\x00\xfe", "/wD+"),
+        ] {
+            assert_eq!(base64::encode(bytes), text);
+            assert_eq!(base64::decode(text).as_deref(), Some(bytes));
+        }
+        assert_eq!(base64::decode("Zg="), None);
+        assert_eq!(base64::decode("Zg==Zg=="), None);
+
+        let noise = RemovedNoise {
+            path: PathBuf::from("/a/.DS_Store"),
+            contents: Some(b"finder".to_vec()),
+        };
+        let json = serde_json::to_string(&noise).unwrap();
+        assert!(json.contains(r#""contents":"ZmluZGVy""#), "{json}");
+        assert_eq!(serde_json::from_str::<RemovedNoise>(&json).unwrap(), noise);
+        // Journals that saved the bytes as numbers still load.
+        let legacy = r#"{"path":"/a/.DS_Store","contents":[102,105,110,100,101,114]}"#;
+        assert_eq!(serde_json::from_str::<RemovedNoise>(legacy).unwrap(), noise);
+    }
+
+    #[test]
+    fn truncated_journal_is_rejected() {
+        let tmp = TempDir::new().unwrap();
+        let saved = sample_journal().save(tmp.path()).unwrap();
+
+        let data = fs::read_to_string(&saved).unwrap();
+        fs::write(&saved, &data[..data.len() / 2]).unwrap();
+
+        let err = Journal::load(tmp.path()).unwrap_err();
+        assert!(matches!(err, Error::JournalCorrupt { .. }));
+    }
+
+    #[test]
+    fn legacy_unversioned_journal_loads() {
+        let tmp = TempDir::new().unwrap();
+        fs::write(
+            tmp.path().join(JOURNAL_FILE),
+            r#"[{"from": "/a/b", "to": "/a/c"}]"#,
+        )
+        .unwrap();
+
+        let loaded = Journal::load(tmp.path()).unwrap();
+        assert!(loaded.entries().eq(sample_journal().entries()));
+    }
+
+    #[test]
+    fn persistent_journal_flushes_each_change() {
+        let tmp = TempDir::new().unwrap();
+        let mut journal = Journal::persistent(tmp.path()).unwrap();
+
+        journal
+            .append(MoveRecord::new(
+                PathBuf::from("/a/b"),
+                PathBuf::from("/a/c"),
+            ))
+            .unwrap();
+        assert_eq!(Journal::load(tmp.path()).unwrap().entries().count(), 1);
+
+        journal.retract().unwrap();
+        assert!(
+            Journal::load(tmp.path())
+                .unwrap()
+                .entries()
+                .next()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn appends_between_full_saves_go_to_the_pending_log() {
+        let tmp = TempDir::new().unwrap();
+        let path = Journal::default_path(tmp.path());
+        let log = Journal::pending_path(&path);
+        let mut journal = Journal::persistent(tmp.path()).unwrap();
+        let record =
+            |i: usize| MoveRecord::new(format!("/a/a/{i}").into(), format!("/a/{i}").into());
+
+        // The first append saves in full; the next ones are logged until
+        // the log is as long as the journal.
+        for i in 0..4 {
+            journal.append(record(i)).unwrap();
+        }
+        assert!(log.exists());
+        let loaded = Journal::load(&path).unwrap();
+        assert!(loaded.entries().eq(journal.entries()));
+        let full = serde_json::to_string(&loaded.batches).unwrap();
+
+        // A crash between a full save and deleting the log replays nothing
+        // twice; a torn last line is dropped.
+        let lines = fs::read(&log).unwrap();
+        journal.save_to(&path).unwrap();
+        assert!(!log.exists());
+        let mut torn = lines;
+        torn.extend_from_slice(br#"{"batch":1,"sta"#);
+        fs::write(&log, torn).unwrap();
+        let loaded = Journal::load(&path).unwrap();
+        assert_eq!(serde_json::to_string(&loaded.batches).unwrap(), full);
+    }
+
+    #[test]
+    fn compressed_journals_log_appends_instead_of_regzipping() {
+        let tmp = TempDir::new().unwrap();
+        let path = Journal::compressed_path(&Journal::default_path(tmp.path()));
+        let mut journal = Journal::persistent_at(&path).unwrap();
+        journal
+            .append(MoveRecord::new("/a/a/0".into(), "/a/0".into()))
+            .unwrap();
+        let gzipped = fs::read(&path).unwrap();
+
+        journal
+            .append(MoveRecord::new("/a/a/1".into(), "/a/1".into()))
+            .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), gzipped);
+        assert!(Journal::pending_path(&path).exists());
+        assert_eq!(Journal::load(&path).unwrap().entries().count(), 2);
+    }
+
+    #[test]
+    fn failed_save_leaves_no_temp_file() {
+        let tmp = TempDir::new().unwrap();
+        let mut journal = Journal::new();
+        journal.record(vec![MoveRecord::new("/a/a/x".into(), "/a/x".into())]);
+
+        let path = tmp.path().join("journal.json");
+        journal.save_to(&path).unwrap();
+        assert_eq!(Journal::load(&path).unwrap().entries().count(), 1);
+
+        // The rename cannot replace a directory.
+        let blocked = tmp.path().join("blocked");
+        fs::create_dir(&blocked).unwrap();
+        journal.save_to(&blocked).unwrap_err();
+        let names: Vec<_> = fs::read_dir(tmp.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(names.len(), 2, "leftover files: {names:?}");
+    }
+
+    #[test]
+    fn find_all_lists_journals_at_any_depth() {
+        let tmp = TempDir::new().unwrap();
+        let deep = tmp.path().join("a/b");
+        fs::create_dir_all(&deep).unwrap();
+        let plain = sample_journal().save(tmp.path()).unwrap();
+        let compressed = Journal::compressed_path(&Journal::default_path(&deep));
+        sample_journal().save_to(&compressed).unwrap();
+        let stamped = Journal::timestamped_path(&tmp.path().join("a"), "b");
+        sample_journal().save_to(&stamped).unwrap();
+        fs::write(tmp.path().join("a/notes.json"), "{}").unwrap();
+
+        let mut expected = vec![plain, compressed, stamped];
+        expected.sort();
+        assert_eq!(Journal::find_all(tmp.path()).unwrap(), expected);
+    }
+
+    #[test]
+    fn timestamped_journals_do_not_overwrite_each_other() {
+        let tmp = TempDir::new().unwrap();
+        let first = Journal::timestamped_path(tmp.path(), "project");
+        sample_journal().save_to(&first).unwrap();
+        let second = Journal::timestamped_path(tmp.path(), "project");
+        assert_ne!(first, second);
+        // A compressed journal takes its uncompressed name too.
+        let gz = Journal::compressed_path(&second);
+        sample_journal().save_to(&gz).unwrap();
+        let third = Journal::timestamped_path(tmp.path(), "project");
+        assert!(third != first && third != second, "{}", third.display());
+
+        let name = first.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("fs-cleaner-journal-project-"));
+        assert!(
+            Journal::load(&first)
+                .unwrap()
+                .entries()
+                .eq(sample_journal().entries())
+        );
+    }
+
+    #[test]
+    fn applies_accumulate_batches_and_roll_back_newest_first() {
+        let tmp = TempDir::new().unwrap();
+        let mut dests = Vec::new();
+        for name in ["first", "second"] {
+            let dest = tmp.path().join(format!("{name}-moved"));
+            fs::write(&dest, name).unwrap();
+            let mut journal = Journal::persistent(tmp.path()).unwrap();
+            journal
+                .append(MoveRecord::new(tmp.path().join(name), dest.clone()))
+                .unwrap();
+            dests.push(dest);
+        }
+
+        let mut journal = Journal::load(tmp.path()).unwrap();
+        assert_eq!(journal.batches.len(), 2);
+        assert_eq!(journal.batches[1].id, 2);
+
+        let report = journal
+            .rollback(RollbackScope::Latest, RollbackConflict::Abort, None, false)
+            .unwrap();
+        assert_eq!(report.reversed.len(), 1);
+        assert!(tmp.path().join("second").exists());
+        assert!(dests[0].exists());
+
+        // The rolled-back batch is gone from disk; `--all` unwinds the rest.
+        let mut journal = Journal::load(tmp.path()).unwrap();
+        assert_eq!(journal.batches.len(), 1);
+        journal
+            .rollback(RollbackScope::All, RollbackConflict::Abort, None, false)
+            .unwrap();
+        assert!(tmp.path().join("first").exists());
+        assert!(Journal::load(tmp.path()).unwrap().batches.is_empty());
+    }
+
+    #[test]
+    fn rollback_batch_undoes_one_apply_unless_later_ones_depend_on_it() {
+        let tmp = TempDir::new().unwrap();
+        let path = |name: &str| tmp.path().join(name);
+        for name in ["a-moved", "b-moved", "c-moved"] {
+            fs::write(path(name), name).unwrap();
+        }
+
+        let mut journal = Journal::new();
+        for batch in [["a", "a-moved"], ["b", "b-moved"], ["c", "c-moved"]] {
+            journal.begin_batch();
+            journal.record(vec![MoveRecord::new(path(batch[0]), path(batch[1]))]);
+        }
+        // A fourth apply moved `b-moved` again.
+        journal.begin_batch();
+        journal.record(vec![MoveRecord::new(path("b-moved"), path("b-final"))]);
+
+        let err = journal
+            .rollback_batch(2, RollbackConflict::Abort, None, false)
+            .unwrap_err();
+        assert!(
+            matches!(err, Error::BatchDependency { id: 2, ref overlapping } if overlapping.len() == 1)
+        );
+
+        let report = journal
+            .rollback_batch(1, RollbackConflict::Abort, None, false)
+            .unwrap();
+        assert_eq!(report.reversed.len(), 1);
+        assert!(path("a").exists());
+        assert!(path("c-moved").exists());
+        assert_eq!(
+            journal.batches.iter().map(|b| b.id).collect::<Vec<_>>(),
+            vec![2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn version_one_journal_loads_as_one_batch() {
+        let tmp = TempDir::new().unwrap();
+        let entries = r#"[{"from":"/a/b","to":"/a/c"}]"#;
+        let raw = RawValue::from_string(entries.to_string()).unwrap();
+        let file = format!(
+            r#"{{"version":1,"checksum":"{}","entries":{entries}}}"#,
+            checksum(&raw)
+        );
+        fs::write(tmp.path().join(JOURNAL_FILE), file).unwrap();
+
+        let loaded = Journal::load(tmp.path()).unwrap();
+        assert_eq!(loaded.batches.len(), 1);
+        assert!(loaded.entries().eq(sample_journal().entries()));
+    }
+
+    #[test]
+    fn verify_reports_drift_without_touching_anything() {
+        let tmp = TempDir::new().unwrap();
+        let mut journal = occupied_journal(&tmp);
+        // c -> d, then d -> e: `d` is only back once the second is reversed.
+        let [c, d, e] = ["c", "d", "e"].map(|n| tmp.path().join(n));
+        fs::write(&e, "data").unwrap();
+        journal.begin_batch();
+        journal.record(vec![
+            MoveRecord::new(c.clone(), d.clone()),
+            MoveRecord::new(d.clone(), e.clone()),
+        ]);
+
+        let report = journal.verify();
+        let status: Vec<_> = report
+            .entries
+            .iter()
+            .map(|e| (e.to_exists, e.from_occupied, e.rollback_ready))
+            .collect();
+        assert_eq!(
+            status,
+            [
+                // a's origin was recreated by the user.
+                (true, true, false),
+                (true, false, true),
+                (false, false, true),
+                (true, false, true),
+            ]
+        );
+        assert!(!report.is_ready());
+        assert!(e.exists() && !c.exists() && !d.exists());
     }
 
     #[test]
@@ -103,16 +1885,230 @@ mod tests {
 
         fs::write(&dest, "data").unwrap();
 
-        let journal = Journal {
-            entries: vec![MoveRecord {
-                from: src.clone(),
-                to: dest.clone(),
-            }],
-        };
+        let mut journal = Journal::new();
+        journal.record(vec![MoveRecord::new(src.clone(), dest.clone())]);
 
-        let count = journal.rollback().unwrap();
-        assert_eq!(count, 1);
+        let report = journal
+            .rollback(RollbackScope::All, RollbackConflict::Abort, None, false)
+            .unwrap();
+        assert_eq!(report.reversed.len(), 1);
+        assert!(report.is_complete());
         assert!(src.exists());
         assert!(!dest.exists());
     }
+
+    #[test]
+    fn dry_run_rollback_reports_without_moving() {
+        let tmp = TempDir::new().unwrap();
+        let mut journal = occupied_journal(&tmp);
+        fs::remove_file(tmp.path().join("b-moved")).unwrap();
+
+        let report = journal
+            .rollback(RollbackScope::All, RollbackConflict::Skip, None, true)
+            .unwrap();
+        assert!(report.reversed.is_empty());
+        assert_eq!(report.skipped.len(), 1);
+        assert_eq!(report.missing.len(), 1);
+        assert!(report.missing[0].to.ends_with("b-moved"));
+        assert!(tmp.path().join("a-moved").exists());
+    }
+
+    #[test]
+    fn rollback_plan_sizes_ready_entries_and_marks_the_rest() {
+        let tmp = TempDir::new().unwrap();
+        let mut journal = occupied_journal(&tmp);
+        journal.record(vec![MoveRecord::new(
+            tmp.path().join("c-original"),
+            tmp.path().join("c-moved"),
+        )]);
+
+        let report = journal
+            .rollback(RollbackScope::All, RollbackConflict::Skip, None, true)
+            .unwrap();
+        let plan = RollbackPlan::new(&report);
+        let states: Vec<_> = plan.entries.iter().map(|e| e.state).collect();
+        assert_eq!(
+            states,
+            [
+                RevertState::Ready,
+                RevertState::Occupied,
+                RevertState::Missing
+            ]
+        );
+        assert_eq!(plan.bytes, "moved".len() as u64);
+        assert!(plan.first_occupied().unwrap().from.ends_with("a-original"));
+
+        let json = serde_json::to_value(&plan).unwrap();
+        assert_eq!(json["entries"][2]["state"], "missing");
+    }
+
+    #[test]
+    fn dry_run_rollback_follows_chained_moves() {
+        let tmp = TempDir::new().unwrap();
+        let a = tmp.path().join("a");
+        let b = tmp.path().join("b");
+        let c = tmp.path().join("c");
+        fs::write(&c, "data").unwrap();
+
+        // a -> b, then b -> c: `b` is free only once the second is reversed.
+        let mut journal = Journal::new();
+        journal.record(vec![
+            MoveRecord::new(a.clone(), b.clone()),
+            MoveRecord::new(b.clone(), c.clone()),
+        ]);
+
+        let report = journal
+            .rollback(RollbackScope::All, RollbackConflict::Abort, None, true)
+            .unwrap();
+        assert_eq!(report.reversed.len(), 2);
+        assert!(report.missing.is_empty());
+        assert!(c.exists());
+        assert!(!a.exists());
+    }
+
+    fn occupied_journal(tmp: &TempDir) -> Journal {
+        let mut journal = Journal::new();
+        for name in ["a", "b"] {
+            let from = tmp.path().join(format!("{name}-original"));
+            let to = tmp.path().join(format!("{name}-moved"));
+            fs::write(&to, "moved").unwrap();
+            journal.record(vec![MoveRecord::new(from, to)]);
+        }
+        // The user recreated `a` after the apply.
+        fs::write(tmp.path().join("a-original"), "new").unwrap();
+        journal
+    }
+
+    #[test]
+    fn rollback_aborts_on_occupied_origin() {
+        let tmp = TempDir::new().unwrap();
+        let mut journal = occupied_journal(&tmp);
+
+        let err = journal
+            .rollback(RollbackScope::All, RollbackConflict::Abort, None, false)
+            .unwrap_err();
+        assert!(matches!(err, Error::RollbackConflict { path } if path.ends_with("a-original")));
+        assert_eq!(
+            fs::read_to_string(tmp.path().join("a-original")).unwrap(),
+            "new"
+        );
+    }
+
+    #[test]
+    fn rollback_skips_occupied_origin() {
+        let tmp = TempDir::new().unwrap();
+        let mut journal = occupied_journal(&tmp);
+
+        let report = journal
+            .rollback(RollbackScope::All, RollbackConflict::Skip, None, false)
+            .unwrap();
+        assert_eq!(report.reversed.len(), 1);
+        assert_eq!(report.skipped.len(), 1);
+        assert!(!report.is_complete());
+        assert_eq!(
+            fs::read_to_string(tmp.path().join("a-original")).unwrap(),
+            "new"
+        );
+        assert!(tmp.path().join("a-moved").exists());
+        assert!(tmp.path().join("b-original").exists());
+    }
+
+    #[test]
+    fn conflicting_rollback_moves_nothing() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path();
+        fs::create_dir(root.join("nested")).unwrap();
+        for name in ["a", "b"] {
+            fs::write(root.join(name), name).unwrap();
+        }
+        // `a` was moved first, so it is reversed last; its old place is
+        // taken again.
+        fs::write(root.join("nested/a"), "new").unwrap();
+        let mut journal = Journal::new();
+        journal.record(vec![
+            MoveRecord::new(root.join("nested/a"), root.join("a")),
+            MoveRecord::new(root.join("nested/b"), root.join("b")),
+        ]);
+
+        let err = journal
+            .rollback(RollbackScope::All, RollbackConflict::Abort, None, false)
+            .unwrap_err();
+        assert!(matches!(err, Error::RollbackConflict { path } if path == root.join("nested/a")));
+        assert!(root.join("b").exists());
+        assert!(!root.join("nested/b").exists());
+        assert_eq!(journal.entries().count(), 2);
+    }
+
+    #[test]
+    fn rollback_refuses_entries_outside_root() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().join("root");
+        fs::create_dir(&root).unwrap();
+        fs::write(root.join("moved"), "").unwrap();
+        let outside = tmp.path().join("elsewhere");
+        fs::write(&outside, "").unwrap();
+
+        let inside = MoveRecord::new(root.join("nested/moved"), root.join("moved"));
+        let escaping = MoveRecord::new(root.join("nested/../../stolen"), outside.clone());
+        let mut journal = Journal::new();
+        journal.record(vec![inside.clone(), escaping]);
+
+        let err = journal
+            .rollback(
+                RollbackScope::All,
+                RollbackConflict::Abort,
+                Some(&root),
+                false,
+            )
+            .unwrap_err();
+        match err {
+            Error::JournalOutOfBounds { entries, .. } => {
+                assert_eq!(entries.len(), 1);
+                assert_eq!(entries[0].1, outside);
+            }
+            other => panic!("unexpected error: {other}"),
+        }
+        assert!(root.join("moved").exists());
+
+        // The entry that stays inside passes on its own.
+        let mut journal = Journal::new();
+        journal.record(vec![inside]);
+        journal
+            .rollback(
+                RollbackScope::All,
+                RollbackConflict::Abort,
+                Some(&root),
+                false,
+            )
+            .unwrap();
+        assert!(root.join("nested/moved").exists());
+    }
+
+    #[test]
+    fn rollback_without_a_root_keeps_to_the_recorded_roots() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().join("root");
+        fs::create_dir(&root).unwrap();
+        let outside = tmp.path().join("elsewhere");
+        fs::write(&outside, "").unwrap();
+
+        let mut journal = Journal::new();
+        journal.set_roots(vec![root.clone()]);
+        journal.record(vec![MoveRecord::new(root.join("stolen"), outside)]);
+        assert_eq!(
+            journal.recorded_roots(),
+            Some(BTreeSet::from([root.as_path()]))
+        );
+        let err = journal
+            .rollback(RollbackScope::All, RollbackConflict::Abort, None, false)
+            .unwrap_err();
+        assert!(matches!(err, Error::JournalOutOfBounds { .. }));
+
+        // A second root, such as an `--into` destination, admits it.
+        journal.batches[0].roots.push(tmp.path().to_path_buf());
+        journal
+            .rollback(RollbackScope::All, RollbackConflict::Abort, None, false)
+            .unwrap();
+        assert!(root.join("stolen").exists());
+    }
 }
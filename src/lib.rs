@@ -1,7 +1,18 @@
 pub mod analyzer;
+pub mod config;
 pub mod error;
 pub mod journal;
+pub mod lock;
 pub mod mover;
+pub mod observer;
+pub mod output;
+pub mod pipeline;
+pub mod reporter;
+pub mod saved_plan;
 pub mod scanner;
+pub mod tree;
+#[cfg(any(windows, test))]
+mod windows;
 
 pub use error::{Error, Result};
+pub use pipeline::{FlattenOutcome, Options, flatten_directory};
@@ -0,0 +1,220 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use fs2::FileExt;
+use log::{debug, warn};
+
+use crate::{Error, Result};
+
+/// Name of the lock file taken in a tree while it is changed.
+pub const LOCK_FILE: &str = ".fs-cleaner.lock";
+
+/// An advisory lock on a directory tree, held while `apply` or `rollback`
+/// changes it so two processes cannot race on the same renames and
+/// journal. The lock file records the holder's PID and is removed when the
+/// lock is dropped.
+///
+/// The lock is an OS file lock (`flock`/`LockFileEx`), so it is released
+/// even if the holder crashes. A lock can still look held after its owner
+/// is gone where file locks are not shared, such as between hosts on a
+/// network filesystem; [`acquire`](Self::acquire) can break those.
+#[derive(Debug)]
+pub struct TreeLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl TreeLock {
+    /// Lock the tree at `dir`, failing with [`Error::Locked`] if another
+    /// process holds it. With `force`, a lock whose recorded PID is no
+    /// longer running on this host is broken and taken over; a live holder
+    /// is never overridden.
+    pub fn acquire(dir: &Path, force: bool) -> Result<Self> {
+        let path = dir.join(LOCK_FILE);
+        loop {
+            let file = open(&path)?;
+            match file.try_lock_exclusive() {
+                Ok(()) => {}
+                Err(e) if e.kind() == fs2::lock_contended_error().kind() => {
+                    let pid = read_pid(&file);
+                    match pid {
+                        Some(pid) if force && !process_alive(pid) => {
+                            warn!(
+                                "breaking stale lock {} held by process {pid}, which is gone",
+                                path.display()
+                            );
+                            // Windows cannot open a file again until every
+                            // handle to it deleted is closed.
+                            drop(file);
+                            fs::remove_file(&path).map_err(|e| Error::io(path.clone(), e))?;
+                            continue;
+                        }
+                        _ => return Err(Error::Locked { path, pid }),
+                    }
+                }
+                Err(e) => return Err(Error::io(path, e)),
+            }
+            // The previous holder may have removed the file between our open
+            // and lock; that lock guards nothing, so start over.
+            if !still_linked(&file, &path) {
+                continue;
+            }
+            let mut lock = Self { file, path };
+            lock.write_pid()
+                .map_err(|e| Error::io(lock.path.clone(), e))?;
+            debug!("locked {}", dir.display());
+            return Ok(lock);
+        }
+    }
+
+    /// Where the lock file is.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn write_pid(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.rewind()?;
+        writeln!(self.file, "{}", std::process::id())?;
+        self.file.sync_all()
+    }
+}
+
+impl Drop for TreeLock {
+    fn drop(&mut self) {
+        // Remove before unlocking, so a waiter never locks a file that is
+        // about to disappear. Windows will not delete a file while a region
+        // of it is locked, so there it unlocks first; a waiter that wins
+        // the race sees the file gone and starts over.
+        #[cfg(windows)]
+        let _ = FileExt::unlock(&self.file);
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("could not remove lock {}: {e}", self.path.display());
+        }
+        #[cfg(not(windows))]
+        let _ = FileExt::unlock(&self.file);
+    }
+}
+
+fn open(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|e| Error::io(path.to_path_buf(), e))
+}
+
+/// The PID recorded in a held lock file, if it can be read.
+fn read_pid(mut file: &File) -> Option<u32> {
+    let mut data = String::new();
+    file.read_to_string(&mut data).ok()?;
+    data.trim().parse().ok()
+}
+
+/// Whether `file` is still the one at `path`.
+#[cfg(unix)]
+fn still_linked(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(held), Ok(current)) => held.dev() == current.dev() && held.ino() == current.ino(),
+        _ => false,
+    }
+}
+
+/// Without inode numbers to compare, settle for a file still being there.
+#[cfg(not(unix))]
+fn still_linked(_file: &File, path: &Path) -> bool {
+    path.exists()
+}
+
+/// Whether a process with `pid` is running on this host.
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // SAFETY: signal 0 only checks that `pid` exists and may be signalled.
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    io::Error::last_os_error().kind() == io::ErrorKind::PermissionDenied
+}
+
+/// Whether a process with `pid` is running on this host.
+#[cfg(windows)]
+fn process_alive(pid: u32) -> bool {
+    use windows_sys::Win32::Foundation::{CloseHandle, ERROR_ACCESS_DENIED, STILL_ACTIVE};
+    use windows_sys::Win32::System::Threading::{
+        GetExitCodeProcess, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION,
+    };
+
+    // SAFETY: OpenProcess takes no pointers and returns null on failure.
+    let handle = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid) };
+    if handle.is_null() {
+        // A process we may not query is still running.
+        return io::Error::last_os_error().raw_os_error() == Some(ERROR_ACCESS_DENIED as i32);
+    }
+    let mut code = 0;
+    // SAFETY: `handle` is a live process handle, closed right after.
+    let running =
+        unsafe { GetExitCodeProcess(handle, &mut code) } == 0 || code == STILL_ACTIVE as u32;
+    // SAFETY: as above; the handle is not used again.
+    unsafe { CloseHandle(handle) };
+    running
+}
+
+/// Without a way to check, every holder is assumed to be running.
+#[cfg(not(any(unix, windows)))]
+fn process_alive(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn second_lock_is_refused_until_the_first_is_dropped() {
+        let tmp = TempDir::new().unwrap();
+        let lock = TreeLock::acquire(tmp.path(), false).unwrap();
+        assert!(lock.path().exists());
+
+        let err = TreeLock::acquire(tmp.path(), false).unwrap_err();
+        let pid = std::process::id();
+        assert!(matches!(err, Error::Locked { pid: Some(p), .. } if p == pid));
+        // A live holder is not broken, even with force.
+        assert!(TreeLock::acquire(tmp.path(), true).is_err());
+
+        drop(lock);
+        assert!(!tmp.path().join(LOCK_FILE).exists());
+        TreeLock::acquire(tmp.path(), false).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn force_breaks_a_lock_whose_holder_is_gone() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join(LOCK_FILE);
+        // Stand in for a holder on another host: the lock is held here, but
+        // the PID it records is not running.
+        let stale = open(&path).unwrap();
+        stale.try_lock_exclusive().unwrap();
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let dead = child.id();
+        child.wait().unwrap();
+        fs::write(&path, format!("{dead}\n")).unwrap();
+
+        let err = TreeLock::acquire(tmp.path(), false).unwrap_err();
+        assert!(matches!(err, Error::Locked { pid: Some(p), .. } if p == dead));
+        let lock = TreeLock::acquire(tmp.path(), true).unwrap();
+        assert_eq!(
+            fs::read_to_string(lock.path()).unwrap().trim(),
+            std::process::id().to_string()
+        );
+    }
+}
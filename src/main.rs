@@ -1,10 +1,21 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::io::{self, BufRead, IsTerminal, Write};
 use std::path::{Path, PathBuf};
 use std::process;
 
-use clap::{Parser, Subcommand};
-use log::error;
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{error, warn};
 
-use fs_cleaner::{analyzer, journal, mover};
+use fs_cleaner::config::Config;
+use fs_cleaner::lock::TreeLock;
+use fs_cleaner::observer::{LoggingObserver, Observer};
+use fs_cleaner::output::{Event, Output};
+use fs_cleaner::saved_plan::{self, SavedPlan};
+use fs_cleaner::scanner::{CollisionKind, CollisionTypes};
+use fs_cleaner::{analyzer, journal, mover, reporter, scanner, tree};
 
 #[derive(Parser)]
 #[command(
@@ -19,181 +30,2373 @@ struct Cli {
     /// Enable verbose logging
     #[arg(short, long, global = true)]
     verbose: bool,
+
+    /// Output style: human-readable text (default) or one JSON object per line
+    #[arg(long, global = true, value_enum)]
+    output: Option<OutputMode>,
+
+    /// Read option defaults from this file instead of each target's
+    /// `.fs-cleaner.toml`
+    #[arg(long, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Show what a command would change without touching the filesystem
+    /// (apply, rollback and clean; the others never change anything)
+    #[arg(long, global = true)]
+    dry_run: bool,
+}
+
+impl Cli {
+    /// Check the options that need (or rule out) `--dry-run`. Clap can't:
+    /// a global flag given before the subcommand's name is invisible to the
+    /// subcommand's `requires` and `conflicts_with`.
+    fn checked(self) -> Result<Self, clap::Error> {
+        let needs_dry_run = match &self.command {
+            Command::Apply { diff: true, .. } => Some("--diff"),
+            Command::Apply {
+                plan_out: Some(_), ..
+            } => Some("--plan-out"),
+            Command::Rollback {
+                format: Some(_), ..
+            } => Some("--format"),
+            _ => None,
+        };
+        if let (Some(flag), false) = (needs_dry_run, self.dry_run) {
+            return Err(Cli::command().error(
+                ErrorKind::MissingRequiredArgument,
+                format!("{flag} requires --dry-run"),
+            ));
+        }
+        if let (
+            Command::Apply {
+                interactive: true, ..
+            },
+            true,
+        ) = (&self.command, self.dry_run)
+        {
+            return Err(Cli::command().error(
+                ErrorKind::ArgumentConflict,
+                "--interactive cannot be used with --dry-run",
+            ));
+        }
+        Ok(self)
+    }
+}
+
+/// Options controlling which layouts count as redundant nesting.
+#[derive(Args)]
+struct DetectArgs {
+    /// Also treat a lone subdirectory with a different name as a wrapper
+    /// (e.g. `mypkg/mypkg-1.2.3`)
+    #[arg(long)]
+    single_wrapper: bool,
+
+    /// Also treat a lone subdirectory named after a version as a wrapper
+    /// (e.g. `tool/tool-v2`, `tool/1.0.0`)
+    #[arg(long)]
+    version_wrappers: bool,
+
+    /// Compare nested directory names case-insensitively (e.g. `Photos/photos`)
+    #[arg(long)]
+    ignore_case: bool,
+
+    /// Strip this suffix from names before comparing them (repeatable; e.g.
+    /// " (copy)" for `My Project/My Project (copy)`). Fuzzy matching can
+    /// flatten a directory that only looks like a copy; review with
+    /// --interactive
+    #[arg(long, value_name = "SUFFIX")]
+    strip_suffix: Vec<String>,
+
+    /// Strip this prefix from names before comparing them (repeatable; e.g.
+    /// "Copy of "). See --strip-suffix
+    #[arg(long, value_name = "PREFIX")]
+    strip_prefix: Vec<String>,
+
+    /// Ignore leading/trailing whitespace and control characters and Unicode
+    /// normalization differences when comparing names (e.g. `project `)
+    #[arg(long)]
+    normalize_names: bool,
+
+    /// Flatten through a same-named symlink to a directory: move the target's
+    /// contents up, then delete the target and the link. Without this such a
+    /// nesting is refused, since moving the link would leave the contents
+    /// behind
+    #[arg(long)]
+    follow_links: bool,
+
+    /// Only count a same-named directory as redundant when it is the
+    /// parent's only entry (noise files aside)
+    #[arg(long)]
+    only_child: bool,
+
+    /// Search the whole tree beneath the target, not just the target itself
+    #[arg(short, long, conflicts_with_all = ["single_wrapper", "version_wrappers"])]
+    recursive: bool,
+
+    /// Only flatten when the nested directory has at least this many entries
+    /// (default 1)
+    #[arg(long, value_name = "N")]
+    min_children: Option<usize>,
+
+    /// Skip directories excluded by `.gitignore` and other ignore files
+    /// (recursive mode only)
+    #[arg(long, requires = "recursive")]
+    respect_ignore: bool,
+
+    /// File name to ignore beside a redundant directory (repeatable; replaces
+    /// the default .DS_Store, __MACOSX and Thumbs.db)
+    #[arg(long, value_name = "NAME")]
+    noise: Vec<String>,
+
+    /// Order in which each candidate's children are listed and moved
+    #[arg(long, value_enum, default_value_t = SortArg::CodePoint)]
+    sort: SortArg,
+
+    /// Maximum directory depth examined in recursive mode
+    #[arg(long, default_value_t = analyzer::DEFAULT_MAX_DEPTH, requires = "recursive")]
+    max_depth: usize,
+}
+
+impl DetectArgs {
+    fn options(&self, config: &Config) -> analyzer::DetectOptions {
+        let name_match = if !self.strip_suffix.is_empty() || !self.strip_prefix.is_empty() {
+            analyzer::NameMatch::Fuzzy {
+                strip_suffixes: self.strip_suffix.clone(),
+                strip_prefixes: self.strip_prefix.clone(),
+                ignore_case: self.ignore_case,
+            }
+        } else if self.ignore_case {
+            analyzer::NameMatch::CaseInsensitive
+        } else {
+            analyzer::NameMatch::Exact
+        };
+        analyzer::DetectOptions {
+            name_match,
+            min_children: config.min_children(),
+            respect_ignore: self.respect_ignore,
+            normalize_names: self.normalize_names,
+            follow_links: self.follow_links,
+            sort: self.sort.into(),
+            only_child: self.only_child,
+            noise: if self.noise.is_empty() {
+                analyzer::DetectOptions::default().noise
+            } else {
+                self.noise.clone()
+            },
+        }
+    }
+}
+
+/// CLI spelling of [`analyzer::SortOrder`].
+#[derive(Clone, Copy, ValueEnum)]
+enum SortArg {
+    /// By Unicode code point (`B` before `a`)
+    CodePoint,
+    /// Ignoring case, ties broken by code point
+    CaseInsensitive,
+}
+
+impl From<SortArg> for analyzer::SortOrder {
+    fn from(order: SortArg) -> Self {
+        match order {
+            SortArg::CodePoint => analyzer::SortOrder::CodePoint,
+            SortArg::CaseInsensitive => analyzer::SortOrder::CaseInsensitive,
+        }
+    }
+}
+
+/// CLI spelling of [`mover::CollisionPolicy`].
+#[derive(Clone, Copy, ValueEnum)]
+enum OnCollision {
+    /// Abort the whole flatten
+    Abort,
+    /// Move the incoming item to `name (N).ext`
+    Rename,
+    /// Move the existing item aside to `name.bak` first (requires --force)
+    Overwrite,
+}
+
+impl From<OnCollision> for mover::CollisionPolicy {
+    fn from(policy: OnCollision) -> Self {
+        match policy {
+            OnCollision::Abort => mover::CollisionPolicy::Abort,
+            OnCollision::Rename => mover::CollisionPolicy::Rename,
+            OnCollision::Overwrite => mover::CollisionPolicy::Overwrite,
+        }
+    }
+}
+
+/// CLI spelling of [`Output`].
+#[derive(Clone, Copy, ValueEnum)]
+enum OutputMode {
+    Text,
+    Json,
+}
+
+impl From<OutputMode> for Output {
+    fn from(mode: OutputMode) -> Self {
+        match mode {
+            OutputMode::Text => Output::Text,
+            OutputMode::Json => Output::Json,
+        }
+    }
+}
+
+/// CLI spelling of [`reporter::Format`].
+#[derive(Clone, Copy, ValueEnum)]
+enum OutputFormat {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl From<OutputFormat> for reporter::Format {
+    fn from(format: OutputFormat) -> Self {
+        match format {
+            OutputFormat::Json => reporter::Format::Json,
+            OutputFormat::Yaml => reporter::Format::Yaml,
+            OutputFormat::Toml => reporter::Format::Toml,
+        }
+    }
 }
 
 #[derive(Subcommand)]
 enum Command {
     /// Analyze a directory for redundant nesting
     Analyze {
-        /// Target directory to analyze
-        path: PathBuf,
+        /// Target directories to analyze
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+
+        #[command(flatten)]
+        detect: DetectArgs,
+
+        /// Draw each candidate as a tree, expanding child directories down
+        /// to `--tree=DEPTH` levels (default 1: just the items that move)
+        #[arg(
+            long,
+            value_name = "DEPTH",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "1"
+        )]
+        tree: Option<usize>,
+
+        /// Group recursive results under a header per top-level project
+        /// (each directory directly inside the target)
+        #[arg(long, requires = "recursive")]
+        group: bool,
+
+        /// Print only the number of candidates in the whole tree (as with
+        /// --recursive), exiting with 10 if there are any
+        #[arg(long, conflicts_with_all = ["tree", "group"])]
+        count: bool,
     },
 
     /// Apply flattening (moves files up one level)
     Apply {
-        /// Target directory to flatten
-        path: PathBuf,
+        /// Target directories to flatten, each independently
+        #[arg(required_unless_present = "plan", conflicts_with = "plan")]
+        paths: Vec<PathBuf>,
+
+        #[command(flatten)]
+        detect: Box<DetectArgs>,
+
+        /// With --dry-run, also print the paths that would disappear (-) and
+        /// appear (+), diff-style
+        #[arg(long)]
+        diff: bool,
+
+        /// With --dry-run, also write the planned operations to FILE as JSON
+        /// for review; carry them out later with --plan
+        #[arg(long, value_name = "FILE")]
+        plan_out: Option<PathBuf>,
+
+        /// Carry out the operations saved by --plan-out exactly as recorded,
+        /// refusing if anything they touch has changed since
+        #[arg(long, value_name = "FILE", conflicts_with = "plan_out")]
+        plan: Option<PathBuf>,
+
+        /// Merge same-named directories instead of aborting on them
+        #[arg(long)]
+        merge: bool,
+
+        /// Settle directory collisions where one side is empty: leave out an
+        /// empty nested directory, or replace an empty existing one
+        #[arg(long)]
+        merge_empty: bool,
+
+        /// What to do when an item collides with an existing entry (default abort)
+        #[arg(long, value_enum)]
+        on_collision: Option<OnCollision>,
+
+        /// Allow destructive collision handling (--on-collision overwrite),
+        /// run a --plan whose paths changed since it was saved, break a
+        /// lock left by a process that is gone, or exceed --max-total-moves
+        #[arg(long)]
+        force: bool,
+
+        /// Delete nested files that are byte-identical to the colliding file
+        #[arg(long)]
+        skip_identical: bool,
+
+        /// Recreate moved symlinks so relative targets still resolve
+        #[arg(long)]
+        rewrite_symlinks: bool,
+
+        /// Delete noise files (see --noise) left beside the redundant
+        /// directories
+        #[arg(long)]
+        remove_noise: bool,
+
+        /// Only look this many levels below the nested directory for symlinks
+        #[arg(long, value_name = "N")]
+        symlink_scan_depth: Option<usize>,
+
+        /// Don't scan for symlinks on other filesystems mounted below the
+        /// nested directory
+        #[arg(long)]
+        same_file_system: bool,
+
+        /// Warn if a moved item would bring one of these names into the
+        /// destination
+        #[arg(long, value_name = "NAME,...", value_delimiter = ',')]
+        reserved_names: Vec<OsString>,
+
+        /// Move only the children with these names, leaving the rest (and the
+        /// nested directory) in place
+        #[arg(long, value_name = "NAME,...", value_delimiter = ',')]
+        only: Vec<OsString>,
+
+        /// Retry a rename that fails because the file is briefly locked (e.g.
+        /// by antivirus) up to N times, waiting 100ms, then 200ms, ...
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        retries: u32,
+
+        /// Flatten a nested directory that is a mount point by copying its
+        /// contents out, leaving it in place (requires --force)
+        #[arg(long, requires = "force")]
+        copy_across_mounts: bool,
+
+        /// Refuse to flatten a directory whose plan has more than N moves,
+        /// unless --force is given
+        #[arg(long, value_name = "N")]
+        max_total_moves: Option<usize>,
+
+        /// Leave the emptied nested directory in place instead of deleting it
+        #[arg(long)]
+        keep_nested: bool,
+
+        /// Move entries whose destination is free before colliding ones, and
+        /// directories before files, so renamed collisions never take a name
+        /// another entry needs
+        #[arg(long)]
+        sort_moves: bool,
+
+        /// Don't copy extended attributes (SELinux labels, ...) when a move
+        /// falls back to copying across filesystems
+        #[arg(long)]
+        no_xattrs: bool,
+
+        /// Abort if any symlink would point somewhere else after the move
+        #[arg(long)]
+        strict_symlinks: bool,
+
+        /// Abort if part of the nested tree cannot be read while scanning
+        /// for symlinks
+        #[arg(long)]
+        require_complete_scan: bool,
+
+        /// Leave symlinks in the nested directory instead of moving them
+        /// (keeps the nested directory)
+        #[arg(long)]
+        exclude_symlinks: bool,
+
+        /// Ask for confirmation before each move
+        #[arg(short, long)]
+        interactive: bool,
+
+        /// Write journals here, under timestamped names, instead of into
+        /// each flattened directory
+        #[arg(long, value_name = "DIR")]
+        journal_dir: Option<PathBuf>,
 
-        /// Show what would happen without making changes
+        /// Gzip the journal (saved with a `.gz` suffix); rollback and verify
+        /// read compressed journals either way
         #[arg(long)]
-        dry_run: bool,
+        compress_journal: bool,
+
+        /// Record paths in the journal relative to its own directory, so
+        /// rollback still works after the tree is moved elsewhere
+        #[arg(long)]
+        relative_journal: bool,
+
+        /// Move the children into this directory (created if missing)
+        /// instead of the nested directory's parent
+        #[arg(long, value_name = "DIR")]
+        into: Option<PathBuf>,
+
+        /// Leave children whose file name matches this glob in place (repeatable)
+        #[arg(long, value_name = "PATTERN", value_parser = glob::Pattern::new)]
+        exclude: Vec<glob::Pattern>,
+
+        /// Also refuse collisions on this name, like `.git` (repeatable)
+        #[arg(long, value_name = "NAME")]
+        protect: Vec<String>,
     },
 
     /// Roll back a previous apply using the journal
     Rollback {
-        /// Directory where the journal was saved
+        /// Journal file, or the directory it was saved in
+        path: PathBuf,
+
+        /// Skip entries whose original location is occupied instead of aborting
+        #[arg(long)]
+        skip_conflicts: bool,
+
+        /// Undo every recorded apply, newest first, not just the latest
+        #[arg(long)]
+        all: bool,
+
+        /// Undo only the apply with this batch id
+        #[arg(long, value_name = "ID", conflicts_with = "all")]
+        batch: Option<u64>,
+
+        /// Refuse moves outside this directory (default: the directories each
+        /// apply recorded, or the journal's own directory for older journals)
+        #[arg(long, value_name = "DIR")]
+        root: Option<PathBuf>,
+
+        /// Break a lock left by a process that is gone
+        #[arg(long)]
+        force: bool,
+
+        /// With --dry-run, print the preview as JSON, YAML or TOML
+        #[arg(long, value_enum)]
+        format: Option<OutputFormat>,
+    },
+
+    /// Check a journal against the filesystem before trusting a rollback
+    Verify {
+        /// Journal file, or the directory it was saved in
         path: PathBuf,
+
+        /// Print the result as JSON, YAML or TOML instead of text
+        #[arg(long, value_enum)]
+        format: Option<OutputFormat>,
+    },
+
+    /// Delete the journals under a directory once rollback is no longer
+    /// needed
+    Clean {
+        /// Directory to search for journals, recursively
+        path: PathBuf,
+
+        /// Delete without asking for confirmation
+        #[arg(short, long)]
+        yes: bool,
     },
 
     /// Output a JSON report of detected nesting
     Report {
-        /// Target directory to report on
-        path: PathBuf,
+        /// Target directories to report on
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+
+        #[command(flatten)]
+        detect: DetectArgs,
+
+        /// Output format (default json)
+        #[arg(long, value_enum)]
+        format: Option<OutputFormat>,
     },
 }
 
 fn main() {
-    let cli = Cli::parse();
+    // clap exits with 2 on bad usage, which would read as a collision.
+    let cli = Cli::try_parse().and_then(Cli::checked).unwrap_or_else(|e| {
+        let _ = e.print();
+        process::exit(if e.use_stderr() {
+            EXIT_USAGE
+        } else {
+            EXIT_SUCCESS
+        });
+    });
 
-    let log_level = if cli.verbose { "debug" } else { "info" };
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(log_level))
-        .format_timestamp(None)
-        .init();
-
-    let result = match cli.command {
-        Command::Analyze { path } => cmd_analyze(&path),
-        Command::Apply { path, dry_run } => cmd_apply(&path, dry_run),
-        Command::Rollback { path } => cmd_rollback(&path),
-        Command::Report { path } => cmd_report(&path),
+    let mut cli = cli;
+    cli.command.expand_targets();
+    let settings = Settings::new(&cli);
+    // Run-wide settings come from the first target's config.
+    let run_config = match settings.for_target(cli.command.first_path()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {e}");
+            process::exit(exit_code(&e));
+        }
     };
+    let out = run_config.output();
+
+    let log_level = if cli.verbose { "debug" } else { "info" };
+    let mut logger =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(log_level));
+    logger.format_timestamp(None);
+    if out == Output::Json {
+        // Log lines join the event stream on stdout.
+        logger
+            .target(env_logger::Target::Stdout)
+            .format(|buf, record| {
+                let event = Event::Log {
+                    level: record.level().as_str().to_lowercase(),
+                    target: record.target().to_string(),
+                    message: record.args().to_string(),
+                };
+                Output::Json.write(buf, &event)
+            });
+    }
+    logger.init();
 
-    if let Err(e) = result {
-        error!("{e}");
-        process::exit(1);
+    match run(cli, &settings, &run_config) {
+        Outcome::Done | Outcome::Stopped => {}
+        Outcome::NothingToDo => process::exit(EXIT_NOTHING_TO_DO),
+        Outcome::Found => process::exit(EXIT_NESTING_FOUND),
+        Outcome::Failed(code) => process::exit(code),
     }
 }
 
-fn cmd_analyze(path: &Path) -> fs_cleaner::Result<()> {
-    let candidates = analyzer::detect_nesting(path)?;
+/// Carry out the parsed command line, with its targets expanded and its
+/// settings resolved, the way `main` does once logging is set up.
+fn run(cli: Cli, settings: &Settings, run_config: &Config) -> Outcome {
+    let out = run_config.output();
+    let dry_run = cli.dry_run;
+    match cli.command {
+        Command::Analyze {
+            paths,
+            detect,
+            tree,
+            group,
+            count,
+        } => {
+            if count {
+                cmd_count(&paths, &detect, settings)
+            } else {
+                for_each_path(&paths, |p| {
+                    cmd_analyze(p, &detect, tree, group, out, &settings.for_target(Some(p))?)
+                })
+            }
+        }
+        Command::Apply {
+            paths,
+            detect,
+            diff,
+            plan_out,
+            plan,
+            merge,
+            merge_empty,
+            on_collision: _,
+            force,
+            skip_identical,
+            rewrite_symlinks,
+            strict_symlinks,
+            require_complete_scan,
+            exclude_symlinks,
+            remove_noise,
+            only,
+            keep_nested,
+            sort_moves,
+            no_xattrs,
+            retries,
+            max_total_moves,
+            copy_across_mounts,
+            symlink_scan_depth,
+            same_file_system,
+            reserved_names,
+            interactive,
+            journal_dir,
+            compress_journal,
+            relative_journal,
+            exclude: _,
+            protect,
+            into,
+        } => {
+            let mut builder = mover::FlattenOptions::builder()
+                .merge(if merge {
+                    mover::MergeStrategy::MergeDirectories
+                } else {
+                    mover::MergeStrategy::Abort
+                })
+                .skip_identical(skip_identical)
+                .merge_empty(merge_empty)
+                .rewrite_symlinks(rewrite_symlinks)
+                .strict_symlinks(strict_symlinks)
+                .require_complete_scan(require_complete_scan)
+                .exclude_symlinks(exclude_symlinks)
+                .remove_noise(remove_noise)
+                .scan(scanner::ScanOptions {
+                    max_depth: symlink_scan_depth,
+                    same_file_system,
+                    reserved_names,
+                })
+                .remove_nested(!keep_nested)
+                .preserve_xattrs(!no_xattrs && cfg!(unix))
+                .copy_across_mounts(copy_across_mounts)
+                .sort_moves(sort_moves)
+                .dry_run(dry_run)
+                .retry(mover::RetryPolicy {
+                    attempts: retries,
+                    ..Default::default()
+                })
+                .protected(protect);
+            if !only.is_empty() {
+                builder = builder.select(only);
+            }
+            if let Some(into) = into {
+                builder = builder.into_dir(into);
+            }
+            let base = builder.build();
+            let mode = ApplyMode {
+                dry_run,
+                interactive,
+                show_progress: !cli.verbose,
+                out,
+                journal_dir: journal_dir.as_deref(),
+                compress_journal,
+                relative_journal,
+                force,
+                diff,
+                max_total_moves: max_total_moves.filter(|_| !force),
+            };
+            if let Some(file) = plan {
+                for_each_path(&[file], |f| cmd_apply_plan(f, &mode))
+            } else {
+                let mut saved = Vec::new();
+                let outcome = for_each_path(&paths, |p| {
+                    let config = settings.for_target(Some(p))?;
+                    if config.on_collision() == mover::CollisionPolicy::Overwrite && !force {
+                        return Err(fs_cleaner::Error::Other(
+                            "overwriting on collision requires --force".into(),
+                        ));
+                    }
+                    let mut options = base.clone();
+                    options.collisions = config.on_collision();
+                    options.exclude = config.exclude();
+                    cmd_apply(p, &detect, &config, &mode, &options, &mut saved)
+                });
+                match plan_out {
+                    // A partial plan would be misleading to approve.
+                    Some(file) if !matches!(outcome, Outcome::Failed(_)) => {
+                        match saved_plan::save(&saved, &file) {
+                            Ok(()) => {
+                                out.emit(&Event::PlanSaved { path: file });
+                                outcome
+                            }
+                            Err(e) => {
+                                error!("{e}");
+                                Outcome::Failed(exit_code(&e))
+                            }
+                        }
+                    }
+                    _ => outcome,
+                }
+            }
+        }
+        Command::Rollback {
+            path,
+            skip_conflicts,
+            all,
+            batch,
+            root,
+            force,
+            format,
+        } => {
+            let mode = RollbackMode {
+                skip_conflicts,
+                all,
+                batch,
+                root: root.as_deref(),
+                dry_run,
+                force,
+                format: format.map(Into::into),
+            };
+            for_each_path(&[path], |p| cmd_rollback(p, &mode, out))
+        }
+        Command::Verify { path, format } => {
+            for_each_path(&[path], |p| cmd_verify(p, format.map(Into::into), out))
+        }
+        Command::Clean { path, yes } => for_each_path(&[path], |p| cmd_clean(p, dry_run, yes, out)),
+        Command::Report { paths, detect, .. } => {
+            cmd_report(&paths, &detect, settings, run_config.format())
+        }
+    }
+}
+
+impl Command {
+    /// The first target directory, if the command takes any.
+    fn first_path(&self) -> Option<&Path> {
+        match self {
+            Command::Analyze { paths, .. }
+            | Command::Apply { paths, .. }
+            | Command::Report { paths, .. } => paths.first().map(PathBuf::as_path),
+            Command::Rollback { .. } | Command::Verify { .. } | Command::Clean { .. } => None,
+        }
+    }
+
+    /// Expand glob patterns among the target directories.
+    fn expand_targets(&mut self) {
+        match self {
+            Command::Analyze { paths, .. }
+            | Command::Apply { paths, .. }
+            | Command::Report { paths, .. } => *paths = expand_globs(paths),
+            Command::Rollback { .. } | Command::Verify { .. } | Command::Clean { .. } => {}
+        }
+    }
+}
+
+/// Replace each argument that is a glob pattern with the directories it
+/// matches, in sorted order. Arguments naming an existing path are kept as
+/// they are, since the shell has already expanded them, as are patterns
+/// that match no directory, so the usual "not found" error reports them.
+fn expand_globs(args: &[PathBuf]) -> Vec<PathBuf> {
+    let mut targets = Vec::new();
+    for arg in args {
+        let pattern = arg.to_string_lossy();
+        let is_pattern = pattern.contains(['*', '?', '[']);
+        if !is_pattern || arg.exists() {
+            targets.push(arg.clone());
+            continue;
+        }
+        // An invalid pattern matches nothing.
+        let matches: Vec<PathBuf> = glob::glob(&pattern)
+            .into_iter()
+            .flatten()
+            .filter_map(|p| p.ok())
+            .filter(|p| p.is_dir())
+            .collect();
+        if matches.is_empty() {
+            targets.push(arg.clone());
+        } else {
+            targets.extend(matches);
+        }
+    }
+    targets
+}
+
+/// Command-line values layered over each target's config file.
+struct Settings {
+    /// `--config`, used instead of looking for a file in each target.
+    explicit: Option<PathBuf>,
+    /// Options given as flags, which win over any file.
+    flags: Config,
+}
+
+impl Settings {
+    fn new(cli: &Cli) -> Self {
+        let mut flags = Config {
+            output: cli.output.map(Into::into),
+            ..Default::default()
+        };
+        match &cli.command {
+            Command::Analyze { detect, .. } => flags.min_children = detect.min_children,
+            Command::Apply {
+                detect,
+                on_collision,
+                exclude,
+                ..
+            } => {
+                flags.min_children = detect.min_children;
+                flags.on_collision = on_collision.map(Into::into);
+                flags.exclude = (!exclude.is_empty()).then(|| exclude.clone());
+            }
+            Command::Report { detect, format, .. } => {
+                flags.min_children = detect.min_children;
+                flags.format = format.map(Into::into);
+            }
+            Command::Rollback { .. } | Command::Verify { .. } | Command::Clean { .. } => {}
+        }
+        Self {
+            explicit: cli.config.clone(),
+            flags,
+        }
+    }
+
+    /// The effective config for `target`. Without a target only
+    /// `--config` is consulted.
+    fn for_target(&self, target: Option<&Path>) -> fs_cleaner::Result<Config> {
+        let file = match target {
+            Some(target) => Config::resolve(self.explicit.as_deref(), target)?,
+            None => match &self.explicit {
+                Some(path) => Config::load(path)?,
+                None => Config::default(),
+            },
+        };
+        Ok(self.flags.clone().or(file))
+    }
+}
+
+/// How a command ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Done,
+    /// No redundant nesting was found.
+    NothingToDo,
+    /// The user asked to stop; remaining paths are not processed.
+    Stopped,
+    /// `analyze --count` found candidates.
+    Found,
+    /// At least one path failed (already logged); carries its exit code.
+    Failed(i32),
+}
+
+/// Run `cmd` on each path in turn. A failure is logged and processing
+/// moves on; the first failure decides the exit code. `NothingToDo` is
+/// only reported when it held for every path.
+fn for_each_path(
+    paths: &[PathBuf],
+    mut cmd: impl FnMut(&Path) -> fs_cleaner::Result<Outcome>,
+) -> Outcome {
+    let mut outcome = Outcome::NothingToDo;
+    for path in paths {
+        let result = match cmd(path) {
+            Ok(result) => result,
+            Err(e) => {
+                error!("{e}");
+                Outcome::Failed(exit_code(&e))
+            }
+        };
+        outcome = combine(outcome, result);
+        if result == Outcome::Stopped {
+            break;
+        }
+    }
+    outcome
+}
+
+/// Fold the outcome of one more path into the running total.
+fn combine(total: Outcome, next: Outcome) -> Outcome {
+    match (total, next) {
+        (Outcome::Failed(code), _) | (_, Outcome::Failed(code)) => Outcome::Failed(code),
+        (Outcome::NothingToDo, next) => next,
+        (total, _) => total,
+    }
+}
+
+// Exit codes, so wrappers can tell outcomes apart without parsing output.
+
+/// Successful run.
+const EXIT_SUCCESS: i32 = 0;
+/// I/O or other unclassified failure.
+const EXIT_FAILURE: i32 = 1;
+/// A collision aborted the flatten, or a saved plan no longer matches the
+/// filesystem.
+const EXIT_COLLISION: i32 = 2;
+/// Permission denied.
+const EXIT_PERMISSION: i32 = 3;
+/// A cross-device move or copy failed.
+const EXIT_CROSS_DEVICE: i32 = 4;
+/// No redundant nesting was found, so nothing was done.
+const EXIT_NOTHING_TO_DO: i32 = 5;
+/// A broken symlink blocked the operation.
+const EXIT_BROKEN_SYMLINK: i32 = 6;
+/// Rollback found an original location occupied, or a batch that cannot be
+/// undone on its own.
+const EXIT_ROLLBACK_CONFLICT: i32 = 7;
+/// The journal could not be trusted.
+const EXIT_JOURNAL_CORRUPT: i32 = 8;
+/// Another process holds the tree's lock.
+const EXIT_LOCKED: i32 = 9;
+/// `analyze --count` found redundant nesting.
+const EXIT_NESTING_FOUND: i32 = 10;
+/// Invalid command-line usage (`EX_USAGE`), kept apart from collisions.
+const EXIT_USAGE: i32 = 64;
+
+/// The process exit code for a failed command.
+fn exit_code(err: &fs_cleaner::Error) -> i32 {
+    use fs_cleaner::Error;
+
+    match err {
+        Error::Collision { .. }
+        | Error::VcsCollision { .. }
+        | Error::TypeMismatch { .. }
+        | Error::PlanStale { .. } => EXIT_COLLISION,
+        Error::Permission { .. } => EXIT_PERMISSION,
+        Error::CrossDevice { .. } | Error::MountBoundary { .. } => EXIT_CROSS_DEVICE,
+        Error::BrokenSymlink { .. } | Error::NestedIsSymlink { .. } => EXIT_BROKEN_SYMLINK,
+        Error::IncompleteScan { kind, .. } if *kind == std::io::ErrorKind::PermissionDenied => {
+            EXIT_PERMISSION
+        }
+        Error::RollbackConflict { .. } | Error::BatchDependency { .. } => EXIT_ROLLBACK_CONFLICT,
+        Error::JournalCorrupt { .. } | Error::JournalOutOfBounds { .. } => EXIT_JOURNAL_CORRUPT,
+        Error::Locked { .. } => EXIT_LOCKED,
+        Error::Config { .. } | Error::NotADirectory { .. } => EXIT_USAGE,
+        Error::Io { .. }
+        | Error::IncompleteScan { .. }
+        | Error::TooManyMoves { .. }
+        | Error::JournalNotFound { .. }
+        | Error::Other(_) => EXIT_FAILURE,
+    }
+}
+
+/// Run the default same-name detector (over the whole tree in recursive
+/// mode), falling back to version- and then single-wrapper detection only when
+/// the user opted in.
+fn detect_candidates(
+    path: &Path,
+    detect: &DetectArgs,
+    config: &Config,
+) -> fs_cleaner::Result<Vec<analyzer::NestingCandidate>> {
+    if detect.recursive {
+        return analyzer::detect_nesting_recursive(path, detect.max_depth, &detect.options(config));
+    }
+
+    let candidates = analyzer::detect_nesting_with_options(path, &detect.options(config))?;
+    if candidates.is_empty() && detect.version_wrappers {
+        let wrappers = analyzer::detect_version_wrapper(path, &detect.options(config))?;
+        if !wrappers.is_empty() {
+            return Ok(wrappers);
+        }
+    }
+    if candidates.is_empty() && detect.single_wrapper {
+        return analyzer::detect_single_wrapper_with_options(path, &detect.options(config));
+    }
+    Ok(candidates)
+}
+
+fn cmd_analyze(
+    path: &Path,
+    detect: &DetectArgs,
+    tree: Option<usize>,
+    group: bool,
+    out: Output,
+    config: &Config,
+) -> fs_cleaner::Result<Outcome> {
+    let candidates = detect_candidates(path, detect, config)?;
 
     if candidates.is_empty() {
-        println!("No redundant nesting detected in {}", path.display());
-        return Ok(());
+        out.emit(&Event::NoNesting {
+            path: path.to_path_buf(),
+        });
+        return Ok(Outcome::NothingToDo);
+    }
+
+    let show = |c: &analyzer::NestingCandidate| match out {
+        Output::Text => print_analysis(c, tree),
+        Output::Json => out.emit(&Event::Candidate(Box::new(reporter::CandidateReport::new(
+            c,
+        )))),
+    };
+    if group {
+        for (project, candidates) in analyzer::group_by_project(path, candidates)? {
+            out.emit(&Event::Project {
+                path: project,
+                candidates: candidates.len(),
+            });
+            candidates.iter().for_each(show);
+            if out == Output::Text {
+                println!();
+            }
+        }
+    } else {
+        candidates.iter().for_each(show);
     }
 
-    for c in &candidates {
-        println!("Detected redundant nesting: {}", c.nested.display());
+    out.emit(&Event::Analyzed {
+        path: path.to_path_buf(),
+    });
+    Ok(Outcome::Done)
+}
+
+/// Print the number of candidates in the trees under `paths`, for scripts.
+/// Nothing is scanned: only the detector runs.
+fn cmd_count(paths: &[PathBuf], detect: &DetectArgs, settings: &Settings) -> Outcome {
+    let mut total = 0;
+    let outcome = for_each_path(paths, |p| {
+        let config = settings.for_target(Some(p))?;
+        total += analyzer::detect_nesting_recursive(p, detect.max_depth, &detect.options(&config))?
+            .len();
+        Ok(Outcome::Done)
+    });
+    if let Outcome::Failed(_) = outcome {
+        // A partial count would pass for a real one.
+        return outcome;
+    }
+    println!("{total}");
+    if total > 0 {
+        Outcome::Found
+    } else {
+        Outcome::Done
+    }
+}
+
+/// Print what `analyze` found for one candidate: the proposed moves (or a
+/// tree of them) and every risk the scan reported.
+fn print_analysis(c: &analyzer::NestingCandidate, tree: Option<usize>) {
+    match &c.via_symlink {
+        Some(link) => println!(
+            "Detected redundant nesting: {} (via symlink {} -> {})",
+            c.nested.display(),
+            link.display(),
+            c.parent.display()
+        ),
+        None => println!("Detected redundant nesting: {}", c.nested.display()),
+    }
+    let depth = c.depth();
+    println!(
+        "{depth} level{} of redundant nesting",
+        if depth == 1 { "" } else { "s" }
+    );
+    if c.is_empty_nesting() {
+        for dir in c.redundant_dirs() {
+            println!(
+                "Nothing to move; flattening means removing empty redundant directory {}",
+                dir.display()
+            );
+        }
+        return;
+    }
+    let report = fs_cleaner::scanner::scan(c);
+    if let Some(depth) = tree {
+        print!(
+            "{}",
+            tree::render(c, &report, depth, io::stdout().is_terminal())
+        );
+    } else {
         println!("Proposed moves:");
+        let redundant = c.redundant_dirs();
         for child in &c.children {
             if let Some(name) = child.file_name() {
                 let dest = c.parent.join(name);
-                if dest == c.nested {
+                if redundant.contains(&dest) {
                     continue;
                 }
                 println!("  {} -> {}", name.to_string_lossy(), dest.display());
             }
         }
+    }
+    if report.collisions.is_empty() {
+        println!("\nNo collisions detected.");
+    } else {
+        println!("\nCollisions detected ({}):", report.collisions.len());
+        for col in &report.collisions {
+            let note = match (col.types, col.kind) {
+                (CollisionTypes::FileVsDir, _) => " (file vs directory, will be refused)",
+                (CollisionTypes::DirVsFile, _) => " (directory vs file, will be refused)",
+                (_, CollisionKind::SameInode) => " (same inode, safe)",
+                (_, CollisionKind::IdenticalContent) => " (identical content)",
+                (_, CollisionKind::EmptyDirectory) => " (empty directory, safe with --merge-empty)",
+                (_, CollisionKind::Differing) => "",
+            };
+            println!(
+                "  {} conflicts with {}{note}",
+                col.source.display(),
+                col.existing.display()
+            );
+        }
+    }
 
-        let report = fs_cleaner::scanner::scan(c);
-        if report.collisions.is_empty() {
-            println!("\nNo collisions detected.");
-        } else {
-            println!("\nCollisions detected ({}):", report.collisions.len());
-            for col in &report.collisions {
-                println!(
-                    "  {} conflicts with {}",
-                    col.source.display(),
-                    col.existing.display()
-                );
-            }
+    if let Some(space) = &report.space_warning {
+        println!(
+            "Insufficient space for cross-device copy: {} bytes needed, {} available.",
+            space.required_bytes, space.available_bytes
+        );
+    }
+
+    if !report.special_files.is_empty() {
+        println!("Special files ({}):", report.special_files.len());
+        for special in &report.special_files {
+            println!("  {} ({:?})", special.path.display(), special.kind);
+        }
+    }
+
+    if !report.permission_issues.is_empty() {
+        println!(
+            "Insufficient permissions ({}):",
+            report.permission_issues.len()
+        );
+        for path in &report.permission_issues {
+            println!("  {}", path.display());
         }
+    }
 
-        if report.symlink_risks.is_empty() {
-            println!("No symlink risks detected.");
-        } else {
-            println!("Symlink risks ({}):", report.symlink_risks.len());
-            for risk in &report.symlink_risks {
-                println!("  {} -> {}", risk.link.display(), risk.target.display());
-            }
+    if report.symlink_risks.is_empty() {
+        println!("No symlink risks detected.");
+    } else {
+        println!("Symlink risks ({}):", report.symlink_risks.len());
+        for risk in &report.symlink_risks {
+            let note = if risk.breaks_after_move {
+                " (will break)"
+            } else {
+                ""
+            };
+            println!(
+                "  {} -> {}{note}",
+                risk.link.display(),
+                risk.target.display()
+            );
         }
     }
+}
 
-    println!("\nRun with `apply {}` to execute.", path.display());
-    Ok(())
+/// How `apply` runs, beyond what is flattened.
+struct ApplyMode<'a> {
+    dry_run: bool,
+    interactive: bool,
+    show_progress: bool,
+    out: Output,
+    /// Where journals go; `None` keeps each beside its flattened directory.
+    journal_dir: Option<&'a Path>,
+    compress_journal: bool,
+    relative_journal: bool,
+    /// `--force`: run stale plans, lift the move limits saved with them
+    /// and break locks whose holder is gone.
+    force: bool,
+    /// Print a dry run's before/after tree.
+    diff: bool,
+    /// `--max-total-moves`, unless `--force`: the most moves all the plans
+    /// of one target (or one plan file) may add up to.
+    max_total_moves: Option<usize>,
 }
 
-fn cmd_apply(path: &Path, dry_run: bool) -> fs_cleaner::Result<()> {
-    let candidates = analyzer::detect_nesting(path)?;
+fn cmd_apply(
+    path: &Path,
+    detect: &DetectArgs,
+    config: &Config,
+    mode: &ApplyMode,
+    options: &mover::FlattenOptions,
+    saved: &mut Vec<SavedPlan>,
+) -> fs_cleaner::Result<Outcome> {
+    let ApplyMode { dry_run, out, .. } = *mode;
+    prepare_apply(mode)?;
+
+    let candidates = detect_candidates(path, detect, config)?;
 
     if candidates.is_empty() {
-        println!("Nothing to flatten.");
-        return Ok(());
+        out.emit(&Event::NothingToDo);
+        return Ok(Outcome::NothingToDo);
     }
+    let _locks = lock_parents(candidates.iter().map(|c| c.parent.as_path()), mode)?;
 
-    for candidate in &candidates {
-        if dry_run {
-            println!("[dry-run] Would flatten: {}", candidate.nested.display());
-        }
+    // Plan everything before moving anything, so the move limit covers the
+    // whole target and a refused plan leaves every candidate untouched.
+    let plans = candidates
+        .iter()
+        .map(|candidate| {
+            if candidate.is_empty_nesting() {
+                return Ok(None);
+            }
+            // An abort's error names every collision, so all can be fixed
+            // in one go.
+            mover::plan(candidate, options).map(Some)
+        })
+        .collect::<fs_cleaner::Result<Vec<_>>>()?;
+    check_move_total(plans.iter().flatten(), mode.max_total_moves)?;
 
-        let result = mover::flatten(candidate, dry_run)?;
+    let mut summary = mover::Summary::default();
+    for (candidate, plan) in candidates.iter().zip(plans) {
+        out.emit(&Event::Flatten {
+            nested: candidate.nested.clone(),
+            via_symlink: candidate.via_symlink.clone(),
+            dry_run,
+        });
 
-        for m in &result.moved {
-            let prefix = if dry_run { "[dry-run] " } else { "" };
-            println!("{prefix}{} -> {}", m.from.display(), m.to.display());
+        if candidate.is_empty_nesting() {
+            if !options.remove_nested {
+                continue;
+            }
+            for dir in candidate.redundant_dirs() {
+                out.emit(&Event::RemoveEmpty { dir, dry_run });
+            }
+            if dry_run {
+                saved.push(SavedPlan::new(candidate, mover::plan(candidate, options)?)?);
+            } else {
+                mover::remove_empty_nesting(candidate)?;
+            }
+            continue;
         }
 
-        if !dry_run {
-            let mut j = journal::Journal::new();
-            j.record(result.moved);
-            let journal_path = j.save(&candidate.parent)?;
-            println!("Journal saved to {}", journal_path.display());
+        let Some(plan) = plan else {
+            continue;
+        };
+        if dry_run {
+            saved.push(SavedPlan::new(candidate, plan.clone())?);
+        }
+        if run_plan(&candidate.parent, &plan, mode, &mut summary)? {
+            out.emit(&Event::summary(&summary, dry_run));
+            out.emit(&Event::Stopped);
+            return Ok(Outcome::Stopped);
         }
     }
 
+    out.emit(&Event::summary(&summary, dry_run));
+    Ok(Outcome::Done)
+}
+
+/// Refuse to start when `plans` add up to more moves than `limit`.
+fn check_move_total<'a>(
+    plans: impl IntoIterator<Item = &'a mover::FlattenPlan>,
+    limit: Option<usize>,
+) -> fs_cleaner::Result<()> {
+    let Some(limit) = limit else {
+        return Ok(());
+    };
+    let planned = plans.into_iter().map(|plan| plan.moves.len()).sum();
+    if planned > limit {
+        return Err(fs_cleaner::Error::TooManyMoves { planned, limit });
+    }
     Ok(())
 }
 
-fn cmd_rollback(path: &Path) -> fs_cleaner::Result<()> {
-    let j = journal::Journal::load(path)?;
-    let count = j.rollback()?;
-    println!("Rolled back {count} move(s).");
+/// Checks shared by every apply, before anything is planned or moved.
+fn prepare_apply(mode: &ApplyMode) -> fs_cleaner::Result<()> {
+    if mode.interactive && mode.out == Output::Json {
+        return Err(fs_cleaner::Error::Other(
+            "--interactive cannot be combined with --output json".into(),
+        ));
+    }
+    if let (Some(dir), false) = (mode.journal_dir, mode.dry_run) {
+        std::fs::create_dir_all(dir).map_err(|e| fs_cleaner::Error::io(dir.to_path_buf(), e))?;
+    }
     Ok(())
 }
 
-fn cmd_report(path: &Path) -> fs_cleaner::Result<()> {
-    let candidates = analyzer::detect_nesting(path)?;
+/// Lock each flattened parent (canonical, as detection returns it) for
+/// the rest of an apply; `rollback` locks the same directories. Dry runs
+/// change nothing, so they neither take nor wait for the locks.
+fn lock_parents<'a>(
+    parents: impl IntoIterator<Item = &'a Path>,
+    mode: &ApplyMode,
+) -> fs_cleaner::Result<Vec<TreeLock>> {
+    if mode.dry_run {
+        return Ok(Vec::new());
+    }
+    let parents: BTreeSet<&Path> = parents.into_iter().collect();
+    parents
+        .into_iter()
+        .map(|parent| TreeLock::acquire(parent, mode.force))
+        .collect()
+}
 
-    #[derive(serde::Serialize)]
-    struct Report {
-        path: PathBuf,
-        candidates: Vec<CandidateReport>,
+/// Carry out the plans saved by `apply --dry-run --plan-out`, as recorded.
+/// Every plan is checked against the filesystem before the first one runs,
+/// so a stale file changes nothing unless `--force` says to run it anyway.
+fn cmd_apply_plan(file: &Path, mode: &ApplyMode) -> fs_cleaner::Result<Outcome> {
+    let ApplyMode { dry_run, out, .. } = *mode;
+    prepare_apply(mode)?;
+
+    let mut saved = saved_plan::load(file)?;
+    // A limit saved with a plan is lifted like `--max-total-moves`.
+    if mode.force {
+        for entry in &mut saved {
+            entry.plan.max_moves = None;
+        }
+    }
+    let _locks = lock_parents(saved.iter().map(|e| e.parent.as_path()), mode)?;
+    for entry in &saved {
+        match entry.check() {
+            Err(e @ fs_cleaner::Error::PlanStale { .. }) if mode.force => {
+                warn!("{e}; running it anyway (--force)");
+            }
+            checked => checked?,
+        }
+        entry.plan.check_move_limit()?;
+    }
+    check_move_total(saved.iter().map(|e| &e.plan), mode.max_total_moves)?;
+    if saved.is_empty() {
+        out.emit(&Event::NothingToDo);
+        return Ok(Outcome::NothingToDo);
     }
 
-    #[derive(serde::Serialize)]
-    struct CandidateReport {
-        nested: PathBuf,
-        children: Vec<PathBuf>,
-        collisions: usize,
-        symlink_risks: usize,
+    let mut summary = mover::Summary::default();
+    for entry in &saved {
+        out.emit(&Event::Flatten {
+            nested: entry.nested.clone(),
+            via_symlink: None,
+            dry_run,
+        });
+        if run_plan(&entry.parent, &entry.plan, mode, &mut summary)? {
+            out.emit(&Event::summary(&summary, dry_run));
+            out.emit(&Event::Stopped);
+            return Ok(Outcome::Stopped);
+        }
     }
 
-    let mut report = Report {
-        path: path.to_path_buf(),
-        candidates: Vec::new(),
-    };
+    out.emit(&Event::summary(&summary, dry_run));
+    Ok(Outcome::Done)
+}
 
-    for c in &candidates {
-        let scan = fs_cleaner::scanner::scan(c);
-        report.candidates.push(CandidateReport {
-            nested: c.nested.clone(),
-            children: c.children.clone(),
-            collisions: scan.collisions.len(),
-            symlink_risks: scan.symlink_risks.len(),
+/// Execute one flatten's plan, journaling beside `parent` (or in the
+/// journal directory) and adding what it moved to `summary`. Returns
+/// whether the user quit an interactive apply.
+fn run_plan(
+    parent: &Path,
+    plan: &mover::FlattenPlan,
+    mode: &ApplyMode,
+    summary: &mut mover::Summary,
+) -> fs_cleaner::Result<bool> {
+    let ApplyMode {
+        dry_run,
+        interactive,
+        show_progress,
+        out,
+        journal_dir,
+        compress_journal,
+        relative_journal,
+        force: _,
+        diff,
+        max_total_moves: _,
+    } = *mode;
+    if dry_run && diff {
+        out.emit(&Event::Diff {
+            lines: tree::diff(plan, parent),
         });
     }
 
-    let json = serde_json::to_string_pretty(&report)
+    // Journal each move as it happens so a failed or interrupted apply
+    // can still be rolled back.
+    let mut journal_path = match journal_dir {
+        Some(dir) => {
+            let name = parent
+                .file_name()
+                .map_or("root".into(), |n| n.to_string_lossy());
+            journal::Journal::timestamped_path(dir, &name)
+        }
+        None => journal::Journal::default_path(parent),
+    };
+    if compress_journal {
+        journal_path = journal::Journal::compressed_path(&journal_path);
+    }
+    let mut j = if dry_run {
+        journal::Journal::new()
+    } else {
+        journal::Journal::persistent_at(&journal_path)?
+    };
+    if relative_journal {
+        j.set_relative_paths(true);
+    }
+    // Rollback keeps to these and locks the first, wherever the journal is.
+    let mut roots = vec![parent.to_path_buf()];
+    if !plan.dest.starts_with(parent) {
+        roots.push(plan.dest.clone());
+    }
+    j.set_roots(roots);
+    // Every move renames into the destination, so there is no point starting.
+    if !dry_run && plan.report.permission_issues.contains(&plan.dest) {
+        return Err(fs_cleaner::Error::Permission {
+            path: plan.dest.clone(),
+            source: std::io::ErrorKind::PermissionDenied.into(),
+        });
+    }
+    let (moved, quit) = if interactive {
+        let (moved, quit) = apply_interactive(plan, &mut j, prompt_move)?;
+        for m in &moved {
+            out.emit(&Event::moved(m, dry_run));
+        }
+        (moved, quit)
+    } else {
+        let bar = if show_progress && !dry_run && out == Output::Text {
+            progress_bar()
+        } else {
+            ProgressBar::hidden()
+        };
+        let mut observer = PrintObserver {
+            out,
+            dry_run,
+            bar: bar.clone(),
+        };
+        let mut update = |done: usize, total: usize| {
+            bar.set_length(total as u64);
+            bar.set_position(done as u64);
+        };
+        let progress = (!bar.is_hidden()).then_some(&mut update as &mut dyn FnMut(usize, usize));
+        let result = mover::execute_journaled(plan, dry_run, &mut j, &mut observer, progress);
+        bar.finish_and_clear();
+        (result?.moved, false)
+    };
+
+    *summary += mover::MoveResult::new(plan, moved).summary();
+
+    if !dry_run {
+        j.save_to(&journal_path)?;
+        out.emit(&Event::JournalSaved { path: journal_path });
+    }
+    Ok(quit)
+}
+
+/// A reply to the per-move confirmation prompt.
+enum Answer {
+    Yes,
+    No,
+    All,
+    Quit,
+}
+
+/// Print `question` and read the answer from stdin, trimmed and
+/// lowercased; `None` at end of input.
+fn ask(question: &str) -> fs_cleaner::Result<Option<String>> {
+    print!("{question} ");
+    io::stdout()
+        .flush()
+        .map_err(|e| fs_cleaner::Error::Other(e.to_string()))?;
+
+    let mut line = String::new();
+    let read = io::stdin()
+        .lock()
+        .read_line(&mut line)
         .map_err(|e| fs_cleaner::Error::Other(e.to_string()))?;
-    println!("{json}");
+    Ok((read > 0).then(|| line.trim().to_ascii_lowercase()))
+}
+
+/// Ask on stdin whether to perform `group`, one of
+/// [`mover::FlattenPlan::move_groups`]. End of input counts as quit.
+fn prompt_move(group: &[mover::MoveRecord]) -> fs_cleaner::Result<Answer> {
+    let question = format!("{}? [y]es/[n]o/[a]ll/[q]uit:", describe_moves(group));
+    loop {
+        match ask(&question)?.as_deref() {
+            None | Some("q" | "quit") => return Ok(Answer::Quit),
+            Some("y" | "yes") => return Ok(Answer::Yes),
+            Some("n" | "no") => return Ok(Answer::No),
+            Some("a" | "all") => return Ok(Answer::All),
+            Some(_) => println!("Please answer y, n, a or q."),
+        }
+    }
+}
+
+/// What `group` does, for the confirmation prompt, naming records that
+/// delete their source as deletions. An overwrite's backup and the move
+/// into its place are one question.
+fn describe_moves(group: &[mover::MoveRecord]) -> String {
+    match group {
+        [backup, .., incoming] => format!(
+            "Replace {} with {} (keeping the original as {})",
+            incoming.to.display(),
+            incoming.from.display(),
+            backup.to.display()
+        ),
+        [record] => {
+            let (from, to) = (record.from.display(), record.to.display());
+            match record.op {
+                mover::MoveOp::Rename => format!("Move {from} -> {to}"),
+                mover::MoveOp::ReplaceEmpty => {
+                    format!("Replace the empty directory {to} with {from}")
+                }
+                mover::MoveOp::Deduplicate => {
+                    format!("Delete duplicate {from} (identical to {to})")
+                }
+                mover::MoveOp::Unlink => format!("Delete {from} (a hard link to {to})"),
+            }
+        }
+        [] => String::new(),
+    }
+}
+
+/// Drive a flatten one group of moves at a time, asking `ask` before each
+/// (see [`mover::FlattenPlan::move_groups`]). Declined moves are neither
+/// performed nor journaled, noise is only deleted once every move is done,
+/// and directories are only removed once empty. Returns the moves
+/// performed and whether the user quit.
+fn apply_interactive(
+    plan: &mover::FlattenPlan,
+    j: &mut journal::Journal,
+    mut ask: impl FnMut(&[mover::MoveRecord]) -> fs_cleaner::Result<Answer>,
+) -> fs_cleaner::Result<(Vec<mover::MoveRecord>, bool)> {
+    mover::create_dest(plan, j)?;
+    let mut moved = Vec::new();
+    let mut approve_all = false;
+
+    for group in plan.move_groups() {
+        if !approve_all {
+            match ask(group)? {
+                Answer::Yes => {}
+                Answer::No => continue,
+                Answer::All => approve_all = true,
+                Answer::Quit => return Ok((moved, true)),
+            }
+        }
+        for record in group {
+            mover::execute_move(plan, record, j)?;
+            moved.push(record.clone());
+        }
+    }
+
+    // A declined move keeps the nested directory, and its noise with it.
+    if moved.len() == plan.moves.len() {
+        mover::remove_noise(plan, j)?;
+    }
+    mover::remove_emptied_dirs(plan, j)?;
+    Ok((moved, false))
+}
+
+/// Prints collisions and moves as `apply` events as they happen, keeping
+/// them clear of the progress bar; everything else is logged.
+struct PrintObserver {
+    out: Output,
+    dry_run: bool,
+    bar: ProgressBar,
+}
+
+impl Observer for PrintObserver {
+    fn on_move(&mut self, record: &mover::MoveRecord) {
+        self.bar
+            .suspend(|| self.out.emit(&Event::moved(record, self.dry_run)));
+    }
+
+    fn on_collision(&mut self, collision: &scanner::Collision) {
+        self.bar
+            .suspend(|| self.out.emit(&Event::collision(collision)));
+    }
+
+    fn on_symlink_risk(&mut self, risk: &scanner::SymlinkRisk) {
+        LoggingObserver.on_symlink_risk(risk);
+    }
+
+    fn on_removed_dir(&mut self, dir: &Path) {
+        LoggingObserver.on_removed_dir(dir);
+    }
+}
+
+/// A file-count progress bar for `apply`, drawn on stderr.
+fn progress_bar() -> ProgressBar {
+    let bar = ProgressBar::new(0);
+    bar.set_style(
+        ProgressStyle::with_template("{bar:40} {pos}/{len} files ({eta})")
+            .expect("progress template is valid"),
+    );
+    bar
+}
+
+/// How `rollback` runs.
+struct RollbackMode<'a> {
+    skip_conflicts: bool,
+    all: bool,
+    batch: Option<u64>,
+    root: Option<&'a Path>,
+    dry_run: bool,
+    force: bool,
+    /// Serialize a dry run's [`journal::RollbackPlan`] instead of printing
+    /// text.
+    format: Option<reporter::Format>,
+}
+
+fn cmd_rollback(path: &Path, mode: &RollbackMode, out: Output) -> fs_cleaner::Result<Outcome> {
+    let RollbackMode {
+        skip_conflicts,
+        all,
+        batch,
+        root,
+        dry_run,
+        force,
+        format,
+    } = *mode;
+    // Moves must stay under the roots each apply recorded unless told
+    // otherwise; journals from before roots were recorded are held to
+    // their own directory.
+    let recorded: Option<BTreeSet<PathBuf>> = journal::Journal::load(path)?
+        .recorded_roots()
+        .map(|roots| roots.into_iter().map(Path::to_path_buf).collect());
+    let root = match (root, &recorded) {
+        (Some(root), _) => Some(root),
+        (None, Some(_)) => None,
+        (None, None) if path.is_dir() => Some(path),
+        (None, None) => Some(
+            path.parent()
+                .filter(|p| !p.as_os_str().is_empty())
+                .unwrap_or(Path::new(".")),
+        ),
+    };
+    // The same locks as `apply`, on the flattened parents, so a rollback
+    // cannot race one. Journals that predate recorded roots lock the
+    // directory they are held to, canonical like the parents.
+    let _locks = if dry_run {
+        Vec::new()
+    } else {
+        let dirs: Vec<PathBuf> = match &recorded {
+            Some(roots) => roots.iter().cloned().collect(),
+            None => root
+                .into_iter()
+                .map(|root| {
+                    root.canonicalize()
+                        .map_err(|e| fs_cleaner::Error::io(root.to_path_buf(), e))
+                })
+                .collect::<fs_cleaner::Result<_>>()?,
+        };
+        dirs.iter()
+            .map(|dir| TreeLock::acquire(dir, force))
+            .collect::<fs_cleaner::Result<Vec<_>>>()?
+    };
+    let mut j = journal::Journal::load(path)?;
+    let scope = if all {
+        journal::RollbackScope::All
+    } else {
+        journal::RollbackScope::Latest
+    };
+    // A preview lists occupied entries instead of stopping at the first.
+    let on_conflict = if skip_conflicts || dry_run {
+        journal::RollbackConflict::Skip
+    } else {
+        journal::RollbackConflict::Abort
+    };
+    let report = match batch {
+        Some(id) => j.rollback_batch(id, on_conflict, root, dry_run)?,
+        None => j.rollback(scope, on_conflict, root, dry_run)?,
+    };
+    if dry_run {
+        let plan = journal::RollbackPlan::new(&report);
+        print_rollback_plan(&plan, format, out)?;
+        return match plan.first_occupied() {
+            // The real rollback would stop here.
+            Some(_) if !skip_conflicts => Ok(Outcome::Failed(EXIT_ROLLBACK_CONFLICT)),
+            _ => Ok(Outcome::Done),
+        };
+    }
+    out.emit(&Event::rolled_back(&report));
+    Ok(Outcome::Done)
+}
+
+/// Print what a rollback would do: each entry with its state and size,
+/// then the totals.
+fn print_rollback_plan(
+    plan: &journal::RollbackPlan,
+    format: Option<reporter::Format>,
+    out: Output,
+) -> fs_cleaner::Result<()> {
+    if let Some(format) = format {
+        println!("{}", reporter::serialize(plan, format)?);
+        return Ok(());
+    }
+    for entry in &plan.entries {
+        out.emit(&Event::Revert(entry.clone()));
+    }
+    out.emit(&Event::rollback_preview(plan));
     Ok(())
 }
+
+/// Print the state of each journal entry. Fails with the rollback-conflict
+/// exit code when any entry is blocked; missing ones are only reported,
+/// as rollback passes over them.
+fn cmd_verify(
+    path: &Path,
+    format: Option<reporter::Format>,
+    out: Output,
+) -> fs_cleaner::Result<Outcome> {
+    let report = journal::Journal::load(path)?.verify();
+    match format {
+        Some(format) => println!("{}", reporter::serialize(&report, format)?),
+        None => {
+            for entry in &report.entries {
+                out.emit(&Event::Verify(entry.clone()));
+            }
+            let count = |f: fn(&journal::EntryStatus) -> bool| {
+                report.entries.iter().filter(|e| f(e)).count()
+            };
+            out.emit(&Event::Verified {
+                entries: report.entries.len(),
+                ready: count(|e| e.rollback_ready),
+                blocked: count(journal::EntryStatus::is_blocked),
+                missing: count(journal::EntryStatus::is_missing),
+            });
+        }
+    }
+    if report.is_ready() {
+        Ok(Outcome::Done)
+    } else {
+        Ok(Outcome::Failed(EXIT_ROLLBACK_CONFLICT))
+    }
+}
+
+/// Delete the journals at or below `path`, after confirmation unless
+/// `yes`. Nothing is deleted while an apply or rollback holds the tree or
+/// the parent any of the journals was written for.
+fn cmd_clean(path: &Path, dry_run: bool, yes: bool, out: Output) -> fs_cleaner::Result<Outcome> {
+    // Lock before looking, so an apply cannot add a journal unseen.
+    let mut locks = BTreeMap::new();
+    if !dry_run {
+        let dir = path
+            .canonicalize()
+            .map_err(|e| fs_cleaner::Error::io(path.to_path_buf(), e))?;
+        locks.insert(dir.clone(), TreeLock::acquire(&dir, false)?);
+    }
+    let journals = journal::Journal::find_all(path)?;
+    if journals.is_empty() {
+        out.emit(&Event::NoJournals {
+            path: path.to_path_buf(),
+        });
+        return Ok(Outcome::NothingToDo);
+    }
+    if !dry_run {
+        // The locks `apply` and `rollback` take: the parents the journals
+        // recorded, or the directory holding an older one.
+        for dir in journals.iter().flat_map(|journal| clean_lock_dirs(journal)) {
+            if !locks.contains_key(&dir) && dir.is_dir() {
+                let lock = TreeLock::acquire(&dir, false)?;
+                locks.insert(dir, lock);
+            }
+        }
+    }
+    for journal in &journals {
+        out.emit(&Event::JournalFound {
+            path: journal.clone(),
+        });
+    }
+    let count = journals.len();
+    if dry_run {
+        out.emit(&Event::JournalsRemoved {
+            count,
+            dry_run: true,
+        });
+        return Ok(Outcome::Done);
+    }
+    if !yes
+        && !confirm(&format!(
+            "Remove {count} journal(s)? The applies they record can no longer be rolled back."
+        ))?
+    {
+        out.emit(&Event::Stopped);
+        return Ok(Outcome::Stopped);
+    }
+
+    for journal in &journals {
+        std::fs::remove_file(journal).map_err(|e| fs_cleaner::Error::io(journal.clone(), e))?;
+        let log = journal::Journal::pending_path(journal);
+        if let Err(e) = std::fs::remove_file(&log)
+            && e.kind() != io::ErrorKind::NotFound
+        {
+            return Err(fs_cleaner::Error::io(log, e));
+        }
+    }
+    out.emit(&Event::JournalsRemoved {
+        count,
+        dry_run: false,
+    });
+    Ok(Outcome::Done)
+}
+
+/// The canonical directories `rollback` would lock for `journal`: the
+/// parents its batches recorded, or the directory it is in when it
+/// predates them or cannot be read.
+fn clean_lock_dirs(journal: &Path) -> Vec<PathBuf> {
+    let recorded = journal::Journal::load(journal).ok().and_then(|j| {
+        j.recorded_roots()
+            .map(|roots| roots.into_iter().map(Path::to_path_buf).collect())
+    });
+    recorded.unwrap_or_else(|| {
+        journal
+            .parent()
+            .and_then(|dir| dir.canonicalize().ok())
+            .into_iter()
+            .collect()
+    })
+}
+
+/// Ask a yes/no `question` on stdin. Anything but yes, including end of
+/// input, is no.
+fn confirm(question: &str) -> fs_cleaner::Result<bool> {
+    Ok(matches!(
+        ask(&format!("{question} [y/N]:"))?.as_deref(),
+        Some("y" | "yes")
+    ))
+}
+
+fn cmd_report(
+    paths: &[PathBuf],
+    detect: &DetectArgs,
+    settings: &Settings,
+    format: reporter::Format,
+) -> Outcome {
+    let mut reports = Vec::new();
+    let outcome = for_each_path(paths, |path| {
+        let config = settings.for_target(Some(path))?;
+        let candidates = detect_candidates(path, detect, &config)?;
+        reports.push(reporter::build(path, &candidates));
+        Ok(Outcome::Done)
+    });
+
+    if reports.is_empty() {
+        return outcome;
+    }
+    match reporter::render_all(&reports, format) {
+        Ok(rendered) => {
+            println!("{rendered}");
+            outcome
+        }
+        Err(e) => {
+            error!("{e}");
+            combine(outcome, Outcome::Failed(exit_code(&e)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fs_cleaner::Error;
+
+    #[test]
+    fn exit_codes_distinguish_error_categories() {
+        let path = PathBuf::from("/x");
+        let cases = [
+            (
+                Error::Collision {
+                    existing: path.clone(),
+                    collisions: Vec::new(),
+                },
+                EXIT_COLLISION,
+            ),
+            (
+                Error::io(path.clone(), std::io::ErrorKind::PermissionDenied.into()),
+                EXIT_PERMISSION,
+            ),
+            (
+                Error::CrossDevice {
+                    path: path.clone(),
+                    source: std::io::Error::other("exdev"),
+                },
+                EXIT_CROSS_DEVICE,
+            ),
+            (
+                Error::Io {
+                    path,
+                    source: std::io::ErrorKind::NotFound.into(),
+                },
+                EXIT_FAILURE,
+            ),
+            (Error::Other("boom".into()), EXIT_FAILURE),
+        ];
+        for (err, code) in cases {
+            assert_eq!(exit_code(&err), code, "{err}");
+        }
+    }
+
+    #[test]
+    fn for_each_path_keeps_going_after_a_failure() {
+        let paths = [PathBuf::from("a"), PathBuf::from("b"), PathBuf::from("c")];
+        let mut seen = Vec::new();
+        let outcome = for_each_path(&paths, |p| {
+            seen.push(p.to_path_buf());
+            match p.to_str() {
+                Some("a") => Err(Error::Collision {
+                    existing: p.to_path_buf(),
+                    collisions: Vec::new(),
+                }),
+                Some("b") => Ok(Outcome::Done),
+                _ => Ok(Outcome::NothingToDo),
+            }
+        });
+        assert_eq!(seen.len(), 3);
+        assert_eq!(outcome, Outcome::Failed(EXIT_COLLISION));
+
+        let outcome = for_each_path(&paths, |_| Ok(Outcome::NothingToDo));
+        assert_eq!(outcome, Outcome::NothingToDo);
+    }
+
+    #[test]
+    fn count_walks_the_tree_and_signals_candidates() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let root = tmp.path();
+        std::fs::create_dir_all(root.join("a/a")).unwrap();
+        std::fs::create_dir_all(root.join("b/b/x")).unwrap();
+        std::fs::create_dir_all(root.join("c/d")).unwrap();
+
+        let count = |path: &Path| {
+            let cli = Cli::try_parse_from([
+                "fs-cleaner".as_ref(),
+                "analyze".as_ref(),
+                "--count".as_ref(),
+                path.as_os_str(),
+            ])
+            .unwrap();
+            let Command::Analyze { paths, detect, .. } = &cli.command else {
+                unreachable!()
+            };
+            cmd_count(paths, detect, &Settings::new(&cli))
+        };
+        assert_eq!(count(root), Outcome::Found);
+        assert_eq!(count(&root.join("c")), Outcome::Done);
+    }
+
+    #[test]
+    fn global_dry_run_reaches_every_mutating_command() {
+        let parse = |args: &[&str]| {
+            Cli::try_parse_from(std::iter::once("fs-cleaner").chain(args.iter().copied()))
+                .and_then(Cli::checked)
+        };
+        for args in [
+            ["--dry-run", "apply", "p"],
+            ["apply", "--dry-run", "p"],
+            ["rollback", "p", "--dry-run"],
+            ["--dry-run", "clean", "p"],
+        ] {
+            assert!(parse(&args).unwrap().dry_run, "{args:?}");
+        }
+        assert!(parse(&["--dry-run", "rollback", "p", "--format", "json"]).is_ok());
+        assert!(parse(&["rollback", "p", "--format", "json"]).is_err());
+        assert!(parse(&["--dry-run", "apply", "-i", "p"]).is_err());
+    }
+
+    #[test]
+    fn global_dry_run_apply_changes_nothing() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let root = tmp.path();
+        std::fs::create_dir_all(root.join("a/a")).unwrap();
+        std::fs::write(root.join("a/a/f"), "data").unwrap();
+
+        let target = root.join("a");
+        let args = ["fs-cleaner", "--dry-run", "apply", target.to_str().unwrap()];
+        let mut cli = Cli::try_parse_from(args).and_then(Cli::checked).unwrap();
+        cli.command.expand_targets();
+        let settings = Settings::new(&cli);
+        let run_config = settings.for_target(cli.command.first_path()).unwrap();
+        assert_eq!(run(cli, &settings, &run_config), Outcome::Done);
+
+        assert!(root.join("a/a/f").exists());
+        assert!(!root.join("a/f").exists());
+        for dir in [root, &root.join("a"), &root.join("a/a")] {
+            assert!(!dir.join(fs_cleaner::lock::LOCK_FILE).exists());
+        }
+        assert!(journal::Journal::find_all(root).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rollback_dry_run_leaves_the_tree_alone() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let root = tmp.path();
+        std::fs::create_dir_all(root.join("a/a")).unwrap();
+        std::fs::write(root.join("a/a/f"), "data").unwrap();
+        let candidates = analyzer::detect_nesting(&root.join("a")).unwrap();
+        let mut j = journal::Journal::persistent(&root.join("a")).unwrap();
+        mover::flatten_journaled(
+            &candidates[0],
+            false,
+            &Default::default(),
+            &mut j,
+            &mut LoggingObserver,
+        )
+        .unwrap();
+        let journal = std::fs::read(journal::Journal::default_path(&root.join("a"))).unwrap();
+
+        let mode = RollbackMode {
+            skip_conflicts: false,
+            all: false,
+            batch: None,
+            root: None,
+            dry_run: true,
+            force: false,
+            format: None,
+        };
+        assert_eq!(
+            cmd_rollback(&root.join("a"), &mode, Output::Text).unwrap(),
+            Outcome::Done
+        );
+        assert!(root.join("a/f").exists());
+        assert!(!root.join("a/a").exists());
+        assert_eq!(
+            std::fs::read(journal::Journal::default_path(&root.join("a"))).unwrap(),
+            journal
+        );
+    }
+
+    #[test]
+    fn verify_fails_only_on_blocked_entries() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let root = tmp.path();
+        let mut j = journal::Journal::new();
+        j.record(vec![
+            mover::MoveRecord::new(root.join("a/a/f"), root.join("a/f")),
+            mover::MoveRecord::new(root.join("a/a/g"), root.join("a/g")),
+        ]);
+        let saved = j.save(root).unwrap();
+        std::fs::create_dir_all(root.join("a")).unwrap();
+        std::fs::write(root.join("a/f"), "data").unwrap();
+
+        // g is missing, which rollback passes over as well.
+        assert_eq!(
+            cmd_verify(&saved, None, Output::Json).unwrap(),
+            Outcome::Done
+        );
+        std::fs::create_dir_all(root.join("a/a")).unwrap();
+        std::fs::write(root.join("a/a/f"), "user").unwrap();
+        assert_eq!(
+            cmd_verify(&saved, None, Output::Json).unwrap(),
+            Outcome::Failed(EXIT_ROLLBACK_CONFLICT)
+        );
+    }
+
+    #[test]
+    fn rollback_keeps_to_the_roots_the_apply_recorded() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let root = tmp.path().canonicalize().unwrap();
+        std::fs::create_dir_all(root.join("a/a")).unwrap();
+        std::fs::write(root.join("a/a/f"), "data").unwrap();
+        let journals = root.join("journals");
+        std::fs::create_dir(&journals).unwrap();
+        let candidates = analyzer::detect_nesting(&root.join("a")).unwrap();
+        let options = mover::FlattenOptions::builder()
+            .into_dir(root.join("out"))
+            .build();
+        let plan = mover::plan(&candidates[0], &options).unwrap();
+        let apply = ApplyMode {
+            dry_run: false,
+            interactive: false,
+            show_progress: false,
+            out: Output::Text,
+            journal_dir: Some(&journals),
+            compress_journal: false,
+            relative_journal: false,
+            force: false,
+            diff: false,
+            max_total_moves: None,
+        };
+        run_plan(
+            &candidates[0].parent,
+            &plan,
+            &apply,
+            &mut mover::Summary::default(),
+        )
+        .unwrap();
+        assert!(root.join("out/f").exists());
+
+        // The journal lives in neither the flattened directory nor the
+        // destination, yet needs no --root.
+        let journal = std::fs::read_dir(&journals)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        let mode = RollbackMode {
+            skip_conflicts: false,
+            all: false,
+            batch: None,
+            root: None,
+            dry_run: false,
+            force: false,
+            format: None,
+        };
+        assert_eq!(
+            cmd_rollback(&journal.path(), &mode, Output::Text).unwrap(),
+            Outcome::Done
+        );
+        assert!(root.join("a/a/f").exists());
+        assert!(!root.join("out").exists());
+    }
+
+    #[test]
+    fn apply_and_rollback_lock_the_flattened_parent() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let root = tmp.path().canonicalize().unwrap();
+        std::fs::create_dir_all(root.join("a/a")).unwrap();
+        std::fs::write(root.join("a/a/f"), "data").unwrap();
+        let parent = root.join("a");
+        let cli = Cli::try_parse_from(["fs-cleaner", "apply", "--recursive", "."]).unwrap();
+        let Command::Apply { detect, .. } = cli.command else {
+            unreachable!()
+        };
+        let mode = ApplyMode {
+            dry_run: false,
+            interactive: false,
+            show_progress: false,
+            out: Output::Text,
+            journal_dir: None,
+            compress_journal: false,
+            relative_journal: false,
+            force: false,
+            diff: false,
+            max_total_moves: None,
+        };
+        let apply = || {
+            cmd_apply(
+                &root,
+                &detect,
+                &Config::default(),
+                &mode,
+                &mover::FlattenOptions::default(),
+                &mut Vec::new(),
+            )
+        };
+
+        // Applying from above still takes the parent's lock.
+        let held = TreeLock::acquire(&parent, false).unwrap();
+        let err = apply().unwrap_err();
+        assert!(matches!(err, fs_cleaner::Error::Locked { .. }), "{err}");
+        drop(held);
+        assert_eq!(apply().unwrap(), Outcome::Done);
+        assert!(parent.join("f").exists());
+
+        // So does a rollback bounded by a wider --root.
+        let held = TreeLock::acquire(&parent, false).unwrap();
+        let mode = RollbackMode {
+            skip_conflicts: false,
+            all: false,
+            batch: None,
+            root: Some(&root),
+            dry_run: false,
+            force: false,
+            format: None,
+        };
+        let err = cmd_rollback(&parent, &mode, Output::Text).unwrap_err();
+        assert!(matches!(err, fs_cleaner::Error::Locked { .. }), "{err}");
+        drop(held);
+        assert_eq!(
+            cmd_rollback(&parent, &mode, Output::Text).unwrap(),
+            Outcome::Done
+        );
+        assert!(parent.join("a/f").exists());
+    }
+
+    #[test]
+    fn move_limit_covers_every_plan_of_a_target() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let root = tmp.path().canonicalize().unwrap();
+        for name in ["a", "b"] {
+            std::fs::create_dir_all(root.join(name).join(name)).unwrap();
+            std::fs::write(root.join(name).join(name).join("f"), "data").unwrap();
+            std::fs::write(root.join(name).join(name).join("g"), "data").unwrap();
+        }
+        let cli = Cli::try_parse_from(["fs-cleaner", "apply", "--recursive", "."]).unwrap();
+        let Command::Apply { detect, .. } = cli.command else {
+            unreachable!()
+        };
+        let mode = ApplyMode {
+            dry_run: false,
+            interactive: false,
+            show_progress: false,
+            out: Output::Text,
+            journal_dir: None,
+            compress_journal: false,
+            relative_journal: false,
+            force: false,
+            diff: false,
+            max_total_moves: Some(3),
+        };
+        let options = mover::FlattenOptions::default();
+
+        // Two moves each fit the limit, four together do not: nothing moves.
+        let err = cmd_apply(
+            &root,
+            &detect,
+            &Config::default(),
+            &mode,
+            &options,
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert!(
+            matches!(
+                err,
+                fs_cleaner::Error::TooManyMoves {
+                    planned: 4,
+                    limit: 3
+                }
+            ),
+            "{err}"
+        );
+        assert!(root.join("a/a/f").exists() && root.join("b/b/f").exists());
+
+        // A limit saved with a plan gives way to --force.
+        let candidate = analyzer::detect_nesting(&root.join("a")).unwrap().remove(0);
+        let plan = mover::FlattenOptions::builder().max_moves(2).build();
+        let mut plan = mover::plan(&candidate, &plan).unwrap();
+        plan.max_moves = Some(1);
+        let file = root.join("plan.json");
+        saved_plan::save(&[SavedPlan::new(&candidate, plan).unwrap()], &file).unwrap();
+        let mode = ApplyMode {
+            max_total_moves: None,
+            ..mode
+        };
+        let err = cmd_apply_plan(&file, &mode).unwrap_err();
+        assert!(
+            matches!(err, fs_cleaner::Error::TooManyMoves { .. }),
+            "{err}"
+        );
+        let forced = ApplyMode {
+            force: true,
+            ..mode
+        };
+        assert_eq!(cmd_apply_plan(&file, &forced).unwrap(), Outcome::Done);
+        assert!(root.join("a/f").exists());
+    }
+
+    #[test]
+    fn forced_stale_plan_refuses_a_new_collision() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let root = tmp.path().canonicalize().unwrap();
+        std::fs::create_dir_all(root.join("pkg/pkg")).unwrap();
+        std::fs::write(root.join("pkg/pkg/a.txt"), "PLANNED").unwrap();
+        std::fs::write(root.join("pkg/pkg/b.txt"), "PLANNED").unwrap();
+        let candidate = analyzer::detect_nesting(&root.join("pkg"))
+            .unwrap()
+            .remove(0);
+        let plan = mover::plan(&candidate, &Default::default()).unwrap();
+        let file = root.join("plan.json");
+        saved_plan::save(&[SavedPlan::new(&candidate, plan).unwrap()], &file).unwrap();
+        std::fs::write(root.join("pkg/b.txt"), "USER").unwrap();
+
+        let mode = ApplyMode {
+            dry_run: false,
+            interactive: false,
+            show_progress: false,
+            out: Output::Text,
+            journal_dir: None,
+            compress_journal: false,
+            relative_journal: false,
+            force: true,
+            diff: false,
+            max_total_moves: None,
+        };
+        let err = cmd_apply_plan(&file, &mode).unwrap_err();
+        assert!(matches!(err, fs_cleaner::Error::Collision { .. }), "{err}");
+        assert_eq!(
+            std::fs::read_to_string(root.join("pkg/b.txt")).unwrap(),
+            "USER"
+        );
+        // The move before it was undone.
+        assert!(root.join("pkg/pkg/a.txt").exists());
+        assert!(!root.join("pkg/a.txt").exists());
+    }
+
+    #[test]
+    fn interactive_apply_keeps_noise_when_a_move_is_declined() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let root = tmp.path().join("app");
+        std::fs::create_dir_all(root.join("app/app")).unwrap();
+        let store = root.join("app/.DS_Store");
+        std::fs::write(&store, "finder").unwrap();
+        std::fs::write(root.join("app/app/main.rs"), "").unwrap();
+        let candidate = analyzer::detect_nesting(&root).unwrap().remove(0);
+        let options = mover::FlattenOptions::builder().remove_noise(true).build();
+        let plan = mover::plan(&candidate, &options).unwrap();
+        assert!(!plan.noise.is_empty());
+
+        let mut j = journal::Journal::new();
+        apply_interactive(&plan, &mut j, |_| Ok(Answer::No)).unwrap();
+        assert!(store.exists());
+        assert!(root.join("app/app/main.rs").exists());
+
+        apply_interactive(&plan, &mut j, |_| Ok(Answer::Yes)).unwrap();
+        assert!(!store.exists());
+        assert!(root.join("main.rs").exists());
+        assert!(!root.join("app").exists());
+    }
+
+    #[test]
+    fn prompts_name_what_each_move_does() {
+        let record = |op| mover::MoveRecord {
+            op,
+            ..mover::MoveRecord::new("/p/p/a".into(), "/p/a".into())
+        };
+        assert_eq!(
+            describe_moves(&[record(mover::MoveOp::Rename)]),
+            "Move /p/p/a -> /p/a"
+        );
+        assert_eq!(
+            describe_moves(&[record(mover::MoveOp::Deduplicate)]),
+            "Delete duplicate /p/p/a (identical to /p/a)"
+        );
+        assert_eq!(
+            describe_moves(&[record(mover::MoveOp::Unlink)]),
+            "Delete /p/p/a (a hard link to /p/a)"
+        );
+        let backup = mover::MoveRecord::new("/p/a".into(), "/p/a.bak".into());
+        assert_eq!(
+            describe_moves(&[backup, record(mover::MoveOp::Rename)]),
+            "Replace /p/a with /p/p/a (keeping the original as /p/a.bak)"
+        );
+    }
+
+    #[test]
+    fn interactive_overwrite_asks_once_for_backup_and_move() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let root = tmp.path().join("a");
+        std::fs::create_dir_all(root.join("a")).unwrap();
+        std::fs::write(root.join("a.txt"), "ORIGINAL").unwrap();
+        std::fs::write(root.join("a/a.txt"), "INCOMING").unwrap();
+        let candidate = analyzer::detect_nesting(&root).unwrap().remove(0);
+        let options = mover::FlattenOptions::builder()
+            .collision_policy(mover::CollisionPolicy::Overwrite)
+            .build();
+        let plan = mover::plan(&candidate, &options).unwrap();
+
+        // Declining the backup declines the move that needs it.
+        let mut asked = Vec::new();
+        let mut j = journal::Journal::new();
+        let (moved, quit) = apply_interactive(&plan, &mut j, |group| {
+            asked.push(group.len());
+            Ok(Answer::No)
+        })
+        .unwrap();
+        assert_eq!((moved.len(), quit, asked), (0, false, vec![2]));
+        assert_eq!(
+            std::fs::read_to_string(root.join("a.txt")).unwrap(),
+            "ORIGINAL"
+        );
+        assert!(root.join("a/a.txt").exists());
+        assert!(!root.join("a.txt.bak").exists());
+
+        let (moved, _) = apply_interactive(&plan, &mut j, |_| Ok(Answer::Yes)).unwrap();
+        assert_eq!(moved.len(), 2);
+        assert_eq!(
+            std::fs::read_to_string(root.join("a.txt.bak")).unwrap(),
+            "ORIGINAL"
+        );
+        assert_eq!(
+            std::fs::read_to_string(root.join("a.txt")).unwrap(),
+            "INCOMING"
+        );
+    }
+
+    #[test]
+    fn clean_removes_journals_unless_dry_run() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let nested = tmp.path().join("a/b");
+        std::fs::create_dir_all(&nested).unwrap();
+        let mut j = journal::Journal::new();
+        j.record(vec![mover::MoveRecord::new("/x".into(), "/y".into())]);
+        let saved = j.save(&nested).unwrap();
+
+        let out = Output::Text;
+        assert_eq!(
+            cmd_clean(tmp.path(), true, false, out).unwrap(),
+            Outcome::Done
+        );
+        assert!(saved.exists());
+
+        // An apply or rollback holding the journal's parent blocks it.
+        let held = TreeLock::acquire(&nested.canonicalize().unwrap(), false).unwrap();
+        let err = cmd_clean(tmp.path(), false, true, out).unwrap_err();
+        assert!(matches!(err, fs_cleaner::Error::Locked { .. }), "{err}");
+        assert!(saved.exists());
+        drop(held);
+
+        assert_eq!(
+            cmd_clean(tmp.path(), false, true, out).unwrap(),
+            Outcome::Done
+        );
+        assert!(!saved.exists());
+        assert!(!tmp.path().join(fs_cleaner::lock::LOCK_FILE).exists());
+        assert!(!nested.join(fs_cleaner::lock::LOCK_FILE).exists());
+        assert_eq!(
+            cmd_clean(tmp.path(), false, true, out).unwrap(),
+            Outcome::NothingToDo
+        );
+    }
+
+    #[test]
+    fn expand_globs_only_touches_unexpanded_patterns() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let root = tmp.path();
+        for dir in ["b", "a", "odd[1]"] {
+            std::fs::create_dir(root.join(dir)).unwrap();
+        }
+        std::fs::write(root.join("c.txt"), "").unwrap();
+
+        let expanded = expand_globs(&[root.join("*"), root.join("odd[1]"), root.join("none*")]);
+        assert_eq!(
+            expanded,
+            vec![
+                root.join("a"),
+                root.join("b"),
+                root.join("odd[1]"),
+                // As a pattern `[1]` would only match `odd1`; the existing
+                // literal name wins.
+                root.join("odd[1]"),
+                root.join("none*"),
+            ]
+        );
+    }
+}
@@ -1,10 +1,19 @@
+use std::ffi::OsString;
+use std::fmt;
 use std::fs;
-use std::path::PathBuf;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
 
-use log::info;
+use log::{debug, error, info, warn};
 
-use crate::analyzer::NestingCandidate;
-use crate::scanner::{self, ScanReport};
+use crate::analyzer::{self, NestingCandidate};
+use crate::journal::{self, Journal, RemovedNoise};
+use crate::observer::{LoggingObserver, Observer};
+use crate::scanner::{
+    self, CollisionKind, CollisionTypes, ScanOptions, ScanReport, SpecialFile, SymlinkRisk,
+};
 use crate::{Error, Result};
 
 /// Result of applying a flatten operation.
@@ -12,141 +21,3777 @@ use crate::{Error, Result};
 pub struct MoveResult {
     /// Items successfully moved from nested -> parent.
     pub moved: Vec<MoveRecord>,
+    /// Collisions the pre-move scan reported.
+    pub collisions: usize,
+    /// Symlink risks the pre-move scan reported.
+    pub symlink_risks: usize,
+    /// Everything worth telling the user about, as data. The same warnings
+    /// are logged as they arise.
+    pub warnings: Vec<Warning>,
 }
 
-/// A single item that was moved.
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+/// Something a flatten noticed or did that the user should hear about,
+/// without it being an error.
+#[derive(Debug, Clone)]
+pub enum Warning {
+    /// The moves need copying across filesystems and may not fit.
+    LowSpace {
+        required_bytes: u64,
+        available_bytes: u64,
+    },
+    /// A source or destination the process may not be allowed to change.
+    PermissionDenied(PathBuf),
+    /// A fifo, socket or device node that is moved along with the rest.
+    SpecialFile(SpecialFile),
+    /// A symlink that leads back into the nested tree.
+    SymlinkCycle { link: PathBuf, target: PathBuf },
+    /// A symlink the scan flagged, see [`SymlinkRisk`].
+    SymlinkRisk(SymlinkRisk),
+    /// A redundant or merged directory left in place because it holds
+    /// excluded or noise files.
+    DirectoryKept(PathBuf),
+    /// A noise file that was deleted.
+    NoiseRemoved(PathBuf),
+    /// A moved item that lands under one of
+    /// [`ScanOptions::reserved_names`].
+    ReservedName(PathBuf),
+    /// Part of the nested tree the symlink scan could not read.
+    IncompleteScan { path: PathBuf, kind: io::ErrorKind },
+    /// The nested directory is a mount point (see
+    /// [`ScanReport::mount_boundary`]); its contents are copied out and it
+    /// stays.
+    MountBoundary(PathBuf),
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::LowSpace {
+                required_bytes,
+                available_bytes,
+            } => write!(
+                f,
+                "destination is on another filesystem and may run out of space: \
+                 {required_bytes} bytes to copy, {available_bytes} bytes available"
+            ),
+            Warning::PermissionDenied(path) => {
+                write!(f, "insufficient permissions: {}", path.display())
+            }
+            Warning::SpecialFile(special) => write!(
+                f,
+                "special file ({:?}): {}",
+                special.kind,
+                special.path.display()
+            ),
+            Warning::SymlinkCycle { link, target } => write!(
+                f,
+                "symlink cycle: {} -> {} leads back into the nested tree",
+                link.display(),
+                target.display()
+            ),
+            Warning::SymlinkRisk(risk) => write!(f, "symlink risk: {risk}"),
+            Warning::DirectoryKept(dir) => write!(
+                f,
+                "leaving {} in place: it holds excluded or noise files",
+                dir.display()
+            ),
+            Warning::NoiseRemoved(path) => write!(f, "removed noise {}", path.display()),
+            Warning::ReservedName(path) => {
+                write!(f, "{} would introduce a reserved name", path.display())
+            }
+            Warning::IncompleteScan { path, kind } => {
+                write!(f, "couldn't fully scan {} ({kind})", path.display())
+            }
+            Warning::MountBoundary(nested) => write!(
+                f,
+                "{} is a mount point: copying its contents across filesystems and leaving it in place",
+                nested.display()
+            ),
+        }
+    }
+}
+
+/// Totals for a finished (or dry-run) flatten, from [`MoveResult::summary`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize)]
+pub struct Summary {
+    /// Files (and symlinks) relocated, counting those inside moved
+    /// directories.
+    pub files: usize,
+    /// Directories relocated, including those inside moved directories.
+    pub directories: usize,
+    /// Total size of the relocated files.
+    pub bytes: u64,
+    /// Whether the scan reported collisions or symlink risks.
+    pub warnings: bool,
+}
+
+impl std::ops::AddAssign for Summary {
+    fn add_assign(&mut self, other: Summary) {
+        self.files += other.files;
+        self.directories += other.directories;
+        self.bytes += other.bytes;
+        self.warnings |= other.warnings;
+    }
+}
+
+impl MoveResult {
+    /// The moves of `plan` that were carried out, with its scan counts and
+    /// [warnings](FlattenPlan::warnings), plus every symlink risk.
+    pub fn new(plan: &FlattenPlan, moved: Vec<MoveRecord>) -> Self {
+        let mut warnings = plan.warnings();
+        warnings.extend(
+            plan.report
+                .symlink_risks
+                .iter()
+                .cloned()
+                .map(Warning::SymlinkRisk),
+        );
+        Self {
+            moved,
+            collisions: plan.report.collisions.len(),
+            symlink_risks: plan.report.symlink_risks.len(),
+            warnings,
+        }
+    }
+
+    /// Count what was relocated by walking it, wherever it is now: at the
+    /// destination after a move, still at the source after a dry run.
+    /// Deduplicated and unlinked entries relocated nothing and are left out.
+    pub fn summary(&self) -> Summary {
+        let mut summary = Summary {
+            warnings: self.collisions > 0 || self.symlink_risks > 0,
+            ..Summary::default()
+        };
+        for record in self.moved.iter().filter(|r| r.op.moves_source()) {
+            let path = if fs::symlink_metadata(&record.to).is_ok() {
+                &record.to
+            } else {
+                &record.from
+            };
+            for entry in walkdir::WalkDir::new(path)
+                .into_iter()
+                .filter_map(|e| e.ok())
+            {
+                if entry.file_type().is_dir() {
+                    summary.directories += 1;
+                } else {
+                    summary.files += 1;
+                    summary.bytes += entry.metadata().map_or(0, |m| m.len());
+                }
+            }
+        }
+        summary
+    }
+}
+
+/// A single item that was moved. Records compare equal when they describe
+/// the same move, whenever it was carried out.
+#[derive(Debug, Clone, Eq, serde::Serialize, serde::Deserialize)]
 pub struct MoveRecord {
     pub from: PathBuf,
     pub to: PathBuf,
+    /// How `from` was relocated. Omitted from the journal for plain renames
+    /// so older journals stay valid.
+    #[serde(default, skip_serializing_if = "MoveOp::is_rename")]
+    pub op: MoveOp,
+    /// Set when `from` is a symlink recreated at `to` with an adjusted
+    /// relative target instead of being renamed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relink: Option<Relink>,
+    /// When the move was carried out, so the journal doubles as an audit
+    /// log; written as RFC 3339. `None` in plans and in journals from
+    /// before it was recorded. Rollback does not look at it.
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "rfc3339")]
+    pub at: Option<SystemTime>,
+}
+
+impl MoveRecord {
+    /// A plain rename of `from` to `to`.
+    pub fn new(from: PathBuf, to: PathBuf) -> Self {
+        Self {
+            from,
+            to,
+            op: MoveOp::Rename,
+            relink: None,
+            at: None,
+        }
+    }
+
+    /// This record as carried out at `at`, for the journal.
+    fn done_at(&self, at: SystemTime) -> Self {
+        Self {
+            at: Some(at),
+            ..self.clone()
+        }
+    }
+}
+
+impl PartialEq for MoveRecord {
+    fn eq(&self, other: &Self) -> bool {
+        self.from == other.from
+            && self.to == other.to
+            && self.op == other.op
+            && self.relink == other.relink
+    }
+}
+
+/// Serde for [`MoveRecord::at`]: an RFC 3339 timestamp in UTC, with
+/// milliseconds.
+mod rfc3339 {
+    use std::time::SystemTime;
+
+    use serde::de::Error as _;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(at: &Option<SystemTime>, s: S) -> Result<S::Ok, S::Error> {
+        match at {
+            Some(at) => s.collect_str(&humantime::format_rfc3339_millis(*at)),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<SystemTime>, D::Error> {
+        Option::<String>::deserialize(d)?
+            .map(|at| humantime::parse_rfc3339_weak(&at).map_err(D::Error::custom))
+            .transpose()
+    }
+}
+
+/// The targets of a symlink rewritten by a move, so rollback can restore
+/// the original exactly.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Relink {
+    /// The target as read from the link before the move.
+    pub original: PathBuf,
+    /// The target written at the new location.
+    pub rewritten: PathBuf,
+}
+
+/// The filesystem operation behind a [`MoveRecord`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MoveOp {
+    /// `from` was renamed to `to`.
+    #[default]
+    Rename,
+    /// `from` was byte-identical to the existing `to`, so it was deleted
+    /// instead of moved. Rollback restores it by copying `to` back.
+    Deduplicate,
+    /// `from` was another hard link to the inode at `to`, so only the name
+    /// was removed. Rollback re-creates the link.
+    Unlink,
+    /// `to` was an empty directory, removed so `from` could be renamed
+    /// there (see [`FlattenOptions::merge_empty`]). Rollback moves `from`
+    /// back and recreates the empty directory.
+    ReplaceEmpty,
+}
+
+impl MoveOp {
+    fn is_rename(&self) -> bool {
+        *self == MoveOp::Rename
+    }
+
+    /// Whether `from` itself ends up at `to`, rather than being deleted.
+    pub fn moves_source(self) -> bool {
+        matches!(self, MoveOp::Rename | MoveOp::ReplaceEmpty)
+    }
+}
+
+/// How `flatten` handles a child whose destination already exists.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MergeStrategy {
+    /// Abort the whole flatten on any collision (the default).
+    #[default]
+    Abort,
+    /// Merge same-named directories recursively, moving their contents one
+    /// by one. Only collisions involving a file abort the flatten.
+    MergeDirectories,
+}
+
+/// What `flatten` does with a collision that merging does not resolve.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CollisionPolicy {
+    /// Abort the whole flatten (the default).
+    #[default]
+    Abort,
+    /// Move the incoming item to the first free `name (N).ext` slot.
+    Rename,
+    /// Move the existing item aside to `name.bak` (or `name.bak.N` if
+    /// that is taken) and put the incoming one in its place. Both moves are
+    /// journaled, so rollback puts the original back and removes the backup.
+    Overwrite,
+}
+
+/// Collision handling for [`flatten`]. The default aborts on any collision.
+/// Build one with [`FlattenOptions::builder`], or set fields on a default;
+/// fields may be added, so other crates cannot use struct literals.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct FlattenOptions {
+    /// How same-named directories are handled.
+    pub merge: MergeStrategy,
+    /// What happens to collisions that are not merged.
+    pub collisions: CollisionPolicy,
+    /// Treat a file colliding with a byte-identical file as safe: the nested
+    /// copy is deleted instead of moved.
+    pub skip_identical: bool,
+    /// Children whose file name matches any of these are left where they
+    /// are, along with the directories that still hold them.
+    pub exclude: Vec<glob::Pattern>,
+    /// Recreate moved symlinks with relative targets so they still point
+    /// at the same file from their new location.
+    pub rewrite_symlinks: bool,
+    /// Abort with [`Error::BrokenSymlink`] if any symlink would point
+    /// somewhere else after the move (and is not rewritten to compensate).
+    /// Links that stay behind, excluded by name or as symlinks, break only
+    /// when their target moves away.
+    pub strict_symlinks: bool,
+    /// Abort with [`Error::IncompleteScan`] if the symlink walk could not
+    /// read part of the nested tree. Otherwise that is only a warning.
+    pub require_complete_scan: bool,
+    /// Leave symlink children where they are, like excluded names, instead
+    /// of moving them. The nested directory is kept to hold them.
+    pub exclude_symlinks: bool,
+    /// Names protected like [`scanner::VCS_DIRS`]: a collision on any of
+    /// them aborts the flatten whatever the other options say.
+    pub protected: Vec<String>,
+    /// Move the children here instead of into the candidate's parent. The
+    /// directory is created when the flatten runs if it does not exist.
+    pub into: Option<PathBuf>,
+    /// Delete the candidate's [noise files](NestingCandidate::noise) once
+    /// the moves are done. Otherwise they stay, and so do the redundant
+    /// directories holding them.
+    pub remove_noise: bool,
+    /// Limits on the pre-move symlink scan.
+    pub scan: ScanOptions,
+    /// Move only the children with these names; the rest stay (and keep
+    /// the nested directory) as if excluded, and are not scanned. `None`
+    /// moves everything.
+    pub select: Option<Vec<OsString>>,
+    /// Delete the redundant directories (and a followed nested link) once
+    /// they are emptied. On by default; when off they stay, empty, and the
+    /// journal leaves them out of the directories rollback recreates.
+    pub remove_nested: bool,
+    /// Retrying of renames that fail transiently. None by default.
+    pub retry: RetryPolicy,
+    /// Settle directory collisions where one side is empty
+    /// ([`CollisionKind::EmptyDirectory`]) instead of applying the
+    /// collision policy: an empty nested directory is left out and removed
+    /// with the redundant ones, an empty existing one is replaced by the
+    /// incoming directory ([`MoveOp::ReplaceEmpty`]). Both are journaled.
+    pub merge_empty: bool,
+    /// Copy extended attributes (SELinux labels, `user.*` metadata, ...)
+    /// when a move falls back to copying. On by default on unix; filesystems
+    /// without xattr support only get a warning. Ignored elsewhere.
+    pub preserve_xattrs: bool,
+    /// Flatten a nested directory that is a mount point
+    /// ([`ScanReport::mount_boundary`]) by copying its contents out, leaving
+    /// the (emptied) mount point and the directories above it in place.
+    /// Refused with [`Error::MountBoundary`] otherwise.
+    pub copy_across_mounts: bool,
+    /// Refuse with [`Error::TooManyMoves`] a plan of more moves than this,
+    /// counting each one journaled (merged contents and backups included).
+    /// A guard against flattening the wrong, huge tree. No limit by default.
+    pub max_moves: Option<usize>,
+    /// Plan (and so move and journal) the entries of each directory in a
+    /// collision-safe order instead of the listing order: those whose
+    /// destination is free first, then the colliding ones, directories
+    /// before files within each. Applies to the candidate's children and to
+    /// the contents of every merged directory. A name invented to settle a
+    /// collision is then never one a later entry needs under its own name.
+    /// Off by default.
+    pub sort_moves: bool,
+    /// Plan as usual, but have [`flatten`] and [`execute`] change nothing,
+    /// as if their `dry_run` argument were true (either one is enough).
+    /// Carried in [`FlattenPlan::dry_run`]. Off by default.
+    pub dry_run: bool,
+}
+
+impl Default for FlattenOptions {
+    fn default() -> Self {
+        Self {
+            merge: MergeStrategy::default(),
+            collisions: CollisionPolicy::default(),
+            skip_identical: false,
+            exclude: Vec::new(),
+            rewrite_symlinks: false,
+            strict_symlinks: false,
+            require_complete_scan: false,
+            exclude_symlinks: false,
+            protected: Vec::new(),
+            into: None,
+            remove_noise: false,
+            scan: ScanOptions::default(),
+            select: None,
+            remove_nested: true,
+            retry: RetryPolicy::default(),
+            merge_empty: false,
+            preserve_xattrs: cfg!(unix),
+            max_moves: None,
+            copy_across_mounts: false,
+            sort_moves: false,
+            dry_run: false,
+        }
+    }
+}
+
+/// Builds [`FlattenOptions`] one setting at a time, starting from the
+/// defaults, so callers only name what they change and keep compiling as
+/// settings are added:
+///
+/// ```
+/// use fs_cleaner::mover::{CollisionPolicy, FlattenOptions};
+///
+/// let options = FlattenOptions::builder()
+///     .collision_policy(CollisionPolicy::Rename)
+///     .skip_identical(true)
+///     .max_moves(10_000)
+///     .dry_run(true)
+///     .build();
+/// assert!(options.remove_nested);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct FlattenOptionsBuilder {
+    options: FlattenOptions,
+}
+
+impl FlattenOptions {
+    /// Start a [`FlattenOptionsBuilder`] from [`FlattenOptions::default`].
+    pub fn builder() -> FlattenOptionsBuilder {
+        FlattenOptionsBuilder::default()
+    }
+}
+
+impl FlattenOptionsBuilder {
+    /// See [`FlattenOptions::merge`].
+    pub fn merge(mut self, merge: MergeStrategy) -> Self {
+        self.options.merge = merge;
+        self
+    }
+
+    /// See [`FlattenOptions::collisions`].
+    pub fn collision_policy(mut self, policy: CollisionPolicy) -> Self {
+        self.options.collisions = policy;
+        self
+    }
+
+    /// See [`FlattenOptions::skip_identical`].
+    pub fn skip_identical(mut self, skip: bool) -> Self {
+        self.options.skip_identical = skip;
+        self
+    }
+
+    /// See [`FlattenOptions::exclude`].
+    pub fn exclude(mut self, patterns: impl IntoIterator<Item = glob::Pattern>) -> Self {
+        self.options.exclude = patterns.into_iter().collect();
+        self
+    }
+
+    /// See [`FlattenOptions::rewrite_symlinks`].
+    pub fn rewrite_symlinks(mut self, rewrite: bool) -> Self {
+        self.options.rewrite_symlinks = rewrite;
+        self
+    }
+
+    /// See [`FlattenOptions::strict_symlinks`].
+    pub fn strict_symlinks(mut self, strict: bool) -> Self {
+        self.options.strict_symlinks = strict;
+        self
+    }
+
+    /// See [`FlattenOptions::require_complete_scan`].
+    pub fn require_complete_scan(mut self, require: bool) -> Self {
+        self.options.require_complete_scan = require;
+        self
+    }
+
+    /// See [`FlattenOptions::exclude_symlinks`].
+    pub fn exclude_symlinks(mut self, exclude: bool) -> Self {
+        self.options.exclude_symlinks = exclude;
+        self
+    }
+
+    /// See [`FlattenOptions::protected`].
+    pub fn protected(mut self, names: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.options.protected = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// See [`FlattenOptions::into`].
+    pub fn into_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.options.into = Some(dir.into());
+        self
+    }
+
+    /// See [`FlattenOptions::remove_noise`].
+    pub fn remove_noise(mut self, remove: bool) -> Self {
+        self.options.remove_noise = remove;
+        self
+    }
+
+    /// See [`FlattenOptions::scan`].
+    pub fn scan(mut self, scan: ScanOptions) -> Self {
+        self.options.scan = scan;
+        self
+    }
+
+    /// See [`FlattenOptions::select`].
+    pub fn select(mut self, names: impl IntoIterator<Item = impl Into<OsString>>) -> Self {
+        self.options.select = Some(names.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// See [`FlattenOptions::remove_nested`].
+    pub fn remove_nested(mut self, remove: bool) -> Self {
+        self.options.remove_nested = remove;
+        self
+    }
+
+    /// See [`FlattenOptions::retry`].
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.options.retry = retry;
+        self
+    }
+
+    /// See [`FlattenOptions::merge_empty`].
+    pub fn merge_empty(mut self, merge: bool) -> Self {
+        self.options.merge_empty = merge;
+        self
+    }
+
+    /// See [`FlattenOptions::preserve_xattrs`].
+    pub fn preserve_xattrs(mut self, preserve: bool) -> Self {
+        self.options.preserve_xattrs = preserve;
+        self
+    }
+
+    /// See [`FlattenOptions::copy_across_mounts`].
+    pub fn copy_across_mounts(mut self, copy: bool) -> Self {
+        self.options.copy_across_mounts = copy;
+        self
+    }
+
+    /// See [`FlattenOptions::max_moves`].
+    pub fn max_moves(mut self, limit: usize) -> Self {
+        self.options.max_moves = Some(limit);
+        self
+    }
+
+    /// See [`FlattenOptions::sort_moves`].
+    pub fn sort_moves(mut self, sort: bool) -> Self {
+        self.options.sort_moves = sort;
+        self
+    }
+
+    /// See [`FlattenOptions::dry_run`].
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.options.dry_run = dry_run;
+        self
+    }
+
+    /// The options as set so far.
+    pub fn build(self) -> FlattenOptions {
+        self.options
+    }
+}
+
+/// How often [`execute`] retries a rename that failed with a transient
+/// error, such as a file briefly locked by an antivirus scanner on Windows.
+/// Errors that won't clear up by themselves (a missing file, a permission
+/// problem) are never retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt. The default, 0, never retries.
+    pub attempts: u32,
+    /// Wait before the first retry, doubling before each one after it.
+    pub base_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 0,
+            base_delay: Duration::from_millis(100),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Run `op` until it succeeds, fails with a permanent error, or the
+    /// retries are used up.
+    fn run<T>(&self, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        let mut delay = self.base_delay;
+        for _ in 0..self.attempts {
+            match op() {
+                Err(e) if is_transient(&e) => {
+                    warn!("{e}; retrying in {delay:?}");
+                    thread::sleep(delay);
+                    delay = delay.saturating_mul(2);
+                }
+                result => return result,
+            }
+        }
+        op()
+    }
+}
+
+/// Whether an I/O error may clear up on its own: a sharing or lock
+/// violation on Windows, a busy resource, or an interrupted or timed-out
+/// call.
+fn is_transient(e: &io::Error) -> bool {
+    // ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION.
+    #[cfg(windows)]
+    if matches!(e.raw_os_error(), Some(32 | 33)) {
+        return true;
+    }
+    matches!(
+        e.kind(),
+        io::ErrorKind::ResourceBusy
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+    )
+}
+
+impl FlattenOptions {
+    /// Whether `path` is excluded by name.
+    fn is_excluded(&self, path: &Path) -> bool {
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            return false;
+        };
+        self.exclude.iter().any(|p| p.matches(name))
+    }
+}
+
+/// Plan and optionally execute a flatten operation.
+///
+/// When `dry_run` (or [`FlattenOptions::dry_run`]) is true, no filesystem
+/// changes are made — the function returns what *would* happen. All collisions are resolved up front, so an
+/// abort never leaves a partially moved tree behind.
+///
+/// This is [`plan`] followed by [`execute`].
+pub fn flatten(
+    candidate: &NestingCandidate,
+    dry_run: bool,
+    options: &FlattenOptions,
+) -> Result<MoveResult> {
+    flatten_journaled(
+        candidate,
+        dry_run,
+        options,
+        &mut Journal::new(),
+        &mut LoggingObserver,
+    )
+}
+
+/// Like [`flatten`], appending each move to `journal` as soon as it
+/// succeeds and reporting what happens to `observer` (where [`flatten`]
+/// logs it). With a [`Journal::persistent`] journal the record is on disk
+/// after every move, so even an interrupted flatten can be rolled back.
+pub fn flatten_journaled(
+    candidate: &NestingCandidate,
+    dry_run: bool,
+    options: &FlattenOptions,
+    journal: &mut Journal,
+    observer: &mut dyn Observer,
+) -> Result<MoveResult> {
+    let plan = plan(candidate, options)?;
+    execute_journaled(&plan, dry_run, journal, observer, None)
+}
+
+/// The ordered work a flatten would perform, as computed by [`plan`].
+/// Serializable so it can be reviewed and carried out later, see
+/// [`saved_plan`](crate::saved_plan).
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct FlattenPlan {
+    /// Where the children go: the candidate's parent, or
+    /// [`FlattenOptions::into`].
+    pub dest: PathBuf,
+    /// Whether `dest` has to be created before the first move.
+    pub create_dest: bool,
+    /// The pre-move scan (collisions, symlink risks, space, ...), made
+    /// against `dest`.
+    pub report: ScanReport,
+    /// Moves, in execution order: the candidate's child order, or the
+    /// collision-safe order of [`FlattenOptions::sort_moves`] (see
+    /// [`plan`]).
+    pub moves: Vec<MoveRecord>,
+    /// Source directories emptied by merging, deepest first.
+    pub merged_dirs: Vec<PathBuf>,
+    /// The redundant directories to remove, innermost first (none when
+    /// [`FlattenOptions::remove_nested`] is off).
+    pub redundant_dirs: Vec<PathBuf>,
+    /// The followed symlink to remove after them (see
+    /// [`NestingCandidate::nested_link`]), unless they are kept.
+    pub nested_link: Option<PathBuf>,
+    /// Paths left behind because they matched an exclude pattern, or are
+    /// noise files that are not being removed.
+    pub excluded: Vec<PathBuf>,
+    /// Noise files to delete after the moves, before the directories.
+    pub noise: Vec<PathBuf>,
+    /// Retrying of failed renames, from [`FlattenOptions::retry`].
+    pub retry: RetryPolicy,
+    /// The limits the scan was made with, from [`FlattenOptions::scan`].
+    /// Plans saved before it was recorded load with the defaults.
+    #[serde(default)]
+    pub scan: ScanOptions,
+    /// From [`FlattenOptions::preserve_xattrs`].
+    #[serde(default = "preserve_xattrs_by_default")]
+    pub preserve_xattrs: bool,
+    /// From [`FlattenOptions::max_moves`], checked again by [`execute`].
+    #[serde(default)]
+    pub max_moves: Option<usize>,
+    /// From [`FlattenOptions::dry_run`]: executing the plan, in full or
+    /// one step at a time, changes nothing. Not saved, so a plan loaded
+    /// from a file runs for real.
+    #[serde(skip)]
+    pub dry_run: bool,
+}
+
+fn preserve_xattrs_by_default() -> bool {
+    cfg!(unix)
+}
+
+impl FlattenPlan {
+    /// The moves in the groups that must be carried out together, in plan
+    /// order: an overwrite's backup of the existing item with the move into
+    /// its place (see [`CollisionPolicy::Overwrite`]), every other move on
+    /// its own. Driving a plan with [`execute_move`], perform all of a
+    /// group or none of it.
+    pub fn move_groups(&self) -> impl Iterator<Item = &[MoveRecord]> {
+        self.moves.chunk_by(|backup, next| next.to == backup.from)
+    }
+
+    /// Directories to remove once every move is done, in removal order.
+    /// Directories still holding excluded paths are kept.
+    pub fn dirs_to_remove(&self) -> impl Iterator<Item = &PathBuf> {
+        self.merged_dirs
+            .iter()
+            .chain(&self.redundant_dirs)
+            .filter(|dir| !self.keeps(dir))
+    }
+
+    /// What the pre-move scan and planning found worth telling the user:
+    /// space, permissions, special files, symlinks looping back into the
+    /// nested tree, and directories left in place.
+    /// [`plan`] logs each of these; symlink risks go to the [`Observer`]
+    /// when the plan is executed.
+    pub fn warnings(&self) -> Vec<Warning> {
+        let report = &self.report;
+        let mut warnings = Vec::new();
+        if let Some(space) = &report.space_warning {
+            warnings.push(Warning::LowSpace {
+                required_bytes: space.required_bytes,
+                available_bytes: space.available_bytes,
+            });
+        }
+        warnings.extend(
+            report
+                .permission_issues
+                .iter()
+                .cloned()
+                .map(Warning::PermissionDenied),
+        );
+        warnings.extend(
+            report
+                .special_files
+                .iter()
+                .cloned()
+                .map(Warning::SpecialFile),
+        );
+        warnings.extend(
+            report
+                .reserved_names
+                .iter()
+                .cloned()
+                .map(Warning::ReservedName),
+        );
+        warnings.extend(report.mount_boundary.clone().map(Warning::MountBoundary));
+        warnings.extend(
+            report
+                .scan_errors
+                .iter()
+                .map(|(path, kind)| Warning::IncompleteScan {
+                    path: path.clone(),
+                    kind: *kind,
+                }),
+        );
+        for risk in report.symlink_risks.iter().filter(|r| r.is_cyclic) {
+            if risk.target_inside_nested {
+                warnings.push(Warning::SymlinkCycle {
+                    link: risk.link.clone(),
+                    target: risk.target.clone(),
+                });
+            }
+        }
+        for dir in self.merged_dirs.iter().chain(&self.redundant_dirs) {
+            if self.keeps(dir) {
+                warnings.push(Warning::DirectoryKept(dir.clone()));
+            }
+        }
+        warnings
+    }
+
+    /// Fail with [`Error::TooManyMoves`] if the plan has more moves than its
+    /// [`max_moves`](Self::max_moves).
+    pub fn check_move_limit(&self) -> Result<()> {
+        match self.max_moves {
+            Some(limit) if self.moves.len() > limit => Err(Error::TooManyMoves {
+                planned: self.moves.len(),
+                limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether `dir` must stay because an excluded path lives under it.
+    pub fn keeps(&self, dir: &Path) -> bool {
+        self.excluded.iter().any(|p| p.starts_with(dir))
+    }
+}
+
+/// Scan a candidate and compute its flatten plan without touching the
+/// filesystem. Collisions are resolved here, so an abort happens before
+/// anything moves; under [`CollisionPolicy::Abort`] planning carries on
+/// past the first one, and the [`Error::Collision`] lists them all, along
+/// with any file/directory mismatch (on its own, a mismatch is an
+/// [`Error::TypeMismatch`]).
+///
+/// Children are planned, and so moved, in the candidate's order, and the
+/// contents of a merged directory in code-point order; with
+/// [`FlattenOptions::sort_moves`] each of those is regrouped so free
+/// destinations come before collisions and directories before files.
+///
+/// Pass the plan to [`execute`], or use [`execute_move`] and
+/// [`remove_emptied_dirs`] to drive it one move at a time (e.g. for
+/// interactive approval).
+pub fn plan(candidate: &NestingCandidate, options: &FlattenOptions) -> Result<FlattenPlan> {
+    // Flattening elsewhere is flattening into a different parent.
+    let retargeted;
+    let candidate = match &options.into {
+        Some(into) => {
+            // Resolved like the candidate's paths, so a symlink into the
+            // nested tree is caught; the directory may not exist yet.
+            let dest = journal::canonical(
+                &std::path::absolute(into).map_err(|e| Error::io(into.clone(), e))?,
+            );
+            if dest.starts_with(&candidate.nested) {
+                return Err(Error::Other(format!(
+                    "cannot flatten {} into {}: it would be removed",
+                    candidate.nested.display(),
+                    dest.display()
+                )));
+            }
+            retargeted = NestingCandidate {
+                parent: dest,
+                ..candidate.clone()
+            };
+            &retargeted
+        }
+        None => candidate,
+    };
+
+    // A partial flatten scans and plans only the selected children.
+    let mut unselected = Vec::new();
+    let selected;
+    let candidate = match &options.select {
+        Some(names) => {
+            for name in names {
+                if !candidate
+                    .children
+                    .iter()
+                    .any(|c| c.file_name() == Some(name))
+                {
+                    warn!("nothing named {} to move", name.to_string_lossy());
+                }
+            }
+            let (chosen, rest): (Vec<PathBuf>, Vec<PathBuf>) =
+                candidate.children.iter().cloned().partition(|c| {
+                    c.file_name()
+                        .is_some_and(|n| names.iter().any(|name| name == n))
+                });
+            unselected = rest;
+            selected = NestingCandidate {
+                children: chosen,
+                ..candidate.clone()
+            };
+            &selected
+        }
+        None => candidate,
+    };
+    let report: ScanReport = scanner::scan_with_options(candidate, &options.scan);
+
+    check_special_files(&report)?;
+    if let Some(nested) = &report.mount_boundary
+        && !options.copy_across_mounts
+    {
+        return Err(Error::MountBoundary {
+            nested: nested.clone(),
+        });
+    }
+    if let (true, Some((path, kind))) = (options.require_complete_scan, report.scan_errors.first())
+    {
+        return Err(Error::IncompleteScan {
+            path: path.clone(),
+            kind: *kind,
+        });
+    }
+    if let Some(collision) = report.vcs_collisions(&options.protected).next() {
+        return Err(Error::VcsCollision {
+            existing: collision.existing.clone(),
+        });
+    }
+
+    let mut moves = Vec::new();
+    let mut merged_dirs = Vec::new();
+    let mut collisions = Vec::new();
+    let mut excluded: Vec<PathBuf> = unselected
+        .into_iter()
+        .filter(|c| candidate.destination(c).is_some())
+        .collect();
+    let mut redundant_dirs = candidate.redundant_dirs();
+    // A mount point can't be removed, nor can the directories holding it,
+    // nor the link leading to them.
+    let mut nested_link = candidate.nested_link.clone();
+    if let Some(mount) = &report.mount_boundary {
+        redundant_dirs.retain(|dir| !mount.starts_with(dir));
+        nested_link = None;
+    }
+
+    for child in plan_order(&candidate.children, |c| candidate.destination(c), options) {
+        if child.file_name().is_none() {
+            return Err(Error::Other(format!("no filename for {}", child.display())));
+        }
+        let Some(dest) = candidate.destination(child) else {
+            // Skip the nested directory entry itself; we'll remove it after.
+            continue;
+        };
+
+        plan_move(
+            child,
+            dest,
+            options,
+            &mut moves,
+            &mut merged_dirs,
+            &mut excluded,
+            &mut collisions,
+        )?;
+    }
+    match collisions.as_slice() {
+        [] => {}
+        // A lone file/directory clash keeps its own, plainer message.
+        [only] if only.types.is_mismatch() => {
+            return Err(Error::TypeMismatch {
+                path: only.source.clone(),
+                existing: only.existing.clone(),
+            });
+        }
+        [first, ..] => {
+            return Err(Error::Collision {
+                existing: first.existing.clone(),
+                collisions,
+            });
+        }
+    }
+
+    if options.rewrite_symlinks {
+        for record in &mut moves {
+            record.relink = plan_relink(candidate, record);
+        }
+    }
+
+    if options.strict_symlinks {
+        check_symlinks(&report, &moves, &excluded)?;
+    }
+
+    // A noise file can share its name with a moved child; never delete
+    // what lands there.
+    let (noise, kept_noise): (Vec<PathBuf>, Vec<PathBuf>) = candidate
+        .noise
+        .iter()
+        .cloned()
+        .partition(|n| options.remove_noise && !moves.iter().any(|m| m.to == *n));
+    excluded.extend(kept_noise);
+
+    let plan = FlattenPlan {
+        dest: candidate.parent.clone(),
+        create_dest: !candidate.parent.exists(),
+        report,
+        moves,
+        merged_dirs,
+        redundant_dirs: if options.remove_nested {
+            redundant_dirs
+        } else {
+            Vec::new()
+        },
+        nested_link: nested_link.filter(|_| options.remove_nested),
+        excluded,
+        noise,
+        retry: options.retry,
+        scan: options.scan.clone(),
+        preserve_xattrs: options.preserve_xattrs,
+        max_moves: options.max_moves,
+        dry_run: options.dry_run,
+    };
+    plan.check_move_limit()?;
+    for warning in plan.warnings() {
+        warn!("{warning}");
+    }
+    Ok(plan)
+}
+
+/// The former name of [`plan`].
+#[deprecated(note = "renamed to `plan`")]
+pub fn flatten_plan(candidate: &NestingCandidate, options: &FlattenOptions) -> Result<FlattenPlan> {
+    plan(candidate, options)
+}
+
+/// Carry out a plan: perform every move, then remove the directories they
+/// emptied. If any step fails, the completed ones are undone before the
+/// error is returned. When `dry_run` (or [`FlattenPlan::dry_run`]) is true
+/// nothing is touched.
+///
+/// `progress`, when given, is called as `(done, total)` in files: once per
+/// move, and once per file while a move falls back to copying. With the
+/// `parallel` feature it fires once per move, as each one finishes. The
+/// sources are only walked to count their files when it is given.
+pub fn execute(
+    plan: &FlattenPlan,
+    dry_run: bool,
+    progress: Option<&mut dyn FnMut(usize, usize)>,
+) -> Result<MoveResult> {
+    execute_journaled(
+        plan,
+        dry_run,
+        &mut Journal::new(),
+        &mut LoggingObserver,
+        progress,
+    )
+}
+
+/// Like [`execute`], appending each move to `journal` as soon as it lands
+/// and reporting collisions, symlink risks, moves and removed directories
+/// to `observer` (where [`execute`] logs them).
+pub fn execute_journaled(
+    plan: &FlattenPlan,
+    dry_run: bool,
+    journal: &mut Journal,
+    observer: &mut dyn Observer,
+    progress: Option<&mut dyn FnMut(usize, usize)>,
+) -> Result<MoveResult> {
+    plan.check_move_limit()?;
+    let dry_run = dry_run || plan.dry_run;
+    for collision in &plan.report.collisions {
+        observer.on_collision(collision);
+    }
+    for risk in &plan.report.symlink_risks {
+        observer.on_symlink_risk(risk);
+    }
+
+    let mut result = MoveResult::new(plan, plan.moves.clone());
+    if dry_run {
+        for record in &plan.moves {
+            observer.on_move(record);
+        }
+    } else {
+        let mut done = Vec::new();
+        match execute_steps(plan, journal, observer, &mut done, progress) {
+            Ok(noise) => result
+                .warnings
+                .extend(noise.into_iter().map(Warning::NoiseRemoved)),
+            Err(e) => {
+                undo_steps(&done, journal);
+                return Err(e);
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Perform a single move of `plan` and journal it, retrying a failed rename
+/// per the plan's [`RetryPolicy`]. A [dry-run](FlattenPlan::dry_run) plan's
+/// move is only logged.
+pub fn execute_move(plan: &FlattenPlan, record: &MoveRecord, journal: &mut Journal) -> Result<()> {
+    if plan.dry_run {
+        LoggingObserver.on_move(record);
+        return Ok(());
+    }
+    apply_record(record, plan.retry, plan.preserve_xattrs, &mut || {})?;
+    LoggingObserver.on_move(record);
+    journal.append(record.done_at(SystemTime::now()))
+}
+
+/// Refuse the first symlink that would break, unless its move rewrites it.
+///
+/// The scan takes every link to move, so a link that stays (excluded, or
+/// under an excluded path) is judged again where it is: it breaks if its
+/// target moves.
+fn check_symlinks(report: &ScanReport, moves: &[MoveRecord], excluded: &[PathBuf]) -> Result<()> {
+    let rewritten = |link: &Path| moves.iter().any(|m| m.from == link && m.relink.is_some());
+    let stays = |link: &Path| excluded.iter().any(|e| link.starts_with(e));
+    let breaks = |risk: &SymlinkRisk| {
+        if stays(&risk.link) {
+            let resolved = scanner::resolve_link(&risk.link, &risk.target);
+            scanner::relocate_by(moves, &resolved) != resolved
+        } else {
+            risk.breaks_after_move && !rewritten(&risk.link)
+        }
+    };
+    match report.symlink_risks.iter().find(|r| breaks(r)) {
+        Some(risk) => Err(Error::BrokenSymlink {
+            link: risk.link.clone(),
+            target: risk.target.clone(),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Create the plan's destination if it has to be, along with any missing
+/// ancestors, and record them in `journal` so rollback removes them again.
+/// Returns the directories created, outermost first, or none for a
+/// [dry-run](FlattenPlan::dry_run) plan. [`execute`] does this itself; call
+/// it before driving a plan with [`execute_move`].
+pub fn create_dest(plan: &FlattenPlan, journal: &mut Journal) -> Result<Vec<PathBuf>> {
+    if !plan.create_dest || plan.dry_run {
+        return Ok(Vec::new());
+    }
+    let mut created: Vec<PathBuf> = plan
+        .dest
+        .ancestors()
+        .take_while(|dir| !dir.exists())
+        .map(Path::to_path_buf)
+        .collect();
+    if created.is_empty() {
+        return Ok(created);
+    }
+    created.reverse();
+    fs::create_dir_all(&plan.dest).map_err(|e| Error::io(plan.dest.clone(), e))?;
+    info!("created {}", plan.dest.display());
+    journal.record_created_dirs(created.clone())?;
+    Ok(created)
+}
+
+/// Delete the plan's noise files (directories such as `__MACOSX`
+/// included). [`execute`] does this itself, after the moves; call it before
+/// [`remove_emptied_dirs`] when driving a plan with [`execute_move`].
+/// Each file is recorded in `journal` before it is deleted, with the
+/// contents of small files (see [`RemovedNoise::capture`]) so rollback can
+/// recreate them. Returns what was deleted.
+pub fn remove_noise(plan: &FlattenPlan, journal: &mut Journal) -> Result<Vec<RemovedNoise>> {
+    let mut deleted = Vec::new();
+    if plan.dry_run {
+        return Ok(deleted);
+    }
+    for path in &plan.noise {
+        deleted.extend(remove_noise_path(path, journal)?);
+    }
+    Ok(deleted)
+}
+
+/// Capture one noise path, journal it and delete it; `None` if it is
+/// already gone. The record is retracted if the deletion fails.
+fn remove_noise_path(path: &Path, journal: &mut Journal) -> Result<Option<RemovedNoise>> {
+    let noise = match RemovedNoise::capture(path) {
+        Ok(noise) => noise,
+        Err(Error::Io { source, .. }) if source.kind() == io::ErrorKind::NotFound => {
+            return Ok(None);
+        }
+        Err(e) => return Err(e),
+    };
+    journal.record_removed_noise(vec![noise.clone()])?;
+    let removed = if path.is_dir() && !path.is_symlink() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    };
+    match removed {
+        Ok(()) => {
+            info!("removed noise {}", path.display());
+            Ok(Some(noise))
+        }
+        Err(e) => {
+            journal.retract_removed_noise(path)?;
+            if e.kind() == io::ErrorKind::NotFound {
+                Ok(None)
+            } else {
+                Err(Error::io(path.to_path_buf(), e))
+            }
+        }
+    }
+}
+
+/// Remove the plan's directories that ended up empty, leaving (and warning
+/// about) any that still hold skipped items, and record them in `journal`
+/// for rollback to recreate. Returns the removed paths; none for a
+/// [dry-run](FlattenPlan::dry_run) plan, which removes nothing.
+pub fn remove_emptied_dirs(plan: &FlattenPlan, journal: &mut Journal) -> Result<Vec<PathBuf>> {
+    if plan.dry_run {
+        return Ok(Vec::new());
+    }
+    let mut removed = remove_if_empty(plan.dirs_to_remove())?;
+    journal.record_removed_dirs(removed.clone())?;
+    let link = remove_nested_link(plan.nested_link.as_deref(), &plan.redundant_dirs)?;
+    removed.extend(link.map(|(link, _)| link));
+    Ok(removed)
+}
+
+/// Flatten an [empty nesting](NestingCandidate::is_empty_nesting) without
+/// scanning or journaling: there is nothing to move, only the redundant
+/// directories to delete. Returns the removed paths.
+pub fn remove_empty_nesting(candidate: &NestingCandidate) -> Result<Vec<PathBuf>> {
+    let redundant = candidate.redundant_dirs();
+    let mut removed = remove_if_empty(&redundant)?;
+    let link = remove_nested_link(candidate.nested_link.as_deref(), &redundant)?;
+    removed.extend(link.map(|(link, _)| link));
+    Ok(removed)
+}
+
+/// Remove a followed nested symlink once the directories it led to are
+/// gone. Returns the link if it was removed, with its target.
+fn remove_nested_link(
+    link: Option<&Path>,
+    redundant_dirs: &[PathBuf],
+) -> Result<Option<(PathBuf, PathBuf)>> {
+    let Some(link) = link else {
+        return Ok(None);
+    };
+    if redundant_dirs.iter().any(|d| d.exists()) {
+        warn!("leaving symlink {}: its target was kept", link.display());
+        return Ok(None);
+    }
+    let target = fs::read_link(link).map_err(|e| Error::io(link.to_path_buf(), e))?;
+    fs::remove_file(link).map_err(|e| Error::io(link.to_path_buf(), e))?;
+    info!("removed symlink {}", link.display());
+    Ok(Some((link.to_path_buf(), target)))
+}
+
+fn remove_if_empty<'a>(dirs: impl IntoIterator<Item = &'a PathBuf>) -> Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+    for dir in dirs {
+        let is_empty = fs::read_dir(dir)
+            .map_err(|e| Error::io(dir.clone(), e))?
+            .next()
+            .is_none();
+        if is_empty && remove_dir(dir)? {
+            removed.push(dir.clone());
+        } else {
+            warn!("leaving non-empty directory {}", dir.display());
+        }
+    }
+    Ok(removed)
+}
+
+/// Remove a directory found empty a moment ago. Something may have been
+/// created in it since; that leaves it in place and returns `false` rather
+/// than failing.
+fn remove_dir(dir: &Path) -> Result<bool> {
+    match fs::remove_dir(dir) {
+        Ok(()) => {
+            info!("removed empty directory {}", dir.display());
+            Ok(true)
+        }
+        Err(e) if e.kind() == io::ErrorKind::DirectoryNotEmpty => Ok(false),
+        Err(e) => Err(Error::io(dir.to_path_buf(), e)),
+    }
+}
+
+/// Refuse special files outright when the move would have to copy them
+/// across devices (copying a fifo would hang). Otherwise they are only
+/// warned about.
+fn check_special_files(report: &ScanReport) -> Result<()> {
+    match report.special_files.first() {
+        Some(special) if !report.space.same_device => Err(Error::CrossDevice {
+            path: special.path.clone(),
+            source: io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{:?} cannot be copied across devices", special.kind),
+            ),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// A filesystem change made by [`execute_steps`], kept so it can be undone.
+enum Step {
+    CreatedDir(PathBuf),
+    Moved(MoveRecord),
+    RemovedDir(PathBuf),
+    RemovedLink { link: PathBuf, target: PathBuf },
+    RemovedNoise(RemovedNoise),
+}
+
+/// Perform the planned moves, then remove the directories they emptied.
+/// Every completed change is pushed to `done` so a failure can be undone,
+/// and each move is journaled as soon as it lands. Returns the noise files
+/// deleted.
+fn execute_steps(
+    plan: &FlattenPlan,
+    journal: &mut Journal,
+    observer: &mut dyn Observer,
+    done: &mut Vec<Step>,
+    progress: Option<&mut dyn FnMut(usize, usize)>,
+) -> Result<Vec<PathBuf>> {
+    for dir in create_dest(plan, journal)? {
+        done.push(Step::CreatedDir(dir));
+    }
+    apply_moves(
+        &plan.moves,
+        plan.retry,
+        plan.preserve_xattrs,
+        journal,
+        observer,
+        done,
+        progress,
+    )?;
+    let mut deleted = Vec::new();
+    for path in &plan.noise {
+        if let Some(removed) = remove_noise_path(path, journal)? {
+            deleted.push(removed.path.clone());
+            done.push(Step::RemovedNoise(removed));
+        }
+    }
+
+    let mut removed = Vec::new();
+    for dir in plan.dirs_to_remove() {
+        fs::remove_dir(dir).map_err(|e| Error::io(dir.clone(), e))?;
+        observer.on_removed_dir(dir);
+        done.push(Step::RemovedDir(dir.clone()));
+        removed.push(dir.clone());
+    }
+    if let Some((link, target)) =
+        remove_nested_link(plan.nested_link.as_deref(), &plan.redundant_dirs)?
+    {
+        done.push(Step::RemovedLink { link, target });
+    }
+    journal.record_removed_dirs(removed)?;
+
+    Ok(deleted)
 }
 
-/// Plan and optionally execute a flatten operation.
-///
-/// When `dry_run` is true, no filesystem changes are made — the function
-/// returns what *would* happen.
-pub fn flatten(candidate: &NestingCandidate, dry_run: bool) -> Result<MoveResult> {
-    let report: ScanReport = scanner::scan(candidate);
-
-    if !report.collisions.is_empty() {
-        let first = &report.collisions[0];
-        return Err(Error::Collision {
-            existing: first.existing.clone(),
-        });
+#[cfg(not(feature = "parallel"))]
+fn apply_moves(
+    moves: &[MoveRecord],
+    retry: RetryPolicy,
+    xattrs: bool,
+    journal: &mut Journal,
+    observer: &mut dyn Observer,
+    done: &mut Vec<Step>,
+    mut progress: Option<&mut dyn FnMut(usize, usize)>,
+) -> Result<()> {
+    let weights = file_counts(moves, progress.is_some());
+    let total = weights.iter().sum();
+    let mut finished = 0;
+    for (i, record) in moves.iter().enumerate() {
+        match progress.as_deref_mut() {
+            Some(progress) => {
+                let mut copied = 0;
+                apply_record(record, retry, xattrs, &mut || {
+                    copied += 1;
+                    progress(finished + copied, total);
+                })?;
+                finished += weights[i];
+                if copied < weights[i] {
+                    progress(finished, total);
+                }
+            }
+            None => apply_record(record, retry, xattrs, &mut || {})?,
+        }
+        observer.on_move(record);
+        done.push(Step::Moved(record.clone()));
+        journal.append(record.done_at(SystemTime::now()))?;
+    }
+    Ok(())
+}
+
+/// Perform the moves on the rayon pool, wave by wave (see [`waves`]).
+/// Results stream back to this thread as the moves land, so each one is
+/// journaled as soon as it is done. Once a move fails the rest of its wave
+/// still finishes, no later wave starts, and the failure first in plan order
+/// is returned.
+#[cfg(feature = "parallel")]
+fn apply_moves(
+    moves: &[MoveRecord],
+    retry: RetryPolicy,
+    xattrs: bool,
+    journal: &mut Journal,
+    observer: &mut dyn Observer,
+    done: &mut Vec<Step>,
+    mut progress: Option<&mut dyn FnMut(usize, usize)>,
+) -> Result<()> {
+    use rayon::prelude::*;
+    use std::sync::mpsc;
+
+    let weights = file_counts(moves, progress.is_some());
+    let total = weights.iter().sum();
+    let mut finished = 0;
+    let mut first_err: Option<(usize, Error)> = None;
+    let mut journal_failed = false;
+    for wave in waves(moves) {
+        std::thread::scope(|scope| {
+            let (sender, results) = mpsc::channel();
+            let wave = &wave;
+            scope.spawn(move || {
+                wave.par_iter().for_each_with(sender, |sender, &i| {
+                    let result = apply_record(&moves[i], retry, xattrs, &mut || {})
+                        .map(|()| SystemTime::now());
+                    // The receiver outlives the wave, so this cannot fail.
+                    let _ = sender.send((i, result));
+                });
+            });
+            for (i, result) in results {
+                let record = &moves[i];
+                let failure = match result {
+                    Ok(at) => {
+                        if let Some(progress) = progress.as_deref_mut() {
+                            finished += weights[i];
+                            progress(finished, total);
+                        }
+                        observer.on_move(record);
+                        done.push(Step::Moved(record.clone()));
+                        // After a failed append, later ones would leave a
+                        // gap in the journal; `undo_steps` reverts them all.
+                        if journal_failed {
+                            continue;
+                        }
+                        match journal.append(record.done_at(at)) {
+                            Ok(()) => continue,
+                            Err(e) => {
+                                journal_failed = true;
+                                e
+                            }
+                        }
+                    }
+                    Err(e) => e,
+                };
+                if first_err.as_ref().is_none_or(|(first, _)| i < *first) {
+                    first_err = Some((i, failure));
+                }
+            }
+        });
+        if first_err.is_some() {
+            break;
+        }
+    }
+    first_err.map_or(Ok(()), |(_, e)| Err(e))
+}
+
+/// Group the moves (as indices into `moves`) into waves that can each run
+/// in parallel. A move joins the wave after the latest earlier one whose
+/// source or destination is, contains or lies inside one of its own paths,
+/// so a move that depends on another (an overwrite's incoming move on the
+/// backup vacating its destination, say) keeps its plan order, and moves in
+/// the same wave never touch the same part of the tree.
+#[cfg(feature = "parallel")]
+fn waves(moves: &[MoveRecord]) -> Vec<Vec<usize>> {
+    use std::collections::HashMap;
+
+    // The wave of the latest move touching exactly this path, and of the
+    // latest touching anything below it.
+    let mut at: HashMap<&Path, usize> = HashMap::new();
+    let mut below: HashMap<&Path, usize> = HashMap::new();
+    let mut waves: Vec<Vec<usize>> = Vec::new();
+    for (i, record) in moves.iter().enumerate() {
+        let paths = [record.from.as_path(), record.to.as_path()];
+        let wave = paths
+            .iter()
+            .flat_map(|path| {
+                path.ancestors()
+                    .filter_map(|a| at.get(a))
+                    .chain(below.get(path))
+            })
+            .max()
+            .map_or(0, |latest| latest + 1);
+        for path in paths {
+            at.insert(path, wave);
+            for ancestor in path.ancestors().skip(1) {
+                let latest = below.entry(ancestor).or_insert(wave);
+                *latest = (*latest).max(wave);
+            }
+        }
+        match waves.get_mut(wave) {
+            Some(members) => members.push(i),
+            None => waves.push(vec![i]),
+        }
+    }
+    waves
+}
+
+/// The number of files each move carries, for progress; empty unless
+/// `wanted`, since counting walks every source.
+fn file_counts(moves: &[MoveRecord], wanted: bool) -> Vec<usize> {
+    if !wanted {
+        return Vec::new();
+    }
+    #[cfg(feature = "parallel")]
+    {
+        use rayon::prelude::*;
+        moves.par_iter().map(|r| count_files(&r.from)).collect()
+    }
+    #[cfg(not(feature = "parallel"))]
+    moves.iter().map(|r| count_files(&r.from)).collect()
+}
+
+/// Reverse completed steps (last-in, first-out) after a failed flatten so
+/// the tree is left as it was found, retracting journal entries as their
+/// moves are undone. Failures here are logged rather than returned so the
+/// original error reaches the caller; anything not undone stays journaled.
+fn undo_steps(done: &[Step], journal: &mut Journal) {
+    for step in done.iter().rev() {
+        let undone = match step {
+            Step::Moved(record) => revert_record(record).and_then(|()| {
+                // The journal may lag `done` by one if the failure was the
+                // append itself.
+                if journal.last_entry() == Some(record) {
+                    journal.retract()?;
+                }
+                Ok(())
+            }),
+            Step::RemovedDir(dir) => fs::create_dir(dir).map_err(|e| Error::io(dir.clone(), e)),
+            Step::CreatedDir(dir) => fs::remove_dir(dir)
+                .map_err(|e| Error::io(dir.clone(), e))
+                .and_then(|()| journal.retract_created_dir(dir)),
+            Step::RemovedLink { link, target } => {
+                symlink(target, link).map_err(|e| Error::io(link.clone(), e))
+            }
+            Step::RemovedNoise(noise) => noise
+                .restore()
+                .and_then(|()| journal.retract_removed_noise(&noise.path)),
+        };
+        match (step, undone) {
+            (Step::Moved(record), Ok(())) => {
+                info!(
+                    "restored {} -> {}",
+                    record.to.display(),
+                    record.from.display()
+                )
+            }
+            (Step::RemovedDir(dir), Ok(())) => info!("recreated {}", dir.display()),
+            (Step::CreatedDir(dir), Ok(())) => info!("removed {}", dir.display()),
+            (Step::RemovedLink { link, .. }, Ok(())) => info!("recreated {}", link.display()),
+            (Step::RemovedNoise(noise), Ok(())) => info!("restored {}", noise.path.display()),
+            (_, Err(e)) => error!("failed to undo partial flatten: {e}"),
+        }
+    }
+}
+
+/// Carry out a single planned record, retrying a failed rename per `retry`.
+/// `on_file` is called for each file copied if the move falls back to
+/// copying, which carries extended attributes over when `xattrs` is set.
+fn apply_record(
+    record: &MoveRecord,
+    retry: RetryPolicy,
+    xattrs: bool,
+    on_file: &mut dyn FnMut(),
+) -> Result<()> {
+    check_record(record)?;
+    match record.op {
+        MoveOp::Rename => match &record.relink {
+            Some(relink) => relink_path(&record.from, &record.to, &relink.rewritten)?,
+            None => move_path_with(&record.from, &record.to, retry, xattrs, on_file)?,
+        },
+        MoveOp::Deduplicate | MoveOp::Unlink => {
+            fs::remove_file(&record.from).map_err(|e| Error::io(record.from.clone(), e))?;
+        }
+        MoveOp::ReplaceEmpty => {
+            fs::remove_dir(&record.to).map_err(|e| Error::io(record.to.clone(), e))?;
+            if let Err(e) = move_path_with(&record.from, &record.to, retry, xattrs, on_file) {
+                if let Err(ce) = fs::create_dir(&record.to) {
+                    error!("failed to recreate {}: {ce}", record.to.display());
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Refuse `record` with a [`Error::Collision`] if what its op was planned
+/// on no longer holds, so nothing is lost when the tree changed after
+/// planning (a saved plan run with `--force`, say): a rename's destination
+/// must still be free, a deduplicated file still identical to the one
+/// kept, an unlinked name still the same inode, and a replaced directory
+/// still empty.
+fn check_record(record: &MoveRecord) -> Result<()> {
+    let (from, to) = (&record.from, &record.to);
+    let holds = match record.op {
+        MoveOp::Rename => {
+            matches!(fs::symlink_metadata(to), Err(e) if e.kind() == io::ErrorKind::NotFound)
+        }
+        MoveOp::Deduplicate => matches!(
+            scanner::classify_collision(from, to),
+            CollisionKind::IdenticalContent | CollisionKind::SameInode
+        ),
+        MoveOp::Unlink => scanner::same_inode(from, to),
+        MoveOp::ReplaceEmpty => scanner::is_empty_dir(to),
+    };
+    if holds {
+        return Ok(());
+    }
+    Err(Error::Collision {
+        existing: to.clone(),
+        collisions: vec![scanner::Collision {
+            kind: scanner::classify_collision(from, to),
+            types: CollisionTypes::of(from, to),
+            source: from.clone(),
+            existing: to.clone(),
+        }],
+    })
+}
+
+/// Undo a single record, putting `from` back in place.
+pub(crate) fn revert_record(record: &MoveRecord) -> Result<()> {
+    match record.op {
+        MoveOp::Rename => match &record.relink {
+            Some(relink) => relink_path(&record.to, &record.from, &relink.original),
+            None => move_path(&record.to, &record.from),
+        },
+        MoveOp::Deduplicate => fs::copy(&record.to, &record.from)
+            .map(|_| ())
+            .map_err(|e| Error::io(record.from.clone(), e)),
+        MoveOp::Unlink => {
+            fs::hard_link(&record.to, &record.from).map_err(|e| Error::io(record.from.clone(), e))
+        }
+        MoveOp::ReplaceEmpty => {
+            move_path(&record.to, &record.from)?;
+            fs::create_dir(&record.to).map_err(|e| Error::io(record.to.clone(), e))
+        }
+    }
+}
+
+/// Replace the symlink `from` with one at `to` pointing at `target`.
+fn relink_path(from: &Path, to: &Path, target: &Path) -> Result<()> {
+    symlink(target, to).map_err(|e| Error::io(to.to_path_buf(), e))?;
+    fs::remove_file(from).map_err(|e| Error::io(from.to_path_buf(), e))
+}
+
+/// Create a symlink at `link` pointing at `target`, where supported.
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    return std::os::unix::fs::symlink(target, link);
+    #[cfg(not(unix))]
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "cannot create symlink {} -> {}",
+            link.display(),
+            target.display()
+        ),
+    ))
+}
+
+/// For a planned rename of a symlink with a relative target, the target
+/// that keeps it pointing at the same file from `record.to`. `None` when
+/// nothing needs rewriting.
+fn plan_relink(candidate: &NestingCandidate, record: &MoveRecord) -> Option<Relink> {
+    if record.op != MoveOp::Rename || !record.from.is_symlink() {
+        return None;
+    }
+    let original = fs::read_link(&record.from).ok()?;
+    if original.is_absolute() {
+        return None;
+    }
+    let resolved = scanner::resolve_link(&record.from, &original);
+    let up = MoveRecord::new(candidate.nested.clone(), candidate.parent.clone());
+    let destination = scanner::relocate_by(std::slice::from_ref(&up), &resolved);
+    let rewritten = relative_path(record.to.parent()?, &destination);
+    (rewritten != original).then_some(Relink {
+        original,
+        rewritten,
+    })
+}
+
+/// The relative path leading from directory `base` to `target`; both must
+/// be absolute and normalized.
+pub(crate) fn relative_path(base: &Path, target: &Path) -> PathBuf {
+    let base: Vec<_> = base.components().collect();
+    let target: Vec<_> = target.components().collect();
+    let common = base.iter().zip(&target).take_while(|(a, b)| a == b).count();
+
+    let mut path = PathBuf::new();
+    for _ in common..base.len() {
+        path.push("..");
+    }
+    for component in &target[common..] {
+        path.push(component);
+    }
+    if path.as_os_str().is_empty() {
+        path.push(".");
+    }
+    path
+}
+
+/// Move `from` to `to`, falling back to copy-then-delete when they live on
+/// different filesystems (`EXDEV`).
+pub(crate) fn move_path(from: &Path, to: &Path) -> Result<()> {
+    move_path_with(
+        from,
+        to,
+        RetryPolicy::default(),
+        preserve_xattrs_by_default(),
+        &mut || {},
+    )
+}
+
+/// [`move_path`], retrying the rename per `retry` and calling `on_file`
+/// after each file copied by the fallback.
+fn move_path_with(
+    from: &Path,
+    to: &Path,
+    retry: RetryPolicy,
+    xattrs: bool,
+    on_file: &mut dyn FnMut(),
+) -> Result<()> {
+    #[cfg(windows)]
+    let renamed = retry.run(|| {
+        fs::rename(
+            crate::windows::long_path(from),
+            crate::windows::long_path(to),
+        )
+    });
+    #[cfg(not(windows))]
+    let renamed = retry.run(|| fs::rename(from, to));
+
+    match renamed {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            warn!(
+                "{} and {} are on different filesystems; copying instead",
+                from.display(),
+                to.display()
+            );
+            copy_then_remove(from, to, xattrs, on_file)
+        }
+        Err(e) => Err(Error::io(from.to_path_buf(), e)),
+    }
+}
+
+/// Copy `from` to `to` recursively, then remove `from`.
+///
+/// A failed copy removes whatever was written to `to` and leaves `from`
+/// untouched. Once the copy is complete the destination is kept even if
+/// removing the source fails, so data is never lost.
+fn copy_then_remove(from: &Path, to: &Path, xattrs: bool, on_file: &mut dyn FnMut()) -> Result<()> {
+    if let Err(e) = copy_recursive(from, to, xattrs, on_file) {
+        let cleanup = match fs::symlink_metadata(to) {
+            Ok(m) if m.is_dir() => fs::remove_dir_all(to),
+            Ok(_) => fs::remove_file(to),
+            Err(_) => Ok(()),
+        };
+        if let Err(ce) = cleanup {
+            warn!("failed to clean up partial copy {}: {ce}", to.display());
+        }
+        return Err(Error::CrossDevice {
+            path: from.to_path_buf(),
+            source: e,
+        });
+    }
+
+    let meta = fs::symlink_metadata(from).map_err(|e| Error::io(from.to_path_buf(), e))?;
+    let removed = if meta.is_dir() {
+        fs::remove_dir_all(from)
+    } else {
+        fs::remove_file(from)
+    };
+    removed.map_err(|e| Error::io(from.to_path_buf(), e))
+}
+
+/// Recursively copy files, directories and symlinks, preserving ownership,
+/// mode bits and modification times, and extended attributes if `xattrs`.
+/// Other file types are rejected.
+fn copy_recursive(
+    from: &Path,
+    to: &Path,
+    xattrs: bool,
+    on_file: &mut dyn FnMut(),
+) -> io::Result<()> {
+    let meta = fs::symlink_metadata(from)?;
+    let file_type = meta.file_type();
+
+    if file_type.is_symlink() {
+        let target = fs::read_link(from)?;
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(target, to)?;
+            copy_ownership(&meta, to)?;
+            if xattrs {
+                copy_xattrs(from, to)?;
+            }
+            on_file();
+            return Ok(());
+        }
+        #[cfg(not(unix))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "cannot copy symlink {} -> {}",
+                from.display(),
+                target.display()
+            ),
+        ));
+    }
+
+    // A handle on the copy, taken while its permissions are still ours
+    // rather than the source's, which may not let us open it (mode 0o000,
+    // write-only files).
+    let copy = if file_type.is_dir() {
+        fs::create_dir(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_recursive(&entry.path(), &to.join(entry.file_name()), xattrs, on_file)?;
+        }
+        fs::File::open(to)?
+    } else if file_type.is_file() {
+        let mut copy = fs::File::create(to)?;
+        io::copy(&mut fs::File::open(from)?, &mut copy)?;
+        copy
+    } else {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("cannot copy special file {}", from.display()),
+        ));
+    };
+
+    // Applied last so populating a directory does not bump its mtime: the
+    // times first, through the handle, then ownership before mode since
+    // chown clears the setuid/setgid bits. Attributes go between those
+    // two: chown drops `security.capability`, and a read-only mode would
+    // refuse new `user.*` attributes.
+    copy.set_modified(meta.modified()?)?;
+    drop(copy);
+    #[cfg(unix)]
+    copy_ownership(&meta, to)?;
+    #[cfg(unix)]
+    if xattrs {
+        copy_xattrs(from, to)?;
+    }
+    #[cfg(not(unix))]
+    let _ = xattrs;
+    fs::set_permissions(to, meta.permissions())?;
+    if !file_type.is_dir() {
+        on_file();
+    }
+    Ok(())
+}
+
+/// Number of non-directory entries at or under `path`, counting at least
+/// one so every move advances progress.
+fn count_files(path: &Path) -> usize {
+    walkdir::WalkDir::new(path)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| !e.file_type().is_dir())
+        .count()
+        .max(1)
+}
+
+/// Give `to` the uid and gid recorded in `meta`, without following
+/// symlinks. Lacking the privilege to chown is only a warning: the copy is
+/// still usable, just owned by whoever ran the flatten.
+#[cfg(unix)]
+fn copy_ownership(meta: &fs::Metadata, to: &Path) -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    match std::os::unix::fs::lchown(to, Some(meta.uid()), Some(meta.gid())) {
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            warn!(
+                "could not preserve ownership {}:{} of {}: {e}",
+                meta.uid(),
+                meta.gid(),
+                to.display()
+            );
+            Ok(())
+        }
+        other => other,
+    }
+}
+
+/// Copy the extended attributes of `from` (not following symlinks) onto
+/// `to`. A filesystem without xattrs, or an attribute we may not set (such
+/// as `trusted.*` without privileges), is only a warning: the copy is still
+/// usable without them.
+#[cfg(unix)]
+fn copy_xattrs(from: &Path, to: &Path) -> io::Result<()> {
+    let skippable = |e: &io::Error| {
+        matches!(
+            e.kind(),
+            io::ErrorKind::Unsupported | io::ErrorKind::PermissionDenied
+        )
+    };
+    let names = match xattr::list(from) {
+        Ok(names) => names,
+        Err(e) if skippable(&e) => {
+            warn!(
+                "could not read extended attributes of {}: {e}",
+                from.display()
+            );
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    for name in names {
+        let Some(value) = xattr::get(from, &name)? else {
+            continue;
+        };
+        match xattr::set(to, &name, &value) {
+            Err(e) if skippable(&e) => warn!(
+                "could not preserve extended attribute {} of {}: {e}",
+                name.to_string_lossy(),
+                to.display()
+            ),
+            other => other?,
+        }
+    }
+    Ok(())
+}
+
+/// The order `plan` takes `entries` in, which is the order their moves run
+/// and are journaled: as listed, unless [`FlattenOptions::sort_moves`] is
+/// set. Then those whose `destination` is free come first and those that
+/// collide last; within each, directories before files, and otherwise the
+/// listing order.
+///
+/// Collision handling invents names (`name (1)`, `name.bak`) that are only
+/// checked against what is already on disk or planned. Planning every
+/// direct destination first means an invented name can never claim a slot
+/// that a later entry needs under its own name, which would turn that entry
+/// into a collision of its own.
+fn plan_order<'a>(
+    entries: &'a [PathBuf],
+    destination: impl Fn(&Path) -> Option<PathBuf>,
+    options: &FlattenOptions,
+) -> Vec<&'a PathBuf> {
+    let mut entries: Vec<&PathBuf> = entries.iter().collect();
+    if options.sort_moves {
+        entries.sort_by_cached_key(|entry| {
+            let collides =
+                destination(entry).is_some_and(|dest| fs::symlink_metadata(dest).is_ok());
+            let is_dir = fs::symlink_metadata(entry).is_ok_and(|m| m.is_dir());
+            (collides, !is_dir)
+        });
+    }
+    entries
+}
+
+/// Queue the move of `from` to `to`, descending into same-named directories
+/// when merging. Merged source directories are pushed to `merged_dirs` after
+/// their contents, so removing them in order goes deepest first.
+fn plan_move(
+    from: &Path,
+    to: PathBuf,
+    options: &FlattenOptions,
+    moves: &mut Vec<MoveRecord>,
+    merged_dirs: &mut Vec<PathBuf>,
+    excluded: &mut Vec<PathBuf>,
+    collisions: &mut Vec<scanner::Collision>,
+) -> Result<()> {
+    if options.is_excluded(from) || options.exclude_symlinks && from.is_symlink() {
+        debug!("excluded: {}", from.display());
+        excluded.push(from.to_path_buf());
+        return Ok(());
+    }
+
+    // Renaming an entry onto itself is at best a no-op, and the same-inode
+    // check below would delete it as a duplicate of itself. Keep it, and the
+    // directories holding it, where they are.
+    if same_location(from, &to) {
+        warn!(
+            "{} would move onto itself ({}); left in place",
+            from.display(),
+            to.display()
+        );
+        excluded.push(from.to_path_buf());
+        return Ok(());
+    }
+
+    if !is_taken(&to, moves) {
+        moves.push(MoveRecord::new(from.to_path_buf(), to));
+        return Ok(());
+    }
+
+    if scanner::same_inode(from, &to) {
+        moves.push(MoveRecord {
+            op: MoveOp::Unlink,
+            ..MoveRecord::new(from.to_path_buf(), to)
+        });
+        return Ok(());
+    }
+
+    if options.skip_identical
+        && scanner::classify_collision(from, &to) == CollisionKind::IdenticalContent
+    {
+        moves.push(MoveRecord {
+            op: MoveOp::Deduplicate,
+            ..MoveRecord::new(from.to_path_buf(), to)
+        });
+        return Ok(());
+    }
+
+    // A file meeting a directory can't be merged, and replacing either
+    // with the other is too easy to get wrong; no policy applies. It is
+    // recorded like an aborting collision so the rest are still found.
+    let types = CollisionTypes::of(from, &to);
+    if types.is_mismatch() {
+        collisions.push(scanner::Collision {
+            kind: scanner::classify_collision(from, &to),
+            types,
+            source: from.to_path_buf(),
+            existing: to,
+        });
+        return Ok(());
+    }
+
+    if options.merge_empty && types == CollisionTypes::DirVsDir {
+        if scanner::is_empty_dir(from) {
+            debug!("left out empty {}", from.display());
+            merged_dirs.push(from.to_path_buf());
+            return Ok(());
+        }
+        if scanner::is_empty_dir(&to) && !moves.iter().any(|m| m.to.starts_with(&to)) {
+            moves.push(MoveRecord {
+                op: MoveOp::ReplaceEmpty,
+                ..MoveRecord::new(from.to_path_buf(), to)
+            });
+            return Ok(());
+        }
+    }
+
+    if options.merge == MergeStrategy::MergeDirectories && types == CollisionTypes::DirVsDir {
+        let children = analyzer::list_dir(from)?;
+        let destination = |child: &Path| child.file_name().map(|name| to.join(name));
+        for child in plan_order(&children, destination, options) {
+            let Some(dest) = destination(child) else {
+                continue;
+            };
+            plan_move(
+                child,
+                dest,
+                options,
+                moves,
+                merged_dirs,
+                excluded,
+                collisions,
+            )?;
+        }
+        merged_dirs.push(from.to_path_buf());
+        return Ok(());
+    }
+
+    match options.collisions {
+        // Recorded rather than returned, so the caller hears of every
+        // collision at once.
+        CollisionPolicy::Abort => {
+            collisions.push(scanner::Collision {
+                kind: scanner::classify_collision(from, &to),
+                types,
+                source: from.to_path_buf(),
+                existing: to,
+            });
+            Ok(())
+        }
+        CollisionPolicy::Rename => {
+            let to = free_slot(&to, moves);
+            moves.push(MoveRecord::new(from.to_path_buf(), to));
+            Ok(())
+        }
+        CollisionPolicy::Overwrite => {
+            let backup = backup_slot(&to, moves);
+            moves.push(MoveRecord::new(to.clone(), backup));
+            moves.push(MoveRecord::new(from.to_path_buf(), to));
+            Ok(())
+        }
+    }
+}
+
+/// Whether `from` and `to` name the same directory entry once `.` and `..`
+/// and symlinked parent directories are resolved. The entries themselves
+/// are not followed, so a link and its target still differ.
+fn same_location(from: &Path, to: &Path) -> bool {
+    let resolve = |path: &Path| {
+        let path = scanner::normalize(path);
+        match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => parent
+                .canonicalize()
+                .map_or_else(|_| path.clone(), |dir| dir.join(name)),
+            _ => path,
+        }
+    };
+    resolve(from) == resolve(to)
+}
+
+/// Whether `path` already exists on disk or is claimed by a planned move.
+///
+/// Uses `symlink_metadata` so a dangling symlink still counts as occupied
+/// and is never silently replaced.
+fn is_taken(path: &Path, moves: &[MoveRecord]) -> bool {
+    fs::symlink_metadata(path).is_ok() || moves.iter().any(|m| m.to == path)
+}
+
+/// Find the first free backup name for `path`: `name.bak`, then
+/// `name.bak.1`, `name.bak.2`, ...
+fn backup_slot(path: &Path, moves: &[MoveRecord]) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".bak");
+    let backup = path.with_file_name(&name);
+    if !is_taken(&backup, moves) {
+        return backup;
+    }
+    (1..)
+        .map(|n| {
+            let mut numbered = name.clone();
+            numbered.push(format!(".{n}"));
+            path.with_file_name(numbered)
+        })
+        .find(|p| !is_taken(p, moves))
+        .expect("unbounded suffix search always finds a free slot")
+}
+
+/// Find the first free `name (N).ext` sibling of `path`.
+///
+/// The suffix goes before the last extension (`file (1).txt`); names without
+/// one, including dotfiles, get it at the end (`Makefile (1)`, `.env (1)`).
+fn free_slot(path: &Path, moves: &[MoveRecord]) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+
+    (1..)
+        .map(|n| path.with_file_name(format!("{stem} ({n}){ext}")))
+        .find(|p| !is_taken(p, moves))
+        .expect("unbounded suffix search always finds a free slot")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::observer::NoopObserver;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn setup() -> (TempDir, NestingCandidate) {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().join("project");
+        let nested = root.join("project");
+
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(nested.join("file.txt"), "data").unwrap();
+        fs::create_dir(nested.join("src")).unwrap();
+
+        let root_canon = root.canonicalize().unwrap();
+        let nested_canon = root_canon.join("project");
+
+        let children = vec![nested_canon.join("file.txt"), nested_canon.join("src")];
+
+        let candidate = NestingCandidate {
+            parent: root_canon,
+            nested: nested_canon,
+            children,
+            collapse_depth: 1,
+            via_symlink: None,
+            nested_link: None,
+            noise: vec![],
+        };
+
+        (tmp, candidate)
+    }
+
+    fn merge_options() -> FlattenOptions {
+        FlattenOptions {
+            merge: MergeStrategy::MergeDirectories,
+            ..Default::default()
+        }
+    }
+
+    fn rename_options() -> FlattenOptions {
+        FlattenOptions {
+            collisions: CollisionPolicy::Rename,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn dry_run_does_not_modify_filesystem() {
+        let (_tmp, candidate) = setup();
+        let result = flatten(&candidate, true, &FlattenOptions::default()).unwrap();
+
+        assert_eq!(result.moved.len(), 2);
+        // Nested dir should still exist
+        assert!(candidate.nested.exists());
+    }
+
+    #[test]
+    fn dry_run_option_does_not_modify_filesystem() {
+        let (_tmp, candidate) = setup();
+        let options = FlattenOptions::builder().dry_run(true).build();
+        let result = flatten(&candidate, false, &options).unwrap();
+        assert_eq!(result.moved.len(), 2);
+        assert!(candidate.nested.join("file.txt").exists());
+
+        let plan = plan(&candidate, &options).unwrap();
+        assert!(plan.dry_run);
+        execute(&plan, false, None).unwrap();
+        let mut journal = Journal::new();
+        execute_move(&plan, &plan.moves[0], &mut journal).unwrap();
+        assert!(remove_emptied_dirs(&plan, &mut journal).unwrap().is_empty());
+        assert!(journal.entries().next().is_none());
+        assert!(candidate.nested.join("file.txt").exists());
+        assert!(!candidate.parent.join("file.txt").exists());
+
+        // A saved plan runs for real once loaded.
+        let loaded: FlattenPlan =
+            serde_json::from_str(&serde_json::to_string(&plan).unwrap()).unwrap();
+        assert!(!loaded.dry_run);
+    }
+
+    #[test]
+    fn apply_moves_files() {
+        let (_tmp, candidate) = setup();
+        let result = flatten(&candidate, false, &FlattenOptions::default()).unwrap();
+
+        assert_eq!(result.moved.len(), 2);
+        // Nested dir should be removed
+        assert!(!candidate.nested.exists());
+        // Files should be in parent
+        assert!(candidate.parent.join("file.txt").exists());
+        assert!(candidate.parent.join("src").is_dir());
+    }
+
+    #[test]
+    fn apply_collapses_multi_level_chain() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().join("project");
+        let innermost = root.join("project").join("project");
+        fs::create_dir_all(innermost.join("src")).unwrap();
+        fs::write(innermost.join("file.txt"), "data").unwrap();
+
+        let candidate = crate::analyzer::detect_nesting(&root).unwrap().remove(0);
+        assert_eq!(candidate.collapse_depth, 2);
+
+        let result = flatten(&candidate, false, &FlattenOptions::default()).unwrap();
+        assert_eq!(result.moved.len(), 2);
+        assert!(!candidate.parent.join("project").exists());
+        assert!(candidate.parent.join("file.txt").exists());
+        assert!(candidate.parent.join("src").is_dir());
+    }
+
+    #[test]
+    fn collision_aborts() {
+        let (_tmp, candidate) = setup();
+
+        // Create conflicting file in parent
+        fs::write(candidate.parent.join("file.txt"), "conflict").unwrap();
+
+        let err = flatten(&candidate, false, &FlattenOptions::default()).unwrap_err();
+        assert!(matches!(err, Error::Collision { .. }));
+        // Nested dir should still exist — nothing was moved
+        assert!(candidate.nested.exists());
+    }
+
+    #[test]
+    fn merge_directories_combines_contents() {
+        let (_tmp, candidate) = setup();
+        fs::write(candidate.nested.join("src/main.rs"), "fn main() {}").unwrap();
+        fs::create_dir(candidate.parent.join("src")).unwrap();
+        fs::write(candidate.parent.join("src/lib.rs"), "").unwrap();
+
+        let result = flatten(&candidate, false, &merge_options()).unwrap();
+
+        let moved: Vec<_> = result.moved.iter().map(|m| m.to.clone()).collect();
+        assert_eq!(
+            moved,
+            vec![
+                candidate.parent.join("file.txt"),
+                candidate.parent.join("src/main.rs"),
+            ]
+        );
+        assert!(!candidate.nested.exists());
+        assert!(candidate.parent.join("src/lib.rs").exists());
+        assert!(candidate.parent.join("src/main.rs").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn child_whose_destination_is_itself_stays_put() {
+        let (tmp, candidate) = setup();
+        // A parent that is an alias of the nested directory sends every
+        // child onto itself.
+        let alias = tmp.path().join("alias");
+        std::os::unix::fs::symlink(&candidate.nested, &alias).unwrap();
+        let candidate = NestingCandidate {
+            parent: alias.clone(),
+            ..candidate
+        };
+
+        let result = flatten(&candidate, false, &FlattenOptions::default()).unwrap();
+        assert!(result.moved.is_empty());
+        assert_eq!(
+            fs::read_to_string(candidate.nested.join("file.txt")).unwrap(),
+            "data"
+        );
+        assert!(candidate.nested.join("src").is_dir());
+        assert!(alias.join("file.txt").exists());
+    }
+
+    #[test]
+    fn builder_starts_from_the_defaults() {
+        // Debug output covers every field, so nothing can drift unseen.
+        assert_eq!(
+            format!("{:?}", FlattenOptions::builder().build()),
+            format!("{:?}", FlattenOptions::default())
+        );
+
+        let pattern = glob::Pattern::new("*.log").unwrap();
+        let scan = ScanOptions {
+            max_depth: Some(3),
+            same_file_system: true,
+            reserved_names: vec![OsString::from("CON")],
+        };
+        let retry = RetryPolicy {
+            attempts: 2,
+            base_delay: Duration::from_millis(5),
+        };
+        let options = FlattenOptions::builder()
+            .merge(MergeStrategy::MergeDirectories)
+            .collision_policy(CollisionPolicy::Rename)
+            .skip_identical(true)
+            .exclude([pattern.clone()])
+            .rewrite_symlinks(true)
+            .strict_symlinks(true)
+            .require_complete_scan(true)
+            .exclude_symlinks(true)
+            .protected([".jj"])
+            .into_dir("out")
+            .remove_noise(true)
+            .scan(scan.clone())
+            .select(["src"])
+            .remove_nested(false)
+            .retry(retry)
+            .merge_empty(true)
+            .preserve_xattrs(!cfg!(unix))
+            .copy_across_mounts(true)
+            .max_moves(5)
+            .sort_moves(true)
+            .dry_run(true)
+            .build();
+        // Every field listed, so a new one needs a setter and a value here.
+        let expected = FlattenOptions {
+            merge: MergeStrategy::MergeDirectories,
+            collisions: CollisionPolicy::Rename,
+            skip_identical: true,
+            exclude: vec![pattern],
+            rewrite_symlinks: true,
+            strict_symlinks: true,
+            require_complete_scan: true,
+            exclude_symlinks: true,
+            protected: vec![".jj".to_string()],
+            into: Some(PathBuf::from("out")),
+            remove_noise: true,
+            scan,
+            select: Some(vec![OsString::from("src")]),
+            remove_nested: false,
+            retry,
+            merge_empty: true,
+            preserve_xattrs: !cfg!(unix),
+            copy_across_mounts: true,
+            max_moves: Some(5),
+            sort_moves: true,
+            dry_run: true,
+        };
+        assert_eq!(format!("{options:?}"), format!("{expected:?}"));
+        assert!(options.is_excluded(Path::new("build.log")));
+    }
+
+    #[test]
+    fn abort_reports_every_collision() {
+        let (_tmp, candidate) = setup();
+        fs::write(candidate.nested.join("src/main.rs"), "nested").unwrap();
+        fs::create_dir(candidate.parent.join("src")).unwrap();
+        fs::write(candidate.parent.join("src/main.rs"), "parent").unwrap();
+        fs::write(candidate.parent.join("file.txt"), "parent").unwrap();
+
+        let err = plan(&candidate, &merge_options()).unwrap_err();
+        let Error::Collision {
+            existing,
+            collisions,
+        } = &err
+        else {
+            panic!("expected a collision, got {err}");
+        };
+        let found: Vec<_> = collisions.iter().map(|c| c.existing.clone()).collect();
+        assert_eq!(
+            found,
+            [
+                candidate.parent.join("file.txt"),
+                candidate.parent.join("src/main.rs")
+            ]
+        );
+        assert_eq!(*existing, found[0]);
+        assert_eq!(collisions[0].kind, CollisionKind::Differing);
+        assert!(err.to_string().contains("(and 1 more: "));
+    }
+
+    #[test]
+    fn merge_directories_still_aborts_on_file_collision() {
+        let (_tmp, candidate) = setup();
+        fs::write(candidate.nested.join("src/main.rs"), "nested").unwrap();
+        fs::create_dir(candidate.parent.join("src")).unwrap();
+        fs::write(candidate.parent.join("src/main.rs"), "parent").unwrap();
+
+        let err = flatten(&candidate, false, &merge_options()).unwrap_err();
+        assert!(
+            matches!(err, Error::Collision { existing, .. } if existing.ends_with("src/main.rs"))
+        );
+        // Nothing moved, not even the non-colliding file.
+        assert!(candidate.nested.join("file.txt").exists());
+        assert!(!candidate.parent.join("file.txt").exists());
+    }
+
+    #[test]
+    fn type_mismatch_refused_under_every_policy() {
+        let (_tmp, candidate) = setup();
+        fs::write(candidate.parent.join("src"), "not a dir").unwrap();
+
+        for options in [merge_options(), rename_options(), FlattenOptions::default()] {
+            let err = flatten(&candidate, false, &options).unwrap_err();
+            assert!(matches!(err, Error::TypeMismatch { existing, .. }
+                if existing == candidate.parent.join("src")));
+        }
+        assert!(candidate.nested.join("src").is_dir());
+    }
+
+    #[test]
+    fn execute_rechecks_each_op_against_the_tree() {
+        let (_tmp, candidate) = setup();
+        fs::write(candidate.parent.join("file.txt"), "data").unwrap();
+        let options = FlattenOptions {
+            skip_identical: true,
+            ..Default::default()
+        };
+        let plan = plan(&candidate, &options).unwrap();
+        assert_eq!(plan.moves[0].op, MoveOp::Deduplicate);
+
+        // The kept copy changed after planning: deleting ours would lose it.
+        fs::write(candidate.parent.join("file.txt"), "edited").unwrap();
+        let err = execute(&plan, false, None).unwrap_err();
+        assert!(matches!(err, Error::Collision { .. }), "{err}");
+        assert_eq!(
+            fs::read_to_string(candidate.nested.join("file.txt")).unwrap(),
+            "data"
+        );
+
+        // A destination taken since planning is never replaced.
+        fs::remove_file(candidate.parent.join("file.txt")).unwrap();
+        let plan = super::plan(&candidate, &FlattenOptions::default()).unwrap();
+        fs::create_dir(candidate.parent.join("src")).unwrap();
+        let err = execute(&plan, false, None).unwrap_err();
+        assert!(matches!(err, Error::Collision { .. }), "{err}");
+        assert!(candidate.nested.join("file.txt").exists());
+        assert!(candidate.nested.join("src").is_dir());
+    }
+
+    #[test]
+    fn type_mismatch_does_not_hide_other_collisions() {
+        let (_tmp, candidate) = setup();
+        fs::write(candidate.parent.join("file.txt"), "parent").unwrap();
+        fs::write(candidate.parent.join("src"), "not a dir").unwrap();
+
+        for options in [merge_options(), FlattenOptions::default()] {
+            let err = plan(&candidate, &options).unwrap_err();
+            let Error::Collision { collisions, .. } = &err else {
+                panic!("expected a collision, got {err}");
+            };
+            let mut found: Vec<_> = collisions.iter().map(|c| c.existing.clone()).collect();
+            found.sort();
+            assert_eq!(
+                found,
+                [
+                    candidate.parent.join("file.txt"),
+                    candidate.parent.join("src")
+                ]
+            );
+            assert!(collisions.iter().any(|c| c.types.is_mismatch()));
+        }
+    }
+
+    #[test]
+    fn retry_policy_retries_only_transient_errors() {
+        let policy = RetryPolicy {
+            attempts: 3,
+            base_delay: Duration::ZERO,
+        };
+        let mut calls = 0;
+        let result = policy.run(|| {
+            calls += 1;
+            match calls {
+                1 | 2 => Err(io::Error::from(io::ErrorKind::ResourceBusy)),
+                _ => Ok(calls),
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        let mut calls = 0;
+        let result: io::Result<()> = policy.run(|| {
+            calls += 1;
+            Err(io::Error::from(io::ErrorKind::NotFound))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+
+        // The default never retries.
+        let mut calls = 0;
+        let _ = RetryPolicy::default().run(|| -> io::Result<()> {
+            calls += 1;
+            Err(io::Error::from(io::ErrorKind::ResourceBusy))
+        });
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn vcs_collision_refused_even_when_merging() {
+        let (_tmp, mut candidate) = setup();
+        fs::create_dir(candidate.nested.join(".git")).unwrap();
+        fs::create_dir(candidate.parent.join(".git")).unwrap();
+        candidate.children.push(candidate.nested.join(".git"));
+
+        let err = flatten(&candidate, false, &merge_options()).unwrap_err();
+        assert!(matches!(err, Error::VcsCollision { existing } if existing.ends_with(".git")));
+        assert!(candidate.nested.join("file.txt").exists());
+    }
+
+    #[test]
+    fn rename_picks_first_free_suffix() {
+        let (_tmp, candidate) = setup();
+        fs::write(candidate.parent.join("file.txt"), "parent").unwrap();
+        fs::write(candidate.parent.join("file (1).txt"), "parent").unwrap();
+
+        let result = flatten(&candidate, false, &rename_options()).unwrap();
+
+        assert_eq!(result.moved[0].to, candidate.parent.join("file (2).txt"));
+        assert_eq!(
+            fs::read_to_string(candidate.parent.join("file (2).txt")).unwrap(),
+            "data"
+        );
+        assert_eq!(
+            fs::read_to_string(candidate.parent.join("file.txt")).unwrap(),
+            "parent"
+        );
+    }
+
+    #[test]
+    fn rename_handles_dotfiles_and_extensionless_names() {
+        let (_tmp, candidate) = setup();
+        fs::write(candidate.nested.join(".env"), "nested").unwrap();
+        fs::write(candidate.nested.join("Makefile"), "nested").unwrap();
+        fs::write(candidate.parent.join(".env"), "parent").unwrap();
+        fs::write(candidate.parent.join("Makefile"), "parent").unwrap();
+        let candidate = NestingCandidate {
+            children: analyzer::list_dir(&candidate.nested).unwrap(),
+            ..candidate
+        };
+
+        let result = flatten(&candidate, false, &rename_options()).unwrap();
+
+        let dests: Vec<_> = result.moved.iter().map(|m| m.to.clone()).collect();
+        assert!(dests.contains(&candidate.parent.join(".env (1)")));
+        assert!(dests.contains(&candidate.parent.join("Makefile (1)")));
+    }
+
+    #[test]
+    fn overwrite_backs_up_existing_file_until_rollback() {
+        let (_tmp, candidate) = setup();
+        let existing = candidate.parent.join("file.txt");
+        let backup = candidate.parent.join("file.txt.bak");
+        fs::write(&existing, "parent").unwrap();
+        // The first backup name is taken.
+        fs::write(&backup, "older backup").unwrap();
+        let second = candidate.parent.join("file.txt.bak.1");
+
+        let options = FlattenOptions {
+            collisions: CollisionPolicy::Overwrite,
+            ..Default::default()
+        };
+        let mut journal = crate::journal::Journal::new();
+        flatten_journaled(&candidate, false, &options, &mut journal, &mut NoopObserver).unwrap();
+        assert_eq!(fs::read_to_string(&existing).unwrap(), "data");
+        assert_eq!(fs::read_to_string(&second).unwrap(), "parent");
+        assert_eq!(journal.entries().count(), 3);
+
+        journal
+            .rollback(
+                crate::journal::RollbackScope::Latest,
+                crate::journal::RollbackConflict::Abort,
+                None,
+                false,
+            )
+            .unwrap();
+        assert_eq!(fs::read_to_string(&existing).unwrap(), "parent");
+        assert_eq!(
+            fs::read_to_string(candidate.nested.join("file.txt")).unwrap(),
+            "data"
+        );
+        assert!(!second.exists());
+        assert_eq!(fs::read_to_string(&backup).unwrap(), "older backup");
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn dependent_moves_run_in_later_waves() {
+        let record = |from: &str, to: &str| MoveRecord::new(from.into(), to.into());
+        let moves = [
+            record("/p/a", "/p/a.bak"),
+            record("/p/n/a", "/p/a"),
+            record("/p/n/b", "/p/b"),
+            record("/p/n/c/x", "/p/c/x"),
+            record("/p/c", "/p/c.bak"),
+        ];
+        // The incoming `a` waits for its backup; `c` moves after something
+        // inside it.
+        assert_eq!(waves(&moves), vec![vec![0, 2, 3], vec![1, 4]]);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn chained_backups_never_share_a_wave() {
+        let record = |from: &str, to: &str| MoveRecord::new(from.into(), to.into());
+        let moves = [
+            record("/p/file.txt.bak", "/p/file.txt.bak.bak"),
+            record("/p/file.txt", "/p/file.txt.bak"),
+            record("/p/n/file.txt", "/p/file.txt"),
+        ];
+        assert_eq!(waves(&moves), vec![vec![0], vec![1], vec![2]]);
+    }
+
+    #[test]
+    fn overwrite_backups_that_chain_keep_their_order() {
+        let (_tmp, mut candidate) = setup();
+        fs::write(candidate.nested.join("file.txt.bak"), "nested backup").unwrap();
+        candidate
+            .children
+            .push(candidate.nested.join("file.txt.bak"));
+        fs::write(candidate.parent.join("file.txt"), "parent").unwrap();
+        fs::write(candidate.parent.join("file.txt.bak"), "parent backup").unwrap();
+
+        let options = FlattenOptions {
+            collisions: CollisionPolicy::Overwrite,
+            ..Default::default()
+        };
+        let plan = plan(&candidate, &options).unwrap();
+        let mut journal = crate::journal::Journal::new();
+        execute_journaled(&plan, false, &mut journal, &mut NoopObserver, None).unwrap();
+
+        let read = |name: &str| fs::read_to_string(candidate.parent.join(name)).unwrap();
+        assert_eq!(read("file.txt"), "data");
+        assert_eq!(read("file.txt.bak"), "nested backup");
+        assert_eq!(read("file.txt.bak.bak"), "parent backup");
+        assert_eq!(read("file.txt.bak.1"), "parent");
+
+        journal
+            .rollback(
+                crate::journal::RollbackScope::Latest,
+                crate::journal::RollbackConflict::Abort,
+                None,
+                false,
+            )
+            .unwrap();
+        assert_eq!(read("file.txt"), "parent");
+        assert_eq!(read("file.txt.bak"), "parent backup");
+    }
+
+    #[test]
+    fn renamed_moves_roll_back_to_original_path() {
+        let (_tmp, candidate) = setup();
+        fs::write(candidate.parent.join("file.txt"), "parent").unwrap();
+
+        let result = flatten(&candidate, false, &rename_options()).unwrap();
+        let mut journal = crate::journal::Journal::new();
+        journal.record(result.moved);
+        journal
+            .rollback(
+                crate::journal::RollbackScope::Latest,
+                crate::journal::RollbackConflict::Abort,
+                None,
+                false,
+            )
+            .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(candidate.nested.join("file.txt")).unwrap(),
+            "data"
+        );
+        assert_eq!(
+            fs::read_to_string(candidate.parent.join("file.txt")).unwrap(),
+            "parent"
+        );
+        assert!(!candidate.parent.join("file (1).txt").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn copy_fallback_preserves_permissions_and_mtime() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::time::{Duration, SystemTime};
+
+        let (tmp, candidate) = setup();
+        let src = candidate.nested.join("src");
+        let script = src.join("run.sh");
+        fs::write(&script, "#!/bin/sh").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o750)).unwrap();
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        fs::File::open(&script)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        fs::File::open(&src).unwrap().set_modified(mtime).unwrap();
+
+        let dest = tmp.path().join("copied");
+        copy_then_remove(&src, &dest, true, &mut || {}).unwrap();
+
+        assert!(!src.exists());
+        let copied = fs::metadata(dest.join("run.sh")).unwrap();
+        assert_eq!(copied.permissions().mode() & 0o777, 0o750);
+        assert_eq!(copied.modified().unwrap(), mtime);
+        assert_eq!(fs::metadata(&dest).unwrap().modified().unwrap(), mtime);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn copy_fallback_copies_files_it_cannot_reopen() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::time::{Duration, SystemTime};
+
+        let (tmp, candidate) = setup();
+        let src = candidate.nested.join("src");
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        for (name, mode) in [("locked", 0o000), ("write-only", 0o200)] {
+            let file = src.join(name);
+            fs::write(&file, name).unwrap();
+            fs::File::options()
+                .write(true)
+                .open(&file)
+                .unwrap()
+                .set_modified(mtime)
+                .unwrap();
+            fs::set_permissions(&file, fs::Permissions::from_mode(mode)).unwrap();
+        }
+        // Without root the sources themselves are unreadable.
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+
+        let dest = tmp.path().join("copied");
+        copy_then_remove(&src, &dest, true, &mut || {}).unwrap();
+        for (name, mode) in [("locked", 0o000), ("write-only", 0o200)] {
+            let copied = fs::metadata(dest.join(name)).unwrap();
+            assert_eq!(copied.permissions().mode() & 0o777, mode);
+            assert_eq!(copied.modified().unwrap(), mtime);
+            assert_eq!(fs::read_to_string(dest.join(name)).unwrap(), name);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn copy_fallback_preserves_special_mode_bits_and_ownership() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let (tmp, candidate) = setup();
+        let src = candidate.nested.join("src");
+        let tool = src.join("tool");
+        fs::write(&tool, "#!/bin/sh").unwrap();
+        // Only root can hand files to another user; otherwise ownership
+        // stays ours and the check below still holds.
+        let _ = std::os::unix::fs::lchown(&tool, Some(1234), Some(5678));
+        fs::set_permissions(&tool, fs::Permissions::from_mode(0o4755)).unwrap();
+        fs::set_permissions(&src, fs::Permissions::from_mode(0o1777)).unwrap();
+        let owner = fs::metadata(&tool).unwrap();
+
+        let dest = tmp.path().join("copied");
+        copy_then_remove(&src, &dest, true, &mut || {}).unwrap();
+
+        let copied = fs::metadata(dest.join("tool")).unwrap();
+        assert_eq!(copied.permissions().mode() & 0o7777, 0o4755);
+        assert_eq!((copied.uid(), copied.gid()), (owner.uid(), owner.gid()));
+        let dir = fs::metadata(&dest).unwrap();
+        assert_eq!(dir.permissions().mode() & 0o7777, 0o1777);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn copy_fallback_preserves_extended_attributes() {
+        let (tmp, candidate) = setup();
+        let src = candidate.nested.join("src");
+        let file = src.join("labelled.txt");
+        fs::write(&file, "data").unwrap();
+        match xattr::set(&file, "user.fs-cleaner.test", b"kept") {
+            Ok(()) => {}
+            // tmpfs and some container filesystems lack user xattrs, leaving
+            // nothing to preserve; any other failure is a real one.
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => return,
+            Err(e) => panic!("could not set an xattr: {e}"),
+        }
+        xattr::set(&src, "user.fs-cleaner.test", b"dir").unwrap();
+
+        let dest = tmp.path().join("copied");
+        copy_then_remove(&src, &dest, true, &mut || {}).unwrap();
+        assert_eq!(
+            xattr::get(dest.join("labelled.txt"), "user.fs-cleaner.test").unwrap(),
+            Some(b"kept".to_vec())
+        );
+        assert_eq!(
+            xattr::get(&dest, "user.fs-cleaner.test").unwrap(),
+            Some(b"dir".to_vec())
+        );
+    }
+
+    #[test]
+    fn copy_fallback_reports_each_file() {
+        let (tmp, candidate) = setup();
+        let src = candidate.nested.join("src");
+        fs::create_dir(src.join("deep")).unwrap();
+        fs::write(src.join("a.txt"), "a").unwrap();
+        fs::write(src.join("deep").join("b.txt"), "b").unwrap();
+
+        let mut files = 0;
+        copy_then_remove(&src, &tmp.path().join("copied"), true, &mut || files += 1).unwrap();
+        assert_eq!(files, 2);
+        assert_eq!(count_files(&tmp.path().join("copied")), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failed_copy_cleans_up_partial_destination() {
+        let (tmp, candidate) = setup();
+        let src = candidate.nested.join("src");
+        fs::write(src.join("a.txt"), "data").unwrap();
+        let fifo = src.join("pipe");
+        let status = std::process::Command::new("mkfifo")
+            .arg(&fifo)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let dest = tmp.path().join("copied");
+        let err = copy_then_remove(&src, &dest, true, &mut || {}).unwrap_err();
+
+        assert!(matches!(err, Error::CrossDevice { .. }));
+        assert!(!dest.exists());
+        assert!(src.join("a.txt").exists());
+    }
+
+    #[test]
+    fn failure_midway_restores_original_layout() {
+        let (_tmp, candidate) = setup();
+        fs::write(candidate.nested.join("a.txt"), "a").unwrap();
+        fs::write(candidate.nested.join("z.txt"), "z").unwrap();
+
+        // `ghost.txt` vanished between detection and apply, so its rename
+        // fails after `a.txt` and `file.txt` have already moved.
+        let children = vec![
+            candidate.nested.join("a.txt"),
+            candidate.nested.join("file.txt"),
+            candidate.nested.join("ghost.txt"),
+            candidate.nested.join("src"),
+            candidate.nested.join("z.txt"),
+        ];
+        let candidate = NestingCandidate {
+            children,
+            ..candidate
+        };
+
+        let err = flatten(&candidate, false, &FlattenOptions::default()).unwrap_err();
+        assert!(matches!(err, Error::Io { path, .. } if path.ends_with("ghost.txt")));
+
+        let mut remaining = analyzer::list_dir(&candidate.nested).unwrap();
+        remaining.sort();
+        assert_eq!(
+            remaining,
+            vec![
+                candidate.nested.join("a.txt"),
+                candidate.nested.join("file.txt"),
+                candidate.nested.join("src"),
+                candidate.nested.join("z.txt"),
+            ]
+        );
+        assert_eq!(
+            analyzer::list_dir(&candidate.parent).unwrap(),
+            vec![candidate.nested.clone()]
+        );
+    }
+
+    #[test]
+    fn journaled_flatten_persists_moves_and_undo() {
+        let (tmp, candidate) = setup();
+        let journal_dir = tmp.path().join("journal");
+        fs::create_dir(&journal_dir).unwrap();
+
+        let mut journal = Journal::persistent(&journal_dir).unwrap();
+        flatten_journaled(
+            &candidate,
+            false,
+            &FlattenOptions::default(),
+            &mut journal,
+            &mut NoopObserver,
+        )
+        .unwrap();
+        let saved = Journal::load(&journal_dir).unwrap();
+        assert_eq!(saved.entries().count(), 2);
+        assert!(saved.entries().all(|e| e.at.is_some()));
+
+        // A failed flatten is undone, and so are its journal entries; the
+        // earlier batch is kept.
+        let (_tmp2, candidate) = setup();
+        let candidate = NestingCandidate {
+            children: vec![candidate.nested.join("file.txt")],
+            ..candidate
+        };
+        let mut journal = Journal::persistent(&journal_dir).unwrap();
+        flatten_journaled(
+            &candidate,
+            false,
+            &FlattenOptions::default(),
+            &mut journal,
+            &mut NoopObserver,
+        )
+        .unwrap_err();
+        assert_eq!(Journal::load(&journal_dir).unwrap().entries().count(), 2);
+    }
+
+    #[test]
+    fn execute_reports_progress_per_move() {
+        let (_tmp, candidate) = setup();
+        fs::write(candidate.nested.join("src").join("main.rs"), "").unwrap();
+        let plan = plan(&candidate, &FlattenOptions::default()).unwrap();
+
+        let mut calls = Vec::new();
+        execute(
+            &plan,
+            false,
+            Some(&mut |done, total| calls.push((done, total))),
+        )
+        .unwrap();
+        assert_eq!(calls, vec![(1, 2), (2, 2)]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rewritten_symlinks_survive_flatten_and_rollback() {
+        let (tmp, mut candidate) = setup();
+        let shared = tmp.path().join("shared");
+        fs::write(&shared, "lib").unwrap();
+        let link = candidate.nested.join("lib");
+        std::os::unix::fs::symlink("../../shared", &link).unwrap();
+        candidate.children.push(link.clone());
+
+        let options = FlattenOptions {
+            rewrite_symlinks: true,
+            ..Default::default()
+        };
+        let mut journal = Journal::new();
+        let result =
+            flatten_journaled(&candidate, false, &options, &mut journal, &mut NoopObserver)
+                .unwrap();
+        assert!(
+            result
+                .warnings
+                .iter()
+                .any(|w| matches!(w, Warning::SymlinkRisk(risk) if risk.link == link))
+        );
+
+        let moved = candidate.parent.join("lib");
+        assert_eq!(fs::read_link(&moved).unwrap(), Path::new("../shared"));
+        assert_eq!(fs::read_to_string(&moved).unwrap(), "lib");
+
+        journal
+            .rollback(
+                crate::journal::RollbackScope::Latest,
+                crate::journal::RollbackConflict::Abort,
+                None,
+                false,
+            )
+            .unwrap();
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("../../shared"));
+        assert!(!moved.exists());
+    }
+
+    #[test]
+    fn noise_is_kept_or_removed_on_request() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().join("app");
+        fs::create_dir_all(root.join("app/app")).unwrap();
+        fs::write(root.join("app/.DS_Store"), "").unwrap();
+        fs::write(root.join("app/app/main.rs"), "").unwrap();
+        let candidate = analyzer::detect_nesting(&root).unwrap().remove(0);
+        let middle = candidate.parent.join("app");
+
+        // Left alone, the noise keeps its directory.
+        let plan = plan(&candidate, &FlattenOptions::default()).unwrap();
+        assert!(plan.keeps(&middle));
+        assert!(plan.noise.is_empty());
+        assert!(matches!(&plan.warnings()[..], [Warning::DirectoryKept(dir)] if *dir == middle));
+
+        let options = FlattenOptions {
+            remove_noise: true,
+            ..Default::default()
+        };
+        let result = flatten(&candidate, false, &options).unwrap();
+        assert!(candidate.parent.join("main.rs").exists());
+        assert!(!middle.exists());
+        assert!(
+            matches!(&result.warnings[..], [Warning::NoiseRemoved(path)] if path.ends_with(".DS_Store"))
+        );
+    }
+
+    #[test]
+    fn removed_noise_is_journaled_and_restored_by_rollback() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().join("app");
+        fs::create_dir_all(root.join("app/app")).unwrap();
+        fs::write(root.join("app/.DS_Store"), "finder").unwrap();
+        fs::write(root.join("app/app/main.rs"), "").unwrap();
+        let candidate = analyzer::detect_nesting(&root).unwrap().remove(0);
+        let options = FlattenOptions {
+            remove_noise: true,
+            ..Default::default()
+        };
+
+        let mut journal = Journal::new();
+        flatten_journaled(&candidate, false, &options, &mut journal, &mut NoopObserver).unwrap();
+        let store = candidate.parent.join("app/.DS_Store");
+        assert!(!store.exists());
+
+        let report = journal
+            .rollback(
+                crate::journal::RollbackScope::Latest,
+                crate::journal::RollbackConflict::Abort,
+                None,
+                false,
+            )
+            .unwrap();
+        assert_eq!(report.restored_noise, vec![store.clone()]);
+        assert_eq!(fs::read_to_string(&store).unwrap(), "finder");
+        assert!(candidate.parent.join("app/app/main.rs").exists());
+    }
+
+    #[test]
+    fn noise_is_not_deleted_unless_journaled_first() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().join("app");
+        fs::create_dir_all(root.join("app/app")).unwrap();
+        let store = root.join("app/.DS_Store");
+        fs::write(&store, "finder").unwrap();
+        fs::write(root.join("app/app/main.rs"), "").unwrap();
+        let candidate = analyzer::detect_nesting(&root).unwrap().remove(0);
+        let options = FlattenOptions {
+            remove_noise: true,
+            ..Default::default()
+        };
+        let plan = plan(&candidate, &options).unwrap();
+
+        // A directory where the journal should go makes every save fail.
+        let path = tmp.path().join("journal.json");
+        let mut journal = Journal::persistent_at(&path).unwrap();
+        fs::create_dir(&path).unwrap();
+        assert!(remove_noise(&plan, &mut journal).is_err());
+        assert_eq!(fs::read_to_string(&store).unwrap(), "finder");
+    }
+
+    #[test]
+    fn merge_empty_replaces_an_empty_directory_until_rollback() {
+        let (_tmp, candidate) = setup();
+        let existing = candidate.parent.join("src");
+        fs::create_dir(&existing).unwrap();
+        fs::write(candidate.nested.join("src/lib.rs"), "code").unwrap();
+        assert!(flatten(&candidate, true, &FlattenOptions::default()).is_err());
+
+        let options = FlattenOptions {
+            merge_empty: true,
+            ..Default::default()
+        };
+        let mut journal = Journal::new();
+        let result =
+            flatten_journaled(&candidate, false, &options, &mut journal, &mut NoopObserver)
+                .unwrap();
+        assert!(result.moved.iter().any(|m| m.op == MoveOp::ReplaceEmpty));
+        assert_eq!(fs::read_to_string(existing.join("lib.rs")).unwrap(), "code");
+        assert!(!candidate.nested.exists());
+
+        journal
+            .rollback(
+                crate::journal::RollbackScope::Latest,
+                crate::journal::RollbackConflict::Abort,
+                None,
+                false,
+            )
+            .unwrap();
+        assert!(scanner::is_empty_dir(&existing));
+        assert!(candidate.nested.join("src/lib.rs").exists());
+    }
+
+    #[test]
+    fn merge_empty_leaves_out_an_empty_nested_directory() {
+        let (_tmp, candidate) = setup();
+        let existing = candidate.parent.join("src");
+        fs::create_dir(&existing).unwrap();
+        fs::write(existing.join("main.rs"), "").unwrap();
+
+        let options = FlattenOptions {
+            merge_empty: true,
+            ..Default::default()
+        };
+        let result = flatten(&candidate, false, &options).unwrap();
+        assert_eq!(result.moved.len(), 1);
+        assert!(existing.join("main.rs").exists());
+        assert!(!candidate.nested.exists());
+    }
+
+    #[test]
+    fn partial_flatten_moves_and_journals_only_the_selection() {
+        let (_tmp, mut candidate) = setup();
+        let docs = candidate.nested.join("docs");
+        fs::create_dir(&docs).unwrap();
+        candidate.children.push(docs);
+        // Would collide, but is not selected.
+        fs::write(candidate.parent.join("file.txt"), "parent").unwrap();
+
+        let options = FlattenOptions {
+            select: Some(vec!["src".into(), "docs".into()]),
+            ..Default::default()
+        };
+        let mut journal = Journal::new();
+        let result =
+            flatten_journaled(&candidate, false, &options, &mut journal, &mut NoopObserver)
+                .unwrap();
+
+        assert_eq!(result.moved.len(), 2);
+        assert_eq!(journal.entries().cloned().collect::<Vec<_>>(), result.moved);
+        assert!(candidate.parent.join("src").is_dir());
+        assert!(candidate.parent.join("docs").is_dir());
+        assert_eq!(
+            fs::read_to_string(candidate.nested.join("file.txt")).unwrap(),
+            "data"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn followed_nested_link_is_flattened_and_removed() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().join("app");
+        let store = tmp.path().join("store");
+        fs::create_dir(&root).unwrap();
+        fs::create_dir(&store).unwrap();
+        fs::write(store.join("main.rs"), "fn main() {}").unwrap();
+        std::os::unix::fs::symlink(&store, root.join("app")).unwrap();
+
+        let options = analyzer::DetectOptions {
+            follow_links: true,
+            ..Default::default()
+        };
+        let candidate = analyzer::detect_nesting_with_options(&root, &options)
+            .unwrap()
+            .remove(0);
+        flatten(&candidate, false, &FlattenOptions::default()).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(root.join("main.rs")).unwrap(),
+            "fn main() {}"
+        );
+        assert!(!store.exists());
+        assert!(fs::symlink_metadata(root.join("app")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn strict_symlinks_refuse_only_links_that_break() {
+        let (tmp, mut candidate) = setup();
+        let strict = FlattenOptions {
+            strict_symlinks: true,
+            ..Default::default()
+        };
+        // Moves along with its target: allowed.
+        let sibling = candidate.nested.join("sibling");
+        std::os::unix::fs::symlink("file.txt", &sibling).unwrap();
+        candidate.children.push(sibling);
+        plan(&candidate, &strict).unwrap();
+
+        // Climbs out of the nested dir: lands elsewhere once flattened.
+        fs::write(tmp.path().join("shared"), "lib").unwrap();
+        let escaping = candidate.nested.join("escaping");
+        std::os::unix::fs::symlink("../../shared", &escaping).unwrap();
+        candidate.children.push(escaping);
+        let err = flatten(&candidate, false, &strict).unwrap_err();
+        assert!(matches!(err, Error::BrokenSymlink { link, .. } if link.ends_with("escaping")));
+        assert!(candidate.nested.join("file.txt").exists());
+
+        // Rewriting the link keeps it pointing at the same file.
+        let rewrite = FlattenOptions {
+            rewrite_symlinks: true,
+            ..strict
+        };
+        plan(&candidate, &rewrite).unwrap();
     }
 
-    if !report.symlink_risks.is_empty() {
-        for risk in &report.symlink_risks {
-            log::warn!(
-                "symlink risk: {} -> {} (target inside nested: {})",
-                risk.link.display(),
-                risk.target.display(),
-                risk.target_inside_nested,
+    #[cfg(unix)]
+    #[test]
+    fn incomplete_scan_refused_only_when_required() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (_tmp, candidate) = setup();
+        let src = candidate.nested.join("src");
+        let required = FlattenOptions {
+            require_complete_scan: true,
+            ..Default::default()
+        };
+        fs::set_permissions(&src, fs::Permissions::from_mode(0o000)).unwrap();
+        let lenient = plan(&candidate, &FlattenOptions::default());
+        let strict = plan(&candidate, &required);
+        fs::set_permissions(&src, fs::Permissions::from_mode(0o755)).unwrap();
+        // Root reads the directory anyway.
+        if unsafe { libc::geteuid() } != 0 {
+            assert!(
+                lenient
+                    .unwrap()
+                    .warnings()
+                    .iter()
+                    .any(|w| matches!(w, Warning::IncompleteScan { path, .. } if *path == src))
             );
+            assert!(matches!(strict, Err(Error::IncompleteScan { path, .. }) if path == src));
         }
     }
 
-    let mut moved = Vec::new();
+    #[cfg(unix)]
+    #[test]
+    fn excluded_symlinks_stay_and_keep_the_nested_dir() {
+        let (tmp, mut candidate) = setup();
+        fs::write(tmp.path().join("shared"), "lib").unwrap();
+        let escaping = candidate.nested.join("escaping");
+        std::os::unix::fs::symlink("../../shared", &escaping).unwrap();
+        candidate.children.push(escaping.clone());
 
-    for child in &candidate.children {
-        let name = child
-            .file_name()
-            .ok_or_else(|| Error::Other(format!("no filename for {}", child.display())))?;
-        let dest = candidate.parent.join(name);
+        // Would break, but stays put, so strict mode lets it through.
+        let options = FlattenOptions {
+            exclude_symlinks: true,
+            strict_symlinks: true,
+            ..Default::default()
+        };
+        let result = flatten(&candidate, false, &options).unwrap();
+        assert!(result.moved.iter().all(|m| m.from != escaping));
+        assert!(escaping.is_symlink());
+        assert!(candidate.parent.join("file.txt").exists());
+        assert!(!candidate.parent.join("escaping").exists());
+        assert!(candidate.nested.is_dir());
+    }
 
-        if dest == candidate.nested {
-            // Skip the nested directory entry itself; we'll remove it after.
-            continue;
-        }
+    #[cfg(unix)]
+    #[test]
+    fn staying_links_break_when_their_target_moves() {
+        let (_tmp, mut candidate) = setup();
+        let link = candidate.nested.join("link");
+        std::os::unix::fs::symlink("file.txt", &link).unwrap();
+        candidate.children.push(link.clone());
+        let options = FlattenOptions {
+            exclude_symlinks: true,
+            strict_symlinks: true,
+            ..Default::default()
+        };
+
+        // `link` stays in the nested directory while `file.txt` moves up.
+        let err = plan(&candidate, &options).unwrap_err();
+        assert!(matches!(err, Error::BrokenSymlink { link: l, .. } if l == link));
+
+        // Once its target stays too, nothing breaks.
+        let options = FlattenOptions {
+            exclude: vec![glob::Pattern::new("file.txt").unwrap()],
+            ..options
+        };
+        plan(&candidate, &options).unwrap();
+    }
+
+    #[test]
+    fn relative_path_climbs_to_common_ancestor() {
+        assert_eq!(
+            relative_path(Path::new("/a/b/c"), Path::new("/a/d/e")),
+            Path::new("../../d/e")
+        );
+        assert_eq!(
+            relative_path(Path::new("/a"), Path::new("/a/b")),
+            Path::new("b")
+        );
+    }
 
-        if !dry_run {
-            fs::rename(child, &dest).map_err(|e| Error::Io {
-                path: child.clone(),
-                source: e,
-            })?;
-            info!("moved {} -> {}", child.display(), dest.display());
+    #[test]
+    fn plans_over_the_move_limit_are_refused() {
+        let (_tmp, candidate) = setup();
+        let capped = |limit| FlattenOptions {
+            max_moves: Some(limit),
+            ..Default::default()
+        };
+        let err = plan(&candidate, &capped(1)).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::TooManyMoves {
+                planned: 2,
+                limit: 1
+            }
+        ));
+        assert!(candidate.nested.join("file.txt").exists());
+
+        // A plan edited past its limit is refused before anything moves.
+        let mut plan = plan(&candidate, &capped(2)).unwrap();
+        plan.max_moves = Some(1);
+        assert!(matches!(
+            execute(&plan, false, None),
+            Err(Error::TooManyMoves { .. })
+        ));
+        assert!(candidate.nested.join("file.txt").exists());
+    }
+
+    #[test]
+    fn invented_names_never_take_a_later_childs_slot() {
+        let (_tmp, mut candidate) = setup();
+        // Listed alphabetically, `a` comes first and would be renamed to
+        // `a (1)`, turning the nested `a (1)` into a collision too.
+        fs::write(candidate.parent.join("a"), "parent").unwrap();
+        for name in ["a", "a (1)"] {
+            fs::write(candidate.nested.join(name), name).unwrap();
         }
+        candidate.children = analyzer::list_dir(&candidate.nested).unwrap();
 
-        moved.push(MoveRecord {
-            from: child.clone(),
-            to: dest,
-        });
+        let options = FlattenOptions {
+            collisions: CollisionPolicy::Rename,
+            sort_moves: true,
+            ..Default::default()
+        };
+        let plan = plan(&candidate, &options).unwrap();
+        let to = |name: &str| {
+            plan.moves
+                .iter()
+                .find(|m| m.from == candidate.nested.join(name))
+                .map(|m| m.to.clone())
+        };
+        assert_eq!(to("a (1)"), Some(candidate.parent.join("a (1)")));
+        assert_eq!(to("a"), Some(candidate.parent.join("a (2)")));
+        // Directories lead, the collision comes last.
+        assert_eq!(plan.moves[0].from, candidate.nested.join("src"));
+        assert_eq!(plan.moves.last().unwrap().from, candidate.nested.join("a"));
+
+        execute(&plan, false, None).unwrap();
+        assert_eq!(
+            fs::read_to_string(candidate.parent.join("a (1)")).unwrap(),
+            "a (1)"
+        );
     }
 
-    // Remove the now-empty nested directory.
-    if !dry_run {
-        fs::remove_dir(&candidate.nested).map_err(|e| Error::Io {
-            path: candidate.nested.clone(),
-            source: e,
-        })?;
-        info!("removed empty directory {}", candidate.nested.display());
+    #[test]
+    fn sorted_moves_apply_inside_merged_directories() {
+        let (_tmp, candidate) = setup();
+        fs::create_dir_all(candidate.parent.join("src")).unwrap();
+        fs::write(candidate.parent.join("src/a"), "parent").unwrap();
+        for name in ["a", "a (1)"] {
+            fs::write(candidate.nested.join("src").join(name), name).unwrap();
+        }
+        let planned = |sort_moves| {
+            let options = FlattenOptions {
+                merge: MergeStrategy::MergeDirectories,
+                collisions: CollisionPolicy::Rename,
+                sort_moves,
+                ..Default::default()
+            };
+            plan(&candidate, &options).unwrap().moves
+        };
+        let to = |moves: &[MoveRecord], name: &str| {
+            moves
+                .iter()
+                .find(|m| m.from == candidate.nested.join("src").join(name))
+                .map(|m| m.to.clone())
+        };
+
+        // In listing order `a` takes `a (1)` and pushes the nested one aside.
+        let moves = planned(false);
+        assert_eq!(to(&moves, "a"), Some(candidate.parent.join("src/a (1)")));
+        assert_eq!(
+            to(&moves, "a (1)"),
+            Some(candidate.parent.join("src/a (1) (1)"))
+        );
+
+        let moves = planned(true);
+        assert_eq!(
+            to(&moves, "a (1)"),
+            Some(candidate.parent.join("src/a (1)"))
+        );
+        assert_eq!(to(&moves, "a"), Some(candidate.parent.join("src/a (2)")));
     }
 
-    Ok(MoveResult { moved })
-}
+    #[test]
+    fn journal_follows_plan_order() {
+        let (_tmp, mut candidate) = setup();
+        for i in 0..50 {
+            let path = candidate.nested.join(format!("f{i:02}"));
+            fs::write(&path, "x").unwrap();
+            candidate.children.push(path);
+        }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs;
-    use tempfile::TempDir;
+        let plan = plan(&candidate, &FlattenOptions::default()).unwrap();
+        let mut journal = Journal::new();
+        execute_journaled(&plan, false, &mut journal, &mut NoopObserver, None).unwrap();
+        assert!(journal.entries().eq(&plan.moves));
+    }
 
-    fn setup() -> (TempDir, NestingCandidate) {
+    #[test]
+    fn empty_nesting_removes_every_redundant_level() {
         let tmp = TempDir::new().unwrap();
         let root = tmp.path().join("project");
-        let nested = root.join("project");
-
-        fs::create_dir_all(&nested).unwrap();
-        fs::write(nested.join("file.txt"), "data").unwrap();
-        fs::create_dir(nested.join("src")).unwrap();
+        fs::create_dir_all(root.join("project/project")).unwrap();
+        let candidate = analyzer::detect_nesting(&root).unwrap().remove(0);
+        assert!(candidate.is_empty_nesting());
 
-        let root_canon = root.canonicalize().unwrap();
-        let nested_canon = root_canon.join("project");
+        let removed = remove_empty_nesting(&candidate).unwrap();
+        assert_eq!(removed, candidate.redundant_dirs());
+        assert!(fs::read_dir(&root).unwrap().next().is_none());
 
-        let children = vec![nested_canon.join("file.txt"), nested_canon.join("src")];
+        // A directory that gained an entry after the check is left alone.
+        fs::create_dir_all(root.join("project/late")).unwrap();
+        assert!(!remove_dir(&root.join("project")).unwrap());
+    }
 
+    #[test]
+    fn failed_directory_removal_restores_moves() {
+        let (_tmp, candidate) = setup();
+        // `src` is left out of the plan, so the nested dir is still
+        // non-empty when removal is attempted.
         let candidate = NestingCandidate {
-            parent: root_canon,
-            nested: nested_canon,
-            children,
+            children: vec![candidate.nested.join("file.txt")],
+            ..candidate
         };
 
-        (tmp, candidate)
+        let err = flatten(&candidate, false, &FlattenOptions::default()).unwrap_err();
+        assert!(matches!(err, Error::Io { path, .. } if path == candidate.nested));
+        assert!(candidate.nested.join("file.txt").exists());
+        assert!(!candidate.parent.join("file.txt").exists());
     }
 
     #[test]
-    fn dry_run_does_not_modify_filesystem() {
+    fn identical_collisions_deduplicated_when_enabled() {
+        let (_tmp, candidate) = setup();
+        fs::write(candidate.parent.join("file.txt"), "data").unwrap();
+
+        // Off by default: identical content is still a collision.
+        let err = flatten(&candidate, false, &FlattenOptions::default()).unwrap_err();
+        assert!(matches!(err, Error::Collision { .. }));
+
+        let options = FlattenOptions {
+            skip_identical: true,
+            ..Default::default()
+        };
+        let result = flatten(&candidate, false, &options).unwrap();
+        assert_eq!(result.moved[0].op, MoveOp::Deduplicate);
+        assert!(!candidate.nested.exists());
+        assert_eq!(
+            fs::read_to_string(candidate.parent.join("file.txt")).unwrap(),
+            "data"
+        );
+
+        let mut journal = Journal::new();
+        journal.record(result.moved);
+        journal
+            .rollback(
+                crate::journal::RollbackScope::Latest,
+                crate::journal::RollbackConflict::Abort,
+                None,
+                false,
+            )
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(candidate.nested.join("file.txt")).unwrap(),
+            "data"
+        );
+        assert!(candidate.parent.join("file.txt").exists());
+    }
+
+    #[test]
+    fn differing_collisions_still_abort_with_skip_identical() {
+        let (_tmp, candidate) = setup();
+        fs::write(candidate.parent.join("file.txt"), "other").unwrap();
+
+        let options = FlattenOptions {
+            skip_identical: true,
+            ..Default::default()
+        };
+        let err = flatten(&candidate, false, &options).unwrap_err();
+        assert!(matches!(err, Error::Collision { .. }));
+    }
+
+    #[test]
+    fn special_files_block_cross_device_moves_only() {
         let (_tmp, candidate) = setup();
-        let result = flatten(&candidate, true).unwrap();
+        let fifo = candidate.nested.join("src/pipe");
+        let status = std::process::Command::new("mkfifo")
+            .arg(&fifo)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let mut report = scanner::scan(&candidate);
+        assert!(check_special_files(&report).is_ok());
+
+        report.space.same_device = false;
+        let err = check_special_files(&report).unwrap_err();
+        assert!(matches!(err, Error::CrossDevice { path, .. } if path == fifo));
+
+        // Same-device renames carry the fifo along untouched.
+        flatten(&candidate, false, &FlattenOptions::default()).unwrap();
+        assert!(candidate.parent.join("src/pipe").exists());
+    }
+
+    #[test]
+    fn same_inode_collision_unlinks_and_rolls_back() {
+        let (_tmp, candidate) = setup();
+        fs::hard_link(
+            candidate.nested.join("file.txt"),
+            candidate.parent.join("file.txt"),
+        )
+        .unwrap();
+
+        let result = flatten(&candidate, false, &FlattenOptions::default()).unwrap();
+        assert_eq!(result.moved[0].op, MoveOp::Unlink);
+        assert!(!candidate.nested.exists());
+
+        let mut journal = Journal::new();
+        journal.record(result.moved);
+        journal
+            .rollback(
+                crate::journal::RollbackScope::Latest,
+                crate::journal::RollbackConflict::Abort,
+                None,
+                false,
+            )
+            .unwrap();
+        assert!(scanner::same_inode(
+            &candidate.nested.join("file.txt"),
+            &candidate.parent.join("file.txt")
+        ));
+    }
+
+    #[test]
+    fn excluded_files_stay_behind_with_nested_dir() {
+        let (_tmp, mut candidate) = setup();
+        let lock = candidate.nested.join(".DS_Store");
+        fs::write(&lock, "junk").unwrap();
+        candidate.children.push(lock.clone());
+
+        let options = FlattenOptions {
+            exclude: vec![glob::Pattern::new(".DS_*").unwrap()],
+            ..Default::default()
+        };
+        let result = flatten(&candidate, false, &options).unwrap();
 
         assert_eq!(result.moved.len(), 2);
-        // Nested dir should still exist
+        assert!(candidate.parent.join("file.txt").exists());
+        assert!(!candidate.parent.join(".DS_Store").exists());
+        assert!(lock.exists());
+    }
+
+    #[test]
+    fn exclude_matches_file_name_only() {
+        let (_tmp, candidate) = setup();
+        let options = FlattenOptions {
+            exclude: vec![glob::Pattern::new("*project*").unwrap()],
+            ..Default::default()
+        };
+        let plan = plan(&candidate, &options).unwrap();
+        assert!(plan.excluded.is_empty());
+        assert_eq!(plan.moves.len(), 2);
+    }
+
+    #[test]
+    fn plan_then_execute_matches_flatten() {
+        let (_tmp, candidate) = setup();
+        let plan = plan(&candidate, &FlattenOptions::default()).unwrap();
+        assert!(plan.report.is_safe());
+        assert_eq!(plan.redundant_dirs, vec![candidate.nested.clone()]);
+
+        let dry = execute(&plan, true, None).unwrap();
+        assert_eq!(dry.moved, plan.moves);
         assert!(candidate.nested.exists());
+
+        let result = execute(&plan, false, None).unwrap();
+        assert_eq!(result.moved, plan.moves);
+        assert!(!candidate.nested.exists());
+        assert!(candidate.parent.join("file.txt").exists());
     }
 
     #[test]
-    fn apply_moves_files() {
+    fn summary_counts_relocated_files_directories_and_bytes() {
         let (_tmp, candidate) = setup();
-        let result = flatten(&candidate, false).unwrap();
+        fs::write(candidate.nested.join("src/lib.rs"), "0123456789").unwrap();
+        let plan = plan(&candidate, &FlattenOptions::default()).unwrap();
 
-        assert_eq!(result.moved.len(), 2);
-        // Nested dir should be removed
+        let expected = Summary {
+            files: 2,
+            directories: 1,
+            bytes: 14,
+            warnings: false,
+        };
+        // Dry runs are measured at the source, real runs at the destination.
+        assert_eq!(execute(&plan, true, None).unwrap().summary(), expected);
+        assert_eq!(execute(&plan, false, None).unwrap().summary(), expected);
+    }
+
+    #[test]
+    fn flatten_into_another_directory() {
+        let (tmp, candidate) = setup();
+        let dest = tmp.path().join("clean/archive");
+        let options = FlattenOptions {
+            into: Some(dest.clone()),
+            ..Default::default()
+        };
+
+        let plan = plan(&candidate, &options).unwrap();
+        assert!(plan.create_dest);
+        assert!(!dest.exists());
+        let result = execute(&plan, false, None).unwrap();
+        assert!(result.moved.iter().all(|r| r.to.starts_with(&dest)));
+        assert!(dest.join("file.txt").exists());
+        assert!(dest.join("src").is_dir());
         assert!(!candidate.nested.exists());
-        // Files should be in parent
+        assert!(candidate.parent.exists());
+
+        // An existing destination is checked for collisions like a parent.
+        let (_tmp, candidate) = setup();
+        fs::write(dest.join("other.txt"), "").unwrap();
+        let err = flatten(&candidate, false, &options).unwrap_err();
+        assert!(
+            matches!(err, Error::Collision { existing, .. } if existing == dest.join("file.txt"))
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn into_a_symlink_to_the_nested_tree_is_refused() {
+        let (tmp, candidate) = setup();
+        let link = tmp.path().join("link");
+        std::os::unix::fs::symlink(candidate.nested.join("src"), &link).unwrap();
+
+        for into in [link.clone(), link.join("new")] {
+            let options = FlattenOptions::builder().into_dir(into).build();
+            let err = plan(&candidate, &options).unwrap_err();
+            assert!(err.to_string().contains("it would be removed"), "{err}");
+        }
+        assert!(candidate.nested.join("file.txt").exists());
+    }
+
+    #[test]
+    fn directories_created_for_into_are_removed_by_rollback() {
+        let (tmp, candidate) = setup();
+        let clean = tmp.path().join("clean");
+        let dest = clean.join("archive");
+        let options = FlattenOptions {
+            into: Some(dest.clone()),
+            ..Default::default()
+        };
+
+        let mut journal = Journal::new();
+        flatten_journaled(&candidate, false, &options, &mut journal, &mut NoopObserver).unwrap();
+        assert_eq!(
+            journal.batches[0].created_dirs,
+            vec![clean.clone(), dest.clone()]
+        );
+
+        let report = journal
+            .rollback(
+                crate::journal::RollbackScope::Latest,
+                crate::journal::RollbackConflict::Abort,
+                None,
+                false,
+            )
+            .unwrap();
+        assert_eq!(report.removed_dirs, vec![dest, clean.clone()]);
+        assert!(!clean.exists());
+        assert!(candidate.nested.join("file.txt").exists());
+    }
+
+    #[test]
+    fn plan_driven_partial_flatten_keeps_nested_dir() {
+        let (_tmp, candidate) = setup();
+        let plan = plan(&candidate, &FlattenOptions::default()).unwrap();
+        assert_eq!(plan.moves.len(), 2);
+        // Planning alone touches nothing.
+        assert!(candidate.nested.join("file.txt").exists());
+
+        let mut journal = Journal::new();
+        execute_move(&plan, &plan.moves[0], &mut journal).unwrap();
+        let removed = remove_emptied_dirs(&plan, &mut journal).unwrap();
+
+        assert!(removed.is_empty());
         assert!(candidate.parent.join("file.txt").exists());
-        assert!(candidate.parent.join("src").is_dir());
+        assert!(candidate.nested.join("src").exists());
+        assert!(journal.entries().eq(&plan.moves[..1]));
+
+        execute_move(&plan, &plan.moves[1], &mut journal).unwrap();
+        assert_eq!(
+            remove_emptied_dirs(&plan, &mut journal).unwrap(),
+            vec![candidate.nested.clone()]
+        );
     }
 
     #[test]
-    fn collision_aborts() {
+    fn kept_nested_dir_is_not_recreated_by_rollback() {
+        for remove_nested in [true, false] {
+            let (_tmp, candidate) = setup();
+            let options = FlattenOptions {
+                remove_nested,
+                ..Default::default()
+            };
+            let mut journal = Journal::new();
+            flatten_journaled(&candidate, false, &options, &mut journal, &mut NoopObserver)
+                .unwrap();
+            assert!(candidate.parent.join("file.txt").exists());
+            assert_eq!(candidate.nested.exists(), !remove_nested);
+            let recorded = journal.batches[0].removed_dirs.clone();
+            assert_eq!(recorded.is_empty(), !remove_nested);
+
+            let report = journal
+                .rollback(
+                    crate::journal::RollbackScope::Latest,
+                    crate::journal::RollbackConflict::Abort,
+                    None,
+                    false,
+                )
+                .unwrap();
+            assert_eq!(report.recreated, recorded);
+            assert!(candidate.nested.join("file.txt").exists());
+        }
+    }
+
+    #[test]
+    fn merged_moves_roll_back() {
         let (_tmp, candidate) = setup();
+        fs::write(candidate.nested.join("src/main.rs"), "fn main() {}").unwrap();
+        fs::create_dir(candidate.parent.join("src")).unwrap();
 
-        // Create conflicting file in parent
-        fs::write(candidate.parent.join("file.txt"), "conflict").unwrap();
+        let result = flatten(&candidate, false, &merge_options()).unwrap();
+        let mut journal = crate::journal::Journal::new();
+        journal.record(result.moved);
+        journal
+            .rollback(
+                crate::journal::RollbackScope::Latest,
+                crate::journal::RollbackConflict::Abort,
+                None,
+                false,
+            )
+            .unwrap();
 
-        let err = flatten(&candidate, false).unwrap_err();
-        assert!(matches!(err, Error::Collision { .. }));
-        // Nested dir should still exist — nothing was moved
-        assert!(candidate.nested.exists());
+        assert!(candidate.nested.join("file.txt").exists());
+        assert!(candidate.nested.join("src/main.rs").exists());
+        assert!(
+            candidate
+                .parent
+                .join("src")
+                .read_dir()
+                .unwrap()
+                .next()
+                .is_none()
+        );
     }
 }
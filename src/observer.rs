@@ -0,0 +1,138 @@
+use std::path::Path;
+
+use log::{info, warn};
+
+use crate::mover::{MoveOp, MoveRecord};
+use crate::scanner::{Collision, SymlinkRisk};
+
+/// Callbacks for what a flatten does, for embedding the crate in tools that
+/// present progress themselves. Every method defaults to doing nothing.
+///
+/// [`mover::execute_journaled`](crate::mover::execute_journaled) reports the
+/// plan's collisions and symlink risks first, then each move and removed
+/// directory as it happens. In a dry run every planned move is reported and
+/// nothing is removed. If the flatten fails, moves already reported are
+/// undone.
+pub trait Observer {
+    /// A move was performed (or, in a dry run, would be).
+    fn on_move(&mut self, _record: &MoveRecord) {}
+    /// A child collides with an entry already in the destination.
+    fn on_collision(&mut self, _collision: &Collision) {}
+    /// A moved symlink's target may be affected by the flatten.
+    fn on_symlink_risk(&mut self, _risk: &SymlinkRisk) {}
+    /// A directory emptied by the flatten was removed.
+    fn on_removed_dir(&mut self, _dir: &Path) {}
+}
+
+/// Ignores every event.
+#[derive(Debug, Default)]
+pub struct NoopObserver;
+
+impl Observer for NoopObserver {}
+
+/// Reports events through the `log` macros, as the crate always has.
+/// Collisions are left to the caller, which decides how they are shown.
+#[derive(Debug, Default)]
+pub struct LoggingObserver;
+
+impl Observer for LoggingObserver {
+    fn on_move(&mut self, record: &MoveRecord) {
+        match record.op {
+            MoveOp::Rename => {
+                info!("moved {} -> {}", record.from.display(), record.to.display())
+            }
+            MoveOp::ReplaceEmpty => info!(
+                "moved {} -> {} (replacing an empty directory)",
+                record.from.display(),
+                record.to.display()
+            ),
+            MoveOp::Deduplicate | MoveOp::Unlink => info!(
+                "removed {} (same content as {})",
+                record.from.display(),
+                record.to.display()
+            ),
+        }
+    }
+
+    fn on_symlink_risk(&mut self, risk: &SymlinkRisk) {
+        warn!("symlink risk: {risk}");
+    }
+
+    fn on_removed_dir(&mut self, dir: &Path) {
+        info!("removed empty directory {}", dir.display());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::Journal;
+    use crate::{analyzer, mover};
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[derive(Default)]
+    struct Recorder(Vec<String>);
+
+    impl Observer for Recorder {
+        fn on_move(&mut self, record: &MoveRecord) {
+            self.0.push(format!("move {}", name(&record.from)));
+        }
+        fn on_collision(&mut self, collision: &Collision) {
+            self.0
+                .push(format!("collision {}", name(&collision.source)));
+        }
+        fn on_symlink_risk(&mut self, risk: &SymlinkRisk) {
+            self.0.push(format!("symlink {}", name(&risk.link)));
+        }
+        fn on_removed_dir(&mut self, dir: &Path) {
+            self.0.push(format!("removed {}", name(dir)));
+        }
+    }
+
+    fn name(path: &Path) -> String {
+        path.file_name().unwrap().to_string_lossy().into_owned()
+    }
+
+    fn flatten(root: &Path, dry_run: bool) -> Vec<String> {
+        let candidate = analyzer::detect_nesting(root).unwrap().remove(0);
+        let options = mover::FlattenOptions {
+            collisions: mover::CollisionPolicy::Rename,
+            ..Default::default()
+        };
+        let mut recorder = Recorder::default();
+        mover::flatten_journaled(
+            &candidate,
+            dry_run,
+            &options,
+            &mut Journal::new(),
+            &mut recorder,
+        )
+        .unwrap();
+        recorder.0
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reports_scan_findings_then_moves_then_removals() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().join("app");
+        fs::create_dir_all(root.join("app")).unwrap();
+        fs::write(root.join("app/README.md"), "nested").unwrap();
+        fs::write(root.join("README.md"), "parent").unwrap();
+        std::os::unix::fs::symlink("README.md", root.join("app/docs")).unwrap();
+
+        let expected = [
+            "collision README.md",
+            "symlink docs",
+            "move README.md",
+            "move docs",
+        ];
+        assert_eq!(flatten(&root, true), expected);
+        assert!(root.join("app").exists());
+
+        let mut expected = expected.map(String::from).to_vec();
+        expected.push("removed app".into());
+        assert_eq!(flatten(&root, false), expected);
+    }
+}
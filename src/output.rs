@@ -0,0 +1,488 @@
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::journal::{EntryStatus, PlannedRevert, RevertState, RollbackPlan, RollbackReport};
+use crate::mover::{MoveOp, MoveRecord, Summary};
+use crate::reporter::CandidateReport;
+use crate::scanner::{Collision, CollisionKind, CollisionTypes};
+
+/// How CLI events are written to stdout.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Output {
+    /// Human-readable prose.
+    #[default]
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+/// Something worth telling the user about while a command runs.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// No redundant nesting was found.
+    NothingToDo,
+    /// `analyze` found no redundant nesting under `path`.
+    NoNesting { path: PathBuf },
+    /// `analyze --group`: the candidates that follow belong to `path`.
+    Project { path: PathBuf, candidates: usize },
+    /// A candidate found by `analyze`, with its pre-move scan. Its prose
+    /// (or tree) is printed by `analyze` itself.
+    Candidate(Box<CandidateReport>),
+    /// `analyze` is done with `path`.
+    Analyzed { path: PathBuf },
+    /// About to flatten `nested` into its parent.
+    Flatten {
+        nested: PathBuf,
+        /// The symlink the target was given as, if it was one.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        via_symlink: Option<PathBuf>,
+        dry_run: bool,
+    },
+    /// A collision found by the pre-move scan.
+    Collision {
+        source: PathBuf,
+        existing: PathBuf,
+        kind: CollisionKind,
+        types: CollisionTypes,
+    },
+    /// A move that was (or, in a dry run, would be) carried out.
+    Move {
+        from: PathBuf,
+        to: PathBuf,
+        op: MoveOp,
+        dry_run: bool,
+    },
+    /// An empty redundant directory that was (or would be) removed; the
+    /// whole flatten when there is nothing to move.
+    RemoveEmpty { dir: PathBuf, dry_run: bool },
+    /// Totals at the end of an apply.
+    Summary {
+        files: usize,
+        directories: usize,
+        bytes: u64,
+        warnings: bool,
+        dry_run: bool,
+    },
+    /// A dry run's before/after tree, as [`tree::diff`](crate::tree::diff)
+    /// lines.
+    Diff { lines: Vec<String> },
+    /// The journal was written.
+    JournalSaved { path: PathBuf },
+    /// A dry run's plan was written for review.
+    PlanSaved { path: PathBuf },
+    /// The user stopped an interactive apply.
+    Stopped,
+    /// One entry of a rollback dry run.
+    Revert(PlannedRevert),
+    /// The totals of a rollback dry run.
+    RollbackPreview {
+        ready: usize,
+        bytes: u64,
+        occupied: usize,
+        missing: usize,
+    },
+    /// A rollback finished. `missing` are destinations that were already
+    /// gone; `skipped` moves stayed because their origin is occupied.
+    RolledBack {
+        reversed: usize,
+        missing: Vec<PathBuf>,
+        skipped: Vec<MoveRecord>,
+    },
+    /// One journal entry checked by `verify`.
+    Verify(EntryStatus),
+    /// The totals of `verify`.
+    Verified {
+        entries: usize,
+        ready: usize,
+        blocked: usize,
+        missing: usize,
+    },
+    /// A journal found by `clean`.
+    JournalFound { path: PathBuf },
+    /// `clean` found no journals under `path`.
+    NoJournals { path: PathBuf },
+    /// `clean` removed (or, in a dry run, would remove) `count` journals.
+    JournalsRemoved { count: usize, dry_run: bool },
+    /// A log message (warnings, errors, ...).
+    Log {
+        level: String,
+        target: String,
+        message: String,
+    },
+}
+
+impl Event {
+    pub fn moved(record: &MoveRecord, dry_run: bool) -> Self {
+        Event::Move {
+            from: record.from.clone(),
+            to: record.to.clone(),
+            op: record.op,
+            dry_run,
+        }
+    }
+
+    pub fn summary(summary: &Summary, dry_run: bool) -> Self {
+        Event::Summary {
+            files: summary.files,
+            directories: summary.directories,
+            bytes: summary.bytes,
+            warnings: summary.warnings,
+            dry_run,
+        }
+    }
+
+    pub fn rollback_preview(plan: &RollbackPlan) -> Self {
+        let count = |state| plan.entries.iter().filter(|e| e.state == state).count();
+        Event::RollbackPreview {
+            ready: count(RevertState::Ready),
+            bytes: plan.bytes,
+            occupied: count(RevertState::Occupied),
+            missing: count(RevertState::Missing),
+        }
+    }
+
+    pub fn rolled_back(report: &RollbackReport) -> Self {
+        Event::RolledBack {
+            reversed: report.reversed.len(),
+            missing: report.missing.iter().map(|m| m.to.clone()).collect(),
+            skipped: report.skipped.clone(),
+        }
+    }
+
+    pub fn collision(collision: &Collision) -> Self {
+        Event::Collision {
+            source: collision.source.clone(),
+            existing: collision.existing.clone(),
+            kind: collision.kind,
+            types: collision.types,
+        }
+    }
+}
+
+impl Output {
+    /// Print `event` to stdout.
+    pub fn emit(self, event: &Event) {
+        // A closed stdout is not worth failing a flatten over.
+        let _ = self.write(&mut io::stdout().lock(), event);
+    }
+
+    /// Write `event` to `w`. Text mode prints nothing for events that have
+    /// no prose form (collisions are shown by `analyze`, logs go to stderr).
+    pub fn write(self, w: &mut impl Write, event: &Event) -> io::Result<()> {
+        match self {
+            Output::Json => {
+                serde_json::to_writer(&mut *w, event)?;
+                writeln!(w)
+            }
+            Output::Text => write_text(w, event),
+        }
+    }
+}
+
+fn write_text(w: &mut impl Write, event: &Event) -> io::Result<()> {
+    match event {
+        Event::NothingToDo => writeln!(w, "Nothing to flatten."),
+        Event::NoNesting { path } => {
+            writeln!(w, "No redundant nesting detected in {}", path.display())
+        }
+        Event::Project { path, candidates } => {
+            writeln!(w, "== {} ({candidates}) ==", path.display())
+        }
+        Event::Analyzed { path } => {
+            writeln!(w, "\nRun with `apply {}` to execute.", path.display())
+        }
+        Event::Flatten {
+            nested,
+            via_symlink,
+            dry_run: true,
+        } => match via_symlink {
+            Some(link) => writeln!(
+                w,
+                "[dry-run] Would flatten: {} (via symlink {})",
+                nested.display(),
+                link.display()
+            ),
+            None => writeln!(w, "[dry-run] Would flatten: {}", nested.display()),
+        },
+        Event::Move {
+            from,
+            to,
+            op,
+            dry_run,
+        } => {
+            let prefix = if *dry_run { "[dry-run] " } else { "" };
+            match op {
+                MoveOp::Rename => writeln!(w, "{prefix}{} -> {}", from.display(), to.display()),
+                MoveOp::Deduplicate => writeln!(
+                    w,
+                    "{prefix}{} removed (identical to {})",
+                    from.display(),
+                    to.display()
+                ),
+                MoveOp::ReplaceEmpty => writeln!(
+                    w,
+                    "{prefix}{} -> {} (replacing empty directory)",
+                    from.display(),
+                    to.display()
+                ),
+                MoveOp::Unlink => writeln!(
+                    w,
+                    "{prefix}{} removed (hard link to {})",
+                    from.display(),
+                    to.display()
+                ),
+            }
+        }
+        Event::RemoveEmpty { dir, dry_run: true } => writeln!(
+            w,
+            "[dry-run] Would remove empty redundant directory {}",
+            dir.display()
+        ),
+        Event::RemoveEmpty {
+            dir,
+            dry_run: false,
+        } => writeln!(w, "Removing empty redundant directory {}", dir.display()),
+        Event::Summary {
+            files,
+            directories,
+            bytes,
+            warnings,
+            dry_run,
+        } => {
+            let verb = if *dry_run {
+                "[dry-run] Would move"
+            } else {
+                "Moved"
+            };
+            let note = if *warnings {
+                "; warnings were reported"
+            } else {
+                ""
+            };
+            let plural = |n: u64, one, many| if n == 1 { one } else { many };
+            writeln!(
+                w,
+                "{verb} {files} {} and {directories} {} ({bytes} {}){note}.",
+                plural(*files as u64, "file", "files"),
+                plural(*directories as u64, "directory", "directories"),
+                plural(*bytes, "byte", "bytes"),
+            )
+        }
+        Event::Diff { lines } => lines.iter().try_for_each(|line| writeln!(w, "{line}")),
+        Event::JournalSaved { path } => writeln!(w, "Journal saved to {}", path.display()),
+        Event::PlanSaved { path } => writeln!(w, "Plan saved to {}", path.display()),
+        Event::Stopped => writeln!(w, "Stopped at user request."),
+        Event::Revert(entry) => {
+            let (to, from) = (entry.to.display(), entry.from.display());
+            match entry.state {
+                RevertState::Ready => {
+                    writeln!(w, "[dry-run] {to} -> {from} ({} bytes)", entry.bytes)
+                }
+                RevertState::Occupied => {
+                    writeln!(w, "[dry-run] {to} -> {from} blocked: {from} is occupied")
+                }
+                RevertState::Missing => writeln!(w, "[dry-run] {to} already reverted / missing"),
+            }
+        }
+        Event::RollbackPreview {
+            ready,
+            bytes,
+            occupied,
+            missing,
+        } => writeln!(
+            w,
+            "[dry-run] Would roll back {ready} move(s) ({bytes} bytes); {occupied} occupied, {missing} already reverted / missing."
+        ),
+        Event::RolledBack {
+            reversed,
+            missing,
+            skipped,
+        } => {
+            writeln!(w, "Rolled back {reversed} move(s).")?;
+            if !missing.is_empty() {
+                writeln!(w, "{} move(s) already reverted or missing:", missing.len())?;
+                for to in missing {
+                    writeln!(w, "  {}", to.display())?;
+                }
+            }
+            if !skipped.is_empty() {
+                writeln!(
+                    w,
+                    "Skipped {} move(s) whose original location is occupied:",
+                    skipped.len()
+                )?;
+                for m in skipped {
+                    writeln!(w, "  {} (kept at {})", m.from.display(), m.to.display())?;
+                }
+            }
+            Ok(())
+        }
+        Event::Verify(entry) => {
+            let state = if entry.rollback_ready {
+                "ready"
+            } else if entry.is_blocked() {
+                "blocked"
+            } else {
+                "missing"
+            };
+            writeln!(
+                w,
+                "{state:<8} [{}] {} -> {}",
+                entry.batch,
+                entry.to.display(),
+                entry.from.display()
+            )
+        }
+        Event::Verified { entries, ready, .. } => {
+            writeln!(w, "{ready} of {entries} entries ready for rollback.")
+        }
+        Event::JournalFound { path } => writeln!(w, "{}", path.display()),
+        Event::NoJournals { path } => {
+            writeln!(w, "No journals found under {}.", path.display())
+        }
+        Event::JournalsRemoved {
+            count,
+            dry_run: true,
+        } => writeln!(w, "Would remove {count} journal(s)."),
+        Event::JournalsRemoved {
+            count,
+            dry_run: false,
+        } => writeln!(w, "Removed {count} journal(s)."),
+        Event::Flatten { .. }
+        | Event::Collision { .. }
+        | Event::Candidate(_)
+        | Event::Log { .. } => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(output: Output, event: &Event) -> String {
+        let mut buf = Vec::new();
+        output.write(&mut buf, event).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn text_mode_keeps_the_prose() {
+        let record = MoveRecord::new("/p/p/a".into(), "/p/a".into());
+        assert_eq!(
+            render(Output::Text, &Event::moved(&record, true)),
+            "[dry-run] /p/p/a -> /p/a\n"
+        );
+        assert_eq!(
+            render(
+                Output::Text,
+                &Event::Flatten {
+                    nested: "/p/p".into(),
+                    via_symlink: None,
+                    dry_run: false
+                }
+            ),
+            ""
+        );
+
+        let summary = Summary {
+            files: 3,
+            directories: 1,
+            bytes: 42,
+            warnings: true,
+        };
+        assert_eq!(
+            render(Output::Text, &Event::summary(&summary, false)),
+            "Moved 3 files and 1 directory (42 bytes); warnings were reported.\n"
+        );
+
+        let summary = Summary {
+            files: 1,
+            directories: 0,
+            bytes: 1,
+            warnings: false,
+        };
+        assert_eq!(
+            render(Output::Text, &Event::summary(&summary, true)),
+            "[dry-run] Would move 1 file and 0 directories (1 byte).\n"
+        );
+    }
+
+    #[test]
+    fn json_mode_writes_one_object_per_line() {
+        let record = MoveRecord {
+            op: MoveOp::Deduplicate,
+            ..MoveRecord::new("/p/p/a".into(), "/p/a".into())
+        };
+        let line = render(Output::Json, &Event::moved(&record, false));
+        assert!(line.ends_with('\n'));
+        assert_eq!(line.lines().count(), 1);
+
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["event"], "move");
+        assert_eq!(value["op"], "deduplicate");
+        assert_eq!(value["from"], "/p/p/a");
+        assert_eq!(value["dry_run"], false);
+    }
+
+    #[test]
+    fn rollback_events_have_prose_and_json() {
+        let report = RollbackReport {
+            reversed: vec![MoveRecord::new("/p/p/a".into(), "/p/a".into())],
+            skipped: vec![MoveRecord::new("/p/p/b".into(), "/p/b".into())],
+            ..Default::default()
+        };
+        let event = Event::rolled_back(&report);
+        assert_eq!(
+            render(Output::Text, &event),
+            "Rolled back 1 move(s).\n\
+             Skipped 1 move(s) whose original location is occupied:\n  \
+             /p/p/b (kept at /p/b)\n"
+        );
+
+        let value: serde_json::Value = serde_json::from_str(&render(Output::Json, &event)).unwrap();
+        assert_eq!(value["event"], "rolled_back");
+        assert_eq!(value["reversed"], 1);
+        assert_eq!(value["skipped"][0]["from"], "/p/p/b");
+    }
+
+    #[test]
+    fn clean_events_have_prose_and_json() {
+        let event = Event::JournalsRemoved {
+            count: 2,
+            dry_run: true,
+        };
+        assert_eq!(render(Output::Text, &event), "Would remove 2 journal(s).\n");
+        let value: serde_json::Value = serde_json::from_str(&render(Output::Json, &event)).unwrap();
+        assert_eq!(value["event"], "journals_removed");
+        assert_eq!(value["count"], 2);
+    }
+
+    #[test]
+    fn verify_events_have_prose_and_json() {
+        let entry = EntryStatus {
+            batch: 1,
+            from: "/p/p/a".into(),
+            to: "/p/a".into(),
+            to_exists: true,
+            from_occupied: true,
+            rollback_ready: false,
+        };
+        assert_eq!(
+            render(Output::Text, &Event::Verify(entry)),
+            "blocked  [1] /p/a -> /p/p/a\n"
+        );
+        let event = Event::Verified {
+            entries: 2,
+            ready: 1,
+            blocked: 1,
+            missing: 0,
+        };
+        let value: serde_json::Value = serde_json::from_str(&render(Output::Json, &event)).unwrap();
+        assert_eq!(value["event"], "verified");
+        assert_eq!(value["blocked"], 1);
+    }
+}
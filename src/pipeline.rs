@@ -0,0 +1,173 @@
+use std::path::{Path, PathBuf};
+
+use crate::Result;
+use crate::analyzer;
+use crate::journal::Journal;
+use crate::mover::{self, CollisionPolicy, FlattenOptions, MoveRecord, Warning};
+use crate::observer::NoopObserver;
+
+/// Settings for [`flatten_directory`]. The default applies for real,
+/// aborting on any collision and excluding nothing.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Plan everything but touch nothing (no journal is written either).
+    pub dry_run: bool,
+    /// What happens to children colliding with an entry in the parent.
+    pub collisions: CollisionPolicy,
+    /// Children whose file name matches any of these stay where they are.
+    pub exclude: Vec<glob::Pattern>,
+}
+
+/// What [`flatten_directory`] did (or, in a dry run, would do).
+#[derive(Debug, Clone, Default)]
+pub struct FlattenOutcome {
+    /// The moves, in the order they were performed.
+    pub moves: Vec<MoveRecord>,
+    /// Redundant directories removed because nothing was left in them.
+    pub removed_dirs: Vec<PathBuf>,
+    /// Where the journal for rolling the moves back was saved; `None` in a
+    /// dry run or when nothing moved.
+    pub journal: Option<PathBuf>,
+    /// Everything the flatten warned about (see [`mover::MoveResult::warnings`]).
+    pub warnings: Vec<Warning>,
+}
+
+/// Detect redundant nesting in `path` and flatten it, journaling the moves
+/// beside them as `apply` does. Finding nothing to flatten is not an
+/// error: the outcome is simply empty.
+///
+/// This is the whole `apply` pipeline for one directory, for use as a
+/// library; the individual steps live in [`analyzer`], [`mover`] and
+/// [`journal`](crate::journal).
+pub fn flatten_directory(path: &Path, options: Options) -> Result<FlattenOutcome> {
+    let mut outcome = FlattenOutcome::default();
+    let flatten_options = FlattenOptions::builder()
+        .collision_policy(options.collisions)
+        .exclude(options.exclude)
+        .build();
+
+    for candidate in analyzer::detect_nesting(path)? {
+        if candidate.is_empty_nesting() {
+            outcome.removed_dirs.extend(if options.dry_run {
+                candidate.redundant_dirs()
+            } else {
+                mover::remove_empty_nesting(&candidate)?
+            });
+            continue;
+        }
+
+        let plan = mover::plan(&candidate, &flatten_options)?;
+        if options.dry_run {
+            let result = mover::execute_journaled(
+                &plan,
+                true,
+                &mut Journal::new(),
+                &mut NoopObserver,
+                None,
+            )?;
+            outcome.moves.extend(result.moved);
+            outcome.warnings.extend(result.warnings);
+            continue;
+        }
+
+        let journal_path = Journal::default_path(&candidate.parent);
+        let mut journal = Journal::persistent_at(&journal_path)?;
+        let result = mover::execute_journaled(&plan, false, &mut journal, &mut NoopObserver, None)?;
+        outcome.removed_dirs.extend(plan.dirs_to_remove().cloned());
+        outcome.warnings.extend(result.warnings);
+        if result.moved.is_empty() {
+            continue;
+        }
+        journal.save_to(&journal_path)?;
+        outcome.moves.extend(result.moved);
+        outcome.journal = Some(journal_path);
+    }
+
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::{RollbackConflict, RollbackScope};
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn project(tmp: &TempDir) -> PathBuf {
+        let root = tmp.path().join("project");
+        fs::create_dir_all(root.join("project/src")).unwrap();
+        fs::write(root.join("project/README.md"), "nested").unwrap();
+        fs::write(root.join("project/debug.log"), "").unwrap();
+        root
+    }
+
+    #[test]
+    fn flattens_and_journals_in_one_call() {
+        let tmp = TempDir::new().unwrap();
+        let root = project(&tmp);
+        let options = Options {
+            exclude: vec![glob::Pattern::new("*.log").unwrap()],
+            ..Default::default()
+        };
+
+        let outcome = flatten_directory(&root, options).unwrap();
+        assert_eq!(outcome.moves.len(), 2);
+        assert!(root.join("README.md").exists());
+        assert!(root.join("project/debug.log").exists());
+        assert!(
+            matches!(&outcome.warnings[..], [Warning::DirectoryKept(dir)] if *dir == root.join("project")),
+            "{:?}",
+            outcome.warnings
+        );
+
+        // The journal it reports undoes the flatten.
+        let mut journal = Journal::load(&outcome.journal.unwrap()).unwrap();
+        journal
+            .rollback(RollbackScope::Latest, RollbackConflict::Abort, None, false)
+            .unwrap();
+        assert!(root.join("project/README.md").exists());
+    }
+
+    #[test]
+    fn dry_run_and_nothing_to_do_touch_nothing() {
+        let tmp = TempDir::new().unwrap();
+        let root = project(&tmp);
+        fs::write(root.join("README.md"), "parent").unwrap();
+
+        let options = Options {
+            dry_run: true,
+            collisions: CollisionPolicy::Rename,
+            ..Default::default()
+        };
+        let outcome = flatten_directory(&root, options).unwrap();
+        assert_eq!(outcome.moves.len(), 3);
+        assert!(
+            outcome
+                .moves
+                .iter()
+                .any(|m| m.to.ends_with("README (1).md"))
+        );
+        assert_eq!(outcome.journal, None);
+        assert!(root.join("project/README.md").exists());
+
+        let flat = tmp.path().join("flat");
+        fs::create_dir(&flat).unwrap();
+        let outcome = flatten_directory(&flat, Options::default()).unwrap();
+        assert!(outcome.moves.is_empty());
+    }
+
+    #[test]
+    fn no_journal_when_nothing_moves() {
+        let tmp = TempDir::new().unwrap();
+        let root = project(&tmp);
+        let options = Options {
+            exclude: vec![glob::Pattern::new("*").unwrap()],
+            ..Default::default()
+        };
+
+        let outcome = flatten_directory(&root, options).unwrap();
+        assert!(outcome.moves.is_empty());
+        assert_eq!(outcome.journal, None);
+        assert!(!Journal::default_path(&root).exists());
+    }
+}
@@ -0,0 +1,261 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::analyzer::NestingCandidate;
+use crate::scanner::{self, Collision, EntryKind, SpaceEstimate, SymlinkRisk};
+use crate::{Error, Result};
+
+/// Serialization format for a [`Report`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    #[default]
+    Json,
+    Yaml,
+    Toml,
+}
+
+/// Version of the [`Report`] layout, bumped whenever a field changes
+/// shape. Version 2 turned `children` from bare paths into [`ReportChild`]
+/// records.
+pub const REPORT_VERSION: u32 = 2;
+
+/// Machine-readable summary of the nesting found under a path.
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    /// [`REPORT_VERSION`] at the time of writing.
+    pub version: u32,
+    pub path: PathBuf,
+    pub candidates: Vec<CandidateReport>,
+}
+
+/// One detected candidate with the counts from its pre-move scan.
+#[derive(Debug, Clone, Serialize)]
+pub struct CandidateReport {
+    /// [`NestingCandidate::id`], for remembering decisions between runs.
+    pub id: String,
+    pub nested: PathBuf,
+    /// Redundant levels collapsed, see [`NestingCandidate::depth`].
+    pub depth: usize,
+    pub children: Vec<ReportChild>,
+    pub collisions: usize,
+    pub symlink_risks: usize,
+    pub space: SpaceEstimate,
+    pub space_warning: bool,
+    pub special_files: usize,
+    pub permission_issues: Vec<PathBuf>,
+    pub details: CandidateDetails,
+}
+
+/// The full data behind a [`CandidateReport`]'s counts, kept in its own
+/// table so consumers of the counts are unaffected.
+#[derive(Debug, Clone, Serialize)]
+pub struct CandidateDetails {
+    /// Where each child goes, as [`NestingCandidate::destination`] (and so
+    /// `flatten`) computes it, before any collision policy applies.
+    pub moves: Vec<PlannedMove>,
+    pub collisions: Vec<Collision>,
+    pub symlink_risks: Vec<SymlinkRisk>,
+}
+
+/// A child of the nested directory and what kind of entry it is.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReportChild {
+    pub path: PathBuf,
+    /// `None` when the entry could not be read.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<EntryKind>,
+}
+
+/// A child and the path it moves to.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedMove {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+impl CandidateReport {
+    /// Scan `c` and summarize what it found.
+    pub fn new(c: &NestingCandidate) -> Self {
+        let scan = scanner::scan(c);
+        let moves = c
+            .children
+            .iter()
+            .filter_map(|child| {
+                Some(PlannedMove {
+                    from: child.clone(),
+                    to: c.destination(child)?,
+                })
+            })
+            .collect();
+        CandidateReport {
+            id: c.id(),
+            nested: c.nested.clone(),
+            depth: c.depth(),
+            children: c
+                .children
+                .iter()
+                .map(|path| ReportChild {
+                    path: path.clone(),
+                    kind: EntryKind::of(path).ok(),
+                })
+                .collect(),
+            collisions: scan.collisions.len(),
+            symlink_risks: scan.symlink_risks.len(),
+            space: scan.space,
+            space_warning: scan.space_warning.is_some(),
+            special_files: scan.special_files.len(),
+            permission_issues: scan.permission_issues,
+            details: CandidateDetails {
+                moves,
+                collisions: scan.collisions,
+                symlink_risks: scan.symlink_risks,
+            },
+        }
+    }
+}
+
+/// Scan each candidate and collect the results into a [`Report`].
+pub fn build(path: &Path, candidates: &[NestingCandidate]) -> Report {
+    Report {
+        version: REPORT_VERSION,
+        path: path.to_path_buf(),
+        candidates: candidates.iter().map(CandidateReport::new).collect(),
+    }
+}
+
+/// Serialize `report` in the requested format.
+pub fn render(report: &Report, format: Format) -> Result<String> {
+    serialize(report, format)
+}
+
+/// Serialize several reports as one document, a top-level `reports` list,
+/// however many there are, so its shape does not depend on the path count.
+pub fn render_all(reports: &[Report], format: Format) -> Result<String> {
+    #[derive(Serialize)]
+    struct Reports<'a> {
+        reports: &'a [Report],
+    }
+
+    serialize(&Reports { reports }, format)
+}
+
+/// Serialize any value in `format`, the way reports are.
+pub fn serialize(value: &impl Serialize, format: Format) -> Result<String> {
+    match format {
+        Format::Json => {
+            serde_json::to_string_pretty(value).map_err(|e| Error::Other(e.to_string()))
+        }
+        Format::Yaml => serde_yaml::to_string(value).map_err(|e| Error::Other(e.to_string())),
+        Format::Toml => toml::to_string_pretty(value).map_err(|e| Error::Other(e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn report() -> (TempDir, Report) {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().join("project");
+        fs::create_dir_all(root.join("project")).unwrap();
+        fs::write(root.join("project").join("file.txt"), "data").unwrap();
+        fs::write(root.join("file.txt"), "other").unwrap();
+
+        let candidates = analyzer::detect_nesting(&root).unwrap();
+        let report = build(&root, &candidates);
+        (tmp, report)
+    }
+
+    #[test]
+    fn build_counts_scan_results() {
+        let (_tmp, report) = report();
+        assert_eq!(report.candidates.len(), 1);
+        assert_eq!(report.candidates[0].collisions, 1);
+        assert_eq!(report.candidates[0].children.len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn children_carry_their_entry_kind() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().join("project");
+        let nested = root.join("project");
+        fs::create_dir_all(nested.join("src")).unwrap();
+        fs::write(nested.join("file.txt"), "data").unwrap();
+        std::os::unix::fs::symlink("src", nested.join("link")).unwrap();
+
+        let candidates = analyzer::detect_nesting(&root).unwrap();
+        let report = build(&root, &candidates);
+        let json: serde_json::Value =
+            serde_json::from_str(&render(&report, Format::Json).unwrap()).unwrap();
+        assert_eq!(json["version"], REPORT_VERSION);
+        let kinds: Vec<_> = json["candidates"][0]["children"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| {
+                let path = Path::new(c["path"].as_str().unwrap());
+                let name = path.file_name().unwrap().to_string_lossy();
+                format!("{name}={}", c["kind"].as_str().unwrap())
+            })
+            .collect();
+        assert_eq!(kinds, ["file.txt=file", "link=symlink", "src=dir"]);
+    }
+
+    #[test]
+    fn details_list_moves_and_collisions() {
+        let (_tmp, report) = report();
+        let candidate = &report.candidates[0];
+        let root = candidate.nested.parent().unwrap();
+        let moves = &candidate.details.moves;
+        assert_eq!(moves.len(), 1);
+        assert_eq!(moves[0].from, candidate.nested.join("file.txt"));
+        assert_eq!(moves[0].to, root.join("file.txt"));
+
+        let json: serde_json::Value =
+            serde_json::from_str(&render(&report, Format::Json).unwrap()).unwrap();
+        assert_eq!(json["candidates"][0]["id"], candidate.id.as_str());
+        assert_eq!(json["candidates"][0]["depth"], 1);
+        let details = &json["candidates"][0]["details"];
+        assert_eq!(details["collisions"][0]["kind"], "differing");
+        assert_eq!(
+            details["moves"][0]["to"],
+            root.join("file.txt").to_str().unwrap()
+        );
+        assert!(render(&report, Format::Toml).is_ok());
+    }
+
+    #[test]
+    fn render_supports_every_format() {
+        let (_tmp, report) = report();
+
+        let json: serde_json::Value =
+            serde_json::from_str(&render(&report, Format::Json).unwrap()).unwrap();
+        assert_eq!(json["candidates"][0]["collisions"], 1);
+
+        let yaml = render(&report, Format::Yaml).unwrap();
+        assert!(yaml.contains("collisions: 1"));
+
+        let toml = render(&report, Format::Toml).unwrap();
+        assert!(toml.contains("[[candidates]]"));
+        assert!(toml.contains("collisions = 1"));
+    }
+
+    #[test]
+    fn render_all_always_wraps_reports_in_a_list() {
+        let (_tmp, report) = report();
+
+        let one = render_all(std::slice::from_ref(&report), Format::Json).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&one).unwrap();
+        assert_eq!(value["reports"].as_array().map(Vec::len), Some(1));
+        assert_eq!(value["reports"][0]["version"], REPORT_VERSION);
+
+        let two = render_all(&[report.clone(), report], Format::Toml).unwrap();
+        assert!(two.contains("[[reports]]"));
+    }
+}
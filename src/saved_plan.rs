@@ -0,0 +1,276 @@
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use crate::analyzer::NestingCandidate;
+use crate::journal;
+use crate::mover::FlattenPlan;
+use crate::scanner;
+use crate::{Error, Result};
+
+/// Bumped when the file layout changes incompatibly.
+const PLAN_VERSION: u32 = 1;
+
+/// A [`FlattenPlan`] written out for review (`apply --dry-run --plan-out`)
+/// and carried out later exactly as recorded (`apply --plan`).
+///
+/// Alongside the plan it keeps a snapshot of every path the plan reads or
+/// writes, so [`check`](Self::check) can refuse to run it once the
+/// filesystem has moved on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedPlan {
+    /// The directory that was flattened (the candidate's parent). Journals
+    /// are saved beside it, as for a fresh apply.
+    pub parent: PathBuf,
+    /// The redundant nested directory.
+    pub nested: PathBuf,
+    pub plan: FlattenPlan,
+    /// The paths the plan touches, as they were when it was made.
+    pub snapshot: Vec<PathState>,
+}
+
+/// What was at a path when a plan was made.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathState {
+    pub path: PathBuf,
+    /// `None` when nothing was there.
+    pub stamp: Option<Stamp>,
+}
+
+/// Enough of an entry's metadata to notice it being replaced or edited.
+/// Symlinks are stamped themselves, not their targets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stamp {
+    pub is_dir: bool,
+    pub len: u64,
+    /// For a directory this changes when entries are added or removed
+    /// directly inside it.
+    pub modified: Option<SystemTime>,
+}
+
+/// The on-disk layout: a version, then one plan per flattened candidate.
+#[derive(Serialize, Deserialize)]
+struct PlanFile {
+    version: u32,
+    plans: Vec<SavedPlan>,
+}
+
+impl PathState {
+    fn of(path: &Path) -> Result<Self> {
+        let stamp = match fs::symlink_metadata(path) {
+            Ok(meta) => Some(Stamp {
+                is_dir: meta.is_dir(),
+                len: meta.len(),
+                modified: meta.modified().ok(),
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(Error::io(path.to_path_buf(), e)),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            stamp,
+        })
+    }
+}
+
+impl SavedPlan {
+    /// Record `plan`, made for `candidate`, with a snapshot of the paths it
+    /// touches as they are now.
+    pub fn new(candidate: &NestingCandidate, plan: FlattenPlan) -> Result<Self> {
+        let snapshot = touched_paths(&plan)
+            .into_iter()
+            .map(PathState::of)
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            parent: candidate.parent.clone(),
+            nested: candidate.nested.clone(),
+            plan,
+            snapshot,
+        })
+    }
+
+    /// Fail with [`Error::PlanStale`] naming the first path that no longer
+    /// looks as it did when the plan was made: a source edited, replaced or
+    /// gone, or something now occupying a destination that was free (a new
+    /// collision). The destination directory itself is only required to
+    /// exist or not as before, since unrelated files may come and go there.
+    ///
+    /// The moves are then scanned again, with the plan's scan limits, so a
+    /// symlink that appeared deeper inside a moved directory is caught too.
+    pub fn check(&self) -> Result<()> {
+        for recorded in &self.snapshot {
+            let current = PathState::of(&recorded.path)?;
+            let changed = if recorded.path == self.plan.dest {
+                current.stamp.map(|s| s.is_dir) != recorded.stamp.map(|s| s.is_dir)
+            } else {
+                current != *recorded
+            };
+            if changed {
+                return Err(Error::PlanStale {
+                    path: recorded.path.clone(),
+                });
+            }
+        }
+
+        let report =
+            scanner::scan_moves_with_options(&self.plan.moves, &self.nested, &self.plan.scan);
+        let was_occupied = |path: &Path| {
+            self.snapshot
+                .iter()
+                .any(|s| s.path == path && s.stamp.is_some())
+        };
+        let known_links: HashSet<&Path> = self
+            .plan
+            .report
+            .symlink_risks
+            .iter()
+            .map(|r| r.link.as_path())
+            .collect();
+        let new_collision = report
+            .collisions
+            .into_iter()
+            .map(|c| c.existing)
+            .find(|existing| !was_occupied(existing));
+        let new_risk = report
+            .symlink_risks
+            .into_iter()
+            .map(|r| r.link)
+            .find(|link| !known_links.contains(link.as_path()));
+        match new_collision.or(new_risk) {
+            Some(path) => Err(Error::PlanStale { path }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Every path `plan` moves from or to, or deletes.
+fn touched_paths(plan: &FlattenPlan) -> BTreeSet<&Path> {
+    let mut paths = BTreeSet::from([plan.dest.as_path()]);
+    for record in &plan.moves {
+        paths.insert(&record.from);
+        paths.insert(&record.to);
+    }
+    paths.extend(plan.merged_dirs.iter().map(PathBuf::as_path));
+    paths.extend(plan.redundant_dirs.iter().map(PathBuf::as_path));
+    paths.extend(plan.noise.iter().map(PathBuf::as_path));
+    paths.extend(plan.nested_link.as_deref());
+    paths
+}
+
+/// Write `plans` to `path` as JSON, atomically.
+pub fn save(plans: &[SavedPlan], path: &Path) -> Result<()> {
+    let file = PlanFile {
+        version: PLAN_VERSION,
+        plans: plans.to_vec(),
+    };
+    let json = serde_json::to_string_pretty(&file).map_err(|e| Error::Other(e.to_string()))?;
+    journal::write_atomic(path, json.as_bytes()).map_err(|e| Error::io(path.to_path_buf(), e))
+}
+
+/// Read plans written by [`save`]. They are not [checked](SavedPlan::check).
+pub fn load(path: &Path) -> Result<Vec<SavedPlan>> {
+    let data = fs::read_to_string(path).map_err(|e| Error::io(path.to_path_buf(), e))?;
+    let invalid = |reason: String| Error::Other(format!("plan {}: {reason}", path.display()));
+    let file: PlanFile = serde_json::from_str(&data).map_err(|e| invalid(e.to_string()))?;
+    if file.version > PLAN_VERSION {
+        return Err(invalid(format!(
+            "version {} is newer than supported version {PLAN_VERSION}",
+            file.version
+        )));
+    }
+    Ok(file.plans)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer;
+    use crate::mover::{self, FlattenOptions};
+    use tempfile::TempDir;
+
+    fn saved(tmp: &TempDir) -> (PathBuf, SavedPlan) {
+        let root = tmp.path().join("app");
+        fs::create_dir_all(root.join("app/src/deep")).unwrap();
+        fs::write(root.join("app/main.rs"), "fn main() {}").unwrap();
+        let candidate = analyzer::detect_nesting(&root).unwrap().remove(0);
+        let plan = mover::plan(&candidate, &FlattenOptions::default()).unwrap();
+        (
+            candidate.parent.clone(),
+            SavedPlan::new(&candidate, plan).unwrap(),
+        )
+    }
+
+    #[test]
+    fn round_trips_and_runs_as_recorded() {
+        let tmp = TempDir::new().unwrap();
+        let (parent, saved) = saved(&tmp);
+        let file = tmp.path().join("plan.json");
+        save(&[saved], &file).unwrap();
+
+        let loaded = load(&file).unwrap().remove(0);
+        assert_eq!(loaded.parent, parent);
+        assert_eq!(loaded.plan.moves.len(), 2);
+        loaded.check().unwrap();
+        mover::execute(&loaded.plan, false, None).unwrap();
+        assert!(parent.join("main.rs").exists());
+        assert!(!parent.join("app").exists());
+    }
+
+    #[test]
+    fn plans_saved_without_scan_limits_still_load() {
+        let tmp = TempDir::new().unwrap();
+        let (_, saved) = saved(&tmp);
+        let file = tmp.path().join("plan.json");
+        save(&[saved], &file).unwrap();
+        let mut json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&file).unwrap()).unwrap();
+        json["plans"][0]["plan"]
+            .as_object_mut()
+            .unwrap()
+            .remove("scan")
+            .unwrap();
+        fs::write(&file, json.to_string()).unwrap();
+
+        let loaded = load(&file).unwrap().remove(0);
+        assert_eq!(loaded.plan.scan.max_depth, None);
+        loaded.check().unwrap();
+    }
+
+    #[test]
+    fn changes_since_planning_make_it_stale() {
+        let tmp = TempDir::new().unwrap();
+        let (parent, saved) = saved(&tmp);
+
+        // Unrelated files in the destination don't matter.
+        fs::write(parent.join("notes.txt"), "").unwrap();
+        saved.check().unwrap();
+
+        // A new collision does.
+        fs::write(parent.join("main.rs"), "").unwrap();
+        let err = saved.check().unwrap_err();
+        assert!(matches!(err, Error::PlanStale { path } if path == parent.join("main.rs")));
+        fs::remove_file(parent.join("main.rs")).unwrap();
+
+        // So does an edited source.
+        fs::write(parent.join("app/main.rs"), "fn main() { edited() }").unwrap();
+        let err = saved.check().unwrap_err();
+        assert!(matches!(err, Error::PlanStale { path } if path == parent.join("app/main.rs")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rescan_catches_new_symlink_risks_deep_in_a_moved_directory() {
+        let tmp = TempDir::new().unwrap();
+        let (parent, saved) = saved(&tmp);
+        // `app/src` itself keeps its stamp; only the rescan sees the link.
+        let link = parent.join("app/src/deep/lib");
+        std::os::unix::fs::symlink("../../../../shared", &link).unwrap();
+
+        let err = saved.check().unwrap_err();
+        assert!(matches!(err, Error::PlanStale { path } if path == link));
+    }
+}
@@ -1,102 +1,829 @@
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fmt;
 use std::fs;
-use std::path::PathBuf;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use sha2::{Digest, Sha256};
 
 use walkdir::WalkDir;
 
 use crate::analyzer::NestingCandidate;
+use crate::mover::MoveRecord;
 
 /// Potential risks discovered by scanning a nesting candidate before moving.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct ScanReport {
     /// Files at the destination that would collide with moved items.
     pub collisions: Vec<Collision>,
     /// Symlinks that might break after flattening.
     pub symlink_risks: Vec<SymlinkRisk>,
+    /// Bytes to relocate versus free space at the destination.
+    pub space: SpaceEstimate,
+    /// Set when a copy-based move would not fit at the destination.
+    pub space_warning: Option<SpaceWarning>,
+    /// Entries that are not regular files, directories or symlinks. Always
+    /// empty on non-unix platforms.
+    pub special_files: Vec<SpecialFile>,
+    /// Paths in the nested tree that are hard links to the same inode.
+    /// Always empty on non-unix platforms.
+    pub hard_links: Vec<HardLinkGroup>,
+    /// The parent, if it is not writable, and any child that is not both
+    /// readable and writable. Always empty on non-unix platforms.
+    pub permission_issues: Vec<PathBuf>,
+    /// Destinations named after one of [`ScanOptions::reserved_names`].
+    #[serde(default)]
+    pub reserved_names: Vec<PathBuf>,
+    /// Paths the symlink walk could not read, and why. Links beneath them
+    /// were not checked. Serialized as `{ "path", "kind" }` objects, the
+    /// kind by its [`io::ErrorKind`] name.
+    #[serde(default, with = "scan_errors")]
+    pub scan_errors: Vec<(PathBuf, io::ErrorKind)>,
+    /// The nested directory, when it is a mount point: a filesystem of its
+    /// own rather than a directory of its parent's. See
+    /// [`detect_mount_boundary`].
+    #[serde(default)]
+    pub mount_boundary: Option<PathBuf>,
+}
+
+/// Two or more paths in the nested tree sharing one inode.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct HardLinkGroup {
+    /// The linked paths, sorted.
+    pub paths: Vec<PathBuf>,
+    /// Total link count of the inode, including links outside the tree.
+    pub nlink: u64,
+}
+
+/// A fifo, socket or device node inside the nested tree. These rename fine
+/// but cannot be copied, so they block cross-device moves.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SpecialFile {
+    pub path: PathBuf,
+    pub kind: SpecialKind,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum SpecialKind {
+    Fifo,
+    Socket,
+    BlockDevice,
+    CharDevice,
+    /// Anything else the platform reports.
+    Unknown,
+}
+
+/// Size of the items to be moved and the space available to receive them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SpaceEstimate {
+    /// Total size of all files beneath the items to be moved.
+    pub required_bytes: u64,
+    /// Free space on the destination filesystem, if it could be queried.
+    pub available_bytes: Option<u64>,
+    /// Whether source and destination share a filesystem. Same-device moves
+    /// are renames and need no extra space.
+    pub same_device: bool,
+}
+
+/// Not enough free space for a cross-device (copy) move.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SpaceWarning {
+    pub required_bytes: u64,
+    pub available_bytes: u64,
+}
+
+impl SpaceEstimate {
+    /// A warning when the move needs copying and the data will not fit.
+    /// Same-device renames never warn; unknown free space never warns.
+    pub fn shortfall(&self) -> Option<SpaceWarning> {
+        let available = self.available_bytes?;
+        (!self.same_device && self.required_bytes > available).then_some(SpaceWarning {
+            required_bytes: self.required_bytes,
+            available_bytes: available,
+        })
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Collision {
     /// The source path inside the nested directory.
     pub source: PathBuf,
     /// The conflicting path that already exists in the parent.
     pub existing: PathBuf,
+    /// Whether the two sides hold the same bytes.
+    pub kind: CollisionKind,
+    /// Whether each side is a directory.
+    pub types: CollisionTypes,
+}
+
+/// The entry types on each side of a collision, nested side first.
+/// Anything that is not a directory (symlinks included) counts as a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CollisionTypes {
+    FileVsFile,
+    /// A nested file would land on an existing directory.
+    FileVsDir,
+    /// A nested directory would land on an existing file.
+    DirVsFile,
+    DirVsDir,
+}
+
+impl CollisionTypes {
+    /// Classify `source` (in the nested directory) against `existing`,
+    /// without following symlinks.
+    pub fn of(source: &Path, existing: &Path) -> Self {
+        let is_dir = |p: &Path| fs::symlink_metadata(p).is_ok_and(|m| m.is_dir());
+        match (is_dir(source), is_dir(existing)) {
+            (false, false) => CollisionTypes::FileVsFile,
+            (false, true) => CollisionTypes::FileVsDir,
+            (true, false) => CollisionTypes::DirVsFile,
+            (true, true) => CollisionTypes::DirVsDir,
+        }
+    }
+
+    /// Whether one side is a directory and the other is not. Neither
+    /// merging nor replacing has a sensible meaning for these.
+    pub fn is_mismatch(self) -> bool {
+        matches!(self, CollisionTypes::FileVsDir | CollisionTypes::DirVsFile)
+    }
+}
+
+/// What a directory entry is, read without following symlinks. Anything
+/// that is neither a directory nor a symlink (special files included)
+/// counts as a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+}
+
+impl EntryKind {
+    /// Classify `path` from its `symlink_metadata`.
+    pub fn of(path: &Path) -> io::Result<Self> {
+        let file_type = fs::symlink_metadata(path)?.file_type();
+        Ok(if file_type.is_symlink() {
+            EntryKind::Symlink
+        } else if file_type.is_dir() {
+            EntryKind::Dir
+        } else {
+            EntryKind::File
+        })
+    }
+}
+
+/// How the two sides of a collision compare.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CollisionKind {
+    /// Both names are hard links to the same inode. Dropping the nested name
+    /// loses nothing, so this collision is safe.
+    SameInode,
+    /// Both are regular files with byte-identical content, so moving the
+    /// nested copy would be a no-op.
+    IdenticalContent,
+    /// Both are directories and at least one is empty, so there is nothing
+    /// to merge (see [`FlattenOptions::merge_empty`](crate::mover::FlattenOptions::merge_empty)).
+    EmptyDirectory,
+    /// Anything else: different content, or not two regular files.
+    Differing,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct SymlinkRisk {
     /// The symlink path.
     pub link: PathBuf,
-    /// Where it currently points.
+    /// Where it currently points, as read from the link.
     pub target: PathBuf,
+    /// The file the link leads to: `target` joined to the link's directory
+    /// if relative, then canonicalized (following any further links).
+    /// `None` when the link dangles or the target cannot be resolved.
+    #[serde(default)]
+    pub resolved_target: Option<PathBuf>,
     /// Whether the target lives inside the nested directory being moved.
+    /// Relative targets are resolved against the link's directory.
     pub target_inside_nested: bool,
+    /// Whether the link will point somewhere else once flattened: an
+    /// absolute target that moves, or a relative target that no longer
+    /// leads to the same place from the link's new location.
+    pub breaks_after_move: bool,
+    /// Whether following the link loops: it leads (possibly through other
+    /// links) back to itself or to one of its own ancestors.
+    pub is_cyclic: bool,
+}
+
+impl fmt::Display for SymlinkRisk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} -> {} (target inside nested: {}, breaks after move: {})",
+            self.link.display(),
+            self.target.display(),
+            self.target_inside_nested,
+            self.breaks_after_move,
+        )
+    }
+}
+
+/// How many links [`is_cyclic`] follows before giving up and calling the
+/// chain a cycle, as the kernel's own loop limit does.
+const MAX_LINK_HOPS: usize = 40;
+
+/// Version-control metadata directories that must never collide.
+pub const VCS_DIRS: &[&str] = &[".git", ".svn", ".hg"];
+
+/// Limits on the symlink walk beneath the nested directory, and extra
+/// checks to make. The default walks the whole tree, crossing into other
+/// filesystems.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct ScanOptions {
+    /// Descend at most this many levels below the nested directory
+    /// (1 looks at its immediate children only).
+    pub max_depth: Option<usize>,
+    /// Don't descend into directories on a different filesystem than the
+    /// nested directory, such as a mounted network share.
+    pub same_file_system: bool,
+    /// Names that must never appear in the destination. A move that would
+    /// introduce one is listed in [`ScanReport::reserved_names`]; it is a
+    /// warning, not a collision.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reserved_names: Vec<OsString>,
 }
 
 /// Scan a nesting candidate for potential risks before applying a move.
 pub fn scan(candidate: &NestingCandidate) -> ScanReport {
-    let collisions = detect_collisions(candidate);
-    let symlink_risks = detect_symlink_risks(candidate);
+    scan_with_options(candidate, &ScanOptions::default())
+}
+
+/// Like [`scan`], with the symlink walk limited by `options`.
+pub fn scan_with_options(candidate: &NestingCandidate, options: &ScanOptions) -> ScanReport {
+    let moves = candidate_moves(candidate);
+    // Anything else in the nested tree (beneath a child, or a child left
+    // out of the list) moves up into the parent with it.
+    let mut relocation = moves.clone();
+    relocation.push(MoveRecord::new(
+        candidate.nested.clone(),
+        candidate.parent.clone(),
+    ));
+    scan_planned(&moves, &relocation, &candidate.nested, options)
+}
+
+/// Scan an arbitrary set of planned moves, as [`scan`] does a candidate's,
+/// for callers with their own detector. `nested_root` is the tree the moves
+/// come out of: it is walked for symlinks, special files and hard links.
+/// Collisions are moves whose destination exists; free space and
+/// permissions are checked where the first move lands.
+pub fn scan_moves(moves: &[MoveRecord], nested_root: &Path) -> ScanReport {
+    scan_moves_with_options(moves, nested_root, &ScanOptions::default())
+}
+
+/// Like [`scan_moves`], with the symlink walk limited by `options`.
+pub fn scan_moves_with_options(
+    moves: &[MoveRecord],
+    nested_root: &Path,
+    options: &ScanOptions,
+) -> ScanReport {
+    scan_planned(moves, moves, nested_root, options)
+}
+
+/// Scan `moves`, judging symlinks by where `relocation` (the moves, or
+/// more) takes them and their targets.
+fn scan_planned(
+    moves: &[MoveRecord],
+    relocation: &[MoveRecord],
+    nested_root: &Path,
+    options: &ScanOptions,
+) -> ScanReport {
+    let collisions = detect_collisions(moves);
+    let (symlink_risks, scan_errors) = detect_symlink_risks(relocation, nested_root, options);
+    let space = estimate_moves_space(moves, nested_root);
+    let special_files = detect_special_files(nested_root);
+    let hard_links = detect_hard_links(nested_root);
+    let permission_issues = detect_permission_issues(moves);
+    let reserved_names = detect_reserved_names(moves, &options.reserved_names);
+    let mount_boundary = detect_mount_boundary(nested_root);
 
     ScanReport {
         collisions,
         symlink_risks,
+        space_warning: space.shortfall(),
+        space,
+        special_files,
+        hard_links,
+        permission_issues,
+        reserved_names,
+        scan_errors,
+        mount_boundary,
     }
 }
 
-/// Check whether any child in the nested dir would collide with an
-/// existing entry in the parent directory.
-fn detect_collisions(candidate: &NestingCandidate) -> Vec<Collision> {
-    let mut collisions = Vec::new();
+/// The moves flattening `candidate` makes, before any collision policy:
+/// each child to its [destination](NestingCandidate::destination).
+fn candidate_moves(candidate: &NestingCandidate) -> Vec<MoveRecord> {
+    candidate
+        .children
+        .iter()
+        .filter_map(|child| {
+            Some(MoveRecord::new(
+                child.clone(),
+                candidate.destination(child)?,
+            ))
+        })
+        .collect()
+}
 
-    for child in &candidate.children {
-        if let Some(name) = child.file_name() {
-            let dest = candidate.parent.join(name);
-            // The nested directory itself shares the name — skip that.
-            if dest == candidate.nested {
-                continue;
-            }
-            if dest.exists() {
-                collisions.push(Collision {
-                    source: child.clone(),
-                    existing: dest,
-                });
-            }
+/// Total the bytes beneath a candidate's children and query free space on
+/// the parent's filesystem. Symlinks are counted by their own size, not
+/// followed.
+pub fn estimate_space(candidate: &NestingCandidate) -> SpaceEstimate {
+    estimate_moves_space(&candidate_moves(candidate), &candidate.nested)
+}
+
+/// Total the bytes beneath the sources of `moves` and query free space where
+/// the first one lands (or, with no moves, beside `nested_root`).
+fn estimate_moves_space(moves: &[MoveRecord], nested_root: &Path) -> SpaceEstimate {
+    let dest = moves
+        .first()
+        .and_then(|m| m.to.parent())
+        .or(nested_root.parent())
+        .unwrap_or(nested_root);
+    let required_bytes = moves.iter().map(|m| bytes_under(&m.from)).sum();
+
+    SpaceEstimate {
+        required_bytes,
+        available_bytes: fs2::available_space(dest).ok(),
+        same_device: same_device(nested_root, dest),
+    }
+}
+
+/// Total size of the files at or beneath `path`, without following
+/// symlinks. Unreadable entries count as empty.
+pub fn bytes_under(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .follow_links(false)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.metadata().ok())
+        .filter(|m| !m.is_dir())
+        .map(|m| m.len())
+        .sum()
+}
+
+/// `dir` if it is on a different device than its parent directory: a mount
+/// point or bind mount. Its contents can only be copied out, and the
+/// directory itself cannot be removed while mounted. Always `None` where the
+/// platform cannot tell.
+pub fn detect_mount_boundary(dir: &Path) -> Option<PathBuf> {
+    let parent = dir.parent()?;
+    (!same_device(dir, parent)).then(|| dir.to_path_buf())
+}
+
+/// Whether two paths live on the same filesystem. Assumed true where the
+/// platform cannot tell.
+fn same_device(a: &Path, b: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        match (fs::metadata(a), fs::metadata(b)) {
+            (Ok(a), Ok(b)) => a.dev() == b.dev(),
+            _ => true,
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (a, b);
+        true
+    }
+}
+
+/// Destinations of `moves` whose file name is one of `reserved`.
+fn detect_reserved_names(moves: &[MoveRecord], reserved: &[OsString]) -> Vec<PathBuf> {
+    moves
+        .iter()
+        .filter(|m| {
+            m.to.file_name()
+                .is_some_and(|name| reserved.iter().any(|r| r == name))
+        })
+        .map(|m| m.to.clone())
+        .collect()
+}
+
+/// Check whether any move would land on an existing entry.
+fn detect_collisions(moves: &[MoveRecord]) -> Vec<Collision> {
+    moves
+        .iter()
+        .filter(|m| m.to.exists())
+        .map(|m| Collision {
+            kind: classify_collision(&m.from, &m.to),
+            types: CollisionTypes::of(&m.from, &m.to),
+            source: m.from.clone(),
+            existing: m.to.clone(),
+        })
+        .collect()
+}
+
+/// Compare the two sides of a collision. Content is only hashed when both
+/// are distinct regular files of the same size.
+pub fn classify_collision(source: &Path, existing: &Path) -> CollisionKind {
+    if same_inode(source, existing) {
+        return CollisionKind::SameInode;
+    }
+    if CollisionTypes::of(source, existing) == CollisionTypes::DirVsDir
+        && (is_empty_dir(source) || is_empty_dir(existing))
+    {
+        return CollisionKind::EmptyDirectory;
+    }
+    match same_content(source, existing) {
+        Ok(true) => CollisionKind::IdenticalContent,
+        _ => CollisionKind::Differing,
+    }
+}
+
+/// Whether two paths are hard links to the same inode (without following
+/// symlinks). Always `false` on non-unix platforms.
+pub fn same_inode(a: &Path, b: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        match (fs::symlink_metadata(a), fs::symlink_metadata(b)) {
+            (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+            _ => false,
         }
     }
+    #[cfg(not(unix))]
+    {
+        let _ = (a, b);
+        false
+    }
+}
 
-    collisions
+/// Whether `path` is a directory with no entries. Unreadable ones are not.
+pub fn is_empty_dir(path: &Path) -> bool {
+    fs::read_dir(path).is_ok_and(|mut entries| entries.next().is_none())
+}
+
+fn same_content(a: &Path, b: &Path) -> io::Result<bool> {
+    let (meta_a, meta_b) = (fs::symlink_metadata(a)?, fs::symlink_metadata(b)?);
+    if !meta_a.is_file() || !meta_b.is_file() || meta_a.len() != meta_b.len() {
+        return Ok(false);
+    }
+    Ok(file_digest(a)? == file_digest(b)?)
+}
+
+/// SHA-256 of a file, streamed so large files are never fully buffered.
+fn file_digest(path: &Path) -> io::Result<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().to_vec())
 }
 
 /// Walk the nested directory looking for symlinks that reference paths
-/// inside the nested tree (which will change after a move).
-fn detect_symlink_risks(candidate: &NestingCandidate) -> Vec<SymlinkRisk> {
+/// inside the nested tree (which will change after a move). Links and
+/// targets are relocated by `moves`.
+fn detect_symlink_risks(
+    moves: &[MoveRecord],
+    nested_root: &Path,
+    options: &ScanOptions,
+) -> (Vec<SymlinkRisk>, Vec<(PathBuf, io::ErrorKind)>) {
     let mut risks = Vec::new();
+    let mut errors = Vec::new();
 
-    for entry in WalkDir::new(&candidate.nested)
+    let mut walk = WalkDir::new(nested_root)
         .follow_links(false)
-        .into_iter()
-        .filter_map(|e| e.ok())
-    {
+        .same_file_system(options.same_file_system);
+    if let Some(depth) = options.max_depth {
+        walk = walk.max_depth(depth);
+    }
+    for entry in walk {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                let path = e.path().unwrap_or(nested_root).to_path_buf();
+                let kind = e.io_error().map_or(io::ErrorKind::Other, |e| e.kind());
+                errors.push((path, kind));
+                continue;
+            }
+        };
         let path = entry.path();
-        if path.is_symlink()
+        if is_link(path)
             && let Ok(target) = fs::read_link(path)
         {
-            let target_inside = target.starts_with(&candidate.nested);
+            let resolved = resolve_link(path, &target);
+            let moved_link = relocate_by(moves, path);
             risks.push(SymlinkRisk {
                 link: path.to_path_buf(),
+                target_inside_nested: resolved.starts_with(nested_root),
+                breaks_after_move: resolve_link(&moved_link, &target)
+                    != relocate_by(moves, &resolved),
+                is_cyclic: is_cyclic(path),
+                resolved_target: resolved.canonicalize().ok(),
                 target,
-                target_inside_nested: target_inside,
             });
         }
     }
 
-    risks
+    (risks, errors)
+}
+
+/// Whether `path` is a link the walk must not treat as an ordinary entry:
+/// a symlink, or on Windows also a junction or other reparse point.
+fn is_link(path: &Path) -> bool {
+    #[cfg(windows)]
+    {
+        crate::windows::is_link(path)
+    }
+    #[cfg(not(windows))]
+    {
+        path.is_symlink()
+    }
+}
+
+/// Follow the chain of links starting at `link` (resolving each target
+/// lexically) and report whether it comes back to a link already seen or to
+/// an ancestor of `link`, which a walker following links would recurse into
+/// forever. Chains longer than [`MAX_LINK_HOPS`] count as cycles.
+fn is_cyclic(link: &Path) -> bool {
+    let mut visited = HashSet::new();
+    let mut current = link.to_path_buf();
+    for _ in 0..MAX_LINK_HOPS {
+        if !visited.insert(current.clone()) {
+            return true;
+        }
+        let Ok(target) = fs::read_link(&current) else {
+            return false;
+        };
+        let next = resolve_link(&current, &target);
+        if link.starts_with(&next) {
+            return true;
+        }
+        current = next;
+    }
+    true
+}
+
+/// Where a link at `link` pointing at `target` leads, without touching the
+/// filesystem.
+pub(crate) fn resolve_link(link: &Path, target: &Path) -> PathBuf {
+    let base = link.parent().unwrap_or(Path::new("/"));
+    normalize(&base.join(target))
+}
+
+/// Where `path` ends up once `moves` are made: a path at or beneath a
+/// move's source follows the first such move, everything else stays put.
+pub(crate) fn relocate_by(moves: &[MoveRecord], path: &Path) -> PathBuf {
+    moves
+        .iter()
+        .find_map(|m| match path.strip_prefix(&m.from) {
+            Ok(rest) if rest.as_os_str().is_empty() => Some(m.to.clone()),
+            Ok(rest) => Some(m.to.join(rest)),
+            Err(_) => None,
+        })
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+/// Lexically resolve `.` and `..` components.
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                out.pop();
+            }
+            other => out.push(other),
+        }
+    }
+    out
+}
+
+/// Walk the nested directory looking for entries that are neither regular
+/// files, directories nor symlinks.
+fn detect_special_files(nested_root: &Path) -> Vec<SpecialFile> {
+    WalkDir::new(nested_root)
+        .follow_links(false)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter_map(|entry| {
+            let kind = special_kind(entry.file_type())?;
+            Some(SpecialFile {
+                path: entry.into_path(),
+                kind,
+            })
+        })
+        .collect()
+}
+
+#[cfg(unix)]
+fn special_kind(file_type: fs::FileType) -> Option<SpecialKind> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if file_type.is_file() || file_type.is_dir() || file_type.is_symlink() {
+        None
+    } else if file_type.is_fifo() {
+        Some(SpecialKind::Fifo)
+    } else if file_type.is_socket() {
+        Some(SpecialKind::Socket)
+    } else if file_type.is_block_device() {
+        Some(SpecialKind::BlockDevice)
+    } else if file_type.is_char_device() {
+        Some(SpecialKind::CharDevice)
+    } else {
+        Some(SpecialKind::Unknown)
+    }
+}
+
+#[cfg(not(unix))]
+fn special_kind(_file_type: fs::FileType) -> Option<SpecialKind> {
+    None
+}
+
+/// Group non-directory entries of the nested tree by inode, keeping inodes
+/// reached through more than one path.
+#[cfg(unix)]
+fn detect_hard_links(nested_root: &Path) -> Vec<HardLinkGroup> {
+    use std::collections::BTreeMap;
+    use std::os::unix::fs::MetadataExt;
+
+    let mut by_inode: BTreeMap<(u64, u64), HardLinkGroup> = BTreeMap::new();
+    for entry in WalkDir::new(nested_root)
+        .follow_links(false)
+        .into_iter()
+        .filter_map(|e| e.ok())
+    {
+        let Ok(meta) = entry.metadata() else {
+            continue;
+        };
+        if meta.is_dir() || meta.nlink() < 2 {
+            continue;
+        }
+        by_inode
+            .entry((meta.dev(), meta.ino()))
+            .or_insert_with(|| HardLinkGroup {
+                paths: Vec::new(),
+                nlink: meta.nlink(),
+            })
+            .paths
+            .push(entry.into_path());
+    }
+
+    let mut groups: Vec<HardLinkGroup> = by_inode
+        .into_values()
+        .filter(|g| g.paths.len() > 1)
+        .map(|mut g| {
+            g.paths.sort();
+            g
+        })
+        .collect();
+    groups.sort_by(|a, b| a.paths.cmp(&b.paths));
+    groups
+}
+
+#[cfg(not(unix))]
+fn detect_hard_links(_nested_root: &Path) -> Vec<HardLinkGroup> {
+    Vec::new()
+}
+
+/// Check, without touching anything, that the directories receiving moves
+/// can take new entries and that each source can be read and written.
+/// Symlinks are skipped: their own mode is meaningless and the check would
+/// follow them.
+fn detect_permission_issues(moves: &[MoveRecord]) -> Vec<PathBuf> {
+    let mut issues: Vec<PathBuf> = Vec::new();
+    for dest in moves.iter().filter_map(|m| m.to.parent()) {
+        if !issues.iter().any(|d| d == dest) && !access(dest, Access::Write) {
+            issues.push(dest.to_path_buf());
+        }
+    }
+    let sources = moves
+        .iter()
+        .map(|m| &m.from)
+        .filter(|from| !from.is_symlink() && !access(from, Access::ReadWrite))
+        .cloned();
+    issues.extend(sources);
+    issues
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Access {
+    Write,
+    ReadWrite,
+}
+
+/// Whether the real user may access `path` as asked, per `access(2)`.
+/// Paths that cannot be checked (missing, or an interior NUL) pass.
+#[cfg(unix)]
+fn access(path: &Path, mode: Access) -> bool {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let Ok(c_path) = CString::new(path.as_os_str().as_bytes()) else {
+        return true;
+    };
+    let mode = match mode {
+        Access::Write => libc::W_OK,
+        Access::ReadWrite => libc::R_OK | libc::W_OK,
+    };
+    // SAFETY: `c_path` is a valid NUL-terminated string that outlives the call.
+    if unsafe { libc::access(c_path.as_ptr(), mode) } == 0 {
+        return true;
+    }
+    !matches!(
+        io::Error::last_os_error().kind(),
+        io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem
+    )
+}
+
+#[cfg(not(unix))]
+fn access(_path: &Path, _mode: Access) -> bool {
+    true
 }
 
 impl ScanReport {
-    /// Returns `true` if the scan found no blocking issues.
+    /// Returns `true` if the scan found no blocking issues. Collisions
+    /// between hard links to the same inode, or with an empty directory,
+    /// lose nothing and are not blocking.
     pub fn is_safe(&self) -> bool {
-        self.collisions.is_empty()
+        self.collisions.iter().all(|c| {
+            matches!(
+                c.kind,
+                CollisionKind::SameInode | CollisionKind::EmptyDirectory
+            )
+        })
+    }
+
+    /// Collisions whose name is in [`VCS_DIRS`] or `extra`. Flattening
+    /// these would merge one repository's metadata into another's.
+    pub fn vcs_collisions<'a>(
+        &'a self,
+        extra: &'a [String],
+    ) -> impl Iterator<Item = &'a Collision> {
+        self.collisions.iter().filter(move |c| {
+            c.existing
+                .file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|name| VCS_DIRS.contains(&name) || extra.iter().any(|e| e == name))
+        })
+    }
+}
+
+/// (De)serialization of [`ScanReport::scan_errors`]. `io::ErrorKind` has no
+/// serde support, so kinds are written by name and read back by matching
+/// the names of those a directory walk can report; any other loads as
+/// `Other`.
+mod scan_errors {
+    use std::io::ErrorKind;
+    use std::path::PathBuf;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    const KINDS: &[ErrorKind] = &[
+        ErrorKind::NotFound,
+        ErrorKind::PermissionDenied,
+        ErrorKind::NotADirectory,
+        ErrorKind::IsADirectory,
+        ErrorKind::StaleNetworkFileHandle,
+        ErrorKind::InvalidInput,
+        ErrorKind::InvalidData,
+        ErrorKind::TimedOut,
+        ErrorKind::Interrupted,
+        ErrorKind::Unsupported,
+        ErrorKind::OutOfMemory,
+    ];
+
+    #[derive(Serialize, Deserialize)]
+    struct ScanError {
+        path: PathBuf,
+        kind: String,
+    }
+
+    pub fn serialize<S: Serializer>(
+        errors: &[(PathBuf, ErrorKind)],
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        s.collect_seq(errors.iter().map(|(path, kind)| ScanError {
+            path: path.clone(),
+            kind: format!("{kind:?}"),
+        }))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        d: D,
+    ) -> Result<Vec<(PathBuf, ErrorKind)>, D::Error> {
+        let errors = Vec::<ScanError>::deserialize(d)?;
+        Ok(errors
+            .into_iter()
+            .map(|e| {
+                let kind = KINDS
+                    .iter()
+                    .copied()
+                    .find(|k| format!("{k:?}") == e.kind)
+                    .unwrap_or(ErrorKind::Other);
+                (e.path, kind)
+            })
+            .collect())
     }
 }
 
@@ -120,6 +847,10 @@ mod tests {
             parent: root.canonicalize().unwrap(),
             nested: root.canonicalize().unwrap().join("project"),
             children,
+            collapse_depth: 1,
+            via_symlink: None,
+            nested_link: None,
+            noise: vec![],
         }
     }
 
@@ -142,9 +873,336 @@ mod tests {
 
         let report = scan(&candidate);
         assert_eq!(report.collisions.len(), 1);
+        assert_eq!(report.collisions[0].kind, CollisionKind::Differing);
         assert!(!report.is_safe());
     }
 
+    #[test]
+    fn identical_content_collision_classified() {
+        let tmp = TempDir::new().unwrap();
+        let candidate = make_candidate(&tmp);
+
+        fs::write(candidate.parent.join("file.txt"), "data").unwrap();
+        // Same size, different bytes.
+        fs::create_dir(candidate.parent.join("src")).unwrap();
+        fs::write(candidate.nested.join("src/a.txt"), "aaaa").unwrap();
+        fs::write(candidate.parent.join("src/a.txt"), "bbbb").unwrap();
+
+        let report = scan(&candidate);
+        assert_eq!(report.collisions[0].kind, CollisionKind::IdenticalContent);
+        assert_eq!(report.collisions[1].kind, CollisionKind::Differing);
+        assert_eq!(
+            classify_collision(
+                &candidate.nested.join("src/a.txt"),
+                &candidate.parent.join("src/a.txt")
+            ),
+            CollisionKind::Differing
+        );
+    }
+
+    #[test]
+    fn empty_directory_collision_is_safe() {
+        let tmp = TempDir::new().unwrap();
+        let candidate = make_candidate(&tmp);
+        // The nested side is empty...
+        fs::create_dir(candidate.parent.join("src")).unwrap();
+        fs::write(candidate.parent.join("src/lib.rs"), "").unwrap();
+
+        let report = scan(&candidate);
+        assert_eq!(report.collisions[0].kind, CollisionKind::EmptyDirectory);
+        assert!(report.is_safe());
+
+        // ...or the parent's is.
+        fs::rename(
+            candidate.parent.join("src/lib.rs"),
+            candidate.nested.join("src/lib.rs"),
+        )
+        .unwrap();
+        assert_eq!(
+            scan(&candidate).collisions[0].kind,
+            CollisionKind::EmptyDirectory
+        );
+    }
+
+    #[test]
+    fn collisions_record_entry_types() {
+        let tmp = TempDir::new().unwrap();
+        let candidate = make_candidate(&tmp);
+        // `src` is a directory in the nested dir but a file in the parent.
+        fs::write(candidate.parent.join("src"), "not a dir").unwrap();
+        fs::write(candidate.parent.join("file.txt"), "conflict").unwrap();
+
+        let report = scan(&candidate);
+        let types: Vec<_> = report.collisions.iter().map(|c| c.types).collect();
+        assert_eq!(
+            types,
+            [CollisionTypes::FileVsFile, CollisionTypes::DirVsFile]
+        );
+        assert!(types[1].is_mismatch());
+
+        let json = serde_json::to_value(&report.collisions[1]).unwrap();
+        assert_eq!(json["types"], "dir_vs_file");
+    }
+
+    #[test]
+    fn moves_introducing_a_reserved_name_are_reported() {
+        let tmp = TempDir::new().unwrap();
+        let candidate = make_candidate(&tmp);
+        let options = ScanOptions {
+            reserved_names: vec!["file.txt".into(), "absent".into()],
+            ..Default::default()
+        };
+        let report = scan_with_options(&candidate, &options);
+        assert_eq!(
+            report.reserved_names,
+            vec![candidate.parent.join("file.txt")]
+        );
+        assert!(scan(&candidate).reserved_names.is_empty());
+    }
+
+    #[test]
+    fn scan_moves_checks_an_arbitrary_plan() {
+        let tmp = TempDir::new().unwrap();
+        let (src, dst) = (tmp.path().join("src"), tmp.path().join("dst"));
+        fs::create_dir_all(src.join("lib")).unwrap();
+        fs::create_dir(&dst).unwrap();
+        fs::write(src.join("a.txt"), "new").unwrap();
+        fs::write(dst.join("a.txt"), "old").unwrap();
+        // Moves along with its target, so it keeps working...
+        unix_fs::symlink("a.txt", src.join("same")).unwrap();
+        // ...unlike this one, whose target stays behind.
+        unix_fs::symlink("../a.txt", src.join("lib/up")).unwrap();
+
+        let moves = vec![
+            MoveRecord::new(src.join("a.txt"), dst.join("a.txt")),
+            MoveRecord::new(src.join("same"), dst.join("same")),
+            MoveRecord::new(src.join("lib"), dst.join("vendor/lib")),
+        ];
+        let report = scan_moves(&moves, &src);
+        assert_eq!(report.collisions.len(), 1);
+        assert_eq!(report.collisions[0].existing, dst.join("a.txt"));
+        let breaks: Vec<_> = report
+            .symlink_risks
+            .iter()
+            .filter(|r| r.breaks_after_move)
+            .map(|r| r.link.clone())
+            .collect();
+        assert_eq!(breaks, [src.join("lib/up")]);
+        // The file, plus each symlink by the length of its target.
+        assert_eq!(
+            report.space.required_bytes,
+            (3 + "a.txt".len() + "../a.txt".len()) as u64
+        );
+    }
+
+    #[test]
+    fn space_estimate_totals_file_sizes() {
+        let tmp = TempDir::new().unwrap();
+        let candidate = make_candidate(&tmp);
+        fs::write(candidate.nested.join("src/lib.rs"), "0123456789").unwrap();
+
+        let space = estimate_space(&candidate);
+        assert_eq!(space.required_bytes, 14); // "data" + "0123456789"
+        assert!(space.available_bytes.is_some());
+        assert!(space.same_device);
+        assert!(scan(&candidate).space_warning.is_none());
+    }
+
+    #[test]
+    fn space_shortfall_only_for_cross_device() {
+        let mut space = SpaceEstimate {
+            required_bytes: 100,
+            available_bytes: Some(10),
+            same_device: true,
+        };
+        assert!(space.shortfall().is_none());
+
+        space.same_device = false;
+        assert_eq!(
+            space.shortfall(),
+            Some(SpaceWarning {
+                required_bytes: 100,
+                available_bytes: 10,
+            })
+        );
+
+        space.available_bytes = None;
+        assert!(space.shortfall().is_none());
+    }
+
+    #[test]
+    fn vcs_collisions_use_builtin_and_extra_names() {
+        let tmp = TempDir::new().unwrap();
+        let candidate = make_candidate(&tmp);
+        for name in [".git", ".jj"] {
+            fs::create_dir(candidate.parent.join(name)).unwrap();
+            fs::create_dir(candidate.nested.join(name)).unwrap();
+        }
+        let candidate = NestingCandidate {
+            children: crate::analyzer::list_dir(&candidate.nested).unwrap(),
+            ..candidate
+        };
+
+        let report = scan(&candidate);
+        let names = |extra: &[String]| -> Vec<PathBuf> {
+            let mut names: Vec<_> = report
+                .vcs_collisions(extra)
+                .map(|c| c.existing.clone())
+                .collect();
+            names.sort();
+            names
+        };
+        assert_eq!(names(&[]), vec![candidate.parent.join(".git")]);
+        assert_eq!(
+            names(&[".jj".into()]),
+            vec![candidate.parent.join(".git"), candidate.parent.join(".jj")]
+        );
+    }
+
+    #[test]
+    fn special_files_detected() {
+        let tmp = TempDir::new().unwrap();
+        let candidate = make_candidate(&tmp);
+
+        let fifo = candidate.nested.join("src/pipe");
+        let status = std::process::Command::new("mkfifo")
+            .arg(&fifo)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let socket = candidate.nested.join("app.sock");
+        let _listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+
+        let report = scan(&candidate);
+        let mut found: Vec<_> = report
+            .special_files
+            .iter()
+            .map(|f| (f.path.clone(), f.kind))
+            .collect();
+        found.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            found,
+            vec![(socket, SpecialKind::Socket), (fifo, SpecialKind::Fifo)]
+        );
+    }
+
+    #[test]
+    fn same_inode_collision_is_safe() {
+        let tmp = TempDir::new().unwrap();
+        let candidate = make_candidate(&tmp);
+
+        fs::hard_link(
+            candidate.nested.join("file.txt"),
+            candidate.parent.join("file.txt"),
+        )
+        .unwrap();
+
+        let report = scan(&candidate);
+        assert_eq!(report.collisions.len(), 1);
+        assert_eq!(report.collisions[0].kind, CollisionKind::SameInode);
+        assert!(report.is_safe());
+    }
+
+    #[test]
+    fn hard_links_grouped_by_inode() {
+        let tmp = TempDir::new().unwrap();
+        let candidate = make_candidate(&tmp);
+
+        let original = candidate.nested.join("file.txt");
+        let copy = candidate.nested.join("src/file-link.txt");
+        fs::hard_link(&original, &copy).unwrap();
+        // A third link outside the tree only shows up in the count.
+        fs::hard_link(&original, tmp.path().join("outside.txt")).unwrap();
+
+        let report = scan(&candidate);
+        assert_eq!(
+            report.hard_links,
+            vec![HardLinkGroup {
+                paths: vec![original, copy],
+                nlink: 3,
+            }]
+        );
+    }
+
+    #[test]
+    fn permission_issues_flag_read_only_parent() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = TempDir::new().unwrap();
+        let candidate = make_candidate(&tmp);
+        assert!(scan(&candidate).permission_issues.is_empty());
+
+        fs::set_permissions(&candidate.parent, fs::Permissions::from_mode(0o555)).unwrap();
+        let report = scan(&candidate);
+        fs::set_permissions(&candidate.parent, fs::Permissions::from_mode(0o755)).unwrap();
+        // Root bypasses mode bits, so only an unprivileged run sees the issue.
+        if unsafe { libc::geteuid() } != 0 {
+            assert_eq!(report.permission_issues, vec![candidate.parent.clone()]);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unreadable_subtree_is_reported_as_a_scan_error() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = TempDir::new().unwrap();
+        let candidate = make_candidate(&tmp);
+        let locked = candidate.nested.join("src");
+        unix_fs::symlink("../../elsewhere", locked.join("hidden")).unwrap();
+        assert!(scan(&candidate).scan_errors.is_empty());
+
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+        let report = scan(&candidate);
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+        // Root reads the directory anyway.
+        if unsafe { libc::geteuid() } != 0 {
+            assert_eq!(
+                report.scan_errors,
+                vec![(locked, io::ErrorKind::PermissionDenied)]
+            );
+            assert!(report.symlink_risks.is_empty());
+        }
+    }
+
+    #[test]
+    fn scan_errors_are_serialized_by_kind_name() {
+        let tmp = TempDir::new().unwrap();
+        let mut report = scan(&make_candidate(&tmp));
+        report.scan_errors = vec![
+            (PathBuf::from("/a/locked"), io::ErrorKind::PermissionDenied),
+            (PathBuf::from("/a/odd"), io::ErrorKind::BrokenPipe),
+        ];
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(
+            json["scan_errors"][0],
+            serde_json::json!({"path": "/a/locked", "kind": "PermissionDenied"})
+        );
+
+        let loaded: ScanReport = serde_json::from_value(json).unwrap();
+        assert_eq!(
+            loaded.scan_errors,
+            [
+                (PathBuf::from("/a/locked"), io::ErrorKind::PermissionDenied),
+                (PathBuf::from("/a/odd"), io::ErrorKind::Other),
+            ]
+        );
+    }
+
+    #[test]
+    fn mount_points_are_boundaries() {
+        let tmp = TempDir::new().unwrap();
+        let candidate = make_candidate(&tmp);
+        assert_eq!(scan(&candidate).mount_boundary, None);
+        assert_eq!(detect_mount_boundary(Path::new("/")), None);
+
+        // /proc is its own filesystem wherever it is mounted at all.
+        let proc = Path::new("/proc");
+        if proc.join("self").exists() {
+            assert_eq!(detect_mount_boundary(proc), Some(proc.to_path_buf()));
+        }
+    }
+
     #[test]
     fn symlink_risk_detected() {
         let tmp = TempDir::new().unwrap();
@@ -158,4 +1216,118 @@ mod tests {
         let report = scan(&candidate);
         assert!(!report.symlink_risks.is_empty());
     }
+
+    #[test]
+    fn resolved_target_follows_relative_links_and_skips_dangling_ones() {
+        let tmp = TempDir::new().unwrap();
+        let candidate = make_candidate(&tmp);
+        unix_fs::symlink("../file.txt", candidate.nested.join("src/up")).unwrap();
+        unix_fs::symlink("missing", candidate.nested.join("dangling")).unwrap();
+
+        let report = scan(&candidate);
+        let resolved = |name: &str| {
+            report
+                .symlink_risks
+                .iter()
+                .find(|r| r.link.ends_with(name))
+                .unwrap()
+                .resolved_target
+                .clone()
+        };
+        assert_eq!(
+            resolved("up"),
+            Some(candidate.nested.join("file.txt").canonicalize().unwrap())
+        );
+        assert_eq!(resolved("dangling"), None);
+    }
+
+    #[test]
+    fn symlink_breakage_accounts_for_relative_targets() {
+        let tmp = TempDir::new().unwrap();
+        let candidate = make_candidate(&tmp);
+        let shared = candidate.parent.parent().unwrap().join("shared");
+        fs::create_dir(&shared).unwrap();
+
+        // Moves with its target: still fine.
+        unix_fs::symlink("file.txt", candidate.nested.join("sibling")).unwrap();
+        // Escapes the nested dir: one level up now lands somewhere else.
+        unix_fs::symlink("../shared", candidate.nested.join("escaping")).unwrap();
+        // Absolute, outside the tree: unaffected.
+        unix_fs::symlink(&shared, candidate.nested.join("absolute_out")).unwrap();
+        // Absolute, inside the tree: its target moves away.
+        unix_fs::symlink(
+            candidate.nested.join("file.txt"),
+            candidate.nested.join("absolute_in"),
+        )
+        .unwrap();
+
+        let report = scan(&candidate);
+        let risk = |name: &str| {
+            report
+                .symlink_risks
+                .iter()
+                .find(|r| r.link.ends_with(name))
+                .unwrap()
+        };
+        assert!(risk("sibling").target_inside_nested);
+        assert!(!risk("sibling").breaks_after_move);
+        assert!(!risk("escaping").target_inside_nested);
+        assert!(risk("escaping").breaks_after_move);
+        assert!(!risk("absolute_out").breaks_after_move);
+        assert!(risk("absolute_in").breaks_after_move);
+    }
+
+    #[test]
+    fn symlink_scan_depth_can_be_limited() {
+        let tmp = TempDir::new().unwrap();
+        let candidate = make_candidate(&tmp);
+        let deep = candidate.nested.join("a/b");
+        fs::create_dir_all(&deep).unwrap();
+        unix_fs::symlink("file.txt", candidate.nested.join("top")).unwrap();
+        unix_fs::symlink("../x", deep.join("deep")).unwrap();
+
+        let links = |options: &ScanOptions| {
+            scan_with_options(&candidate, options)
+                .symlink_risks
+                .into_iter()
+                .map(|r| r.link.file_name().unwrap().to_os_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(links(&ScanOptions::default()).len(), 2);
+        let shallow = ScanOptions {
+            max_depth: Some(2),
+            same_file_system: true,
+            ..Default::default()
+        };
+        assert_eq!(links(&shallow), vec!["top"]);
+    }
+
+    #[test]
+    fn cyclic_symlinks_are_flagged() {
+        let tmp = TempDir::new().unwrap();
+        let candidate = make_candidate(&tmp);
+        let sub = candidate.nested.join("sub");
+        fs::create_dir(&sub).unwrap();
+        // Back up to its own ancestor.
+        unix_fs::symlink("..", sub.join("up")).unwrap();
+        // Two links pointing at each other.
+        unix_fs::symlink("pong", candidate.nested.join("ping")).unwrap();
+        unix_fs::symlink("ping", candidate.nested.join("pong")).unwrap();
+        // An ordinary link to a sibling.
+        unix_fs::symlink("file.txt", candidate.nested.join("plain")).unwrap();
+
+        let report = scan(&candidate);
+        let cyclic = |name: &str| {
+            report
+                .symlink_risks
+                .iter()
+                .find(|r| r.link.ends_with(name))
+                .unwrap()
+                .is_cyclic
+        };
+        assert!(cyclic("up"));
+        assert!(cyclic("ping"));
+        assert!(cyclic("pong"));
+        assert!(!cyclic("plain"));
+    }
 }
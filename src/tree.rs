@@ -0,0 +1,281 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::analyzer::NestingCandidate;
+use crate::mover::FlattenPlan;
+use crate::scanner::ScanReport;
+
+const RED: &str = "\x1b[31m";
+const CYAN: &str = "\x1b[36m";
+const RESET: &str = "\x1b[0m";
+
+/// Render a candidate as a box-drawing tree: the nested directory, each
+/// child with the path it moves to, and (below `max_depth` 1) the contents
+/// of child directories down to `max_depth` levels. Colliding children are
+/// marked, symlinks show their target. With `color`, collisions are red and
+/// symlinks cyan.
+pub fn render(
+    candidate: &NestingCandidate,
+    report: &ScanReport,
+    max_depth: usize,
+    color: bool,
+) -> String {
+    let mut out = format!(
+        "{} -> {}\n",
+        candidate.nested.display(),
+        candidate.parent.display()
+    );
+    let redundant = candidate.redundant_dirs();
+    let children: Vec<&Path> = candidate
+        .children
+        .iter()
+        .map(|c| c.as_path())
+        .filter(|c| {
+            c.file_name()
+                .is_none_or(|n| !redundant.contains(&candidate.parent.join(n)))
+        })
+        .collect();
+
+    for (i, child) in children.iter().enumerate() {
+        let last = i + 1 == children.len();
+        let name = child.file_name().unwrap_or_default();
+        let dest = candidate.parent.join(name);
+        let collision = report.collisions.iter().any(|c| c.source == *child);
+
+        let mut label = entry_label(child, color);
+        write!(label, " => {}", dest.display()).unwrap();
+        if collision {
+            label = paint(&format!("{label} (collision)"), RED, color);
+        }
+        if let Some(risk) = report.symlink_risks.iter().find(|r| r.link == *child)
+            && risk.breaks_after_move
+        {
+            label.push_str(" (will break)");
+        }
+        writeln!(out, "{}{label}", connector(last)).unwrap();
+
+        if max_depth > 1 {
+            render_dir(&mut out, child, &indent("", last), max_depth - 1, color);
+        }
+    }
+    out
+}
+
+/// Append the contents of `dir` (if it is a real directory) as subtree lines.
+fn render_dir(out: &mut String, dir: &Path, prefix: &str, depth: usize, color: bool) {
+    if depth == 0 || dir.is_symlink() {
+        return;
+    }
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut entries: Vec<_> = entries.filter_map(|e| e.ok()).map(|e| e.path()).collect();
+    entries.sort();
+
+    for (i, entry) in entries.iter().enumerate() {
+        let last = i + 1 == entries.len();
+        writeln!(
+            out,
+            "{prefix}{}{}",
+            connector(last),
+            entry_label(entry, color)
+        )
+        .unwrap();
+        render_dir(out, entry, &indent(prefix, last), depth - 1, color);
+    }
+}
+
+/// The entry's name, with a `/` for directories and `@ -> target` for
+/// symlinks.
+fn entry_label(path: &Path, color: bool) -> String {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    if let Ok(target) = fs::read_link(path) {
+        return paint(&format!("{name}@ -> {}", target.display()), CYAN, color);
+    }
+    if path.is_dir() {
+        format!("{name}/")
+    } else {
+        name.into_owned()
+    }
+}
+
+/// The paths `plan` would remove and add, as diff-style lines: `-` for a
+/// path that exists now and would not afterwards, `+` for one that would
+/// appear, sorted by path and shown relative to `base` (usually the
+/// candidate's parent). Directories end in `/`.
+///
+/// The tree is walked as it is now below every path the plan touches, then
+/// the plan is replayed on that listing: each move carries its whole
+/// subtree along, deduplicated items vanish, and the emptied redundant,
+/// merged and noise paths go last. Nothing on disk is changed.
+pub fn diff(plan: &FlattenPlan, base: &Path) -> Vec<String> {
+    let mut roots: Vec<&Path> = vec![&plan.dest];
+    for record in &plan.moves {
+        roots.extend([record.from.as_path(), record.to.as_path()]);
+    }
+    roots.extend(plan.dirs_to_remove().map(PathBuf::as_path));
+    roots.extend(plan.noise.iter().map(PathBuf::as_path));
+    roots.extend(plan.nested_link.as_deref());
+
+    // Path -> whether it is a directory (symlinks are not followed).
+    let mut before = BTreeMap::new();
+    for root in roots {
+        for entry in walkdir::WalkDir::new(root)
+            .into_iter()
+            .filter_map(|e| e.ok())
+        {
+            before.insert(entry.path().to_path_buf(), entry.file_type().is_dir());
+        }
+    }
+
+    let mut after = before.clone();
+    if plan.create_dest {
+        after.insert(plan.dest.clone(), true);
+    }
+    for record in &plan.moves {
+        let moved = take_subtree(&mut after, &record.from);
+        if record.op.moves_source() {
+            take_subtree(&mut after, &record.to);
+            for (path, is_dir) in moved {
+                let rest = path.strip_prefix(&record.from).unwrap_or(Path::new(""));
+                after.insert(record.to.join(rest), is_dir);
+            }
+        }
+    }
+    for path in plan.noise.iter().chain(plan.dirs_to_remove()) {
+        take_subtree(&mut after, path);
+    }
+    if let Some(link) = &plan.nested_link {
+        after.remove(link);
+    }
+
+    let mut changes: Vec<(&PathBuf, bool, char)> = before
+        .iter()
+        .filter(|(path, _)| !after.contains_key(*path))
+        .map(|(path, is_dir)| (path, *is_dir, '-'))
+        .chain(
+            after
+                .iter()
+                .filter(|(path, _)| !before.contains_key(*path))
+                .map(|(path, is_dir)| (path, *is_dir, '+')),
+        )
+        .collect();
+    changes.sort();
+    changes
+        .into_iter()
+        .map(|(path, is_dir, sign)| {
+            let shown = path.strip_prefix(base).unwrap_or(path);
+            let slash = if is_dir { "/" } else { "" };
+            format!("{sign} {}{slash}", shown.display())
+        })
+        .collect()
+}
+
+/// Remove `root` and everything below it from `paths`, returning them.
+fn take_subtree(paths: &mut BTreeMap<PathBuf, bool>, root: &Path) -> Vec<(PathBuf, bool)> {
+    let inside: Vec<PathBuf> = paths
+        .range(root.to_path_buf()..)
+        .take_while(|(path, _)| path.starts_with(root))
+        .map(|(path, _)| path.clone())
+        .collect();
+    inside
+        .into_iter()
+        .filter_map(|path| paths.remove_entry(&path))
+        .collect()
+}
+
+fn connector(last: bool) -> &'static str {
+    if last { "└── " } else { "├── " }
+}
+
+fn indent(prefix: &str, last: bool) -> String {
+    format!("{prefix}{}", if last { "    " } else { "│   " })
+}
+
+fn paint(text: &str, code: &str, color: bool) -> String {
+    if color {
+        format!("{code}{text}{RESET}")
+    } else {
+        text.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{analyzer, scanner};
+    use tempfile::TempDir;
+
+    fn candidate(tmp: &TempDir) -> NestingCandidate {
+        let root = tmp.path().join("app");
+        let nested = root.join("app");
+        fs::create_dir_all(nested.join("src/bin")).unwrap();
+        fs::write(nested.join("src/lib.rs"), "").unwrap();
+        fs::write(nested.join("src/bin/main.rs"), "").unwrap();
+        fs::write(nested.join("README.md"), "nested").unwrap();
+        fs::write(root.join("README.md"), "parent").unwrap();
+        std::os::unix::fs::symlink("README.md", nested.join("docs")).unwrap();
+        analyzer::detect_nesting(&root).unwrap().remove(0)
+    }
+
+    #[test]
+    fn diff_moves_subtrees_and_drops_the_nested_directory() {
+        let tmp = TempDir::new().unwrap();
+        let candidate = candidate(&tmp);
+        let options = crate::mover::FlattenOptions {
+            collisions: crate::mover::CollisionPolicy::Rename,
+            ..Default::default()
+        };
+        let plan = crate::mover::plan(&candidate, &options).unwrap();
+
+        assert_eq!(
+            diff(&plan, &candidate.parent),
+            [
+                "+ README (1).md",
+                "- app/",
+                "- app/README.md",
+                "- app/docs",
+                "- app/src/",
+                "- app/src/bin/",
+                "- app/src/bin/main.rs",
+                "- app/src/lib.rs",
+                "+ docs",
+                "+ src/",
+                "+ src/bin/",
+                "+ src/bin/main.rs",
+                "+ src/lib.rs",
+            ]
+        );
+        // Nothing was touched.
+        assert!(candidate.nested.join("src/lib.rs").exists());
+    }
+
+    #[test]
+    fn renders_destinations_collisions_and_symlinks() {
+        let tmp = TempDir::new().unwrap();
+        let candidate = candidate(&tmp);
+        let report = scanner::scan(&candidate);
+        let (nested, parent) = (&candidate.nested, &candidate.parent);
+
+        let tree = render(&candidate, &report, 2, false);
+        let expected = format!(
+            "{n} -> {p}\n\
+             ├── README.md => {p}/README.md (collision)\n\
+             ├── docs@ -> README.md => {p}/docs\n\
+             └── src/ => {p}/src\n\
+             \x20   ├── bin/\n\
+             \x20   └── lib.rs\n",
+            n = nested.display(),
+            p = parent.display(),
+        );
+        assert_eq!(tree, expected);
+
+        // One level deeper reaches `bin/main.rs`; colors wrap the markers.
+        let tree = render(&candidate, &report, 3, true);
+        assert!(tree.contains("    │   └── main.rs\n"));
+        assert!(tree.contains(&format!("{RED}README.md => ")));
+        assert!(tree.contains(&format!("{CYAN}docs@ -> README.md{RESET}")));
+    }
+}
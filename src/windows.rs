@@ -0,0 +1,99 @@
+//! Windows path handling: paths past the 260-character `MAX_PATH` limit,
+//! which `fs::rename` rejects unless given in verbatim (`\\?\`) form, and
+//! directory junctions, which are reparse points but not symlinks to
+//! `Path::is_symlink`. Everything that touches the filesystem is
+//! `#[cfg(windows)]`; the string and attribute logic is plain so it can be
+//! tested anywhere.
+
+#[cfg(windows)]
+use std::borrow::Cow;
+#[cfg(windows)]
+use std::path::{Path, PathBuf};
+
+/// Longest path (in UTF-16 units, terminator included) accepted without
+/// the verbatim prefix.
+const MAX_PATH: usize = 260;
+
+const VERBATIM: &str = r"\\?\";
+
+/// `FILE_ATTRIBUTE_REPARSE_POINT`: set on symlinks, junctions and other
+/// reparse points.
+const FILE_ATTRIBUTE_REPARSE_POINT: u32 = 0x400;
+
+/// The verbatim form of an absolute `path` too long for `MAX_PATH`, with
+/// `/` separators turned into `\` (verbatim paths are not normalized).
+/// `None` if the path is short enough, already verbatim, or relative.
+pub(crate) fn verbatim(path: &str) -> Option<String> {
+    if path.encode_utf16().count() < MAX_PATH || path.starts_with(VERBATIM) {
+        return None;
+    }
+    let path = path.replace('/', r"\");
+    if let Some(share) = path.strip_prefix(r"\\") {
+        return Some(format!(r"{VERBATIM}UNC\{share}"));
+    }
+    let bytes = path.as_bytes();
+    let has_drive = bytes.len() > 2 && bytes[0].is_ascii_alphabetic() && &bytes[1..3] == br":\";
+    has_drive.then(|| format!("{VERBATIM}{path}"))
+}
+
+/// Whether file attributes mark a reparse point (symlink or junction).
+pub(crate) fn is_reparse_point(attributes: u32) -> bool {
+    attributes & FILE_ATTRIBUTE_REPARSE_POINT != 0
+}
+
+/// `path`, converted with [`verbatim`] when it needs to be.
+#[cfg(windows)]
+pub(crate) fn long_path(path: &Path) -> Cow<'_, Path> {
+    match path.to_str().and_then(verbatim) {
+        Some(long) => Cow::Owned(PathBuf::from(long)),
+        None => Cow::Borrowed(path),
+    }
+}
+
+/// Whether `path` is a symlink or any other reparse point, such as a
+/// directory junction. Not followed.
+#[cfg(windows)]
+pub(crate) fn is_link(path: &Path) -> bool {
+    use std::os::windows::fs::MetadataExt;
+
+    std::fs::symlink_metadata(path)
+        .is_ok_and(|m| m.file_type().is_symlink() || is_reparse_point(m.file_attributes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_long_absolute_paths_become_verbatim() {
+        let long = format!(r"C:\{}\file.txt", "d".repeat(300));
+        assert_eq!(verbatim(&long), Some(format!(r"\\?\{long}")));
+
+        let unc = format!(r"\\server\share\{}", "d".repeat(300));
+        assert_eq!(
+            verbatim(&unc),
+            Some(format!(r"\\?\UNC\server\share\{}", "d".repeat(300)))
+        );
+
+        let slashes = format!("C:/{}/f", "d".repeat(300));
+        assert_eq!(
+            verbatim(&slashes),
+            Some(format!(r"\\?\C:\{}\f", "d".repeat(300)))
+        );
+
+        assert_eq!(verbatim(r"C:\short\path"), None);
+        assert_eq!(verbatim(&format!(r"\\?\{long}")), None);
+        assert_eq!(verbatim(&format!(r"relative\{}", "d".repeat(300))), None);
+    }
+
+    #[test]
+    fn reparse_points_are_recognized_by_attribute() {
+        const FILE_ATTRIBUTE_DIRECTORY: u32 = 0x10;
+        // A junction: a directory that is also a reparse point.
+        assert!(is_reparse_point(
+            FILE_ATTRIBUTE_DIRECTORY | FILE_ATTRIBUTE_REPARSE_POINT
+        ));
+        assert!(!is_reparse_point(FILE_ATTRIBUTE_DIRECTORY));
+        assert!(!is_reparse_point(0x20));
+    }
+}